@@ -67,7 +67,9 @@ impl Calculator {
 
   fn send_response(&mut self, res: json::JsonValue, ctx: thunder_rs::RequestContext) {
     let s = json::stringify(res);
-    ctx.send(s);
+    if let Err(e) = ctx.send(s) {
+      println!("failed to send response: {}", e);
+    }
   }
 }
 
@@ -77,8 +79,6 @@ impl thunder_rs::Plugin for Calculator {
       .unwrap();
     self.dispatch_request(req, ctx);
   }
-  fn on_client_connect(&mut self, _channel_id: u32) { }
-  fn on_client_disconnect(&mut self, _channel_id: u32) { }
 }
 
 fn sample_plugin_init(_conf: thunder_rs::PluginConfig) -> Box<dyn thunder_rs::Plugin> {
@@ -23,7 +23,7 @@ impl thunder_rs::Plugin for SamplePlugin {
     println!("\tchannel:{0}", ctx.channel);
     println!("\tauth_token:{0}", ctx.auth_token);
 
-    // ctx.responder is a std::sync::mpsc::channel. You can clone() if necessary
+    // ctx.responder is a bounded queue to Thunder. You can clone() if necessary
     // let tx = ctx.responder.clone();
 
     std::thread::spawn(move || {
@@ -33,7 +33,7 @@ impl thunder_rs::Plugin for SamplePlugin {
       let s = r#"{"jsonrpc":"2.0", "id":4, "result":"Hello from rust"}"#
         .to_string();
 
-      ctx.responder.send(thunder_rs::Message{ channel: ctx.channel, data: s })
+      ctx.responder.send(thunder_rs::Message::new(ctx.channel, s))
         .unwrap();
 
       // RequestContext also have a convenience method
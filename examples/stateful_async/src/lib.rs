@@ -50,7 +50,7 @@ struct JsonRpcRequest {
 
 impl StatefulService {
     async fn state_async(&self) -> String {
-        String::from(self.state.clone())
+        self.state.clone()
     }
     fn set_state(&mut self, state: String) {
         self.state = state
@@ -89,8 +89,8 @@ impl thunder_rs::Plugin for StatefulPlugin {
         async_std::task::block_on(async {
             let r = tx
                 .send(PluginRequest {
-                    json: json,
-                    ctx: ctx,
+                    json,
+                    ctx,
                 })
                 .await;
             match r {
@@ -123,10 +123,7 @@ fn stateful_plugin_init(_conf: thunder_rs::PluginConfig) -> Box<dyn thunder_rs::
             println!("Sending {}", s);
             msg.ctx
                 .responder
-                .send(thunder_rs::Message {
-                    channel: msg.ctx.channel,
-                    data: s,
-                })
+                .send(thunder_rs::Message::new(msg.ctx.channel, s))
                 .unwrap();
         }
     });
@@ -0,0 +1,202 @@
+/*
+ * Copyright 2022 Comcast Cable Communications Management, LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+//! A Unix socket to ask a running host how it's doing, without restarting
+//! it or scraping its logs. `--admin=` names the socket; every line written
+//! to it is a command, answered with a line of JSON:
+//!
+//! - `status`: the plugins' names, versions and queued messages, whether
+//!   Thunder is connected and how long the host has been up
+//! - `reload`: reloads the plugin libraries, as SIGHUP does
+//! - `dump-state`: the status, with what the main loop knows of each
+//!   plugin and the journal
+//!
+//! `status` doesn't need the main loop, so it's answered while a plugin
+//! holds it up; `dump-state` says so after a while instead. The socket is
+//! made before privileges are dropped, and only its owner may use it.
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+use serde_json::{json, Value};
+
+use crate::{protocol, transport, Request};
+
+/// How long dump-state waits for the main loop
+const DUMP_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// A plugin, as status shows it.
+pub struct Plugin {
+  pub name: String,
+  pub version: String,
+  /// Its queue to Thunder
+  pub queue: thunder_rs::Responder
+}
+
+/// The socket, made but not yet answered on.
+pub struct Listener {
+  path: PathBuf,
+  #[cfg(unix)]
+  listener: std::os::unix::net::UnixListener
+}
+
+/// Makes the socket at `path`, replacing one left by a host before.
+#[cfg(unix)]
+pub fn bind(path: &Path) -> Result<Listener, String> {
+  use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+  if std::fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_socket()) {
+    let _ = std::fs::remove_file(path);
+  }
+  // Made owner only from the start, so nobody can connect before the
+  // permissions below are set
+  let umask = unsafe { libc::umask(0o177) };
+  let bound = std::os::unix::net::UnixListener::bind(path);
+  unsafe { libc::umask(umask) };
+  let listener = bound.map_err(|e| format!("failed to bind {}: {}", path.display(), e))?;
+  std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))
+    .map_err(|e| format!("failed to restrict {}: {}", path.display(), e))?;
+  info!("RUST REMOTE: admin socket at {}", path.display());
+  Ok(Listener { path: path.to_path_buf(), listener })
+}
+
+#[cfg(not(unix))]
+pub fn bind(_path: &Path) -> Result<Listener, String> {
+  Err(String::from("the admin socket isn't supported on this platform"))
+}
+
+/// The host as the socket sees it.
+pub struct Admin {
+  path: PathBuf,
+  started: Instant,
+  plugins: Mutex<Vec<Plugin>>,
+  outbound: Arc<transport::Outbound>,
+  session: protocol::Session,
+  requests: mpsc::Sender<(Option<u32>, Request)>
+}
+
+impl Admin {
+  /// Answers commands on `listener`, each connection on its own thread.
+  /// `requests` is the main loop's, for the commands it has to carry out.
+  pub fn serve(listener: Listener, plugins: Vec<Plugin>, outbound: Arc<transport::Outbound>,
+    session: protocol::Session, requests: mpsc::Sender<(Option<u32>, Request)>) -> Arc<Admin>
+  {
+    let admin = Arc::new(Admin {
+      path: listener.path.clone(),
+      started: Instant::now(),
+      plugins: Mutex::new(plugins),
+      outbound,
+      session,
+      requests
+    });
+    #[cfg(unix)]
+    {
+      let server = admin.clone();
+      std::thread::spawn(move || {
+        for stream in listener.listener.incoming() {
+          match stream {
+            Ok(stream) => {
+              let server = server.clone();
+              std::thread::spawn(move || server.answer(stream));
+            }
+            Err(e) => warn!("RUST REMOTE: admin socket: {}", e)
+          }
+        }
+      });
+    }
+    admin
+  }
+
+  /// Shows plugin `id` as reloaded.
+  pub fn set_plugin(&self, id: usize, plugin: Plugin) {
+    if let Some(old) = self.plugins.lock().unwrap().get_mut(id) {
+      *old = plugin;
+    }
+  }
+
+  /// Removes the socket, for a host that's stopping.
+  pub fn close(&self) {
+    let _ = std::fs::remove_file(&self.path);
+  }
+
+  #[cfg(unix)]
+  fn answer(&self, stream: std::os::unix::net::UnixStream) {
+    let mut writer = match stream.try_clone() {
+      Ok(writer) => writer,
+      Err(e) => {
+        warn!("RUST REMOTE: admin socket: {}", e);
+        return;
+      }
+    };
+    for line in BufReader::new(stream).lines() {
+      let Ok(line) = line else { break };
+      let command = line.trim();
+      if command.is_empty() {
+        continue;
+      }
+      info!("RUST REMOTE: admin command {}", command);
+      let reply = self.run(command);
+      if writeln!(writer, "{}", reply).is_err() {
+        break;
+      }
+    }
+  }
+
+  fn run(&self, command: &str) -> Value {
+    match command {
+      "status" => self.status(),
+      "reload" => match self.requests.send((None, Request::Reload(String::from("admin command")))) {
+        Ok(()) => json!({ "reload": "requested" }),
+        Err(_) => json!({ "error": "the host is stopping" })
+      },
+      "dump-state" => self.dump_state(),
+      _ => json!({ "error": format!("unknown command {}, try status, reload or dump-state", command) })
+    }
+  }
+
+  fn status(&self) -> Value {
+    let plugins: Vec<Value> = self.plugins.lock().unwrap().iter()
+      .map(|plugin| json!({
+        "name": plugin.name,
+        "version": plugin.version,
+        "queued": plugin.queue.queued()
+      }))
+      .collect();
+    json!({
+      "pid": std::process::id(),
+      "uptime_secs": self.started.elapsed().as_secs(),
+      "connected": self.outbound.connected(),
+      "codec": self.session.codec().name(),
+      "plugins": plugins
+    })
+  }
+
+  fn dump_state(&self) -> Value {
+    let mut state = self.status();
+    let (reply, answer) = mpsc::channel();
+    let dumped = self.requests.send((None, Request::DumpState(reply))).is_ok()
+      .then(|| answer.recv_timeout(DUMP_TIMEOUT).ok())
+      .flatten();
+    match dumped {
+      Some(Value::Object(dumped)) => state.as_object_mut().unwrap().extend(dumped),
+      _ => state["error"] = json!("the main loop didn't answer, a plugin may be holding it up")
+    }
+    state
+  }
+}
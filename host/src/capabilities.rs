@@ -0,0 +1,121 @@
+/*
+ * Copyright 2022 Comcast Cable Communications Management, LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+//! Probes the platform for optional capabilities at startup so that a
+//! feature silently falling back on one device shows up in the log.
+use std::path::Path;
+
+use log::{info, warn};
+
+/// What the platform (and this build of the host) supports.
+#[derive(Debug, Clone)]
+pub struct Capabilities {
+  /// /dev/shm is present and writable
+  pub shm: bool,
+  /// Unix domain sockets, needed to pass file descriptors with SCM_RIGHTS
+  pub scm_rights: bool,
+  /// "v1", "v2" or None when no cgroup hierarchy is mounted
+  pub cgroups: Option<&'static str>,
+  /// zstd compression is built in
+  pub zstd: bool,
+  /// zlib compression is built in
+  pub zlib: bool,
+  /// TLS backends built in
+  pub tls: Vec<&'static str>,
+  /// Vendor frames in thunder_rs::framework::CUSTOM_FRAME_IDS are passed
+  /// through to the plugin
+  pub custom_frames: bool
+}
+
+impl Capabilities {
+  pub fn probe() -> Self {
+    Capabilities {
+      shm: probe_shm(),
+      scm_rights: probe_unix_sockets(),
+      cgroups: probe_cgroups(),
+      zstd: cfg!(feature = "zstd"),
+      zlib: cfg!(feature = "zlib"),
+      tls: if cfg!(feature = "tls") { vec!["rustls"] } else { Vec::new() },
+      custom_frames: true
+    }
+  }
+
+  /// The report as JSON, in the form it's exchanged with Thunder.
+  pub fn to_json(&self) -> serde_json::Value {
+    serde_json::json!({
+      "shm": self.shm,
+      "scm_rights": self.scm_rights,
+      "cgroups": self.cgroups,
+      "zstd": self.zstd,
+      "zlib": self.zlib,
+      "tls": self.tls,
+      "custom_frames": self.custom_frames
+    })
+  }
+
+  /// Logs the whole report on one line, plus a warning for each capability
+  /// that's missing and what the host does instead.
+  pub fn report(&self) {
+    info!("RUST REMOTE: capabilities {}", self.to_json());
+    if !self.shm {
+      warn!("RUST REMOTE: shared memory unavailable, payloads are copied over the socket");
+    }
+    if !self.scm_rights {
+      warn!("RUST REMOTE: unix sockets unavailable, file descriptors can't be passed");
+    }
+    if self.cgroups.is_none() {
+      warn!("RUST REMOTE: no cgroup hierarchy, resource limits are not enforced");
+    }
+    if !self.zstd && !self.zlib {
+      warn!("RUST REMOTE: no compression built in, frames are sent uncompressed");
+    }
+    if self.tls.is_empty() {
+      warn!("RUST REMOTE: no TLS backend built in, the connection to Thunder is plaintext");
+    }
+  }
+}
+
+fn probe_shm() -> bool {
+  let path = Path::new("/dev/shm").join(format!("thunder_rs_probe_{}", std::process::id()));
+  match std::fs::File::create(&path) {
+    Ok(_) => {
+      let _ = std::fs::remove_file(&path);
+      true
+    }
+    Err(_) => false
+  }
+}
+
+#[cfg(unix)]
+fn probe_unix_sockets() -> bool {
+  std::os::unix::net::UnixStream::pair().is_ok()
+}
+
+#[cfg(not(unix))]
+fn probe_unix_sockets() -> bool {
+  false
+}
+
+fn probe_cgroups() -> Option<&'static str> {
+  if Path::new("/sys/fs/cgroup/cgroup.controllers").exists() {
+    Some("v2")
+  } else if Path::new("/sys/fs/cgroup/cpu").exists() {
+    Some("v1")
+  } else {
+    None
+  }
+}
@@ -0,0 +1,215 @@
+/*
+ * Copyright 2022 Comcast Cable Communications Management, LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+//! Puts the host, and with it the plugin it loads, in a cgroup of its own
+//! with memory and CPU limits, so a runaway plugin takes down its host
+//! rather than the whole box. `--memory-max=` takes bytes, or K, M or G of
+//! them, and `--cpu-max=` a percentage of one CPU, over 100 for more than
+//! one. The group is `--cgroup=` under the hierarchy's root, by default
+//! thunder_rs/<pid>; both cgroup v2 and the memory and cpu controllers of
+//! v1 are handled.
+//!
+//! A group can't be removed while the host is in it, so those of hosts
+//! that have exited are removed when the next one starts.
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use log::{debug, info};
+
+const ROOT: &str = "/sys/fs/cgroup";
+
+/// The scheduler period the CPU quota is a share of, in microseconds
+const CPU_PERIOD_US: u64 = 100_000;
+
+/// The limits from the command line; none set leaves the host where it was
+/// started.
+#[derive(Debug, Clone, Default)]
+pub struct Resources {
+  pub group: Option<String>,
+  /// Bytes of memory, swap included on v1
+  pub memory: Option<u64>,
+  /// Percent of one CPU
+  pub cpu: Option<u32>
+}
+
+impl Resources {
+  /// Takes a --memory-max value: bytes, or a number with a K, M or G suffix.
+  pub fn parse_memory(s: &str) -> Result<u64, String> {
+    let (digits, unit) = match s.char_indices().last() {
+      Some((i, 'K' | 'k')) => (&s[..i], 1 << 10),
+      Some((i, 'M' | 'm')) => (&s[..i], 1 << 20),
+      Some((i, 'G' | 'g')) => (&s[..i], 1 << 30),
+      _ => (s, 1)
+    };
+    digits.parse::<u64>().ok()
+      .and_then(|n| n.checked_mul(unit))
+      .filter(|n| *n > 0)
+      .ok_or_else(|| format!("invalid memory size {}", s))
+  }
+
+  /// Takes a --cpu-max value, a percentage of one CPU.
+  pub fn parse_cpu(s: &str) -> Result<u32, String> {
+    s.trim_end_matches('%').parse::<u32>().ok()
+      .filter(|n| *n > 0)
+      .ok_or_else(|| format!("invalid CPU percentage {}", s))
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.memory.is_none() && self.cpu.is_none()
+  }
+
+  /// Moves the host into its group with the limits set, on the cgroup
+  /// `version` capabilities found. Does nothing without any limits.
+  pub fn apply(&self, version: Option<&str>) -> Result<(), String> {
+    if self.is_empty() {
+      return Ok(());
+    }
+    let group = match &self.group {
+      Some(group) => group.trim_matches('/').to_string(),
+      None => format!("thunder_rs/{}", std::process::id())
+    };
+    match version {
+      Some("v2") => self.apply_v2(&group),
+      Some("v1") => self.apply_v1(&group),
+      _ => Err(String::from("no cgroup hierarchy to enforce resource limits with"))
+    }?;
+    info!("RUST REMOTE: in cgroup {} with memory_max={:?} cpu_max={:?}%", group, self.memory, self.cpu);
+    Ok(())
+  }
+
+  fn apply_v2(&self, group: &str) -> Result<(), String> {
+    let dir = Path::new(ROOT).join(group);
+    remove_stale(&dir);
+    // A controller has to be enabled in every group above the one using it
+    let mut controllers = Vec::new();
+    if self.memory.is_some() {
+      controllers.push("+memory");
+    }
+    if self.cpu.is_some() {
+      controllers.push("+cpu");
+    }
+    let mut parent = PathBuf::from(ROOT);
+    for part in Path::new(group).iter() {
+      write(&parent.join("cgroup.subtree_control"), &controllers.join(" "))?;
+      parent.push(part);
+      create(&parent)?;
+    }
+    if let Some(memory) = self.memory {
+      write(&dir.join("memory.max"), &memory.to_string())?;
+    }
+    if let Some(cpu) = self.cpu {
+      write(&dir.join("cpu.max"), &format!("{} {}", quota(cpu), CPU_PERIOD_US))?;
+    }
+    write(&dir.join("cgroup.procs"), &std::process::id().to_string())
+  }
+
+  fn apply_v1(&self, group: &str) -> Result<(), String> {
+    if let Some(memory) = self.memory {
+      let dir = Path::new(ROOT).join("memory").join(group);
+      remove_stale(&dir);
+      create(&dir)?;
+      write(&dir.join("memory.limit_in_bytes"), &memory.to_string())?;
+      // Not there without swap accounting, when the limit above is all
+      let _ = write(&dir.join("memory.memsw.limit_in_bytes"), &memory.to_string());
+      write(&dir.join("cgroup.procs"), &std::process::id().to_string())?;
+    }
+    if let Some(cpu) = self.cpu {
+      let dir = Path::new(ROOT).join("cpu").join(group);
+      remove_stale(&dir);
+      create(&dir)?;
+      write(&dir.join("cpu.cfs_period_us"), &CPU_PERIOD_US.to_string())?;
+      write(&dir.join("cpu.cfs_quota_us"), &quota(cpu).to_string())?;
+      write(&dir.join("cgroup.procs"), &std::process::id().to_string())?;
+    }
+    Ok(())
+  }
+}
+
+fn quota(cpu: u32) -> u64 {
+  CPU_PERIOD_US * cpu as u64 / 100
+}
+
+fn create(dir: &Path) -> Result<(), String> {
+  match fs::create_dir(dir) {
+    Err(e) if e.kind() != std::io::ErrorKind::AlreadyExists => {
+      Err(format!("failed to create cgroup {}: {}", dir.display(), e))
+    }
+    _ => Ok(())
+  }
+}
+
+fn write(path: &Path, value: &str) -> Result<(), String> {
+  fs::write(path, value).map_err(|e| format!("failed to write {} to {}: {}", value, path.display(), e))
+}
+
+/// Removes the groups next to `dir` left empty by hosts that have exited.
+/// The kernel refuses to remove one with a process in it.
+fn remove_stale(dir: &Path) {
+  let entries = match dir.parent().and_then(|parent| fs::read_dir(parent).ok()) {
+    Some(entries) => entries,
+    None => return
+  };
+  for entry in entries.flatten() {
+    let path = entry.path();
+    let pid = path.file_name().and_then(|name| name.to_str()).and_then(|name| name.parse::<u32>().ok());
+    if pid.is_some() && path != dir && fs::remove_dir(&path).is_ok() {
+      debug!("RUST REMOTE: removed stale cgroup {}", path.display());
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn memory_takes_bytes_or_a_unit() {
+    assert_eq!(Resources::parse_memory("4096"), Ok(4096));
+    assert_eq!(Resources::parse_memory("64K"), Ok(64 << 10));
+    assert_eq!(Resources::parse_memory("64m"), Ok(64 << 20));
+    assert_eq!(Resources::parse_memory("2G"), Ok(2 << 30));
+  }
+
+  #[test]
+  fn memory_refuses_nothing_and_nonsense() {
+    for s in ["", "0", "0M", "M", "-1", "1.5G", "64MB", "64 M"] {
+      assert!(Resources::parse_memory(s).is_err(), "{:?} was taken", s);
+    }
+  }
+
+  #[test]
+  fn memory_refuses_overflow() {
+    assert!(Resources::parse_memory(&format!("{}G", u64::MAX >> 20)).is_err());
+    assert_eq!(Resources::parse_memory(&u64::MAX.to_string()), Ok(u64::MAX));
+  }
+
+  #[test]
+  fn cpu_takes_a_percentage() {
+    assert_eq!(Resources::parse_cpu("50"), Ok(50));
+    assert_eq!(Resources::parse_cpu("250%"), Ok(250));
+    for s in ["", "0", "0%", "-5", "1.5", "%"] {
+      assert!(Resources::parse_cpu(s).is_err(), "{:?} was taken", s);
+    }
+  }
+
+  #[test]
+  fn quota_is_a_share_of_the_period() {
+    assert_eq!(quota(100), CPU_PERIOD_US);
+    assert_eq!(quota(50), CPU_PERIOD_US / 2);
+    assert_eq!(quota(250), CPU_PERIOD_US * 5 / 2);
+  }
+}
@@ -0,0 +1,230 @@
+/*
+ * Copyright 2022 Comcast Cable Communications Management, LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+//! The host's command line. Thunder starts it as `WPEHost <library>
+//! <address> <port> [options]`, which still works; `--library` and
+//! `--connect host:port` say the same by name. Whichever comes last wins,
+//! as for every option given twice, so those on the command line win over
+//! those from a config file, see config.rs. A bad command line is reported
+//! with the usage and exits with status 2, before anything is started.
+use std::net::IpAddr;
+use std::path::PathBuf;
+
+use clap::error::ErrorKind;
+use clap::{CommandFactory, Parser};
+
+use thunder_rs_wire::Limits;
+
+use crate::{cgroup, isolation, seccomp};
+
+/// Runs thunder_rs plugins out of process for Thunder.
+#[derive(Debug, Parser)]
+#[command(name = "WPEHost", version, args_override_self = true)]
+pub struct Cli {
+  /// Plugin libraries, as for --library
+  #[arg(value_name = "LIBRARY", overrides_with = "library")]
+  library_arg: Option<String>,
+  /// Thunder's address, as for --connect
+  #[arg(value_name = "ADDRESS", overrides_with = "connect")]
+  address_arg: Option<String>,
+  /// Thunder's port, as for --connect
+  #[arg(value_name = "PORT", overrides_with = "connect")]
+  port_arg: Option<String>,
+
+  /// Plugin libraries, separated by commas; path#name picks a plugin from a
+  /// library exporting several
+  #[arg(long, value_name = "PATHS", overrides_with = "library_arg")]
+  library: Option<String>,
+  /// Where Thunder is: host:port, vsock:cid:port or a Unix socket's path
+  #[arg(long, value_name = "HOST:PORT", overrides_with_all = ["address_arg", "port_arg"])]
+  connect: Option<String>,
+  /// Settings from a TOML or JSON file, also named by THUNDER_RS_HOST_CONFIG
+  #[arg(long, value_name = "FILE")]
+  pub config: Option<PathBuf>,
+
+  /// Wait for Thunder to connect instead of connecting
+  #[arg(long)]
+  pub listen: bool,
+  /// Serve the link as a gRPC service
+  #[arg(long)]
+  pub grpc: bool,
+  /// Talk to Thunder over stdin and stdout
+  #[arg(long)]
+  pub stdio: bool,
+  /// Talk to Thunder over an inherited socket
+  #[arg(long, value_name = "FD")]
+  pub fd: Option<i32>,
+  /// Serve the plugins to WebSocket clients as JSON-RPC, without Thunder, at
+  /// ADDRESS:PORT or 127.0.0.1:9998
+  #[arg(long, value_name = "ADDRESS:PORT", num_args = 0..=1, require_equals = true,
+    default_missing_value = "127.0.0.1:9998")]
+  pub standalone: Option<std::net::SocketAddr>,
+  /// Play back traffic recorded with --record to the plugins, in place of
+  /// Thunder
+  #[arg(long, value_name = "FILE")]
+  pub replay: Option<PathBuf>,
+  /// Record the traffic to and from Thunder to FILE
+  #[arg(long, value_name = "FILE")]
+  pub record: Option<PathBuf>,
+  /// Addresses Thunder may connect from when listening
+  #[arg(long, value_name = "IPS", value_delimiter = ',')]
+  pub allow: Vec<IpAddr>,
+  /// Times to try reconnecting when the connection drops, 0 for never
+  #[arg(long, value_name = "N")]
+  pub retries: Option<u32>,
+  /// Answer status and admin commands on a Unix socket at PATH
+  #[arg(long, value_name = "PATH")]
+  pub admin: Option<PathBuf>,
+  /// Serve the host's and the plugins' metrics to Prometheus at ADDRESS
+  #[arg(long, value_name = "ADDRESS:PORT")]
+  pub metrics: Option<std::net::SocketAddr>,
+
+  /// The plugin to run from a library exporting several
+  #[arg(long, value_name = "NAME")]
+  pub plugin: Option<String>,
+  /// Wait for Thunder's config before creating the plugins
+  #[arg(long)]
+  pub await_config: bool,
+
+  /// What to log, as "level,target=level"; overrides THUNDER_RS_LOG
+  #[arg(long, value_name = "LEVELS")]
+  pub log_level: Option<String>,
+
+  /// Largest security token accepted, in bytes
+  #[arg(long, value_name = "BYTES")]
+  max_token: Option<u32>,
+  /// Largest request, binary data or body accepted, in bytes
+  #[arg(long, value_name = "BYTES")]
+  max_payload: Option<u32>,
+
+  /// Memory limit, in bytes or with a K, M or G suffix
+  #[arg(long, value_name = "SIZE", value_parser = cgroup::Resources::parse_memory)]
+  memory_max: Option<u64>,
+  /// CPU limit, in percent of one CPU
+  #[arg(long, value_name = "PERCENT", value_parser = cgroup::Resources::parse_cpu)]
+  cpu_max: Option<u32>,
+  /// The cgroup to apply limits in, under /sys/fs/cgroup
+  #[arg(long, value_name = "PATH")]
+  cgroup: Option<String>,
+
+  /// Filter system calls, doing this to those not allowed
+  #[arg(long, value_name = "kill|errno|log", value_parser = seccomp::Action::parse)]
+  seccomp: Option<seccomp::Action>,
+  /// System calls allowed besides the built in list, by name or number
+  #[arg(long, value_name = "CALLS", value_delimiter = ',', value_parser = seccomp::Filter::parse_call)]
+  seccomp_allow: Vec<i64>,
+  /// The user to switch to once connected
+  #[arg(long, value_name = "USER")]
+  pub user: Option<String>,
+  /// The group to switch to once connected
+  #[arg(long, value_name = "GROUP")]
+  pub group: Option<String>,
+  /// Namespaces to enter new ones of
+  #[arg(long, value_name = "NAMESPACES", value_delimiter = ',', value_parser = ["mount", "net", "pid"])]
+  unshare: Vec<String>,
+  /// The root to change to before loading the libraries
+  #[arg(long, value_name = "DIR")]
+  chroot: Option<PathBuf>,
+
+  /// Print the frame protocol's description as JSON
+  #[arg(long)]
+  pub protocol_doc: bool,
+  /// Print the protobuf schema of the frames
+  #[cfg(feature = "protobuf")]
+  #[arg(long)]
+  pub protocol_proto: bool
+}
+
+impl Cli {
+  /// Parses `args`, exiting with the usage if they won't do.
+  pub fn parse_args(args: &[String]) -> Cli {
+    let cli = Cli::parse_from(args);
+    #[cfg(feature = "protobuf")]
+    let printing = cli.protocol_doc || cli.protocol_proto;
+    #[cfg(not(feature = "protobuf"))]
+    let printing = cli.protocol_doc;
+    if printing {
+      return cli;
+    }
+    if cli.libraries().is_none() {
+      Cli::command().error(ErrorKind::MissingRequiredArgument, "no plugin library, give --library or LIBRARY").exit();
+    }
+    if let Err(e) = cli.address() {
+      Cli::command().error(ErrorKind::MissingRequiredArgument, e).exit();
+    }
+    cli
+  }
+
+  /// The libraries, with or without --library.
+  pub fn libraries(&self) -> Option<&str> {
+    self.library.as_deref().or(self.library_arg.as_deref())
+  }
+
+  /// Thunder's address and port, with or without --connect. With --stdio,
+  /// --fd, --standalone or --replay there are none to give, and they're left
+  /// empty.
+  pub fn address(&self) -> Result<(String, String), String> {
+    if let Some(connect) = &self.connect {
+      return Ok(match connect.rsplit_once(':') {
+        Some((address, port)) => (address.to_string(), port.to_string()),
+        // A Unix socket's path, with no port
+        None => (connect.clone(), String::new())
+      });
+    }
+    match (&self.address_arg, &self.port_arg) {
+      (Some(address), Some(port)) => Ok((address.clone(), port.clone())),
+      (Some(address), None) if address.contains('/') => Ok((address.clone(), String::new())),
+      (None, _) if self.stdio || self.fd.is_some() || self.standalone.is_some() || self.replay.is_some() => {
+        Ok((String::new(), String::new()))
+      }
+      _ => Err(String::from("no address for Thunder, give --connect or ADDRESS and PORT"))
+    }
+  }
+
+  pub fn limits(&self) -> Limits {
+    let default = Limits::default();
+    Limits {
+      max_token: self.max_token.unwrap_or(default.max_token),
+      max_payload: self.max_payload.unwrap_or(default.max_payload)
+    }
+  }
+
+  pub fn resources(&self) -> cgroup::Resources {
+    cgroup::Resources {
+      group: self.cgroup.clone(),
+      memory: self.memory_max,
+      cpu: self.cpu_max
+    }
+  }
+
+  pub fn filter(&self) -> seccomp::Filter {
+    seccomp::Filter {
+      action: self.seccomp,
+      allow: self.seccomp_allow.clone()
+    }
+  }
+
+  pub fn isolation(&self) -> isolation::Isolation {
+    let unshare = |namespace: &str| self.unshare.iter().any(|n| n == namespace);
+    isolation::Isolation {
+      mount: unshare("mount"),
+      net: unshare("net"),
+      pid: unshare("pid"),
+      root: self.chroot.clone()
+    }
+  }
+}
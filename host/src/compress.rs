@@ -0,0 +1,99 @@
+/*
+ * Copyright 2022 Comcast Cable Communications Management, LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+//! Compression of large outgoing payloads. Each algorithm built in is
+//! offered in the hello as a feature; once Thunder agrees to one, payloads
+//! of at least THUNDER_RS_COMPRESS_THRESHOLD bytes (64 KiB by default) are
+//! compressed and flagged with FLAG_COMPRESSED. When Thunder agrees to both,
+//! zstd is used. Payloads that don't shrink go out as they are.
+use std::env;
+
+/// Payloads smaller than this aren't worth compressing.
+const DEFAULT_THRESHOLD: usize = 64 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Algorithm {
+  Zstd,
+  Zlib
+}
+
+impl Algorithm {
+  /// Every algorithm, preferred first.
+  pub const ALL: [Algorithm; 2] = [Algorithm::Zstd, Algorithm::Zlib];
+
+  /// The feature agreeing to it in the handshake.
+  pub fn feature(self) -> &'static str {
+    match self {
+      Algorithm::Zstd => "zstd_payloads",
+      Algorithm::Zlib => "zlib_payloads"
+    }
+  }
+
+  pub fn from_feature(feature: &str) -> Option<Algorithm> {
+    Algorithm::ALL.into_iter().find(|a| a.feature() == feature)
+  }
+
+  /// Whether this build of the host has it.
+  pub fn built_in(self) -> bool {
+    match self {
+      Algorithm::Zstd => cfg!(feature = "zstd"),
+      Algorithm::Zlib => cfg!(feature = "zlib")
+    }
+  }
+
+  #[cfg_attr(not(any(feature = "zstd", feature = "zlib")), allow(unused_variables))]
+  fn compress(self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+    match self {
+      #[cfg(feature = "zstd")]
+      Algorithm::Zstd => zstd::bulk::compress(data, 1),
+      #[cfg(feature = "zlib")]
+      Algorithm::Zlib => {
+        use std::io::Write;
+        let mut encoder = flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::fast());
+        encoder.write_all(data)?;
+        encoder.finish()
+      }
+      #[allow(unreachable_patterns)]
+      _ => Err(std::io::Error::new(std::io::ErrorKind::Unsupported, format!("{:?} isn't built in", self)))
+    }
+  }
+}
+
+/// Compresses payloads with the agreed algorithm.
+#[derive(Debug, Clone, Copy)]
+pub struct Compressor {
+  pub algorithm: Algorithm,
+  pub threshold: usize
+}
+
+impl Compressor {
+  /// The compressed payload, or None if it's better sent as it is.
+  pub fn apply(&self, data: &[u8]) -> Option<Vec<u8>> {
+    if data.len() < self.threshold {
+      return None;
+    }
+    self.algorithm.compress(data).ok()
+      .filter(|compressed| compressed.len() < data.len())
+  }
+}
+
+/// The smallest payload compressed, from THUNDER_RS_COMPRESS_THRESHOLD.
+pub fn threshold() -> usize {
+  env::var("THUNDER_RS_COMPRESS_THRESHOLD").ok()
+    .and_then(|s| s.parse().ok())
+    .unwrap_or(DEFAULT_THRESHOLD)
+}
@@ -0,0 +1,260 @@
+/*
+ * Copyright 2022 Comcast Cable Communications Management, LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+//! The host's settings from a file, for fleets configured declaratively.
+//! `--config=` or THUNDER_RS_HOST_CONFIG names it; it's JSON if it ends in
+//! .json and TOML otherwise, e.g.
+//!
+//! ```toml
+//! [plugin]
+//! library = ["/usr/lib/libfoo.so", "/usr/lib/libbar.so#Bar"]
+//! await_config = true
+//!
+//! [transport]
+//! address = "127.0.0.1"
+//! port = 5555
+//!
+//! [logging]
+//! level = "info,thunder_rs::wire=debug"
+//!
+//! [limits]
+//! max_payload = 1048576
+//! memory_max = "64M"
+//!
+//! [sandbox]
+//! user = "plugin"
+//! seccomp = "kill"
+//!
+//! [env]
+//! THUNDER_RS_RECONNECT_ATTEMPTS = 5
+//! ```
+//!
+//! Each setting stands for the command line option of the same name, with
+//! `library` for --library and `address` and `port` for --connect, so the
+//! file is turned into options and those on the command line go after them,
+//! where they win, see cli.rs. `logging` and `env` set environment variables
+//! the environment doesn't already have.
+use std::env;
+use std::fs;
+use std::path::Path;
+
+use serde_json::{Map, Value};
+
+/// A section's settings and the option each stands for; `None` for those
+/// that make up --library and --connect or go into the environment
+type Settings = &'static [(&'static str, Option<&'static str>)];
+
+const SECTIONS: &[(&str, Settings)] = &[
+  ("plugin", &[("library", None), ("name", Some("--plugin")), ("await_config", Some("--await-config"))]),
+  ("transport", &[("address", None), ("port", None), ("listen", Some("--listen")), ("grpc", Some("--grpc")),
+    ("stdio", Some("--stdio")), ("fd", Some("--fd")), ("standalone", Some("--standalone")),
+    ("replay", Some("--replay")), ("record", Some("--record")),
+    ("allow", Some("--allow")), ("admin", Some("--admin")), ("metrics", Some("--metrics"))]),
+  ("logging", &[("level", None), ("payloads", None), ("output", None)]),
+  ("limits", &[("max_token", Some("--max-token")), ("max_payload", Some("--max-payload")),
+    ("memory_max", Some("--memory-max")), ("cpu_max", Some("--cpu-max")), ("cgroup", Some("--cgroup"))]),
+  ("sandbox", &[("user", Some("--user")), ("group", Some("--group")), ("seccomp", Some("--seccomp")),
+    ("seccomp_allow", Some("--seccomp-allow")), ("unshare", Some("--unshare")), ("chroot", Some("--chroot"))]),
+  ("env", &[])
+];
+
+/// The host's arguments, those from the config file first. Without a
+/// config file they're the command line as it is. Sets the environment the
+/// file asks for, so has to be called before any thread is started.
+pub fn args() -> Result<Vec<String>, String> {
+  let cli: Vec<String> = env::args().collect();
+  let given = cli.iter().enumerate().find_map(|(i, arg)| match arg.strip_prefix("--config") {
+    Some("") => cli.get(i + 1).cloned(),
+    Some(path) => path.strip_prefix('=').map(str::to_string),
+    None => None
+  });
+  let path = match given {
+    Some(path) => path,
+    None => match env::var("THUNDER_RS_HOST_CONFIG") {
+      Ok(path) if !path.is_empty() => path,
+      _ => return Ok(cli)
+    }
+  };
+  let config = load(Path::new(&path)).map_err(|e| format!("{}: {}", path, e))?;
+  to_args(cli, &config).map_err(|e| format!("{}: {}", path, e))
+}
+
+/// `cli` with the options `config` stands for put before its arguments,
+/// setting the environment it asks for.
+fn to_args(cli: Vec<String>, config: &Map<String, Value>) -> Result<Vec<String>, String> {
+  let (mut library, mut address, mut port) = (None, None, None);
+  let mut flags = Vec::new();
+  for (section, settings) in config {
+    let known = SECTIONS.iter().find(|(name, _)| name == section)
+      .map(|(_, known)| *known)
+      .ok_or_else(|| format!("unknown section [{}]", section))?;
+    let settings = settings.as_object().ok_or_else(|| format!("[{}] isn't a table", section))?;
+    for (key, value) in settings {
+      let arg = if section == "env" {
+        None
+      } else {
+        known.iter().find(|(name, _)| name == key)
+          .ok_or_else(|| format!("unknown setting {} in [{}]", key, section))?.1
+      };
+      let value = to_arg(value).ok_or_else(|| format!("invalid {} in [{}]", key, section))?;
+      match (section.as_str(), key.as_str()) {
+        ("env", name) => set_default(name, &value),
+        ("plugin", "library") => library = Some(value),
+        ("transport", "address") => address = Some(value),
+        ("transport", "port") => port = Some(value),
+        ("logging", "level") => set_default("THUNDER_RS_LOG", &value),
+        ("logging", "payloads") => set_default("THUNDER_RS_LOG_PAYLOADS", if value == "true" { "1" } else { "0" }),
+        ("logging", "output") => set_default("THUNDER_RS_PLUGIN_OUTPUT", &value),
+        _ => match (arg, value.as_str()) {
+          // Switches are given or not
+          (Some(arg), "true") => flags.push(arg.to_string()),
+          (Some(_), "false") | (None, _) => (),
+          (Some(arg), _) => flags.push(format!("{}={}", arg, value))
+        }
+      }
+    }
+  }
+
+  let mut args = vec![cli[0].clone()];
+  if let Some(library) = library {
+    args.push(format!("--library={}", library));
+  }
+  // Thunder's address and port go together, as for --connect
+  match (address, port) {
+    (Some(address), Some(port)) => args.push(format!("--connect={}:{}", address, port)),
+    (Some(address), None) => args.push(format!("--connect={}", address)),
+    (None, Some(_)) => return Err(String::from("transport.port without transport.address")),
+    (None, None) => ()
+  }
+  args.extend(flags);
+  args.extend(cli.into_iter().skip(1));
+  Ok(args)
+}
+
+fn load(path: &Path) -> Result<Map<String, Value>, String> {
+  let text = fs::read_to_string(path).map_err(|e| e.to_string())?;
+  let value: Value = if path.extension().is_some_and(|ext| ext == "json") {
+    serde_json::from_str(&text).map_err(|e| e.to_string())?
+  } else {
+    toml::from_str(&text).map_err(|e| e.to_string())?
+  };
+  match value {
+    Value::Object(map) => Ok(map),
+    _ => Err(String::from("not a table of sections"))
+  }
+}
+
+/// A setting as it's written on the command line; lists are separated by
+/// commas, as the host takes them.
+fn to_arg(value: &Value) -> Option<String> {
+  match value {
+    Value::String(s) => Some(s.clone()),
+    Value::Number(n) => Some(n.to_string()),
+    Value::Bool(b) => Some(b.to_string()),
+    Value::Array(items) => items.iter()
+      .map(|item| match item {
+        Value::Array(_) | Value::Object(_) => None,
+        item => to_arg(item)
+      })
+      .collect::<Option<Vec<_>>>()
+      .map(|items| items.join(",")),
+    _ => None
+  }
+}
+
+fn set_default(name: &str, value: &str) {
+  if env::var_os(name).is_none() {
+    env::set_var(name, value);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn cli(args: &[&str]) -> Vec<String> {
+    std::iter::once("WPEHost").chain(args.iter().copied()).map(String::from).collect()
+  }
+
+  fn toml(text: &str) -> Map<String, Value> {
+    match toml::from_str(text).unwrap() {
+      Value::Object(map) => map,
+      _ => unreachable!()
+    }
+  }
+
+  #[test]
+  fn settings_become_options_before_the_command_line() {
+    let config = toml(r#"
+      [plugin]
+      library = ["/usr/lib/libfoo.so", "/usr/lib/libbar.so#Bar"]
+      await_config = true
+
+      [transport]
+      address = "127.0.0.1"
+      port = 5555
+
+      [limits]
+      max_payload = 1048576
+      memory_max = "64M"
+    "#);
+    let args = to_args(cli(&["--max-payload=10"]), &config).unwrap();
+    assert_eq!(args, cli(&["--library=/usr/lib/libfoo.so,/usr/lib/libbar.so#Bar", "--connect=127.0.0.1:5555",
+      "--max-payload=1048576", "--memory-max=64M", "--await-config", "--max-payload=10"]));
+  }
+
+  #[test]
+  fn switches_set_false_are_left_out() {
+    let config = toml("[plugin]\nawait_config = false\n[transport]\nstdio = true\n");
+    assert_eq!(to_args(cli(&[]), &config).unwrap(), cli(&["--stdio"]));
+  }
+
+  #[test]
+  fn address_goes_without_a_port() {
+    let config = toml("[transport]\naddress = \"/run/thunder.sock\"\n");
+    assert_eq!(to_args(cli(&[]), &config).unwrap(), cli(&["--connect=/run/thunder.sock"]));
+  }
+
+  #[test]
+  fn port_needs_an_address() {
+    let config = toml("[transport]\nport = 5555\n");
+    assert_eq!(to_args(cli(&[]), &config).unwrap_err(), "transport.port without transport.address");
+  }
+
+  #[test]
+  fn unknown_sections_and_settings_are_refused() {
+    assert_eq!(to_args(cli(&[]), &toml("[plugins]\nlibrary = \"x\"\n")).unwrap_err(), "unknown section [plugins]");
+    assert_eq!(to_args(cli(&[]), &toml("[plugin]\nlibary = \"x\"\n")).unwrap_err(),
+      "unknown setting libary in [plugin]");
+  }
+
+  #[test]
+  fn nested_values_are_refused() {
+    let config = toml("[limits]\nmax_payload = { size = 1 }\n");
+    assert_eq!(to_args(cli(&[]), &config).unwrap_err(), "invalid max_payload in [limits]");
+    assert_eq!(to_arg(&serde_json::json!([1, [2]])), None);
+  }
+
+  #[test]
+  fn env_doesnt_override_the_environment() {
+    env::set_var("THUNDER_RS_CONFIG_TEST_SET", "outside");
+    let config = toml("[env]\nTHUNDER_RS_CONFIG_TEST_SET = \"file\"\nTHUNDER_RS_CONFIG_TEST_UNSET = 5\n");
+    assert_eq!(to_args(cli(&[]), &config).unwrap(), cli(&[]));
+    assert_eq!(env::var("THUNDER_RS_CONFIG_TEST_SET").unwrap(), "outside");
+    assert_eq!(env::var("THUNDER_RS_CONFIG_TEST_UNSET").unwrap(), "5");
+  }
+}
@@ -0,0 +1,317 @@
+/*
+ * Copyright 2022 Comcast Cable Communications Management, LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+//! Leaves a report behind when the host crashes, so a crash on a device in
+//! the field can be diagnosed afterwards. With THUNDER_RS_CRASH_DIR set,
+//! a panic in the host, or a fatal signal (SIGSEGV, SIGBUS, SIGILL, SIGFPE,
+//! SIGABRT), writes crash-<pid>-<unix seconds>.txt there with what happened
+//! and a backtrace of the thread it happened on. With the minidump feature a
+//! fatal signal also writes a Breakpad minidump of the whole process next to
+//! it, for minidump_stackwalk.
+//!
+//! The signal handler then restores the default action and raises the
+//! signal again, so the process still dies of it and the system keeps a core
+//! as usual. Little is safe to do in a signal handler: the report is created
+//! in the directory install opened and its first lines are written with
+//! async-signal-safe calls only. The backtrace and the minidump after them
+//! aren't safe, so an alarm is armed first, and if they hang on a lock the
+//! crashed thread held it raises the signal again all the same. A panic a
+//! plugin catches itself, see hosted.rs, isn't a crash and leaves no report,
+//! and neither does a stack overflow, with no stack left to write one on.
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::{info, warn};
+
+static DIR: OnceLock<PathBuf> = OnceLock::new();
+
+/// Starts writing crash reports to THUNDER_RS_CRASH_DIR, creating it if
+/// need be. Does nothing if it isn't set. Called once the panic hook the
+/// logger uses is set, which the report's is chained to.
+pub fn install() {
+  let dir = match std::env::var_os("THUNDER_RS_CRASH_DIR") {
+    Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+    _ => return
+  };
+  if let Err(e) = fs::create_dir_all(&dir) {
+    warn!("RUST REMOTE: not writing crash reports to {}: {}", dir.display(), e);
+    return;
+  }
+  info!("RUST REMOTE: writing crash reports to {}", dir.display());
+  imp::open(&dir);
+  let _ = DIR.set(dir);
+
+  let previous = std::panic::take_hook();
+  std::panic::set_hook(Box::new(move |info| {
+    report(&format!("{}", info));
+    previous(info);
+  }));
+  imp::install();
+}
+
+/// Where the report for a crash now goes, without an extension.
+fn path(dir: &Path) -> PathBuf {
+  let secs = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+  dir.join(format!("crash-{}-{}", std::process::id(), secs))
+}
+
+/// Writes `what` and a backtrace of this thread to a new report.
+fn report(what: &str) -> Option<PathBuf> {
+  let path = path(DIR.get()?);
+  let thread = std::thread::current();
+  let text = format!("{}\nthread: {}\nhost: {} {}\n\n{}\n", what, thread.name().unwrap_or("<unnamed>"),
+    env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"), std::backtrace::Backtrace::force_capture());
+  let written = fs::File::create(path.with_extension("txt"))
+    .and_then(|mut file| file.write_all(text.as_bytes()));
+  written.ok().map(|_| path)
+}
+
+#[cfg(unix)]
+mod imp {
+  use std::io::Write;
+  use std::os::fd::IntoRawFd;
+  use std::path::Path;
+  use std::sync::atomic::{AtomicI32, Ordering};
+
+  use log::warn;
+
+  /// THUNDER_RS_CRASH_DIR, opened ahead so the handler needn't look it up
+  static DIR_FD: AtomicI32 = AtomicI32::new(-1);
+  /// The signal being handled, for the watchdog to raise again
+  static CRASHING: AtomicI32 = AtomicI32::new(0);
+  /// How long the parts of a report that aren't async-signal-safe may take,
+  /// longer than a minidump's child is given
+  const WATCHDOG_SECS: libc::c_uint = 45;
+
+  const SIGNALS: [(libc::c_int, &str); 5] = [
+    (libc::SIGSEGV, "SIGSEGV"),
+    (libc::SIGBUS, "SIGBUS"),
+    (libc::SIGILL, "SIGILL"),
+    (libc::SIGFPE, "SIGFPE"),
+    (libc::SIGABRT, "SIGABRT")
+  ];
+
+  pub fn open(dir: &Path) {
+    match std::fs::File::open(dir) {
+      Ok(dir) => DIR_FD.store(dir.into_raw_fd(), Ordering::Release),
+      Err(e) => warn!("RUST REMOTE: no crash report for fatal signals in {}: {}", dir.display(), e)
+    }
+  }
+
+  pub fn install() {
+    for (signal, name) in SIGNALS {
+      unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = handle as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        // On the thread's own stack, as the alternate one std sets up is
+        // too small for a backtrace; reset on entry, so a crash in here
+        // ends it
+        action.sa_flags = libc::SA_RESETHAND;
+        libc::sigemptyset(&mut action.sa_mask);
+        if libc::sigaction(signal, &action, std::ptr::null_mut()) != 0 {
+          warn!("RUST REMOTE: no crash report for {}: {}", name, std::io::Error::last_os_error());
+        }
+      }
+    }
+  }
+
+  /// Text built on the stack, as the handler can't allocate.
+  struct Text {
+    buf: [u8; 256],
+    len: usize
+  }
+
+  impl Text {
+    fn new() -> Self {
+      Text { buf: [0; 256], len: 0 }
+    }
+
+    /// Appends `s`, cut short if the buffer is full.
+    fn push(&mut self, s: &[u8]) -> &mut Self {
+      let n = s.len().min(self.buf.len() - self.len);
+      self.buf[self.len..self.len + n].copy_from_slice(&s[..n]);
+      self.len += n;
+      self
+    }
+
+    /// Appends `n` in decimal.
+    fn number(&mut self, mut n: u64) -> &mut Self {
+      let mut digits = [0u8; 20];
+      let mut i = digits.len();
+      loop {
+        i -= 1;
+        digits[i] = b'0' + (n % 10) as u8;
+        n /= 10;
+        if n == 0 {
+          break;
+        }
+      }
+      self.push(&digits[i..])
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+      &self.buf[..self.len]
+    }
+  }
+
+  /// A file descriptor written to with write(2) alone.
+  struct Fd(libc::c_int);
+
+  impl Write for Fd {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+      match unsafe { libc::write(self.0, buf.as_ptr() as *const libc::c_void, buf.len()) } {
+        n if n < 0 => Err(std::io::Error::last_os_error()),
+        n => Ok(n as usize)
+      }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+      Ok(())
+    }
+  }
+
+  /// Raises the crashing signal again once the report has taken too long.
+  extern "C" fn expire(_: libc::c_int) {
+    let signal = CRASHING.load(Ordering::Acquire);
+    unsafe {
+      // Blocked while its own handler runs
+      let mut set: libc::sigset_t = std::mem::zeroed();
+      libc::sigemptyset(&mut set);
+      libc::sigaddset(&mut set, signal);
+      libc::signal(signal, libc::SIG_DFL);
+      libc::pthread_sigmask(libc::SIG_UNBLOCK, &set, std::ptr::null_mut());
+      libc::raise(signal);
+      libc::_exit(128 + signal);
+    }
+  }
+
+  extern "C" fn handle(signal: libc::c_int) {
+    CRASHING.store(signal, Ordering::Release);
+    let name = SIGNALS.iter().find(|(s, _)| *s == signal).map_or("signal", |(_, name)| name);
+
+    // crash-<pid>-<unix seconds>, as for a panic
+    let mut now: libc::timespec = unsafe { std::mem::zeroed() };
+    unsafe { libc::clock_gettime(libc::CLOCK_REALTIME, &mut now) };
+    let mut stem = Text::new();
+    stem.push(b"crash-").number(unsafe { libc::getpid() } as u64).push(b"-").number(now.tv_sec as u64);
+    let mut file = Text::new();
+    file.push(stem.as_bytes()).push(b".txt\0");
+    let fd = unsafe {
+      libc::openat(DIR_FD.load(Ordering::Acquire), file.as_bytes().as_ptr() as *const libc::c_char,
+        libc::O_WRONLY | libc::O_CREAT | libc::O_TRUNC | libc::O_CLOEXEC, 0o644 as libc::c_uint)
+    };
+    if fd >= 0 {
+      let mut head = Text::new();
+      head.push(b"fatal signal ").push(name.as_bytes())
+        .push(b"\nthread: ").number(unsafe { libc::syscall(libc::SYS_gettid) } as u64)
+        .push(b"\nhost: ").push(env!("CARGO_PKG_NAME").as_bytes()).push(b" ").push(env!("CARGO_PKG_VERSION").as_bytes())
+        .push(b"\n\n");
+      let _ = Fd(fd).write_all(head.as_bytes());
+    }
+
+    unsafe {
+      let mut action: libc::sigaction = std::mem::zeroed();
+      action.sa_sigaction = expire as extern "C" fn(libc::c_int) as libc::sighandler_t;
+      libc::sigemptyset(&mut action.sa_mask);
+      libc::sigaction(libc::SIGALRM, &action, std::ptr::null_mut());
+      libc::alarm(WATCHDOG_SECS);
+    }
+    if fd >= 0 {
+      let _ = writeln!(Fd(fd), "{}", std::backtrace::Backtrace::force_capture());
+      unsafe { libc::close(fd) };
+    }
+    #[cfg(all(feature = "minidump", any(target_os = "linux", target_os = "android")))]
+    if let Some(dir) = super::DIR.get() {
+      use std::os::unix::ffi::OsStrExt;
+      minidump::write(&dir.join(std::ffi::OsStr::from_bytes(stem.as_bytes())).with_extension("dmp"));
+    }
+
+    unsafe {
+      libc::alarm(0);
+      libc::signal(signal, libc::SIG_DFL);
+      libc::raise(signal);
+    }
+  }
+
+  /// ptrace can't inspect the process it's called from, so a forked child
+  /// writes the minidump while the crashed thread waits for it.
+  #[cfg(all(feature = "minidump", any(target_os = "linux", target_os = "android")))]
+  mod minidump {
+    use std::path::Path;
+
+    use minidump_writer::minidump_writer::MinidumpWriterConfig;
+
+    /// After which a child that hasn't finished is killed
+    const TIMEOUT_SECS: libc::c_uint = 30;
+
+    pub fn write(path: &Path) {
+      unsafe {
+        let pid = libc::getpid();
+        let tid = libc::syscall(libc::SYS_gettid) as libc::pid_t;
+        // The child has to wait until it's allowed to trace its parent
+        let mut ready = [0; 2];
+        if libc::pipe(ready.as_mut_ptr()) != 0 {
+          return;
+        }
+        match libc::fork() {
+          -1 => (),
+          0 => {
+            libc::close(ready[1]);
+            libc::alarm(TIMEOUT_SECS);
+            let mut byte = 0u8;
+            libc::read(ready[0], &mut byte as *mut u8 as *mut libc::c_void, 1);
+            let written = std::fs::File::create(path).ok()
+              .and_then(|mut file| MinidumpWriterConfig::new(pid, tid).write(&mut file).ok());
+            libc::_exit(if written.is_some() { 0 } else { 1 });
+          }
+          child => {
+            libc::prctl(libc::PR_SET_PTRACER, child as libc::c_ulong);
+            libc::write(ready[1], [1u8].as_ptr() as *const libc::c_void, 1);
+            let mut status = 0;
+            libc::waitpid(child, &mut status, 0);
+          }
+        }
+        libc::close(ready[0]);
+        libc::close(ready[1]);
+      }
+    }
+  }
+
+  #[cfg(test)]
+  mod tests {
+    use super::*;
+
+    #[test]
+    fn text_is_built_without_allocating() {
+      let mut text = Text::new();
+      text.push(b"crash-").number(0).push(b"-").number(u64::MAX);
+      assert_eq!(text.as_bytes(), b"crash-0-18446744073709551615");
+      text.push(&[b'x'; 300]);
+      assert_eq!(text.as_bytes().len(), 256);
+    }
+  }
+}
+
+#[cfg(not(unix))]
+mod imp {
+  pub fn open(_dir: &std::path::Path) { }
+
+  pub fn install() { }
+}
@@ -0,0 +1,258 @@
+/*
+ * Copyright 2022 Comcast Cable Communications Management, LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+//! The link served over gRPC (`--grpc`), for orchestrators and test tools
+//! driving plugins without Thunder. The Host service from
+//! wire/proto/wire.proto is served on the address and port arguments, and a
+//! Link call stands in for Thunder's connection: the commands streamed in
+//! are what Thunder would send, and responses stream back on the call. One
+//! call is taken at a time, while the host is waiting for a connection;
+//! others are turned away.
+//!
+//! Inside the host a call is a Connection like any other, carrying legacy
+//! frames. The call already types every frame, so bincode_frames and
+//! protobuf_frames are never agreed over it.
+
+#[cfg(feature = "grpc")]
+mod imp {
+  use std::io::{self, Read, Write};
+  use std::net::{IpAddr, ToSocketAddrs};
+  use std::sync::{mpsc, Arc, Mutex};
+
+  use log::{info, warn};
+  use thunder_rs_wire::protobuf::proto;
+  use thunder_rs_wire::protobuf::proto::host_server::{Host, HostServer};
+  use thunder_rs_wire::{legacy, Command};
+  use tokio_stream::wrappers::{ReceiverStream, TcpListenerStream};
+  use tonic::{Status, Streaming};
+
+  use crate::transport::Connection;
+
+  // Responses queued for a call before the plugin's writes block.
+  const QUEUED_RESPONSES: usize = 64;
+
+  /// Where a call's responses are queued.
+  type Responses = tokio::sync::mpsc::Sender<Result<proto::Response, Status>>;
+
+  /// The gRPC server, running on its own runtime for as long as it lives.
+  pub struct Server {
+    incoming: Mutex<mpsc::Receiver<Connection>>,
+    _runtime: tokio::runtime::Runtime
+  }
+
+  impl Server {
+    /// Serves the Host service on `addr`, taking calls from `allow` only
+    /// unless it's empty.
+    pub fn serve(addr: &str, allow: &[IpAddr]) -> Result<Self, String> {
+      let addr = addr.to_socket_addrs().map_err(|e| format!("invalid gRPC address {}: {}", addr, e))?
+        .next()
+        .ok_or_else(|| format!("gRPC address {} resolves to nothing", addr))?;
+      // Bound here so a taken port fails the host's startup
+      let listener = std::net::TcpListener::bind(addr).map_err(|e| format!("failed to bind {}: {}", addr, e))?;
+      listener.set_nonblocking(true).map_err(|e| e.to_string())?;
+
+      let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(2)
+        .thread_name("grpc")
+        .enable_all()
+        .build()
+        .map_err(|e| e.to_string())?;
+      // A rendezvous, so a call is only taken when the host is waiting
+      let (connections, incoming) = mpsc::sync_channel(0);
+      let link = Link { connections, allow: allow.to_vec() };
+      runtime.spawn(async move {
+        let listener = match tokio::net::TcpListener::from_std(listener) {
+          Ok(listener) => listener,
+          Err(e) => return warn!("RUST REMOTE: gRPC server failed: {}", e)
+        };
+        let served = tonic::transport::Server::builder()
+          .add_service(HostServer::new(link))
+          .serve_with_incoming(TcpListenerStream::new(listener))
+          .await;
+        if let Err(e) = served {
+          warn!("RUST REMOTE: gRPC server failed: {}", e);
+        }
+      });
+      info!("RUST REMOTE: serving gRPC on {}", addr);
+
+      Ok(Server { incoming: Mutex::new(incoming), _runtime: runtime })
+    }
+
+    /// Waits for the next Link call.
+    pub fn accept(&self) -> Result<Connection, String> {
+      self.incoming.lock().unwrap().recv().map_err(|_| String::from("gRPC server stopped"))
+    }
+  }
+
+  struct Link {
+    connections: mpsc::SyncSender<Connection>,
+    allow: Vec<IpAddr>
+  }
+
+  #[tonic::async_trait]
+  impl Host for Link {
+    type LinkStream = ReceiverStream<Result<proto::Response, Status>>;
+
+    async fn link(&self, request: tonic::Request<Streaming<proto::Command>>)
+      -> Result<tonic::Response<Self::LinkStream>, Status>
+    {
+      let peer = request.remote_addr();
+      if !self.allow.is_empty() && !peer.is_some_and(|peer| self.allow.contains(&peer.ip())) {
+        warn!("RUST REMOTE: refused gRPC call from {:?}", peer);
+        return Err(Status::permission_denied("not in the host's --allow list"));
+      }
+
+      let (commands, commands_rx) = mpsc::channel::<Option<Vec<u8>>>();
+      let (responses, responses_rx) = tokio::sync::mpsc::channel(QUEUED_RESPONSES);
+      let responses = Arc::new(Mutex::new(Some(responses)));
+      let close_responses = responses.clone();
+      let close_commands = commands.clone();
+      let connection = Connection::new(
+        Box::new(LinkReader { commands: commands_rx, pending: Vec::new(), read: 0, ended: false }),
+        Box::new(LinkWriter { responses, buf: Vec::new() }),
+        Box::new(move || {
+          close_responses.lock().unwrap().take();
+          let _ = close_commands.send(None);
+        })
+      );
+      if self.connections.try_send(connection).is_err() {
+        return Err(Status::unavailable("the host already has a link"));
+      }
+      info!("RUST REMOTE: gRPC link from {:?}", peer);
+
+      let mut stream = request.into_inner();
+      tokio::spawn(async move {
+        loop {
+          match stream.message().await {
+            Ok(Some(command)) => match Command::try_from(command) {
+              Ok(command) => {
+                if commands.send(Some(legacy::encode_command(&legacy_only(command)))).is_err() {
+                  break;
+                }
+              }
+              Err(e) => warn!("RUST REMOTE: ignored gRPC command: {}", e)
+            },
+            Ok(None) => break,
+            Err(e) => {
+              warn!("RUST REMOTE: gRPC link failed: {}", e);
+              break;
+            }
+          }
+        }
+        let _ = commands.send(None);
+      });
+
+      Ok(tonic::Response::new(ReceiverStream::new(responses_rx)))
+    }
+  }
+
+  /// Takes the frame codecs out of a hello, as the host would otherwise
+  /// switch the link inside it away from legacy frames.
+  fn legacy_only(command: Command) -> Command {
+    match command {
+      Command::Hello { version, json } => {
+        let mut hello: serde_json::Value = serde_json::from_str(&json).unwrap_or_default();
+        if let Some(features) = hello.get_mut("features").and_then(serde_json::Value::as_array_mut) {
+          features.retain(|f| f != "bincode_frames" && f != "protobuf_frames");
+        }
+        Command::Hello { version, json: hello.to_string() }
+      }
+      command => command
+    }
+  }
+
+  /// The commands of a call as legacy frames. None, or the call ending,
+  /// reads as the end of the stream.
+  struct LinkReader {
+    commands: mpsc::Receiver<Option<Vec<u8>>>,
+    pending: Vec<u8>,
+    read: usize,
+    ended: bool
+  }
+
+  impl Read for LinkReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+      while self.read == self.pending.len() {
+        if self.ended {
+          return Ok(0);
+        }
+        match self.commands.recv() {
+          Ok(Some(frame)) => {
+            self.pending = frame;
+            self.read = 0;
+          }
+          Ok(None) | Err(_) => self.ended = true
+        }
+      }
+      let n = buf.len().min(self.pending.len() - self.read);
+      buf[..n].copy_from_slice(&self.pending[self.read..self.read + n]);
+      self.read += n;
+      Ok(n)
+    }
+  }
+
+  /// Turns the host's legacy frames back into responses on the call,
+  /// holding on to any partly written frame.
+  struct LinkWriter {
+    responses: Arc<Mutex<Option<Responses>>>,
+    buf: Vec<u8>
+  }
+
+  impl Write for LinkWriter {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+      self.buf.extend_from_slice(data);
+      loop {
+        let mut rest = self.buf.as_slice();
+        let response = match legacy::read_response(&mut rest) {
+          Ok(response) => response,
+          Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+          Err(e) => return Err(e)
+        };
+        let used = self.buf.len() - rest.len();
+        self.buf.drain(..used);
+        // Cloned so closing the call isn't held up by a full queue
+        let responses = self.responses.lock().unwrap().clone();
+        let sent = responses.is_some_and(|tx| tx.blocking_send(Ok(proto::Response::from(response))).is_ok());
+        if !sent {
+          return Err(io::Error::new(io::ErrorKind::BrokenPipe, "gRPC link closed"));
+        }
+      }
+      Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+      Ok(())
+    }
+  }
+}
+
+#[cfg(feature = "grpc")]
+pub use imp::Server;
+
+#[cfg(not(feature = "grpc"))]
+pub struct Server;
+
+#[cfg(not(feature = "grpc"))]
+impl Server {
+  pub fn serve(_addr: &str, _allow: &[std::net::IpAddr]) -> Result<Self, String> {
+    Err(String::from("built without gRPC"))
+  }
+
+  pub fn accept(&self) -> Result<crate::transport::Connection, String> {
+    Err(String::from("built without gRPC"))
+  }
+}
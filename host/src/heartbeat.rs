@@ -0,0 +1,124 @@
+/*
+ * Copyright 2022 Comcast Cable Communications Management, LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+//! Detects a hung or half-open connection to Thunder. Once both ends have
+//! agreed on the heartbeat feature the host sends a ping control message
+//! every interval and Thunder answers each with a pong command; after too
+//! many unanswered pings in a row the connection is closed, which makes
+//! the host reconnect or stop. Thunder can ping the host the same way.
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::{trace, warn};
+
+use crate::protocol::Session;
+use crate::CONTROL_CHANNEL;
+
+#[derive(Default)]
+struct Counters {
+  sent: AtomicU32,
+  answered: AtomicU32
+}
+
+/// How often to ping and how many misses to put up with, from
+/// THUNDER_RS_HEARTBEAT_INTERVAL_MS (default 5000, 0 turns pings off) and
+/// THUNDER_RS_HEARTBEAT_MISSES (default 3).
+#[derive(Clone)]
+pub struct Heartbeat {
+  interval: Duration,
+  misses: u32,
+  counters: Arc<Counters>
+}
+
+fn env_u64(name: &str, default: u64) -> u64 {
+  match std::env::var(name) {
+    Ok(s) => s.parse().unwrap_or_else(|_| {
+      warn!("RUST REMOTE: ignoring invalid {}={}", name, s);
+      default
+    }),
+    Err(_) => default
+  }
+}
+
+impl Heartbeat {
+  pub fn from_env() -> Self {
+    Heartbeat {
+      interval: Duration::from_millis(env_u64("THUNDER_RS_HEARTBEAT_INTERVAL_MS", 5000)),
+      misses: env_u64("THUNDER_RS_HEARTBEAT_MISSES", 3).max(1) as u32,
+      counters: Arc::default()
+    }
+  }
+
+  /// Records Thunder's answer to ping `seq`.
+  pub fn pong(&self, seq: u32) {
+    trace!("RUST REMOTE: pong {}", seq);
+    self.counters.answered.fetch_max(seq, Ordering::AcqRel);
+  }
+
+  /// Pings on a thread of its own until `stopping` is set, calling `dead`
+  /// when too many pings in a row go unanswered.
+  pub fn start<F>(&self, tx: thunder_rs::Responder, session: Session, stopping: Arc<AtomicBool>, dead: F)
+    where F: Fn() + Send + 'static
+  {
+    if self.interval.is_zero() {
+      return;
+    }
+    let heartbeat = self.clone();
+    std::thread::spawn(move || {
+      let counters = &heartbeat.counters;
+      while !stopping.load(Ordering::Acquire) {
+        std::thread::sleep(heartbeat.interval);
+        // Bridges that can't answer aren't pinged, and a new connection
+        // starts counting afresh
+        if !session.agreed("heartbeat") {
+          counters.answered.store(counters.sent.load(Ordering::Acquire), Ordering::Release);
+          continue;
+        }
+        let sent = counters.sent.load(Ordering::Acquire);
+        let missed = sent - counters.answered.load(Ordering::Acquire).min(sent);
+        if missed >= heartbeat.misses {
+          warn!("RUST REMOTE: {} pings unanswered, closing the connection to Thunder", missed);
+          thunder_rs::journal::record(thunder_rs::journal::Source::Wire, "heartbeat_missed", None,
+            format!("missed={}", missed));
+          counters.answered.store(sent, Ordering::Release);
+          dead();
+          continue;
+        }
+        let seq = sent + 1;
+        counters.sent.store(seq, Ordering::Release);
+        trace!("RUST REMOTE: ping {}", seq);
+        let msg = serde_json::json!({ "command": "ping", "seq": seq });
+        // Never waits behind a stalled writer; a ping that doesn't fit in
+        // the queue counts as missed, which is what finds the stall
+        match tx.try_send(thunder_rs::Message::new(CONTROL_CHANNEL, msg.to_string())) {
+          Ok(()) => (),
+          Err(thunder_rs::SendError::QueueFull) => {
+            trace!("RUST REMOTE: ping {} not sent, the queue to Thunder is full", seq);
+          }
+          Err(_) => break
+        }
+      }
+    });
+  }
+}
+
+/// The answer to Thunder's ping `seq`.
+pub fn pong(seq: u32) -> thunder_rs::Message {
+  trace!("RUST REMOTE: answering ping {}", seq);
+  thunder_rs::Message::new(CONTROL_CHANNEL, serde_json::json!({ "command": "pong", "seq": seq }).to_string())
+}
@@ -0,0 +1,655 @@
+/*
+ * Copyright 2022 Comcast Cable Communications Management, LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+//! A plugin the host runs, with what it keeps for each one: the plugin's
+//! clients, rate limiter, uploads and the queue its messages leave through.
+//! A host may run several, each known to Thunder by its position in the
+//! list of libraries. The first one's frames are neither routed nor tagged,
+//! so a host with a single plugin speaks the protocol as it always has.
+//!
+//! A plugin asking for workers has its client callbacks run on a pool,
+//! each channel on its own worker so its requests stay in order, and a slow
+//! request on one channel doesn't hold up the others. A ConcurrentPlugin
+//! takes its requests on whichever worker is next, without the lock.
+//!
+//! A callback that panics is caught, so one bad request doesn't take the
+//! host down. The plugin's RestartPolicy then decides, as in the in-process
+//! bridge: once it has panicked often enough the instance is recreated
+//! through ServiceMetadata::create and told of every attached client again,
+//! or quarantined. Panics are caught with the library's own
+//! ServiceMetadata::catch_panic, as only the std a plugin was built with can
+//! catch them.
+//!
+//! Reloading a plugin's library swaps the instance in place: the old one is
+//! shut down and everything from its library let go of, uploads in progress
+//! included, and the new one takes over its clients and queue to Thunder.
+use std::collections::{BTreeSet, HashMap};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+
+use log::{debug, error, info, warn};
+use thunder_rs::journal::{self, Source};
+use thunder_rs::jsonrpc;
+use thunder_rs::rate_limit::RateLimiter;
+use thunder_rs::workers::WorkerPool;
+use thunder_rs::{CatchPanic, PanicAction, Supervisor};
+
+use crate::{admin, compress, protocol, send_response, send_web_response, stats, transport, HostLink, Request, CONTROL_CHANNEL};
+
+pub struct Hosted {
+  id: u32,
+  plugin: Arc<Mutex<Box<dyn thunder_rs::Plugin>>>,
+  workers: Option<WorkerPool>,
+  concurrent: bool,
+  restart: Arc<Restart>,
+  dispatched: u32,
+  config: thunder_rs::PluginConfig,
+  rate_limiter: Option<RateLimiter>,
+  channels: thunder_rs::Channels,
+  uploads: HashMap<u32, thunder_rs::web::Upload>,
+  tx: thunder_rs::Responder,
+  framework: thunder_rs::Framework,
+  delivery: JoinHandle<()>
+}
+
+impl Hosted {
+  /// Starts delivering plugin `id`'s messages to Thunder through `outbound`.
+  /// `meta` is what the plugin was made from, for restarting it.
+  pub fn start(id: u32, plugin: Box<dyn thunder_rs::Plugin>, meta: &thunder_rs::ServiceMetadata,
+    config: thunder_rs::PluginConfig, outbound: Arc<transport::Outbound>, session: protocol::Session) -> Self
+  {
+    let (tx, rx) = thunder_rs::responder::queue(plugin.queue_limit());
+    let delivery_session = session.clone();
+    let delivery = std::thread::spawn(move || {
+      let threshold = compress::threshold();
+      while let Some(msg) = rx.recv() {
+        let compressor = delivery_session.compression()
+          .map(|algorithm| compress::Compressor { algorithm, threshold });
+        match outbound.write(|writer| send_response(writer, id, &msg, compressor.as_ref(), delivery_session.codec())) {
+          Ok(true) => (),
+          Ok(false) => {
+            // Meant for clients of a connection that's gone
+            journal::record(Source::Wire, "dropped_disconnected", Some(msg.channel), format!("len={}", msg.data.len()));
+          }
+          Err(e) => {
+            // Closing the connection ends the reader too, which reconnects or
+            // stops the host
+            error!("RUST REMOTE: connection to Thunder broken: {}", e);
+            journal::record(Source::Wire, "write_failed", Some(msg.channel), e.to_string());
+            outbound.close();
+          }
+        }
+      }
+    });
+
+    let framework = thunder_rs::Framework::new(HostLink { responder: tx.clone(), session });
+    let mut channels = thunder_rs::Channels::new();
+    channels.set_framework(framework.clone());
+    channels.set_features(config.features.clone());
+    channels.set_paths(config.paths.clone());
+
+    let workers = match plugin.workers() {
+      0 => None,
+      n => Some(WorkerPool::new(n))
+    };
+    let restart = Restart::new(meta, plugin.restart_policy(), config.clone(), BTreeSet::new());
+    restart.set_web_handler(plugin.as_ref());
+    Hosted {
+      id,
+      rate_limiter: plugin.rate_limit().map(RateLimiter::new),
+      concurrent: plugin.concurrent().is_some(),
+      restart: Arc::new(restart),
+      plugin: Arc::new(Mutex::new(plugin)),
+      workers,
+      dispatched: 0,
+      config,
+      channels,
+      uploads: HashMap::new(),
+      tx,
+      framework,
+      delivery
+    }
+  }
+
+  /// The queue to Thunder, shared with the host for its own control messages
+  /// when this is the first plugin.
+  pub fn responder(&self) -> thunder_rs::Responder {
+    self.tx.clone()
+  }
+
+  /// Where call results for this plugin's framework calls go.
+  pub fn framework(&self) -> thunder_rs::Framework {
+    self.framework.clone()
+  }
+
+  /// Adds the stats of every channel to the journal.
+  pub fn record_stats(&self) {
+    self.channels.record_stats();
+  }
+
+  /// Runs `f` against the plugin, on the worker owning `channel` when there's
+  /// a pool and right here otherwise.
+  fn run<F>(&self, channel: u32, f: F)
+    where F: FnOnce(&mut dyn thunder_rs::Plugin) + Send + 'static
+  {
+    self.run_in(channel, caught(f));
+  }
+
+  /// Like run, for an `f` that catches its own panic with the catcher given.
+  fn run_in<F>(&self, channel: u32, f: F)
+    where F: FnOnce(&mut dyn thunder_rs::Plugin, CatchPanic) -> Result<(), String> + Send + 'static
+  {
+    match &self.workers {
+      Some(pool) => {
+        let plugin = self.plugin.clone();
+        let restart = self.restart.clone();
+        pool.submit(channel, move || call_in(&plugin, &restart, channel, f));
+      }
+      None => call_in(&self.plugin, &self.restart, channel, f)
+    }
+  }
+
+  /// Handles a request meant for this plugin.
+  pub fn handle(&mut self, req: Request) {
+    let channels = &mut self.channels;
+    let tx = &self.tx;
+    match req {
+      Request::Invoke(req) => {
+        debug!("RUST REMOTE: invoking");
+        let req_ctx = channels.receive(req.channel, req.token, tx.clone(), req.json.len());
+        if self.restart.quarantined() {
+          if let Some(id) = jsonrpc::request_id(&req.json) {
+            let _ = req_ctx.send(jsonrpc::error_response(&id, jsonrpc::ERROR_UNAVAILABLE,
+              &format!("{} is quarantined after repeated failures", self.restart.name)));
+          }
+          return;
+        }
+        if let Some(limiter) = &mut self.rate_limiter {
+          if !limiter.admit(&req.json, &req_ctx) {
+            return;
+          }
+        }
+        journal::record(Source::Plugin, "on_message", Some(req.channel), format!("len={}", req.json.len()));
+        if let (true, Some(pool)) = (self.concurrent, &self.workers) {
+          let plugin = self.plugin.clone();
+          let restart = self.restart.clone();
+          let channel = req.channel;
+          self.dispatched = self.dispatched.wrapping_add(1);
+          pool.submit(self.dispatched, move || {
+            // Locked only to pick up the current instance, which a restart
+            // replaces
+            let Some(handler) = lock(&plugin).concurrent() else { return };
+            if let Err(cause) = thunder_rs::invoke_concurrent_in(restart.catch, handler.as_ref(), req.json, req_ctx) {
+              restart.panicked(&plugin, channel, &cause);
+            }
+          });
+          return;
+        }
+        self.run_in(req.channel, move |plugin, catch| thunder_rs::invoke_message_in(catch, plugin, req.json, req_ctx));
+      },
+      Request::InvokeBinary(req) => {
+        debug!("RUST REMOTE: invoking binary");
+        let req_ctx = channels.receive(req.channel, req.token, tx.clone(), req.data.len());
+        if let Some(limiter) = &mut self.rate_limiter {
+          if !limiter.try_acquire(req.channel) {
+            warn!("RUST REMOTE: rate limit exceeded on channel {}", req.channel);
+            return;
+          }
+        }
+        journal::record(Source::Plugin, "on_binary_message", Some(req.channel),
+          format!("len={}", req.data.len()));
+        self.run(req.channel, move |plugin| plugin.on_binary_message(&req.data, req_ctx));
+      },
+      Request::InvokeRaw(req) => {
+        let req_ctx = channels.receive(req.channel, req.token, tx.clone(), req.data.len());
+        if let Some(limiter) = &mut self.rate_limiter {
+          if !limiter.try_acquire(req.channel) {
+            warn!("RUST REMOTE: rate limit exceeded on channel {}", req.channel);
+            return;
+          }
+        }
+        journal::record(Source::Plugin, "on_raw_data", Some(req.channel), format!("len={}", req.data.len()));
+        self.run(req.channel, move |plugin| plugin.on_raw_data(&req.data, req_ctx));
+      },
+      Request::Attach(req) => {
+        debug!("RUST REMOTE: attaching");
+        if req.attach {
+          channels.connect(req.channel);
+          self.restart.attached.lock().unwrap().insert(req.channel);
+          journal::record(Source::Plugin, "on_client_connect", Some(req.channel), String::new());
+          self.run(req.channel, move |plugin| plugin.on_client_connect(req.channel));
+        } else {
+          channels.disconnect(req.channel);
+          self.restart.attached.lock().unwrap().remove(&req.channel);
+          if let Some(limiter) = &mut self.rate_limiter {
+            limiter.forget(req.channel);
+          }
+          // Nobody is left to answer
+          self.uploads.retain(|_, upload| upload.channel() != req.channel);
+          journal::record(Source::Plugin, "on_client_disconnect", Some(req.channel), String::new());
+          self.run(req.channel, move |plugin| plugin.on_client_disconnect(req.channel));
+        }
+      },
+      Request::Peer(channel, peer) => {
+        debug!("RUST REMOTE: channel {} is {:?}", channel, peer);
+        channels.set_peer(channel, peer);
+      },
+      Request::Subsystem(id, active) => {
+        if let Some(subsystem) = self.framework.subsystem_changed(id, active) {
+          journal::record(Source::Plugin, "on_subsystem_change", None,
+            format!("subsystem={} active={}", subsystem.name(), active));
+          self.run(0, move |plugin| plugin.on_subsystem_change(subsystem, active));
+        }
+      },
+      Request::Memory() => {
+        // Thunder's Monitor polls this; the answer goes back as a control
+        // message with null figures if the plugin doesn't report any, or
+        // none at all if it panics
+        let tx = tx.clone();
+        self.run(0, move |plugin| {
+          let usage = plugin.memory_usage();
+          let msg = serde_json::json!({
+            "command": "memory",
+            "resident": usage.map(|m| m.resident),
+            "allocated": usage.map(|m| m.allocated),
+            "shared": usage.map(|m| m.shared)
+          });
+          if let Err(e) = tx.send(thunder_rs::Message::new(CONTROL_CHANNEL, msg.to_string())) {
+            warn!("RUST REMOTE: failed to send memory usage: {}", e);
+          }
+        });
+      },
+      Request::Custom(id, data) => {
+        journal::record(Source::Plugin, "on_custom_frame", None, format!("id={:#x} len={}", id, data.len()));
+        self.run(0, move |plugin| plugin.on_custom_frame(id, &data));
+      },
+      Request::Metrics() => {
+        let msg = serde_json::json!({
+          "command": "metrics",
+          "metrics": self.config.metrics.snapshot()
+        });
+        if let Err(e) = tx.send(thunder_rs::Message::new(CONTROL_CHANNEL, msg.to_string())) {
+          warn!("RUST REMOTE: failed to send metrics: {}", e);
+        }
+      },
+      Request::ConfigChanged(json) => {
+        match thunder_rs::secrets::parse_config(&json) {
+          Ok(config) => {
+            info!("RUST REMOTE: config changed");
+            journal::record(Source::Plugin, "on_config_changed", None, format!("len={}", json.len()));
+            self.config.config = config.clone();
+            self.restart.config.lock().unwrap().config = config.clone();
+            // The feature listeners it calls are the plugin's too
+            let features = self.config.features.clone();
+            self.run(0, move |plugin| {
+              features.load_config(&config);
+              plugin.on_config_changed(&config);
+            });
+          }
+          Err(e) => error!("RUST REMOTE: ignoring config change: {}", e)
+        }
+      },
+      Request::Web(req) => {
+        debug!("RUST REMOTE: {} {}", req.request.method, req.request.path);
+        let ctx = channels.receive(req.channel, req.token, tx.clone(), req.request.body.len());
+        journal::record(Source::Plugin, "on_web_request", Some(req.channel),
+          format!("{} {}", req.request.method, req.request.path));
+        // Kept by the restart so the handler runs without the plugin's lock
+        let handler = self.restart.web_handler();
+        let catch = self.restart.catch;
+        if req.streamed {
+          self.uploads.insert(req.id, thunder_rs::web::Upload::begin_in(catch, handler, req.request, ctx));
+          return;
+        }
+        let res = thunder_rs::web::dispatch_in(catch, handler, &req.request, &ctx);
+        send_web_response(tx, req.id, res, catch);
+      },
+      Request::WebBody(id, data) => {
+        if data.is_empty() {
+          match self.uploads.remove(&id) {
+            Some(upload) => send_web_response(tx, id, upload.finish(), self.restart.catch),
+            None => warn!("RUST REMOTE: end of body for unknown web request {}", id)
+          }
+        } else if let Some(upload) = self.uploads.get_mut(&id) {
+          upload.write(&data);
+        } else {
+          warn!("RUST REMOTE: dropping body of unknown web request {}", id);
+        }
+      },
+      Request::Reset(reason) => {
+        info!("RUST REMOTE: dropping all clients: {}", reason);
+        let detached = channels.disconnect_all();
+        self.restart.attached.lock().unwrap().clear();
+        if let Some(limiter) = &mut self.rate_limiter {
+          limiter.forget_all();
+        }
+        self.uploads.clear();
+        let mut pruned = 0;
+        let prune_events = self.restart.prune_events;
+        if let Err(cause) = (self.restart.catch)(&mut || pruned = prune_events()) {
+          error!("RUST REMOTE: failed to drop event subscriptions: {}", cause);
+        }
+        journal::record(Source::Plugin, "on_all_clients_disconnected", None,
+          format!("channels={} subscriptions={} reason={}", detached.len(), pruned, reason));
+        // Messages already queued for other workers see their contexts as
+        // disconnected
+        self.run(0, move |plugin| plugin.on_all_clients_disconnected(&detached, &reason));
+      },
+      _ => {
+        warn!("RUST REMOTE: plugin {} can't handle that request", self.id);
+      }
+    }
+  }
+
+  /// The plugin's own metrics, for the host to serve.
+  pub fn metrics(&self) -> thunder_rs::Metrics {
+    self.config.metrics.clone()
+  }
+
+  pub fn name(&self) -> &str {
+    self.restart.name
+  }
+
+  /// The plugin as the admin socket's status shows it.
+  pub fn describe(&self) -> admin::Plugin {
+    let (major, minor, patch) = self.restart.version;
+    admin::Plugin {
+      name: self.restart.name.to_string(),
+      version: format!("{}.{}.{}", major, minor, patch),
+      queue: self.tx.clone()
+    }
+  }
+
+  /// What the admin socket's dump-state shows of the plugin.
+  pub fn state(&self) -> serde_json::Value {
+    let supervisor = self.restart.supervisor.lock().unwrap();
+    serde_json::json!({
+      "id": self.id,
+      "name": self.restart.name,
+      "attached": *self.restart.attached.lock().unwrap(),
+      "quarantined": supervisor.is_quarantined(),
+      "restarts": supervisor.restarts(),
+      "workers": self.workers.is_some(),
+      "concurrent": self.concurrent,
+      "uploads": self.uploads.len(),
+      "queued": self.tx.queued()
+    })
+  }
+
+  /// Calls the plugin's on_shutdown. A panic in it is only logged, as the
+  /// instance is going away anyway.
+  fn on_shutdown(&self) {
+    let mut plugin = lock(&self.plugin);
+    if let Err(cause) = (self.restart.catch)(&mut || plugin.on_shutdown()) {
+      error!("RUST REMOTE: plugin panicked shutting down: {}", cause);
+      journal::record(Source::Plugin, "panic", None, cause);
+    }
+  }
+
+  /// Tells the plugin the host is stopping.
+  pub fn shutdown(&mut self) {
+    // Lets callbacks already queued on the workers finish
+    self.workers = None;
+    journal::record(Source::Plugin, "on_shutdown", None, String::new());
+    self.on_shutdown();
+    self.config.scope.shutdown();
+  }
+
+  /// Shuts the instance down for its library to be reloaded, leaving
+  /// nothing from the library behind. Returns its config as JSON, for the
+  /// new instance.
+  pub fn unload(&mut self) -> String {
+    self.workers = None;
+    journal::record(Source::Plugin, "on_shutdown", None, String::from("reload"));
+    self.on_shutdown();
+    self.config.scope.shutdown();
+    if !self.uploads.is_empty() {
+      warn!("RUST REMOTE: dropping {} web uploads in progress to reload", self.uploads.len());
+      self.uploads.clear();
+    }
+    *lock(&self.plugin) = Box::new(Unloaded);
+    // The timers and feature listeners hold the plugin's closures, and the
+    // restart's create and catch point into its library
+    self.channels.reset_timers();
+    self.config.features.clear_listeners();
+    let unloaded = thunder_rs::PluginConfig {
+      auth_token: String::new(),
+      scope: self.config.scope.clone(),
+      config: serde_json::Value::Null,
+      features: thunder_rs::Features::new(),
+      metrics: self.config.metrics.clone(),
+      paths: self.config.paths.clone()
+    };
+    let config = std::mem::replace(&mut self.config, unloaded.clone());
+    self.channels.set_features(unloaded.features.clone());
+    let attached = std::mem::take(&mut *self.restart.attached.lock().unwrap());
+    self.restart = Arc::new(Restart::unloaded(unloaded, attached));
+    config.config.to_string()
+  }
+
+  /// Takes on `plugin`, made from the reloaded library `meta` is from, in
+  /// place of the instance unloaded, and tells it of every client still
+  /// attached.
+  pub fn reload(&mut self, plugin: Box<dyn thunder_rs::Plugin>, meta: &thunder_rs::ServiceMetadata,
+    config: thunder_rs::PluginConfig)
+  {
+    self.channels.set_features(config.features.clone());
+    self.channels.set_paths(config.paths.clone());
+    self.workers = match plugin.workers() {
+      0 => None,
+      n => Some(WorkerPool::new(n))
+    };
+    self.rate_limiter = plugin.rate_limit().map(RateLimiter::new);
+    self.concurrent = plugin.concurrent().is_some();
+    let attached = std::mem::take(&mut *self.restart.attached.lock().unwrap());
+    self.restart = Arc::new(Restart::new(meta, plugin.restart_policy(), config.clone(), attached.clone()));
+    self.restart.set_web_handler(plugin.as_ref());
+    *lock(&self.plugin) = plugin;
+    self.config = config;
+    journal::record(Source::Plugin, "reload", None, format!("attached={}", attached.len()));
+    for channel in attached {
+      self.run(channel, move |plugin| plugin.on_client_connect(channel));
+    }
+  }
+
+  /// Lets delivery end once whatever the plugin queued has gone out.
+  pub fn close(&self) {
+    self.tx.close();
+  }
+
+  /// Whether everything the plugin queued has been written.
+  pub fn delivered(&self) -> bool {
+    self.delivery.is_finished()
+  }
+
+  /// Waits for delivery if it's done, otherwise drops what's left, returning
+  /// the number of messages dropped.
+  pub fn finish(self) -> usize {
+    if self.delivery.is_finished() {
+      let _ = self.delivery.join();
+      0
+    } else {
+      // Most likely stuck writing to a Thunder that stopped reading
+      self.tx.discard()
+    }
+  }
+}
+
+/// What it takes to replace a plugin instance that keeps panicking.
+struct Restart {
+  name: &'static str,
+  version: (u32, u32, u32),
+  create: fn(thunder_rs::PluginConfig) -> Box<dyn thunder_rs::Plugin>,
+  catch: CatchPanic,
+  /// The library's events::prune_all
+  prune_events: fn() -> usize,
+  /// As last changed by Thunder
+  config: Mutex<thunder_rs::PluginConfig>,
+  supervisor: Mutex<Supervisor>,
+  /// Clients attached, to replay to a new instance
+  attached: Mutex<BTreeSet<u32>>,
+  /// The current instance's, taken when it's created
+  web: Mutex<Option<Arc<dyn thunder_rs::WebHandler>>>
+}
+
+impl Restart {
+  fn new(meta: &thunder_rs::ServiceMetadata, policy: thunder_rs::RestartPolicy, config: thunder_rs::PluginConfig,
+    attached: BTreeSet<u32>) -> Self
+  {
+    Restart {
+      name: meta.name,
+      version: meta.version,
+      create: meta.create,
+      catch: meta.catch_panic,
+      prune_events: meta.prune_events,
+      config: Mutex::new(config),
+      supervisor: Mutex::new(Supervisor::new(meta.name, policy)),
+      attached: Mutex::new(attached),
+      web: Mutex::new(None)
+    }
+  }
+
+  /// Stands in while the plugin's library is reloaded, holding nothing of
+  /// it.
+  fn unloaded(config: thunder_rs::PluginConfig, attached: BTreeSet<u32>) -> Self {
+    Restart {
+      name: "<unloaded>",
+      version: (0, 0, 0),
+      create: |_| Box::new(Unloaded),
+      catch: thunder_rs::catch_panic,
+      prune_events: || 0,
+      config: Mutex::new(config),
+      supervisor: Mutex::new(Supervisor::new("<unloaded>", thunder_rs::RestartPolicy::default())),
+      attached: Mutex::new(attached),
+      web: Mutex::new(None)
+    }
+  }
+
+  /// Takes the web handler of `plugin`, the instance from now on.
+  fn set_web_handler(&self, plugin: &dyn thunder_rs::Plugin) {
+    let mut handler = None;
+    if let Err(cause) = (self.catch)(&mut || handler = plugin.web_handler()) {
+      error!("RUST REMOTE: plugin panicked giving its web handler: {}", cause);
+    }
+    *self.web.lock().unwrap() = handler;
+  }
+
+  /// The current instance's web handler, None once it's quarantined.
+  fn web_handler(&self) -> Option<Arc<dyn thunder_rs::WebHandler>> {
+    if self.quarantined() {
+      return None;
+    }
+    self.web.lock().unwrap().clone()
+  }
+
+  fn quarantined(&self) -> bool {
+    self.supervisor.lock().unwrap().is_quarantined()
+  }
+
+  /// Counts a panic on `channel`, replacing the instance behind `plugin` if
+  /// the policy says so.
+  fn panicked(&self, plugin: &Mutex<Box<dyn thunder_rs::Plugin>>, channel: u32, cause: &str) {
+    error!("RUST REMOTE: plugin panicked on channel {}: {}", channel, cause);
+    journal::record(Source::Plugin, "panic", Some(channel), cause.to_string());
+    stats::panicked(self.name);
+    let action = self.supervisor.lock().unwrap().record_panic();
+    if action == Some(PanicAction::Restart) {
+      match self.create() {
+        Ok(instance) => *lock(plugin) = instance,
+        Err(cause) => {
+          error!("RUST REMOTE: {} panicked restarting, quarantined: {}", self.name, cause);
+          journal::record(Source::Plugin, "restart_failed", None, cause);
+          self.supervisor.lock().unwrap().quarantine();
+        }
+      }
+    }
+  }
+
+  /// A new instance, restored from the last checkpoint, that has been told
+  /// of every client still attached.
+  fn create(&self) -> Result<Box<dyn thunder_rs::Plugin>, String> {
+    let config = self.config.lock().unwrap().clone();
+    let mut plugin = create(self.create, self.catch, config)?;
+    self.set_web_handler(plugin.as_ref());
+    let attached: Vec<u32> = self.attached.lock().unwrap().iter().copied().collect();
+    journal::record(Source::Plugin, "restart", None, format!("attached={}", attached.len()));
+    for channel in attached {
+      if let Err(cause) = (self.catch)(&mut || plugin.on_client_connect(channel)) {
+        error!("RUST REMOTE: restarted plugin panicked reattaching channel {}: {}", channel, cause);
+      }
+    }
+    Ok(plugin)
+  }
+}
+
+/// Creates an instance with `create` and restores it from the last
+/// checkpoint, catching a panic in either with `catch`.
+pub fn create(create: fn(thunder_rs::PluginConfig) -> Box<dyn thunder_rs::Plugin>, catch: CatchPanic,
+  config: thunder_rs::PluginConfig) -> Result<Box<dyn thunder_rs::Plugin>, String>
+{
+  let scope = config.scope.clone();
+  let mut config = Some(config);
+  let mut plugin = None;
+  catch(&mut || {
+    if let Some(config) = config.take() {
+      let mut instance = create(config);
+      instance.on_init(scope.restore());
+      plugin = Some(instance);
+    }
+  })?;
+  plugin.ok_or_else(|| String::from("create panicked"))
+}
+
+/// Stands in for an instance while its library is reloaded.
+struct Unloaded;
+
+impl thunder_rs::Plugin for Unloaded {
+  fn on_message(&mut self, _json: String, _ctx: thunder_rs::RequestContext) { }
+}
+
+/// Runs `f` against the plugin, unless it's quarantined. `f` catches its own
+/// panic with the catcher given.
+fn call_in<F>(plugin: &Mutex<Box<dyn thunder_rs::Plugin>>, restart: &Restart, channel: u32, f: F)
+  where F: FnOnce(&mut dyn thunder_rs::Plugin, CatchPanic) -> Result<(), String>
+{
+  if restart.quarantined() {
+    return;
+  }
+  let result = f(lock(plugin).as_mut(), restart.catch);
+  if let Err(cause) = result {
+    restart.panicked(plugin, channel, &cause);
+  }
+}
+
+/// Runs a plain callback inside the catcher.
+fn caught<F>(f: F) -> impl FnOnce(&mut dyn thunder_rs::Plugin, CatchPanic) -> Result<(), String>
+  where F: FnOnce(&mut dyn thunder_rs::Plugin)
+{
+  move |plugin, catch| {
+    let mut f = Some(f);
+    catch(&mut || {
+      if let Some(f) = f.take() {
+        f(&mut *plugin);
+      }
+    })
+  }
+}
+
+/// A callback that panicked poisons the lock; carry on with the plugin as is.
+fn lock(plugin: &Mutex<Box<dyn thunder_rs::Plugin>>) -> std::sync::MutexGuard<'_, Box<dyn thunder_rs::Plugin>> {
+  plugin.lock().unwrap_or_else(|e| e.into_inner())
+}
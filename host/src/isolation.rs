@@ -0,0 +1,199 @@
+/*
+ * Copyright 2022 Comcast Cable Communications Management, LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+//! Isolates the host, and the plugin in it, from the rest of the box without
+//! a container runtime. `--unshare=` takes a list of namespaces to enter new
+//! ones of: "mount", "net" and "pid". `--chroot=` makes a directory the
+//! root before the library is loaded.
+//!
+//! A process can't move itself into a new pid namespace, only its children,
+//! so with "pid" the host forks before it does anything else. The child
+//! carries on as the host, as pid 1 of the namespace; the parent passes on
+//! SIGTERM, SIGINT and SIGHUP to it and exits the way it does. With "mount" as well,
+//! a /proc for the new namespace is mounted over the old one, inside the new
+//! root if there is one.
+//!
+//! With "net", only the loopback interface is there, and down: Thunder has
+//! to be reached with --stdio, --fd or a Unix socket. After --chroot every
+//! path is inside the new root, the library's, TLS files', a Unix socket's
+//! THUNDER_RS_CRASH_DIR and THUNDER_RS_JOURNAL_DIR among them.
+use std::path::PathBuf;
+
+use log::info;
+
+/// What to isolate the host from. The namespaces have to be entered before
+/// any thread is started, the new root just before the library is loaded.
+#[derive(Debug, Clone, Default)]
+pub struct Isolation {
+  pub mount: bool,
+  pub net: bool,
+  pub pid: bool,
+  pub root: Option<PathBuf>
+}
+
+impl Isolation {
+  /// Enters the new namespaces. With a new pid namespace this only returns
+  /// in the child; the parent exits once the child has.
+  pub fn enter_namespaces(&self) -> Result<(), String> {
+    if self.mount || self.net || self.pid {
+      imp::unshare(self.mount, self.net, self.pid)?;
+    }
+    Ok(())
+  }
+
+  /// Makes the new root the root, with its own /proc in a new pid and
+  /// mount namespace.
+  pub fn enter_root(&self) -> Result<(), String> {
+    if let Some(root) = &self.root {
+      imp::chroot(root)?;
+      info!("RUST REMOTE: changed root to {}", root.display());
+    }
+    if self.mount && self.pid {
+      imp::mount_proc()?;
+    }
+    if self.mount || self.net || self.pid {
+      info!("RUST REMOTE: in new namespaces mount={} net={} pid={}", self.mount, self.net, self.pid);
+    }
+    Ok(())
+  }
+}
+
+#[cfg(target_os = "linux")]
+mod imp {
+  use std::ffi::CString;
+  use std::io;
+  use std::os::unix::ffi::OsStrExt;
+  use std::path::Path;
+  use std::sync::atomic::{AtomicI32, Ordering};
+
+  /// The host the parent waits for, for its signal handler
+  static CHILD: AtomicI32 = AtomicI32::new(0);
+
+  fn last_error(what: &str) -> String {
+    format!("failed to {}: {}", what, io::Error::last_os_error())
+  }
+
+  pub fn unshare(mount: bool, net: bool, pid: bool) -> Result<(), String> {
+    let mut flags = 0;
+    if mount {
+      flags |= libc::CLONE_NEWNS;
+    }
+    if net {
+      flags |= libc::CLONE_NEWNET;
+    }
+    if pid {
+      flags |= libc::CLONE_NEWPID;
+    }
+    unsafe {
+      if libc::unshare(flags) != 0 {
+        return Err(last_error("enter new namespaces"));
+      }
+      // Or mounts made in here would show up outside too
+      let root = c"/".as_ptr();
+      if mount && libc::mount(std::ptr::null(), root, std::ptr::null(),
+          libc::MS_REC | libc::MS_PRIVATE, std::ptr::null()) != 0 {
+        return Err(last_error("make mounts private"));
+      }
+    }
+    if pid {
+      fork_into_namespace()?;
+    }
+    Ok(())
+  }
+
+  fn fork_into_namespace() -> Result<(), String> {
+    let child = unsafe { libc::fork() };
+    match child {
+      -1 => Err(last_error("fork into the new pid namespace")),
+      0 => Ok(()),
+      child => {
+        CHILD.store(child, Ordering::Release);
+        unsafe {
+          for signal in [libc::SIGTERM, libc::SIGINT, libc::SIGHUP] {
+            libc::signal(signal, forward as extern "C" fn(libc::c_int) as libc::sighandler_t);
+          }
+        }
+        let mut status = 0;
+        while unsafe { libc::waitpid(child, &mut status, 0) } == -1 {
+          if io::Error::last_os_error().kind() != io::ErrorKind::Interrupted {
+            std::process::exit(1);
+          }
+        }
+        if libc::WIFSIGNALED(status) {
+          unsafe {
+            libc::signal(libc::WTERMSIG(status), libc::SIG_DFL);
+            libc::raise(libc::WTERMSIG(status));
+          }
+        }
+        std::process::exit(libc::WEXITSTATUS(status));
+      }
+    }
+  }
+
+  extern "C" fn forward(signal: libc::c_int) {
+    unsafe {
+      libc::kill(CHILD.load(Ordering::Acquire), signal);
+    }
+  }
+
+  pub fn chroot(root: &Path) -> Result<(), String> {
+    let path = CString::new(root.as_os_str().as_bytes()).map_err(|e| e.to_string())?;
+    unsafe {
+      if libc::chroot(path.as_ptr()) != 0 {
+        return Err(last_error(&format!("change root to {}", root.display())));
+      }
+      if libc::chdir(c"/".as_ptr()) != 0 {
+        return Err(last_error("change directory to the new root"));
+      }
+    }
+    Ok(())
+  }
+
+  /// Mounts a /proc that shows the new pid namespace, if there's a /proc
+  /// to mount it on.
+  pub fn mount_proc() -> Result<(), String> {
+    if !Path::new("/proc").is_dir() {
+      return Ok(());
+    }
+    let proc = c"proc".as_ptr();
+    let rc = unsafe {
+      libc::mount(proc, c"/proc".as_ptr(), proc,
+        libc::MS_NOSUID | libc::MS_NODEV | libc::MS_NOEXEC, std::ptr::null())
+    };
+    if rc != 0 {
+      return Err(last_error("mount /proc"));
+    }
+    Ok(())
+  }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod imp {
+  use std::path::Path;
+
+  pub fn unshare(_mount: bool, _net: bool, _pid: bool) -> Result<(), String> {
+    Err(String::from("namespaces aren't supported on this platform"))
+  }
+
+  pub fn chroot(_root: &Path) -> Result<(), String> {
+    Err(String::from("changing root isn't supported on this platform"))
+  }
+
+  pub fn mount_proc() -> Result<(), String> {
+    Ok(())
+  }
+}
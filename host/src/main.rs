@@ -18,14 +18,51 @@
 use std::env;
 use std::ptr;
 use std::num::ParseIntError;
-use std::{thread, time};
-use std::net::{TcpStream};
-use std::io::{Read, Write};
-use byteorder::{ByteOrder, NetworkEndian};
+use std::net::IpAddr;
+use std::io::{self, Read};
+use std::sync::mpsc;
+use base64::Engine;
+use log::{debug, error, info, trace, warn};
+use thunder_rs::journal::{self, Source};
+use thunder_rs::logging;
 
-pub const ID_INVOKE:      u32 = 1;
-pub const ID_ATTACH:      u32 = 2;
-pub const ID_EXIT:        u32 = 3;
+mod admin;
+mod capabilities;
+mod cli;
+mod cgroup;
+mod compress;
+mod config;
+mod crash;
+mod grpc;
+mod heartbeat;
+mod hosted;
+mod isolation;
+mod notify;
+mod output;
+mod privileges;
+mod protocol;
+mod reconnect;
+mod record;
+mod seccomp;
+mod signals;
+mod socket;
+mod standalone;
+mod startup;
+mod stats;
+mod tls;
+mod transport;
+#[cfg(target_os = "linux")]
+mod vsock;
+
+use thunder_rs_wire::{self as wire, Command, Limits};
+use startup::Phase;
+
+// Control messages go out here, as JSON, whichever codec frames them
+pub use thunder_rs_wire::CONTROL_CHANNEL;
+
+// How long a stopping host waits for queued responses to reach Thunder, as
+// the SDK does when a plugin is destroyed.
+const DEFAULT_DRAIN_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(1);
 
 #[derive(Debug)]
 pub struct InvokeRequest {
@@ -34,6 +71,23 @@ pub struct InvokeRequest {
   pub json: String
 }
 
+#[derive(Debug)]
+pub struct BinaryRequest {
+  pub channel: u32,
+  pub token: String,
+  pub data: Vec<u8>
+}
+
+#[derive(Debug)]
+pub struct WebRequest {
+  pub id: u32,
+  pub channel: u32,
+  pub token: String,
+  pub request: thunder_rs::WebRequest,
+  /// The body follows in web_body frames
+  pub streamed: bool
+}
+
 #[derive(Debug)]
 pub struct AttachRequest {
   pub channel: u32,
@@ -42,107 +96,374 @@ pub struct AttachRequest {
 
 pub enum Request {
   Invoke(InvokeRequest),
+  InvokeBinary(BinaryRequest),
+  InvokeRaw(BinaryRequest),
   Attach(AttachRequest),
+  DumpJournal(),
+  CallResult(u32, String),
+  Subsystem(u32, bool),
+  Memory(),
+  Reset(String),
+  Metrics(),
+  Custom(u32, Vec<u8>),
+  Peer(u32, thunder_rs::Peer),
+  ConfigChanged(String),
+  Web(WebRequest),
+  WebBody(u32, Vec<u8>),
+  Hello(u32, String),
+  Config(String),
+  Ping(u32),
+  Pong(u32),
+  /// A request for the plugin with the given id
+  Routed(u32, Box<Request>),
+  /// Stop, for the given reason. Not a frame; sent by the signal thread, or
+  /// by the reader when it can't read any further.
+  Shutdown(String),
+  /// Reload the plugin libraries, for the given reason. Not a frame either;
+  /// sent by the signal thread on SIGHUP, or by the admin socket.
+  Reload(String),
+  /// Feed systemd's watchdog. Not a frame; sent by the watchdog thread, so
+  /// the watchdog is fed only while the main loop keeps up.
+  Watchdog(),
+  /// Send what the plugins are up to, and the journal, to the admin socket.
+  /// Not a frame.
+  DumpState(mpsc::Sender<serde_json::Value>),
   Exit(),
   Err(String)
 }
 
-pub fn read_request(stream: &mut TcpStream) -> Request {
-  let mut buf = [0; 4];
+impl Request {
+  /// The command the request came as, for the host's metrics; None for
+  /// those that aren't frames.
+  pub fn command(&self) -> Option<&'static str> {
+    Some(match self {
+      Request::Invoke(_) => "invoke",
+      Request::InvokeBinary(_) => "invoke_binary",
+      Request::InvokeRaw(_) => "invoke_raw",
+      Request::Attach(_) => "attach",
+      Request::DumpJournal() => "dump_journal",
+      Request::CallResult(..) => "call_result",
+      Request::Subsystem(..) => "subsystem",
+      Request::Memory() => "memory",
+      Request::Reset(_) => "reset",
+      Request::Metrics() => "metrics",
+      Request::Custom(..) => "custom",
+      Request::Peer(..) => "peer",
+      Request::ConfigChanged(_) => "config_changed",
+      Request::Web(_) => "web",
+      Request::WebBody(..) => "web_body",
+      Request::Hello(..) => "hello",
+      Request::Config(_) => "config",
+      Request::Ping(_) => "ping",
+      Request::Pong(_) => "pong",
+      Request::Exit() => "exit",
+      Request::Err(_) => "bad_frame",
+      Request::Routed(_, req) => return req.command(),
+      Request::Shutdown(_) | Request::Reload(_) | Request::Watchdog() | Request::DumpState(_) => return None
+    })
+  }
+
+  /// Splits off the plugin a routed request is for; unrouted ones are for
+  /// the first plugin, or for the host.
+  pub fn unroute(self) -> (Option<u32>, Request) {
+    match self {
+      Request::Routed(plugin, req) => (Some(plugin), *req),
+      req => (None, req)
+    }
+  }
+}
 
-  stream.read(&mut buf).expect("read_request failed to read command_id");
-  let command_id = NetworkEndian::read_u32(&buf);
-  println!("RUST REMOTE: read command_id {}", command_id);
+/// Reads the next frame in `codec`. Frames that can't be handled but were
+/// read whole, e.g. with a field over its limit, come back as Request::Err;
+/// an error means the connection is gone or out of step and can't be read
+/// further.
+pub fn read_request(stream: &mut impl Read, limits: &Limits, codec: wire::Codec) -> io::Result<Request> {
+  match codec.read_command(stream, limits)? {
+    Ok(command) => {
+      record::inbound(&command);
+      Ok(to_request(command))
+    }
+    Err(bad) => {
+      journal::record(Source::Wire, "bad_frame", None, bad.to_string());
+      Ok(Request::Err(bad.to_string()))
+    }
+  }
+}
 
-  if command_id == ID_INVOKE {
+fn to_request(command: Command) -> Request {
+  trace!("RUST REMOTE: read command_id {}", command.id());
 
-    stream.read(&mut buf).expect("read_request failed to read channel");
-    let channel = NetworkEndian::read_u32(&buf);
-    println!("RUST REMOTE: read channel {}", channel);
-  
-    stream.read(&mut buf).expect("read_request failed to read token_len");
-    let token_len = NetworkEndian::read_u32(&buf);
-    println!("RUST REMOTE: read token_len {}", token_len);
-  
-    stream.read(&mut buf).expect("read_request failed to read json_len");
-    let json_len = NetworkEndian::read_u32(&buf);
-    println!("RUST REMOTE: read json_len {}", json_len);
-  
-    let mut token = String::new();
-  
-    if token_len > 0 {
-      let mut jbuf = vec![0u8; token_len as usize];
-      stream.read_exact(&mut jbuf).expect("read_request failed to read token");
-      token = String::from_utf8(jbuf).expect("read_request failed to read token");
-      println!("RUST REMOTE: read token {}", token);
+  match command {
+    Command::Invoke { channel, token, json } => {
+      let req = InvokeRequest {
+        channel,
+        token,
+        json
+      };
+      debug!("RUST REMOTE: read invoke request: channel={} json={}", req.channel, logging::payload(req.json.as_bytes()));
+      journal::record(Source::Wire, "recv_invoke", Some(req.channel),
+        format!("token_len={} json_len={}", req.token.len(), req.json.len()));
+      Request::Invoke(req)
     }
-  
-    let mut json = String::new();
-  
-    if json_len > 0 {
-      let mut jbuf = vec![0u8; json_len as usize];
-      stream.read_exact(&mut jbuf).expect("read_request failed to read json");
-      json = String::from_utf8(jbuf).expect("read_request failed to read json");
-      println!("RUST REMOTE: read json {}", json);
+    Command::Attach { channel, attach } => {
+      let req = AttachRequest {
+        channel,
+        attach
+      };
+      debug!("RUST REMOTE: read attach request: {:?}", req);
+      journal::record(Source::Wire, "recv_attach", Some(req.channel), format!("attach={}", req.attach));
+      Request::Attach(req)
     }
-  
-    let req = InvokeRequest {
-      channel: channel,
-      token: token,
-      json: json
-    };
-  
-    println!("RUST REMOTE: read invoke request: {:?}", req);
+    Command::InvokeBinary { channel, token, data } => {
+      let req = BinaryRequest {
+        channel,
+        token,
+        data
+      };
+      debug!("RUST REMOTE: read binary request: channel={} len={}", req.channel, req.data.len());
+      journal::record(Source::Wire, "recv_invoke_binary", Some(req.channel),
+        format!("token_len={} data_len={}", req.token.len(), req.data.len()));
+      Request::InvokeBinary(req)
+    }
+    Command::InvokeRaw { channel, token, data } => {
+      let req = BinaryRequest {
+        channel,
+        token,
+        data
+      };
+      debug!("RUST REMOTE: read raw data: channel={} len={}", req.channel, req.data.len());
+      journal::record(Source::Wire, "recv_invoke_raw", Some(req.channel),
+        format!("token_len={} data_len={}", req.token.len(), req.data.len()));
+      Request::InvokeRaw(req)
+    }
+    Command::DumpJournal { path } => {
+      journal::record(Source::Wire, "recv_dump_journal", None, format!("path={}", path));
+      Request::DumpJournal()
+    }
+    Command::CallResult { id, json } => {
+      trace!("RUST REMOTE: read call result {} {}", id, logging::payload(json.as_bytes()));
+      journal::record(Source::Wire, "recv_call_result", None, format!("id={} json_len={}", id, json.len()));
+      Request::CallResult(id, json)
+    }
+    Command::Subsystem { subsystem, active } => {
+      trace!("RUST REMOTE: read subsystem {} active {}", subsystem, active);
+      journal::record(Source::Wire, "recv_subsystem", None, format!("subsystem={} active={}", subsystem, active));
+      Request::Subsystem(subsystem, active)
+    }
+    Command::Memory => {
+      journal::record(Source::Wire, "recv_memory", None, String::new());
+      Request::Memory()
+    }
+    Command::Reset { reason } => {
+      journal::record(Source::Wire, "recv_reset", None, format!("reason={}", reason));
+      Request::Reset(reason)
+    }
+    Command::Metrics => {
+      journal::record(Source::Wire, "recv_metrics", None, String::new());
+      Request::Metrics()
+    }
+    Command::ConfigChanged { json } => {
+      journal::record(Source::Wire, "recv_config_changed", None, format!("json_len={}", json.len()));
+      Request::ConfigChanged(json)
+    }
+    Command::Peer { channel, channel_type, address, origin } => {
+      let peer = thunder_rs::Peer {
+        remote_address: Some(address).filter(|s| !s.is_empty()),
+        origin: Some(origin).filter(|s| !s.is_empty()),
+        channel_type: thunder_rs::ChannelType::from_raw(channel_type)
+      };
+      journal::record(Source::Wire, "recv_peer", Some(channel), format!("{:?}", peer));
+      Request::Peer(channel, peer)
+    }
+    Command::WebRequest { id, channel, token, method, path, headers, body } => {
+      let streamed = body.is_none();
+      journal::record(Source::Wire, "recv_web_request", Some(channel),
+        format!("id={} {} {} body_len={}", id, method, path,
+          body.as_ref().map_or_else(|| "streamed".into(), |body| body.len().to_string())));
+      Request::Web(WebRequest {
+        id,
+        channel,
+        token,
+        request: thunder_rs::WebRequest {
+          method,
+          path,
+          headers: thunder_rs::web::parse_headers(&headers),
+          body: body.unwrap_or_default()
+        },
+        streamed
+      })
+    }
+    Command::WebBody { id, data } => {
+      trace!("RUST REMOTE: read {} bytes of web body {}", data.len(), id);
+      Request::WebBody(id, data)
+    }
+    Command::Hello { version, json } => {
+      journal::record(Source::Wire, "recv_hello", None, format!("version={} {}", version, json));
+      Request::Hello(version, json)
+    }
+    Command::Config { json } => {
+      journal::record(Source::Wire, "recv_config", None, format!("json_len={}", json.len()));
+      Request::Config(json)
+    }
+    Command::Ping { seq } => Request::Ping(seq),
+    Command::Pong { seq } => Request::Pong(seq),
+    Command::Route { plugin, command } => {
+      trace!("RUST REMOTE: read route to plugin {}", plugin);
+      Request::Routed(plugin, Box::new(to_request(*command)))
+    }
+    Command::Custom { id, data } => {
+      journal::record(Source::Wire, "recv_custom", None, format!("id={:#x} len={}", id, data.len()));
+      Request::Custom(id, data)
+    }
+    Command::Exit => {
+      journal::record(Source::Wire, "recv_exit", None, String::new());
+      Request::Exit()
+    }
+  }
+}
 
-    Request::Invoke(req)
+/// Answers web request `id` with web_response, followed by web_chunk
+/// messages when the body is streamed; an empty chunk ends the body. A
+/// panic in the body stream is caught with `catch`.
+fn send_web_response(tx: &thunder_rs::Responder, id: u32, mut res: thunder_rs::WebResponse,
+  catch: thunder_rs::CatchPanic)
+{
+  let send = |msg: serde_json::Value| {
+    if let Err(e) = tx.send(thunder_rs::Message::new(CONTROL_CHANNEL, msg.to_string())) {
+      warn!("RUST REMOTE: failed to send web response {}: {}", id, e);
+    }
+  };
+  if let Err(e) = thunder_rs::web::check_headers(&res.headers) {
+    error!("RUST REMOTE: failing web response {}: {}", id, e);
+    res = thunder_rs::WebResponse::new(500);
+  }
+  let base64 = &base64::engine::general_purpose::STANDARD;
+  let streamed = res.is_streamed();
+  send(serde_json::json!({
+    "command": "web_response",
+    "id": id,
+    "status": res.status,
+    "headers": res.headers,
+    "body": if streamed { String::new() } else { base64.encode(&res.body) },
+    "streamed": streamed
+  }));
+  if streamed {
+    res.write_chunks_in(catch, |data| send(serde_json::json!({
+      "command": "web_chunk",
+      "id": id,
+      "data": base64.encode(data)
+    })));
+    send(serde_json::json!({ "command": "web_chunk", "id": id, "data": "" }));
+  }
+}
+
+/// Writes one frame from plugin `plugin` in `codec`, its payload compressed
+/// if `compressor` finds that worthwhile. The frame is assembled first so it
+/// goes out in as few writes as possible and is never left half written by
+/// a retry.
+pub fn send_response(stream: &mut impl io::Write, plugin: u32, msg: &thunder_rs::Message,
+  compressor: Option<&compress::Compressor>, codec: wire::Codec) -> io::Result<()>
+{
+  let channel = msg.channel;
+  let data: &[u8] = &msg.data;
+  let compressed = compressor.and_then(|c| c.apply(data));
 
-  } else if command_id == ID_ATTACH {
-    
-    stream.read(&mut buf).expect("read_request failed to read channel");
-    let channel = NetworkEndian::read_u32(&buf);
-    println!("RUST REMOTE: read channel {}", channel);
+  let kind = match msg.kind {
+    thunder_rs::MessageKind::Binary => {
+      debug!("RUST REMOTE: sending binary response: channel={} len={}", channel, data.len());
+      wire::Kind::Binary
+    }
+    thunder_rs::MessageKind::Raw => {
+      debug!("RUST REMOTE: sending raw data: channel={} len={}", channel, data.len());
+      wire::Kind::Raw
+    }
+    thunder_rs::MessageKind::Text => {
+      debug!("RUST REMOTE: sending response: channel={} json={}", channel, logging::payload(data));
+      wire::Kind::Text
+    }
+  };
 
-    let mut buf1 = [0; 1];
-    stream.read(&mut buf1).expect("read_request failed to read attach");
-    let attach = buf1[0] != 0;
-    println!("RUST REMOTE: read attach {}", attach);
+  if let Some(compressed) = &compressed {
+    debug!("RUST REMOTE: compressed {} bytes to {}", data.len(), compressed.len());
+  }
+  record::outbound(plugin, channel, kind, data);
 
-    let req = AttachRequest {
-      channel: channel,
-      attach: attach
-    };
-  
-    println!("RUST REMOTE: read attach request: {:?}", req);
+  let response = wire::Response {
+    channel,
+    plugin,
+    kind,
+    compressed: compressed.is_some(),
+    payload: compressed.unwrap_or_else(|| data.to_vec())
+  };
+  let frame = codec.encode_response(&response);
 
-    Request::Attach(req)
+  journal::record(Source::Wire, "send_response", Some(channel),
+    format!("kind={:?} len={} wire_len={}", msg.kind, data.len(), response.payload.len()));
+  trace!("RUST REMOTE: send {} byte frame on channel {}", frame.len(), channel);
 
-  } else if command_id == ID_EXIT {
-  
-    Request::Exit()
-  
-  } else {
+  transport::write_with_retry(stream, &frame)
+}
 
-    Request::Err(format!("Invalid command_id {}", command_id))
-  
-  }
+/// Sends framework calls to the bridge as control messages. Thunder answers
+/// each one with an ID_CALL_RESULT command carrying the same id.
+struct HostLink {
+  responder: thunder_rs::Responder,
+  session: protocol::Session
 }
 
-pub fn send_response(stream: &mut TcpStream, channel: u32, json: String) {
-  let mut buf = [0; 4];
+impl thunder_rs::FrameworkLink for HostLink {
+  fn send_call(&self, id: u32, callsign: &str, method: &str, params: &serde_json::Value) -> Result<(), String> {
+    let msg = serde_json::json!({
+      "command": "call",
+      "id": id,
+      "callsign": callsign,
+      "method": method,
+      "params": params
+    });
+    self.responder.send(thunder_rs::Message::new(CONTROL_CHANNEL, msg.to_string()))
+      .map_err(|e| e.to_string())
+  }
 
-  println!("RUST REMOTE: sending response: channel={} json={}", channel, json);
+  fn set_subsystem(&self, subsystem: thunder_rs::Subsystem, active: bool) -> Result<(), String> {
+    let msg = serde_json::json!({
+      "command": "subsystem",
+      "subsystem": subsystem.id(),
+      "active": active
+    });
+    self.responder.send(thunder_rs::Message::new(CONTROL_CHANNEL, msg.to_string()))
+      .map_err(|e| e.to_string())
+  }
 
-  println!("RUST REMOTE: send channel {}", channel);
-  NetworkEndian::write_u32(&mut buf, channel);
-  stream.write(&buf).expect("send_response failed to write channel");
+  fn report_failure(&self, reason: &str) -> Result<(), String> {
+    let msg = serde_json::json!({
+      "command": "failure",
+      "reason": reason
+    });
+    self.responder.send(thunder_rs::Message::new(CONTROL_CHANNEL, msg.to_string()))
+      .map_err(|e| e.to_string())
+  }
 
-  println!("RUST REMOTE: send json_len {}", json.len());
-  NetworkEndian::write_u32(&mut buf, json.len() as u32);
-  stream.write(&buf).expect("send_response failed to write json_len");
+  fn report_warning(&self, warning: &thunder_rs::Warning, message: &str) -> Result<(), String> {
+    let msg = serde_json::json!({
+      "command": "warning",
+      "category": warning.category(),
+      "message": message
+    });
+    self.responder.send(thunder_rs::Message::new(CONTROL_CHANNEL, msg.to_string()))
+      .map_err(|e| e.to_string())
+  }
 
-  if json.len() > 0 {
-    println!("RUST REMOTE: send json {}", json);
-    stream.write(json.as_bytes()).expect("send_response failed to write json");
+  /// Custom frames go out as binary frames on the control channel, their
+  /// payload prefixed with the frame id.
+  fn send_custom(&self, id: u32, data: &[u8]) -> Result<(), String> {
+    if !self.session.supports("custom_frames") {
+      return Err(String::from("Thunder doesn't handle custom frames"));
+    }
+    let mut payload = Vec::with_capacity(4 + data.len());
+    payload.extend_from_slice(&id.to_be_bytes());
+    payload.extend_from_slice(data);
+    self.responder.send(thunder_rs::Message::binary(CONTROL_CHANNEL, payload))
+      .map_err(|e| e.to_string())
   }
 }
 
@@ -160,128 +481,639 @@ impl thunder_rs::PluginProtocol for RemotePluginProtocol{
 }
  */
 
-fn load_library(shared_lib_name: &str) -> Box<libloading::Library> {
-  println!("RUST REMOTE: load_library {}", shared_lib_name);
+fn load_library(shared_lib_name: &str) -> Result<libloading::Library, String> {
+  info!("RUST REMOTE: load_library {}", shared_lib_name);
   unsafe {
-    Box::new(libloading::Library::new(shared_lib_name).unwrap())
+    libloading::Library::new(shared_lib_name).map_err(|e| e.to_string())
   }
 }
 
-fn load_plugin(lib: &Box<libloading::Library>) -> Box<dyn thunder_rs::Plugin> {
+/// The plugin to run: the one called `name` in a library exporting several
+/// with export_plugins!, or the library's only plugin.
+fn resolve_metadata<'a>(lib: &'a libloading::Library, name: Option<&str>)
+  -> Result<&'a thunder_rs::ServiceMetadata, String>
+{
   unsafe {
-    let sym : libloading::Symbol< *mut thunder_rs::ServiceMetadata > = lib.get(b"thunder_service_metadata\0").unwrap();
-    let service_metadata = ptr::NonNull::new(*sym as *mut thunder_rs::ServiceMetadata).unwrap().as_mut();
-    println!("RUST REMOTE: load_plugin = {}", service_metadata.name);
-
-    let auth_token;
-    if let Ok(jwt) = std::env::var("THUNDER_SECURITY_TOKEN") {
-      auth_token = jwt;
+    if let Ok(sym) = lib.get::<*const thunder_rs::ServiceList>(b"thunder_service_list\0") {
+      let list = ptr::NonNull::new(*sym as *mut thunder_rs::ServiceList)
+        .ok_or_else(|| String::from("thunder_service_list is null"))?
+        .as_ref();
+      list.check_abi()?;
+      let service_metadata = match (name, list.services) {
+        (Some(name), _) => list.find(name)
+          .ok_or_else(|| format!("no plugin {} in library, it has {:?}", name, list.names()))?,
+        (None, [only]) => only,
+        (None, _) => return Err(format!("library has plugins {:?}, choose one with --plugin= or path#name", list.names()))
+      };
+      info!("RUST REMOTE: resolved plugin = {}", service_metadata.name);
+      return Ok(service_metadata);
     }
-    else {
-      auth_token = String::new();
+    let sym : libloading::Symbol< *mut thunder_rs::ServiceMetadata > = lib.get(b"thunder_service_metadata\0")
+      .map_err(|e| e.to_string())?;
+    let service_metadata = ptr::NonNull::new(*sym)
+      .ok_or_else(|| String::from("thunder_service_metadata is null"))?
+      .as_ref();
+    service_metadata.check_abi()?;
+    if name.is_some_and(|name| name != service_metadata.name) {
+      return Err(format!("library has plugin {}, not {}", service_metadata.name, name.unwrap()));
     }
-
-    let plugin_config = thunder_rs::PluginConfig {
-      auth_token: auth_token
-    };
-
-    (service_metadata.create)(plugin_config)
+    info!("RUST REMOTE: resolved plugin = {}", service_metadata.name);
+    Ok(service_metadata)
   }
 }
 
-fn connect_stream(addr: String) -> TcpStream {
-  
-  let mut retries: u32 = 20;
-
-  let stream = loop {
-
-    println!("RUST REMOTE: rust remote trying connect {}", addr);
-    
-    match TcpStream::connect(&addr) {
-      Ok(stream) => {
-        println!("RUST REMOTE: rust remote connected to {}", addr);
-        break stream
-      },
-      Err(error) => {
-        println!("RUST REMOTE: rust remote failed to connec to {}, error:{:?}", addr, error);
-        retries = retries - 1;
-        if retries == 0 {
-          panic!("rust remote failed to connect tcp stream");
-        }
-        thread::sleep(time::Duration::from_millis(100));
-        continue;
+/// Reads frames until Thunder has sent a config command for each of the
+/// `plugins`, returning their configs along with the frames that came
+/// before, which are handled once the plugins are running.
+fn read_startup_config(reader: &mut impl Read, limits: &Limits, plugins: usize)
+  -> Result<(Vec<String>, Vec<Request>), String>
+{
+  let mut configs: Vec<Option<String>> = vec![None; plugins];
+  let mut early = Vec::new();
+  while configs.iter().any(Option::is_none) {
+    let req = read_request(reader, limits, wire::Codec::Legacy).map_err(|e| e.to_string())?;
+    match req.unroute() {
+      (plugin, Request::Config(json)) => {
+        let plugin = plugin.unwrap_or(0);
+        *configs.get_mut(plugin as usize)
+          .ok_or_else(|| format!("config for unknown plugin {}", plugin))? = Some(json);
       }
+      (_, Request::Exit()) => return Err(String::from("told to exit before any config")),
+      (Some(plugin), req) => early.push(Request::Routed(plugin, Box::new(req))),
+      (None, req) => early.push(req)
     }
+  }
+  Ok((configs.into_iter().flatten().collect(), early))
+}
+
+/// Creates the plugin, also returning the config it was given so the host
+/// shares its scope, features and metrics. `config` is the JSON Thunder sent
+/// at startup; without it the config comes from the environment, see
+/// `secrets::load_config`.
+fn load_plugin(service_metadata: &thunder_rs::ServiceMetadata, config: Option<&str>)
+  -> Result<(Box<dyn thunder_rs::Plugin>, thunder_rs::PluginConfig), String>
+{
+  let auth_token;
+  if let Ok(jwt) = std::env::var("THUNDER_SECURITY_TOKEN") {
+    auth_token = jwt;
+  }
+  else {
+    auth_token = String::new();
+  }
+
+  let config = match config {
+    Some(json) => thunder_rs::secrets::parse_config(json)
+      .map_err(|e| format!("invalid config from Thunder: {}", e))?,
+    None => thunder_rs::secrets::load_config(service_metadata.name)?
+  };
+  let features = thunder_rs::Features::from_config(&config);
+  let paths = thunder_rs::Paths::from_config(&config);
+  let scope = thunder_rs::PluginScope::new(service_metadata.name, &paths);
+  let plugin_config = thunder_rs::PluginConfig {
+    auth_token,
+    scope: scope.clone(),
+    config,
+    features,
+    metrics: thunder_rs::Metrics::new(),
+    paths
   };
 
-  stream
+  let plugin = hosted::create(service_metadata.create, service_metadata.catch_panic, plugin_config.clone())
+    .map_err(|cause| format!("{} panicked starting: {}", service_metadata.name, cause))?;
+  thunder_rs::telemetry::start(service_metadata.name, &plugin_config.config,
+    &plugin_config.metrics, &scope);
+  Ok((plugin, plugin_config))
 }
 
+/// Unloads plugin `id` and its library, then loads the library at the same
+/// path again and hands `hosted` a new instance from it, with the config the
+/// old one had last.
+fn reload_plugin(id: usize, (path, name): (&str, Option<&str>), libs: &mut Vec<libloading::Library>,
+  hosted: &mut hosted::Hosted) -> Result<(), String>
+{
+  info!("RUST REMOTE: reloading {}", path);
+  let config = hosted.unload();
+  // dlopen hands back a library that's still loaded as it is
+  drop(libs.remove(id));
+  #[cfg(unix)]
+  if unsafe { libloading::os::unix::Library::open(Some(path), libc::RTLD_LAZY | libc::RTLD_NOLOAD) }.is_ok() {
+    warn!("RUST REMOTE: {} can't be unloaded, its plugin is recreated from the library as it was", path);
+  }
+  libs.insert(id, load_library(path)?);
+  let meta = resolve_metadata(&libs[id], name)?;
+  let (plugin, config) = load_plugin(meta, Some(&config))?;
+  hosted.reload(plugin, meta, config);
+  journal::record(Source::Plugin, "reloaded", Some(id as u32), path.to_string());
+  Ok(())
+}
 
 fn main() -> Result<(), ParseIntError> {
 
-  println!("RUST REMOTE: rust remote adapter process start");
+  // Before anything reads the environment the config file can set
+  let args = config::args()
+    .unwrap_or_else(|e| panic!("RUST REMOTE: Invalid config file: {}", e));
+  let cli = cli::Cli::parse_args(&args);
+
+  if cli.protocol_doc {
+    println!("{}", serde_json::to_string_pretty(&protocol::describe()).unwrap());
+    return Ok(());
+  }
+
+  #[cfg(feature = "protobuf")]
+  if cli.protocol_proto {
+    print!("{}", wire::protobuf::PROTO);
+    return Ok(());
+  }
+
+  // Before the root or the namespaces change, which can take systemd's
+  // socket out of reach
+  let notifier = notify::Notifier::from_env();
+
+  // Before any thread is started, as a new pid namespace is entered by
+  // forking
+  let isolation = cli.isolation();
+  isolation.enter_namespaces()
+    .unwrap_or_else(|e| panic!("RUST REMOTE: failed to isolate the host: {}", e));
+
+  signals::install();
+
+  // The frame stream has to own stdout before anything is logged to it
+  let stdio = cli.stdio;
+  let stdio_connection = if stdio {
+    Some(transport::Connection::stdio()
+      .unwrap_or_else(|e| panic!("RUST REMOTE: failed to set up stdin/stdout transport: {}", e)))
+  } else {
+    None
+  };
+
+  logging::init();
+  if let Some(levels) = &cli.log_level {
+    logging::set_levels(levels);
+  }
+
+  info!("RUST REMOTE: rust remote adapter process start");
+
+  let notifier = notifier.unwrap_or_else(|e| {
+    warn!("RUST REMOTE: not notifying systemd: {}", e);
+    notify::Notifier::default()
+  });
+
+  info!("RUST REMOTE: {:?}", args);
+
+  let listen = cli.listen;
+  let grpc = cli.grpc;
+  let allow: Vec<IpAddr> = cli.allow.clone();
+  let plugin_name = cli.plugin.as_deref();
+  let await_config = cli.await_config;
+  let limits = cli.limits();
+  let inherited_fd = cli.fd;
+  let resources = cli.resources();
+  let filter = cli.filter();
+  let credentials = privileges::Credentials::resolve(cli.user.as_deref(), cli.group.as_deref())
+    .unwrap_or_else(|e| panic!("RUST REMOTE: Invalid --user or --group: {}", e));
 
-  let args : Vec<String> = env::args().collect();
-  println!("RUST REMOTE: {:?}", args);
+  let capabilities = capabilities::Capabilities::probe();
+  capabilities.report();
 
-  if args.len() != 4 {
-    panic!("RUST REMOTE: Invalid command line.  Expected 4 arguments.  Got {}", args.len());
+  // Before the library is loaded, so all the plugin allocates counts
+  resources.apply(capabilities.cgroups)
+    .unwrap_or_else(|e| panic!("RUST REMOTE: failed to apply resource limits: {}", e));
+
+  // Before any library is loaded, as its constructors may print too
+  let output = output::Mode::from_env()
+    .and_then(output::capture)
+    .unwrap_or_else(|e| panic!("RUST REMOTE: {}", e));
+  // After capturing output, whose panic hook the crash report's goes before
+  crash::install();
+
+  // Last, as nothing outside the new root can be reached after
+  isolation.enter_root()
+    .unwrap_or_else(|e| panic!("RUST REMOTE: failed to isolate the host: {}", e));
+
+  // One plugin per library, or per entry when several are listed; a
+  // library exporting more than one is told apart by "path#name"
+  let libraries = cli.libraries().unwrap_or_default();
+  let specs: Vec<(&str, Option<&str>)> = libraries.split(',')
+    .map(|spec| match spec.split_once('#') {
+      Some((path, name)) => (path, Some(name)),
+      None => (spec, plugin_name)
+    })
+    .collect();
+  let mut libs: Vec<libloading::Library> = specs.iter()
+    .map(|(path, _)| startup::run(Phase::LoadLibrary, |_| load_library(path)))
+    .collect();
+
+  // With --stdio, --fd, --standalone or --replay the address is unused, and may be left off
+  let (address, port) = cli.address()
+    .unwrap_or_else(|e| panic!("RUST REMOTE: Invalid address: {}", e));
+  let tls = tls::Settings::from_env()
+    .unwrap_or_else(|e| panic!("RUST REMOTE: Invalid TLS settings: {}", e));
+  let endpoint = transport::Endpoint::parse(&address, &port, tls)
+    .unwrap_or_else(|e| panic!("RUST REMOTE: Invalid address: {}", e));
+  // Before anything's sent or received, to have the whole session
+  if let Some(path) = &cli.record {
+    record::start(path).unwrap_or_else(|e| panic!("RUST REMOTE: failed to record traffic: {}", e));
   }
+  // Kept for the host's whole life, as the gRPC server runs on it
+  let mut grpc_server: Option<std::sync::Arc<grpc::Server>> = None;
+  let connection = if let Some(connection) = stdio_connection {
+    info!("RUST REMOTE: rust remote using stdin/stdout");
+    connection
+  } else if let Some(path) = &cli.replay {
+    startup::run(Phase::Accept, |_| record::replay(path))
+  } else if let Some(addr) = cli.standalone {
+    startup::run(Phase::Accept, |_| standalone::serve(addr))
+  } else if let Some(fd) = inherited_fd {
+    info!("RUST REMOTE: rust remote using inherited fd {}", fd);
+    startup::run(Phase::Accept, |_| transport::inherited(fd))
+  } else if grpc {
+    let server = startup::run(Phase::Accept, |_| grpc::Server::serve(&format!("{}:{}", address, port), &allow));
+    let server = grpc_server.insert(std::sync::Arc::new(server));
+    startup::run(Phase::Accept, |_| server.accept())
+  } else if listen {
+    startup::run(Phase::Accept, |_| endpoint.accept(&allow))
+  } else {
+    startup::run(Phase::Connect, |timeout| endpoint.connect(timeout))
+  };
+  let (mut reader, writer, close) = connection.split();
+
+  // Made while the host may still write where the socket goes
+  let admin_listener = cli.admin.as_deref().map(|path| admin::bind(path)
+    .unwrap_or_else(|e| panic!("RUST REMOTE: failed to set up the admin socket: {}", e)));
+  let metrics_listener = cli.metrics.map(|addr| stats::bind(addr)
+    .unwrap_or_else(|e| panic!("RUST REMOTE: failed to serve metrics: {}", e)));
+
+  // With the library loaded and Thunder connected, nothing more is needed
+  // than what the plugin itself does; the user goes first, as the filter
+  // doesn't allow switching
+  credentials.drop_privileges()
+    .unwrap_or_else(|e| panic!("RUST REMOTE: failed to drop privileges: {}", e));
+  filter.apply()
+    .unwrap_or_else(|e| panic!("RUST REMOTE: failed to apply seccomp filter: {}", e));
 
-  let lib = load_library(&args[1]);
+  // How to get a connection back after it drops: there's no getting stdin
+  // and stdout, an inherited socket or a stand-in for Thunder back, while a listening host waits
+  // for Thunder to dial in again, and a gRPC host for the next call
+  let mut reconnect = reconnect::Policy::from_env();
+  if let Some(retries) = cli.retries {
+    reconnect.attempts = retries;
+  }
+  let stand_in = cli.standalone.is_some() || cli.replay.is_some();
+  let redial: Option<Box<dyn Fn() -> Result<transport::Connection, String> + Send>> = if stdio || inherited_fd.is_some() || stand_in || !reconnect.enabled() {
+    None
+  } else if let Some(server) = grpc_server.clone() {
+    Some(Box::new(move || server.accept()))
+  } else if listen {
+    Some(Box::new(move || endpoint.accept(&allow)))
+  } else {
+    Some(Box::new(move || endpoint.connect(std::time::Duration::ZERO)))
+  };
 
-  let addr = format!("{}:{}", args[2], args[3]);
-  let mut stream = connect_stream(addr);
+  // Thunder sends the config first, before anything a plugin would handle
+  let (startup_configs, early) = if await_config {
+    let (configs, early) = startup::run(Phase::Configure, |_| {
+      read_startup_config(&mut reader, &limits, libs.len())
+    });
+    (configs.into_iter().map(Some).collect(), early)
+  } else {
+    (vec![None; libs.len()], Vec::new())
+  };
 
-  let mut plugin = load_plugin(&lib);
+  let metadata: Vec<&thunder_rs::ServiceMetadata> = libs.iter().zip(&specs)
+    .map(|(lib, (_, name))| startup::run(Phase::ResolveSymbol, |_| resolve_metadata(lib, *name)))
+    .collect();
+  let plugins: Vec<_> = metadata.iter().zip(&startup_configs)
+    .map(|(meta, config)| startup::run(Phase::CreatePlugin, |_| load_plugin(meta, config.as_deref())))
+    .collect();
 
   let mut running = true;
 
-  let mut writer = stream.try_clone()
-    .expect("failed to clone TcpStream");
+  // Written before any plugin's queue is delivered, so it's the first frame
+  // Thunder sees
+  let names: Vec<&str> = metadata.iter().map(|meta| meta.name).collect();
+  let hello = thunder_rs::Message::new(CONTROL_CHANNEL, protocol::hello(&capabilities, &names).to_string());
+  let outbound = std::sync::Arc::new(transport::Outbound::new(writer, close));
+  if let Err(e) = outbound.write(|writer| send_response(writer, 0, &hello, None, wire::Codec::Legacy)) {
+    warn!("RUST REMOTE: failed to send hello: {}", e);
+  }
+
+  let session = protocol::Session::new();
+  let mut hosted: Vec<hosted::Hosted> = plugins.into_iter().enumerate()
+    .zip(&metadata)
+    .map(|((id, (plugin, config)), meta)| {
+      hosted::Hosted::start(id as u32, plugin, meta, config, outbound.clone(), session.clone())
+    })
+    .collect();
+  // The host's own control messages go out with the first plugin's
+  let tx = hosted[0].responder();
+  output.forward_to(tx.clone(), session.clone());
 
-  let (tx, rx) = std::sync::mpsc::channel::<thunder_rs::Message>();
+  // Requests are read on their own thread so call results can be delivered
+  // while the plugin is blocked in Framework::call inside on_message.
+  let frameworks: Vec<thunder_rs::Framework> = hosted.iter().map(hosted::Hosted::framework).collect();
+
+  let (req_tx, req_rx) = mpsc::channel::<(Option<u32>, Request)>();
+  let stopping = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+  let reader_stopping = stopping.clone();
+  let reader_session = session.clone();
+  let reader_outbound = outbound.clone();
+  let reader_notifier = notifier.clone();
+  let heartbeat = heartbeat::Heartbeat::from_env();
+  // Set when Thunder went away without telling the host to exit
+  let lost = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+  let reader_lost = lost.clone();
+  let dead_outbound = outbound.clone();
+  let dead_lost = lost.clone();
+  let dead_tx = req_tx.clone();
+  heartbeat.start(tx.clone(), session.clone(), stopping.clone(), move || {
+    dead_outbound.close();
+    // Closing stdin/stdout doesn't end the reader, so the host stops here
+    if stdio {
+      dead_lost.store(true, std::sync::atomic::Ordering::Release);
+      let _ = dead_tx.send((None, Request::Shutdown(String::from("Thunder stopped answering pings"))));
+    }
+  });
+  let reader_tx = tx.clone();
+  let signal_tx = req_tx.clone();
+  signals::on_shutdown(move |signal| {
+    let _ = signal_tx.send((None, Request::Shutdown(format!("{} received", signal))));
+  });
+  let reload_tx = req_tx.clone();
+  signals::on_reload(move || {
+    let _ = reload_tx.send((None, Request::Reload(String::from("SIGHUP received"))));
+  });
+  for (id, h) in hosted.iter().enumerate() {
+    stats::set_plugin(id, h.name(), h.metrics());
+  }
+  if let Some(listener) = metrics_listener {
+    stats::serve(listener);
+  }
+  let admin = admin_listener.map(|listener| {
+    admin::Admin::serve(listener, hosted.iter().map(hosted::Hosted::describe).collect(),
+      outbound.clone(), session.clone(), req_tx.clone())
+  });
+  if let Some(period) = notifier.watchdog() {
+    let watchdog_tx = req_tx.clone();
+    std::thread::spawn(move || loop {
+      std::thread::sleep(period / 2);
+      if watchdog_tx.send((None, Request::Watchdog())).is_err() {
+        break;
+      }
+    });
+  }
+  // The reader says whether Thunder's first frame was its hello
+  let (answered_tx, answered_rx) = mpsc::channel::<bool>();
+  let startup_session = session.clone();
   std::thread::spawn(move || {
-    while running {
-      while let Ok(msg) = rx.recv() {
-        send_response(&mut writer, msg.channel, msg.data);
+    let mut answered_tx = Some(answered_tx);
+    let mut early = early.into_iter();
+    // Thunder's frames are legacy until bincode_frames is agreed
+    let mut codec = wire::Codec::Legacy;
+    loop {
+      let req = match early.next() {
+        Some(req) => req,
+        None => match read_request(&mut reader, &limits, codec) {
+          Ok(req) => req,
+          Err(e) => {
+            // Closing the connection on shutdown ends the read with an error
+            if reader_stopping.load(std::sync::atomic::Ordering::Acquire) {
+              break;
+            }
+            let redial = match &redial {
+              Some(redial) => redial,
+              None => {
+                error!("RUST REMOTE: stopped reading requests: {}", e);
+                reader_lost.store(true, std::sync::atomic::Ordering::Release);
+                // The signal thread keeps the channel open, so the main
+                // loop has to be told
+                let _ = req_tx.send((None, Request::Shutdown(String::from("connection to Thunder closed"))));
+                break;
+              }
+            };
+            error!("RUST REMOTE: connection to Thunder lost: {}", e);
+            journal::record(Source::Wire, "connection_lost", None, e.to_string());
+            reader_outbound.close();
+            reader_session.reset();
+            reader_notifier.status("reconnecting to Thunder");
+            codec = wire::Codec::Legacy;
+            if req_tx.send((None, Request::Reset(String::from("connection to Thunder lost")))).is_err() {
+              break;
+            }
+            match reconnect.run(|| {
+              let (new_reader, mut new_writer, new_close) = redial()?.split();
+              // Thunder has to see the hello first, before any response
+              // queued in the meantime
+              send_response(&mut new_writer, 0, &hello, None, wire::Codec::Legacy).map_err(|e| e.to_string())?;
+              Ok((new_reader, new_writer, new_close))
+            }) {
+              Ok((new_reader, new_writer, new_close)) => {
+                info!("RUST REMOTE: reconnected to Thunder");
+                journal::record(Source::Wire, "reconnected", None, String::new());
+                reader_notifier.status("connected to Thunder");
+                stats::reconnected();
+                reader = new_reader;
+                reader_outbound.replace(new_writer, new_close);
+                continue;
+              }
+              Err(e) => {
+                error!("RUST REMOTE: not reconnecting to Thunder: {}", e);
+                let _ = req_tx.send((None, Request::Shutdown(String::from("connection to Thunder lost"))));
+                break;
+              }
+            }
+          }
+        }
+      };
+      let (plugin, req) = req.unroute();
+      let answered = matches!(req, Request::Hello(..));
+      match req {
+        Request::CallResult(id, json) => match frameworks.get(plugin.unwrap_or(0) as usize) {
+          Some(framework) => framework.complete(id, &json),
+          None => warn!("RUST REMOTE: call result {} for unknown plugin {}", id, plugin.unwrap_or(0))
+        },
+        Request::Hello(version, json) => {
+          session.agree(version, &json);
+          if let Some(agreed) = session.frame_codec() {
+            // Sent under the writer's lock, so no frame in the new codec can
+            // get ahead of it
+            let announce = thunder_rs::Message::new(CONTROL_CHANNEL,
+              serde_json::json!({ "command": "codec", "codec": agreed.name() }).to_string());
+            let switched = reader_outbound.write(|writer| {
+              send_response(writer, 0, &announce, None, wire::Codec::Legacy)?;
+              session.use_codec(agreed);
+              Ok(())
+            });
+            if let Err(e) = switched {
+              warn!("RUST REMOTE: failed to switch to {} frames: {}", agreed.name(), e);
+            }
+            codec = agreed;
+            info!("RUST REMOTE: switched to {} frames", agreed.name());
+          }
+        },
+        // Answered here so a busy plugin doesn't look like a dead connection
+        Request::Ping(seq) => {
+          let _ = reader_tx.send(heartbeat::pong(seq));
+        },
+        Request::Pong(seq) => heartbeat.pong(seq),
+        Request::Exit() => {
+          let _ = req_tx.send((None, Request::Exit()));
+          break;
+        },
+        req => {
+          if req_tx.send((plugin, req)).is_err() {
+            break;
+          }
+        }
+      }
+      // Only once the hello is agreed, so what follows sees it
+      if let Some(answered_tx) = answered_tx.take() {
+        let _ = answered_tx.send(answered);
       }
     }
   });
 
+  startup::handshake(&answered_rx);
+  startup::forward(&tx, &startup_session);
+
+  notifier.ready("connected to Thunder");
+
   while running {
-    match read_request(&mut stream) {
-      Request::Invoke(req) => {
-        println!("RUST REMOTE: invoking");
-        let req_ctx = thunder_rs::RequestContext {
-          channel: req.channel,
-          auth_token: req.token,
-          responder: tx.clone()
-        };
-        plugin.on_message(req.json,  req_ctx);
+    let (plugin, req) = match req_rx.recv() {
+      Ok(req) => req,
+      Err(_) => {
+        error!("RUST REMOTE: request reader stopped");
+        break;
+      }
+    };
+    let command = req.command();
+    let started = std::time::Instant::now();
+    match req {
+      // The path in the frame is ignored, the peer doesn't get to pick
+      // where the host writes.
+      Request::DumpJournal() => {
+        for h in &hosted {
+          h.record_stats();
+        }
+        match std::env::var_os("THUNDER_RS_JOURNAL_DIR") {
+          Some(dir) if !dir.is_empty() => match journal::dump_to_dir(std::path::Path::new(&dir)) {
+            Ok(path) => info!("RUST REMOTE: dumped journal to {}", path.display()),
+            Err(e) => error!("RUST REMOTE: failed to dump journal to {}: {}", dir.to_string_lossy(), e)
+          },
+          _ => warn!("RUST REMOTE: not dumping journal, THUNDER_RS_JOURNAL_DIR isn't set")
+        }
+      },
+      // Unrouted, these concern every plugin
+      Request::Subsystem(id, active) if plugin.is_none() => {
+        for h in &mut hosted {
+          h.handle(Request::Subsystem(id, active));
+        }
       },
-      Request::Attach(req) => {
-        println!("RUST REMOTE: attaching");
-        if req.attach {
-          plugin.on_client_connect(req.channel);
-        } else {
-          plugin.on_client_disconnect(req.channel);
+      Request::Reset(reason) if plugin.is_none() => {
+        for h in &mut hosted {
+          h.handle(Request::Reset(reason.clone()));
         }
       },
+      Request::CallResult(id, _) => {
+        warn!("RUST REMOTE: unexpected call result {}", id);
+      },
+      Request::Hello(..) => {
+        warn!("RUST REMOTE: unexpected hello");
+      },
+      Request::Ping(_) | Request::Pong(_) => {
+        warn!("RUST REMOTE: unexpected heartbeat");
+      },
+      Request::Config(_) => {
+        warn!("RUST REMOTE: ignoring config after startup, changes come with config_changed");
+      },
+      Request::Routed(..) => {
+        warn!("RUST REMOTE: unexpected routed request");
+      },
       Request::Exit() => {
-        println!("RUST REMOTE: exiting");
+        info!("RUST REMOTE: exiting");
+        running = false;
+      },
+      Request::Reload(reason) => {
+        info!("RUST REMOTE: reloading plugins: {}", reason);
+        notifier.reloading();
+        for (id, (h, spec)) in hosted.iter_mut().zip(&specs).enumerate() {
+          if let Err(e) = reload_plugin(id, *spec, &mut libs, h) {
+            // Without its library the plugin can't go on, and neither can
+            // the host
+            error!("RUST REMOTE: failed to reload {}: {}", spec.0, e);
+            journal::record(Source::Plugin, "shutdown", None, format!("reload failed: {}", e));
+            running = false;
+            break;
+          }
+          if let Some(admin) = &admin {
+            admin.set_plugin(id, h.describe());
+          }
+          stats::set_plugin(id, h.name(), h.metrics());
+        }
+        if running {
+          notifier.ready("plugins reloaded");
+        }
+      },
+      Request::Watchdog() => notifier.feed(),
+      Request::DumpState(reply) => {
+        let plugins: Vec<serde_json::Value> = hosted.iter().map(hosted::Hosted::state).collect();
+        let journal: Vec<String> = journal::snapshot().iter().map(ToString::to_string).collect();
+        let _ = reply.send(serde_json::json!({ "state": plugins, "journal": journal }));
+      },
+      Request::Shutdown(reason) => {
+        info!("RUST REMOTE: shutting down: {}", reason);
+        journal::record(Source::Plugin, "shutdown", None, reason);
         running = false;
       },
       Request::Err(e) => {
-        println!("RUST REMOTE: Failed to read request: {}", e);
+        warn!("RUST REMOTE: ignored bad frame: {}", e);
+      },
+      req => {
+        let plugin = plugin.unwrap_or(0);
+        match hosted.get_mut(plugin as usize) {
+          Some(h) => h.handle(req),
+          None => warn!("RUST REMOTE: ignored request for unknown plugin {}", plugin)
+        }
       }
     }
+    if let Some(command) = command {
+      stats::request(command, started.elapsed());
+    }
+  }
+
+  notifier.stopping();
+  if let Some(admin) = &admin {
+    admin.close();
+  }
+  for h in &mut hosted {
+    h.shutdown();
   }
 
-  drop(stream);
+  // Whatever the plugins queued goes out before the connection is closed
+  stopping.store(true, std::sync::atomic::Ordering::Release);
+  for h in &hosted {
+    h.close();
+  }
+  let timeout = env::var("THUNDER_RS_DRAIN_TIMEOUT_MS").ok()
+    .and_then(|s| s.parse::<u64>().ok())
+    .map(std::time::Duration::from_millis)
+    .unwrap_or(DEFAULT_DRAIN_TIMEOUT);
+  let deadline = std::time::Instant::now() + timeout;
+  while !hosted.iter().all(hosted::Hosted::delivered) && std::time::Instant::now() < deadline {
+    std::thread::sleep(std::time::Duration::from_millis(5));
+  }
+  let dropped: usize = hosted.into_iter().map(hosted::Hosted::finish).sum();
+  if dropped > 0 {
+    warn!("RUST REMOTE: dropped {} undelivered messages on shutdown", dropped);
+  }
+  outbound.close();
 
-  println!("RUST REMOTE: rust remote adapter process end");
+  info!("RUST REMOTE: rust remote adapter process end");
+  // A launcher handing the host pipes learns from the status whether it
+  // ended because they broke
+  let broken = stdio && lost.load(std::sync::atomic::Ordering::Acquire);
+  if record::mismatched() || broken {
+    std::process::exit(1);
+  }
   Ok(())
 }
 
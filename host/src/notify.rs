@@ -0,0 +1,158 @@
+/*
+ * Copyright 2022 Comcast Cable Communications Management, LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+//! Tells systemd how the host is doing, for a unit of Type=notify: READY=1
+//! once the plugins are loaded and Thunder is connected, RELOADING=1 while
+//! they're reloaded and STOPPING=1 on the way out. With WatchdogSec= set,
+//! the main loop feeds the watchdog whenever the watchdog thread asks it
+//! to, every half period, so a plugin holding the main loop up gets the
+//! host restarted.
+//!
+//! NOTIFY_SOCKET is connected to first thing, before the host changes its
+//! root or namespaces, and taken out of the environment so the plugin's
+//! children don't speak for the host. With --unshare=pid it's the host's
+//! child that notifies, which takes NotifyAccess=all.
+use std::time::Duration;
+
+use log::{debug, warn};
+
+/// Where systemd is told, if it's listening.
+#[derive(Clone, Default)]
+pub struct Notifier {
+  #[cfg(target_os = "linux")]
+  socket: Option<std::sync::Arc<std::os::unix::net::UnixDatagram>>,
+  watchdog: Option<Duration>
+}
+
+impl Notifier {
+  /// Connects to NOTIFY_SOCKET, if systemd gave one. Has to be called
+  /// before any thread is started, as it changes the environment.
+  pub fn from_env() -> Result<Notifier, String> {
+    let watchdog = watchdog_period();
+    std::env::remove_var("WATCHDOG_USEC");
+    std::env::remove_var("WATCHDOG_PID");
+    let path = match std::env::var_os("NOTIFY_SOCKET") {
+      Some(path) => path,
+      None => return Ok(Notifier::default())
+    };
+    std::env::remove_var("NOTIFY_SOCKET");
+    imp::connect(&path).map(|notifier| Notifier { watchdog, ..notifier })
+  }
+
+  /// The plugins are up and Thunder connected.
+  pub fn ready(&self, status: &str) {
+    self.send(&format!("READY=1\nSTATUS={}", status));
+  }
+
+  /// The plugins are being reloaded; ready says when they're back.
+  pub fn reloading(&self) {
+    self.send(&format!("RELOADING=1\nMONOTONIC_USEC={}", imp::monotonic_usec()));
+  }
+
+  pub fn stopping(&self) {
+    self.send("STOPPING=1");
+  }
+
+  /// A line for `systemctl status` to show.
+  pub fn status(&self, status: &str) {
+    self.send(&format!("STATUS={}", status));
+  }
+
+  /// How often the watchdog has to be fed, if it's on.
+  pub fn watchdog(&self) -> Option<Duration> {
+    self.watchdog
+  }
+
+  pub fn feed(&self) {
+    self.send("WATCHDOG=1");
+  }
+
+  fn send(&self, state: &str) {
+    if let Err(e) = imp::send(self, state) {
+      warn!("RUST REMOTE: failed to notify systemd: {}", e);
+    } else {
+      debug!("RUST REMOTE: notified systemd {:?}", state);
+    }
+  }
+}
+
+/// WATCHDOG_USEC, if it's meant for this process.
+fn watchdog_period() -> Option<Duration> {
+  let usec: u64 = std::env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+  match std::env::var("WATCHDOG_PID") {
+    Ok(pid) if pid.parse() != Ok(std::process::id()) => None,
+    _ if usec == 0 => None,
+    _ => Some(Duration::from_micros(usec))
+  }
+}
+
+#[cfg(target_os = "linux")]
+mod imp {
+  use std::ffi::OsStr;
+  use std::os::linux::net::SocketAddrExt;
+  use std::os::unix::ffi::OsStrExt;
+  use std::os::unix::net::{SocketAddr, UnixDatagram};
+  use std::sync::Arc;
+
+  use super::Notifier;
+
+  /// Connects to `path`, a file or, starting with '@', an abstract name.
+  pub fn connect(path: &OsStr) -> Result<Notifier, String> {
+    let addr = match path.as_bytes().strip_prefix(b"@") {
+      Some(name) => SocketAddr::from_abstract_name(name),
+      None => SocketAddr::from_pathname(path)
+    }.map_err(|e| format!("invalid NOTIFY_SOCKET {:?}: {}", path, e))?;
+    let socket = UnixDatagram::unbound().map_err(|e| e.to_string())?;
+    socket.connect_addr(&addr).map_err(|e| format!("failed to connect to NOTIFY_SOCKET {:?}: {}", path, e))?;
+    Ok(Notifier { socket: Some(Arc::new(socket)), watchdog: None })
+  }
+
+  pub fn send(notifier: &Notifier, state: &str) -> Result<(), String> {
+    match &notifier.socket {
+      Some(socket) => socket.send(state.as_bytes()).map(drop).map_err(|e| e.to_string()),
+      None => Ok(())
+    }
+  }
+
+  pub fn monotonic_usec() -> u64 {
+    let mut now = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+    unsafe {
+      libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut now);
+    }
+    now.tv_sec as u64 * 1_000_000 + now.tv_nsec as u64 / 1_000
+  }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod imp {
+  use std::ffi::OsStr;
+
+  use super::Notifier;
+
+  /// systemd is Linux only; there's nothing to tell elsewhere
+  pub fn connect(_path: &OsStr) -> Result<Notifier, String> {
+    Ok(Notifier::default())
+  }
+
+  pub fn send(_notifier: &Notifier, _state: &str) -> Result<(), String> {
+    Ok(())
+  }
+
+  pub fn monotonic_usec() -> u64 {
+    0
+  }
+}
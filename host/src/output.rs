@@ -0,0 +1,161 @@
+/*
+ * Copyright 2022 Comcast Cable Communications Management, LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+//! Captures what the plugin writes to stdout and stderr, with println! or a
+//! C library's printf alike, so it isn't lost when the host runs as a
+//! daemon. THUNDER_RS_PLUGIN_OUTPUT picks what happens to it: "log" sends
+//! each line through the host's logger, at info for stdout and warn for
+//! stderr, under the targets plugin::stdout and plugin::stderr; "forward"
+//! also sends it to Thunder as an output control message once plugin_output
+//! is agreed. Unset or "off", the streams are left alone.
+//!
+//! The logger carries on writing where stdout went before. Panics are
+//! logged rather than written to stderr, as one may end the process before
+//! its message is read back.
+use std::env;
+use std::sync::{Arc, Mutex};
+
+use crate::protocol::Session;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+  Off,
+  Log,
+  Forward
+}
+
+impl Mode {
+  pub fn from_env() -> Result<Mode, String> {
+    match env::var("THUNDER_RS_PLUGIN_OUTPUT").ok().as_deref() {
+      None | Some("") | Some("off") => Ok(Mode::Off),
+      Some("log") => Ok(Mode::Log),
+      Some("forward") => Ok(Mode::Forward),
+      Some(other) => Err(format!("unknown THUNDER_RS_PLUGIN_OUTPUT {}, expected off, log or forward", other))
+    }
+  }
+}
+
+type Sink = Arc<Mutex<Option<(thunder_rs::Responder, Session)>>>;
+
+/// Where captured lines go besides the log.
+#[derive(Clone, Default)]
+pub struct Capture {
+  forward: Option<Sink>
+}
+
+impl Capture {
+  /// Sends lines to Thunder through `tx` from now on, when forwarding.
+  /// Lines captured before then are only logged.
+  pub fn forward_to(&self, tx: thunder_rs::Responder, session: Session) {
+    if let Some(sink) = &self.forward {
+      *sink.lock().unwrap() = Some((tx, session));
+    }
+  }
+}
+
+/// Redirects stdout and stderr as `mode` says.
+pub fn capture(mode: Mode) -> Result<Capture, String> {
+  if mode == Mode::Off {
+    return Ok(Capture::default());
+  }
+  imp::capture(mode)
+}
+
+#[cfg(unix)]
+mod imp {
+  use std::fs::File;
+  use std::io::{self, BufRead, BufReader, Write};
+  use std::os::fd::{AsFd, AsRawFd, FromRawFd, OwnedFd, RawFd};
+
+  use log::Level;
+
+  use super::{Capture, Mode, Sink};
+  use crate::CONTROL_CHANNEL;
+
+  pub fn capture(mode: Mode) -> Result<Capture, String> {
+    let sink: Option<Sink> = match mode {
+      Mode::Forward => Some(Sink::default()),
+      _ => None
+    };
+
+    io::stdout().flush().map_err(|e| e.to_string())?;
+    let original = io::stdout().as_fd().try_clone_to_owned().map_err(|e| e.to_string())?;
+    thunder_rs::logging::set_output(Box::new(File::from(original)));
+    std::panic::set_hook(Box::new(|info| log::error!("RUST REMOTE: {}", info)));
+
+    let stdout = redirect(libc::STDOUT_FILENO)
+      .map_err(|e| format!("failed to capture stdout: {}", e))?;
+    let stderr = redirect(libc::STDERR_FILENO)
+      .map_err(|e| format!("failed to capture stderr: {}", e))?;
+    read_lines(stdout, "stdout", Level::Info, sink.clone());
+    read_lines(stderr, "stderr", Level::Warn, sink.clone());
+    Ok(Capture { forward: sink })
+  }
+
+  /// Points `fd` at a new pipe, returning the pipe's read end.
+  fn redirect(fd: RawFd) -> io::Result<File> {
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } < 0 {
+      return Err(io::Error::last_os_error());
+    }
+    let (read, write) = unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+    // Processes the plugin starts inherit the redirected fd, not these
+    for end in [&read, &write] {
+      if unsafe { libc::fcntl(end.as_raw_fd(), libc::F_SETFD, libc::FD_CLOEXEC) } < 0 {
+        return Err(io::Error::last_os_error());
+      }
+    }
+    if unsafe { libc::dup2(write.as_raw_fd(), fd) } < 0 {
+      return Err(io::Error::last_os_error());
+    }
+    Ok(File::from(read))
+  }
+
+  fn read_lines(pipe: File, stream: &'static str, level: Level, sink: Option<Sink>) {
+    std::thread::spawn(move || {
+      let target = format!("plugin::{}", stream);
+      let mut reader = BufReader::new(pipe);
+      let mut buf = Vec::new();
+      loop {
+        buf.clear();
+        match reader.read_until(b'\n', &mut buf) {
+          Ok(0) | Err(_) => break,
+          Ok(_) => ()
+        }
+        let line = String::from_utf8_lossy(&buf);
+        let line = line.trim_end_matches(['\n', '\r']);
+        log::log!(target: &target, level, "{}", line);
+        let Some(sink) = &sink else { continue };
+        if let Some((tx, session)) = &*sink.lock().unwrap() {
+          if session.agreed("plugin_output") {
+            let msg = serde_json::json!({ "command": "output", "stream": stream, "line": line });
+            let _ = tx.send(thunder_rs::Message::new(CONTROL_CHANNEL, msg.to_string()));
+          }
+        }
+      }
+    });
+  }
+}
+
+#[cfg(not(unix))]
+mod imp {
+  use super::{Capture, Mode};
+
+  pub fn capture(_mode: Mode) -> Result<Capture, String> {
+    Err(String::from("capturing plugin output is only supported on unix"))
+  }
+}
@@ -0,0 +1,160 @@
+/*
+ * Copyright 2022 Comcast Cable Communications Management, LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+//! Gives up the privileges the host inherits from Thunder, usually root's,
+//! once the library is loaded and the connection is up, so the plugin
+//! doesn't keep them for its whole life. `--user=` and `--group=` take a
+//! name or a number; a user alone brings its primary and supplementary
+//! groups along, a group alone keeps the user the host started as.
+//!
+//! Names are looked up while the command line is read, before anything
+//! that could make the user database unreachable.
+use log::info;
+
+/// Who to become; neither set keeps the host as it was started.
+#[derive(Debug, Clone, Default)]
+pub struct Credentials {
+  user: Option<User>,
+  gid: Option<u32>
+}
+
+#[derive(Debug, Clone)]
+struct User {
+  name: String,
+  uid: u32,
+  gid: u32
+}
+
+impl Credentials {
+  /// Looks up the --user and --group values.
+  pub fn resolve(user: Option<&str>, group: Option<&str>) -> Result<Credentials, String> {
+    let user = user.map(|user| imp::user(user)
+      .map(|(name, uid, gid)| User { name, uid, gid })
+      .ok_or_else(|| format!("no user {}", user)))
+      .transpose()?;
+    let gid = group.map(|group| imp::group(group).ok_or_else(|| format!("no group {}", group)))
+      .transpose()?
+      .or(user.as_ref().map(|user| user.gid));
+    Ok(Credentials { user, gid })
+  }
+
+  /// Switches to the group and then the user, for good. Does nothing
+  /// without either.
+  pub fn drop_privileges(&self) -> Result<(), String> {
+    if let Some(gid) = self.gid {
+      imp::set_group(gid, self.user.as_ref().map(|user| user.name.as_str()))?;
+      info!("RUST REMOTE: switched to group {}", gid);
+    }
+    if let Some(user) = &self.user {
+      imp::set_user(user.uid)?;
+      info!("RUST REMOTE: switched to user {} ({})", user.name, user.uid);
+    }
+    Ok(())
+  }
+}
+
+#[cfg(unix)]
+mod imp {
+  use std::ffi::{CStr, CString};
+  use std::io;
+
+  /// Big enough for the entries of any sane user database
+  const BUF_LEN: usize = 16 * 1024;
+
+  /// The name, uid and primary gid of user `user`, a name or a number.
+  pub fn user(user: &str) -> Option<(String, u32, u32)> {
+    let mut pwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut buf = vec![0 as libc::c_char; BUF_LEN];
+    let mut found: *mut libc::passwd = std::ptr::null_mut();
+    let rc = unsafe {
+      match user.parse::<libc::uid_t>() {
+        Ok(uid) => libc::getpwuid_r(uid, &mut pwd, buf.as_mut_ptr(), buf.len(), &mut found),
+        Err(_) => {
+          let name = CString::new(user).ok()?;
+          libc::getpwnam_r(name.as_ptr(), &mut pwd, buf.as_mut_ptr(), buf.len(), &mut found)
+        }
+      }
+    };
+    if rc != 0 || found.is_null() {
+      return None;
+    }
+    let name = unsafe { CStr::from_ptr(pwd.pw_name) }.to_string_lossy().into_owned();
+    Some((name, pwd.pw_uid, pwd.pw_gid))
+  }
+
+  /// The gid of group `group`, a name or a number.
+  pub fn group(group: &str) -> Option<u32> {
+    if let Ok(gid) = group.parse() {
+      return Some(gid);
+    }
+    let name = CString::new(group).ok()?;
+    let mut grp: libc::group = unsafe { std::mem::zeroed() };
+    let mut buf = vec![0 as libc::c_char; BUF_LEN];
+    let mut found: *mut libc::group = std::ptr::null_mut();
+    let rc = unsafe { libc::getgrnam_r(name.as_ptr(), &mut grp, buf.as_mut_ptr(), buf.len(), &mut found) };
+    if rc != 0 || found.is_null() {
+      return None;
+    }
+    Some(grp.gr_gid)
+  }
+
+  /// Sets the group, and the supplementary groups to those of `user` or to
+  /// none besides it.
+  pub fn set_group(gid: u32, user: Option<&str>) -> Result<(), String> {
+    let groups = match user.map(CString::new) {
+      Some(Ok(name)) => unsafe { libc::initgroups(name.as_ptr(), gid as _) },
+      _ => unsafe { libc::setgroups(1, &gid) }
+    };
+    if groups != 0 {
+      return Err(format!("failed to set supplementary groups: {}", io::Error::last_os_error()));
+    }
+    if unsafe { libc::setgid(gid) } != 0 {
+      return Err(format!("failed to set group {}: {}", gid, io::Error::last_os_error()));
+    }
+    Ok(())
+  }
+
+  /// Sets the user, making sure there's no way back to root.
+  pub fn set_user(uid: u32) -> Result<(), String> {
+    if unsafe { libc::setuid(uid) } != 0 {
+      return Err(format!("failed to set user {}: {}", uid, io::Error::last_os_error()));
+    }
+    if uid != 0 && unsafe { libc::setuid(0) } == 0 {
+      return Err(String::from("still able to become root after switching user"));
+    }
+    Ok(())
+  }
+}
+
+#[cfg(not(unix))]
+mod imp {
+  pub fn user(_user: &str) -> Option<(String, u32, u32)> {
+    None
+  }
+
+  pub fn group(_group: &str) -> Option<u32> {
+    None
+  }
+
+  pub fn set_group(_gid: u32, _user: Option<&str>) -> Result<(), String> {
+    Err(String::from("switching group isn't supported on this platform"))
+  }
+
+  pub fn set_user(_uid: u32) -> Result<(), String> {
+    Err(String::from("switching user isn't supported on this platform"))
+  }
+}
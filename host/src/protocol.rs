@@ -0,0 +1,410 @@
+/*
+ * Copyright 2022 Comcast Cable Communications Management, LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+//! A machine-readable description of the frame protocol, built from the same
+//! constants read_request and send_response use. `WPEHost --protocol-doc`
+//! prints it as JSON for the C++ bridge's test suite to check itself against.
+//!
+//! It also holds the handshake: the host's first frame is a hello control
+//! message with its version and features, and Thunder answers with a hello
+//! command naming the features it handles too. Features the host starts
+//! itself, like outbound custom frames, are only used once agreed. Bridges
+//! that predate the handshake never answer, and get everything.
+//!
+//! A host running several plugins lists them in its hello; a plugin's id is
+//! its index there. Commands for any but the first come wrapped in a route
+//! command, and frames from them carry the plugin flag.
+//!
+//! Compression algorithms are features too, offered only when built in.
+//!
+//! Agreeing to bincode_frames or protobuf_frames switches both directions to
+//! that codec from thunder_rs_wire, protobuf if both are: Thunder from the
+//! frame after its hello, the host from the frame after a codec control
+//! message. Everything before, and everything on a new connection until the
+//! next handshake, is legacy. protobuf_frames is offered only when built in.
+use std::sync::{Arc, RwLock};
+
+use log::{info, warn};
+use serde_json::{json, Value};
+
+use crate::capabilities::Capabilities;
+use crate::compress::Algorithm;
+
+use thunder_rs_wire::{Codec, CONTROL_CHANNEL, FLAG_BINARY, FLAG_COMPRESSED, FLAG_PLUGIN, FLAG_RAW, STREAMED_BODY};
+use thunder_rs_wire::{ID_ATTACH, ID_CALL_RESULT, ID_CONFIG, ID_CONFIG_CHANGED, ID_DUMP_JOURNAL, ID_EXIT, ID_HELLO, ID_INVOKE, ID_INVOKE_BINARY, ID_INVOKE_RAW, ID_MEMORY, ID_METRICS, ID_PEER, ID_PING, ID_PONG, ID_RESET, ID_ROUTE, ID_SUBSYSTEM, ID_WEB_BODY, ID_WEB_REQUEST};
+
+/// Bumped whenever a frame changes shape or a command is added.
+pub const PROTOCOL_VERSION: u32 = 20;
+
+#[derive(Clone, Copy)]
+enum Kind {
+  U32,
+  U8,
+  /// UTF-8 text whose length is given by an earlier field
+  Utf8(&'static str),
+  /// Raw bytes whose length is given by an earlier field
+  Bytes(&'static str)
+}
+
+struct Field {
+  name: &'static str,
+  kind: Kind,
+  doc: &'static str
+}
+
+struct Frame {
+  name: &'static str,
+  id: u32,
+  since: u32,
+  fields: &'static [Field],
+  doc: &'static str
+}
+
+const fn field(name: &'static str, kind: Kind, doc: &'static str) -> Field {
+  Field { name, kind, doc }
+}
+
+/// Commands from Thunder to the host. Each starts with its u32 command id,
+/// which isn't repeated in the field tables.
+static COMMANDS: &[Frame] = &[
+  Frame { name: "invoke", id: ID_INVOKE, since: 1, doc: "A JSON-RPC request from a client", fields: &[
+    field("channel", Kind::U32, "Client channel"),
+    field("token_len", Kind::U32, "Length of token"),
+    field("json_len", Kind::U32, "Length of json"),
+    field("token", Kind::Utf8("token_len"), "Client's security token, may be empty"),
+    field("json", Kind::Utf8("json_len"), "The request")
+  ]},
+  Frame { name: "attach", id: ID_ATTACH, since: 1, doc: "A client connecting or disconnecting", fields: &[
+    field("channel", Kind::U32, "Client channel"),
+    field("attach", Kind::U8, "1 on connect, 0 on disconnect")
+  ]},
+  Frame { name: "exit", id: ID_EXIT, since: 1, doc: "Shut the host down", fields: &[] },
+  Frame { name: "invoke_binary", id: ID_INVOKE_BINARY, since: 1, doc: "A binary WebSocket frame from a client", fields: &[
+    field("channel", Kind::U32, "Client channel"),
+    field("token_len", Kind::U32, "Length of token"),
+    field("data_len", Kind::U32, "Length of data"),
+    field("token", Kind::Utf8("token_len"), "Client's security token, may be empty"),
+    field("data", Kind::Bytes("data_len"), "The frame's payload")
+  ]},
+  Frame { name: "dump_journal", id: ID_DUMP_JOURNAL, since: 1, doc: "Write the event journal to a new file in THUNDER_RS_JOURNAL_DIR", fields: &[
+    field("path_len", Kind::U32, "Length of path"),
+    field("path", Kind::Utf8("path_len"), "Ignored, the host names the file")
+  ]},
+  Frame { name: "call_result", id: ID_CALL_RESULT, since: 1, doc: "The response to a call control message", fields: &[
+    field("call_id", Kind::U32, "id of the call being answered"),
+    field("json_len", Kind::U32, "Length of json"),
+    field("json", Kind::Utf8("json_len"), "JSON-RPC response")
+  ]},
+  Frame { name: "subsystem", id: ID_SUBSYSTEM, since: 1, doc: "A subsystem going up or down", fields: &[
+    field("subsystem", Kind::U32, "Thunder's subsystem id"),
+    field("active", Kind::U8, "1 when up, 0 when down")
+  ]},
+  Frame { name: "memory", id: ID_MEMORY, since: 1, doc: "Ask for a memory control message", fields: &[] },
+  Frame { name: "reset", id: ID_RESET, since: 2, doc: "Every client is gone at once, e.g. Thunder restarted", fields: &[
+    field("reason_len", Kind::U32, "Length of reason"),
+    field("reason", Kind::Utf8("reason_len"), "Why, for the logs")
+  ]},
+  Frame { name: "metrics", id: ID_METRICS, since: 3, doc: "Ask for a metrics control message", fields: &[] },
+  Frame { name: "peer", id: ID_PEER, since: 5, doc: "Who the client on a channel is, sent after attach", fields: &[
+    field("channel", Kind::U32, "Client channel"),
+    field("channel_type", Kind::U32, "1 WebSocket, 2 HTTP, 0 unknown"),
+    field("address_len", Kind::U32, "Length of address"),
+    field("address", Kind::Utf8("address_len"), "Client's remote address, may be empty"),
+    field("origin_len", Kind::U32, "Length of origin"),
+    field("origin", Kind::Utf8("origin_len"), "Origin header the client sent, may be empty")
+  ]},
+  Frame { name: "config_changed", id: ID_CONFIG_CHANGED, since: 6, doc: "New configuration for the running plugin", fields: &[
+    field("json_len", Kind::U32, "Length of json"),
+    field("json", Kind::Utf8("json_len"), "The whole configuration document")
+  ]},
+  Frame { name: "web_request", id: ID_WEB_REQUEST, since: 9, doc: "A plain HTTP request routed to the plugin, answered by a web_response control message", fields: &[
+    field("id", Kind::U32, "Echoed in the web_response"),
+    field("channel", Kind::U32, "Client channel"),
+    field("token_len", Kind::U32, "Length of token"),
+    field("token", Kind::Utf8("token_len"), "Client's security token, may be empty"),
+    field("method_len", Kind::U32, "Length of method"),
+    field("method", Kind::Utf8("method_len"), "HTTP method, e.g. GET"),
+    field("path_len", Kind::U32, "Length of path"),
+    field("path", Kind::Utf8("path_len"), "Path below the callsign, with any query string"),
+    field("headers_len", Kind::U32, "Length of headers"),
+    field("headers", Kind::Utf8("headers_len"), "\"Name: value\" lines, each ending in CRLF"),
+    field("body_len", Kind::U32, "Length of body, or streamed_body (since 10) if it follows in web_body frames"),
+    field("body", Kind::Bytes("body_len"), "Request body")
+  ]},
+  Frame { name: "invoke_raw", id: ID_INVOKE_RAW, since: 11, doc: "Bytes from a client on a raw socket channel", fields: &[
+    field("channel", Kind::U32, "Client channel"),
+    field("token_len", Kind::U32, "Length of token"),
+    field("data_len", Kind::U32, "Length of data"),
+    field("token", Kind::Utf8("token_len"), "Client's security token, may be empty"),
+    field("data", Kind::Bytes("data_len"), "The bytes as they arrived")
+  ]},
+  Frame { name: "web_body", id: ID_WEB_BODY, since: 10, doc: "Part of a streamed web_request body", fields: &[
+    field("id", Kind::U32, "id of the web_request"),
+    field("data_len", Kind::U32, "Length of data, 0 at the end of the body"),
+    field("data", Kind::Bytes("data_len"), "The next part of the body")
+  ]},
+  Frame { name: "hello", id: ID_HELLO, since: 12, doc: "Thunder's answer to the hello control message", fields: &[
+    field("version", Kind::U32, "Thunder's protocol version"),
+    field("json_len", Kind::U32, "Length of json"),
+    field("json", Kind::Utf8("json_len"), "{\"features\": [names]}, the features Thunder handles")
+  ]},
+  Frame { name: "config", id: ID_CONFIG, since: 13, doc: "The plugin's configuration, Thunder's first frame when the host runs with --await-config", fields: &[
+    field("json_len", Kind::U32, "Length of json"),
+    field("json", Kind::Utf8("json_len"), "The whole configuration document")
+  ]},
+  Frame { name: "ping", id: ID_PING, since: 14, doc: "Thunder checking the connection, answered by a pong control message", fields: &[
+    field("seq", Kind::U32, "Echoed in the pong")
+  ]},
+  Frame { name: "pong", id: ID_PONG, since: 14, doc: "The answer to a ping control message", fields: &[
+    field("seq", Kind::U32, "seq of the ping being answered")
+  ]},
+  Frame { name: "route", id: ID_ROUTE, since: 15, doc: "A command for one plugin of several, followed by that command whole", fields: &[
+    field("plugin", Kind::U32, "Index of the plugin in the hello's plugins")
+  ]}
+];
+
+/// The only frame the host sends.
+static RESPONSE: Frame = Frame { name: "response", id: 0, since: 1, doc: "A message for a client, or a control message", fields: &[
+  field("channel", Kind::U32, "Client channel, or the control channel"),
+  field("len", Kind::U32, "Length of payload, with the binary flag"),
+  field("payload", Kind::Bytes("len"), "JSON text, or a binary frame when flagged")
+]};
+
+fn fields_json(fields: &[Field], start: usize) -> Vec<Value> {
+  // Offsets are known up to the first variable length field
+  let mut offset = Some(start);
+  fields.iter().map(|f| {
+    let (kind, size, length_field) = match f.kind {
+      Kind::U32 => ("u32", Some(4), None),
+      Kind::U8 => ("u8", Some(1), None),
+      Kind::Utf8(len) => ("utf8", None, Some(len)),
+      Kind::Bytes(len) => ("bytes", None, Some(len))
+    };
+    let v = json!({
+      "name": f.name,
+      "type": kind,
+      "offset": offset,
+      "size": size,
+      "length_field": length_field,
+      "doc": f.doc
+    });
+    offset = match (offset, size) {
+      (Some(o), Some(s)) => Some(o + s),
+      _ => None
+    };
+    v
+  }).collect()
+}
+
+fn frame_json(frame: &Frame, start: usize) -> Value {
+  json!({
+    "name": frame.name,
+    "id": if start > 0 { Some(frame.id) } else { None },
+    "since": frame.since,
+    "doc": frame.doc,
+    "fields": fields_json(frame.fields, start)
+  })
+}
+
+/// The whole protocol. Integers are big-endian.
+pub fn describe() -> Value {
+  json!({
+    "version": PROTOCOL_VERSION,
+    "byte_order": "big-endian",
+    "commands": COMMANDS.iter().map(|c| frame_json(c, 4)).collect::<Vec<_>>(),
+    "response": frame_json(&RESPONSE, 0),
+    "custom": {
+      "since": 4,
+      "ids": { "first": thunder_rs::framework::CUSTOM_FRAME_IDS.start(), "last": thunder_rs::framework::CUSTOM_FRAME_IDS.end() },
+      "inbound": { "fields": fields_json(&[
+        field("data_len", Kind::U32, "Length of data"),
+        field("data", Kind::Bytes("data_len"), "Vendor defined")
+      ], 4) },
+      "outbound": "A binary frame on the control channel whose payload is the u32 id followed by the data, once custom_frames is agreed"
+    },
+    "flags": {
+      "binary": { "field": "len", "mask": FLAG_BINARY, "since": 1 },
+      "raw": { "field": "len", "mask": FLAG_RAW, "since": 11 },
+      "plugin": { "field": "len", "mask": FLAG_PLUGIN, "since": 15,
+        "doc": "The sending plugin's u32 index follows len, ahead of the payload" },
+      "compressed": { "field": "len", "mask": FLAG_COMPRESSED, "since": 17,
+        "doc": "The payload is compressed with zstd if zstd_payloads was agreed, zlib otherwise; len is its compressed length" },
+      "streamed_body": { "frame": "web_request", "field": "body_len", "value": STREAMED_BODY, "since": 10 }
+    },
+    "control": {
+      "channel": CONTROL_CHANNEL,
+      "messages": [
+        { "command": "hello", "fields": ["version", "features", "capabilities", "plugins"], "since": 12,
+          "doc": "Always the host's first frame. capabilities is what the platform supports; plugins (since 15) names the plugins the host runs, in id order" },
+        { "command": "call", "fields": ["id", "callsign", "method", "params"], "since": 1 },
+        { "command": "subsystem", "fields": ["subsystem", "active"], "since": 1 },
+        { "command": "memory", "fields": ["resident", "allocated", "shared"], "since": 1 },
+        { "command": "metrics", "fields": ["metrics"], "since": 3 },
+        { "command": "failure", "fields": ["reason"], "since": 7 },
+        { "command": "warning", "fields": ["category", "message"], "since": 8 },
+        { "command": "web_response", "fields": ["id", "status", "headers", "body", "streamed"], "since": 9,
+          "doc": "headers is a list of [name, value] pairs, body is base64. streamed (since 10) means the body follows in web_chunk messages" },
+        { "command": "web_chunk", "fields": ["id", "data"], "since": 10,
+          "doc": "Part of a streamed web_response body, base64; empty at the end" },
+        { "command": "ping", "fields": ["seq"], "since": 14,
+          "doc": "Sent every interval once heartbeat is agreed; Thunder answers with a pong command" },
+        { "command": "pong", "fields": ["seq"], "since": 14, "doc": "The answer to a ping command" },
+        { "command": "output", "fields": ["stream", "line"], "since": 16,
+          "doc": "A line the plugin wrote to stdout or stderr, sent when the host forwards plugin output" },
+        { "command": "codec", "fields": ["codec"], "since": 18,
+          "doc": "Sent once bincode_frames or protobuf_frames is agreed, with codec \"bincode\" or \"protobuf\"; the host's frames after it are in that codec" },
+        { "command": "startup", "fields": ["phase", "status", "detail"], "since": 20,
+          "doc": "One per step of the host's startup, as in its log, sent together once the handshake phase is over" }
+      ]
+    },
+    "features": {
+      "binary_frames": { "since": 1, "commands": ["invoke_binary"], "flags": ["binary"] },
+      "framework_calls": { "since": 1, "commands": ["call_result"], "control": ["call"] },
+      "subsystems": { "since": 1, "commands": ["subsystem"], "control": ["subsystem"] },
+      "memory": { "since": 1, "commands": ["memory"], "control": ["memory"] },
+      "journal": { "since": 1, "commands": ["dump_journal"] },
+      "reset": { "since": 2, "commands": ["reset"] },
+      "metrics": { "since": 3, "commands": ["metrics"], "control": ["metrics"] },
+      "custom_frames": { "since": 4 },
+      "peer": { "since": 5, "commands": ["peer"] },
+      "config_changes": { "since": 6, "commands": ["config_changed"] },
+      "failure_reports": { "since": 7, "control": ["failure"] },
+      "warning_reports": { "since": 8, "control": ["warning"] },
+      "web_requests": { "since": 9, "commands": ["web_request"], "control": ["web_response"] },
+      "web_streaming": { "since": 10, "commands": ["web_body"], "control": ["web_chunk"] },
+      "raw_channels": { "since": 11, "commands": ["invoke_raw"], "flags": ["raw"] },
+      "handshake": { "since": 12, "commands": ["hello"], "control": ["hello"] },
+      "startup_config": { "since": 13, "commands": ["config"] },
+      "heartbeat": { "since": 14, "commands": ["ping", "pong"], "control": ["ping", "pong"] },
+      "multi_plugin": { "since": 15, "commands": ["route"], "flags": ["plugin"] },
+      "plugin_output": { "since": 16, "control": ["output"] },
+      "zstd_payloads": { "since": 17, "flags": ["compressed"] },
+      "zlib_payloads": { "since": 17, "flags": ["compressed"] },
+      "bincode_frames": { "since": 18, "control": ["codec"],
+        "doc": "Every frame after the hello in each direction is a u32 length followed by the frame encoded with bincode, as thunder_rs_wire::bincode" },
+      "protobuf_frames": { "since": 19, "control": ["codec"],
+        "doc": "Every frame after the hello in each direction is a u32 length followed by a Command or Response from wire.proto (WPEHost --protocol-proto); preferred over bincode_frames" },
+      "startup_reports": { "since": 20, "control": ["startup"] }
+    }
+  })
+}
+
+/// Names of the features the host offers in its hello.
+pub fn features() -> Vec<String> {
+  match describe()["features"].as_object() {
+    Some(features) => features.keys()
+      .filter(|f| Algorithm::from_feature(f).is_none_or(Algorithm::built_in))
+      .filter(|f| *f != "protobuf_frames" || cfg!(feature = "protobuf"))
+      .cloned()
+      .collect(),
+    None => Vec::new()
+  }
+}
+
+/// The host's first frame, naming the plugins it runs.
+pub fn hello(capabilities: &Capabilities, plugins: &[&str]) -> Value {
+  json!({
+    "command": "hello",
+    "version": PROTOCOL_VERSION,
+    "features": features(),
+    "capabilities": capabilities.to_json(),
+    "plugins": plugins
+  })
+}
+
+/// What the host and Thunder agreed on in the handshake, shared by
+/// everything that sends frames.
+#[derive(Clone, Default)]
+pub struct Session {
+  /// The agreed features, None before Thunder's hello
+  features: Arc<RwLock<Option<Vec<String>>>>,
+  /// How the host's frames are written
+  codec: Arc<RwLock<Codec>>
+}
+
+impl Session {
+  pub fn new() -> Self {
+    Session::default()
+  }
+
+  /// Records Thunder's hello: its version and the JSON listing its features.
+  pub fn agree(&self, version: u32, json: &str) {
+    let theirs: Vec<String> = serde_json::from_str::<Value>(json).ok()
+      .and_then(|v| serde_json::from_value(v["features"].clone()).ok())
+      .unwrap_or_else(|| {
+        warn!("RUST REMOTE: hello without a feature list: {}", json);
+        Vec::new()
+      });
+    let (features, missing): (Vec<String>, Vec<String>) = features().into_iter()
+      .partition(|f| theirs.contains(f));
+    let version = version.min(PROTOCOL_VERSION);
+    info!("RUST REMOTE: agreed on protocol version {} with features {:?}", version, features);
+    if !missing.is_empty() {
+      warn!("RUST REMOTE: Thunder doesn't handle {:?}", missing);
+    }
+    *self.features.write().unwrap() = Some(features);
+  }
+
+  /// Forgets what was agreed, for a new connection.
+  pub fn reset(&self) {
+    *self.features.write().unwrap() = None;
+    *self.codec.write().unwrap() = Codec::Legacy;
+  }
+
+  /// How the host's frames are written. Read while holding the writer, so
+  /// a switch lands between two frames.
+  pub fn codec(&self) -> Codec {
+    *self.codec.read().unwrap()
+  }
+
+  /// The codec both sides agreed to switch to after the handshake, if any.
+  pub fn frame_codec(&self) -> Option<Codec> {
+    #[cfg(feature = "protobuf")]
+    if self.agreed("protobuf_frames") {
+      return Some(Codec::Protobuf);
+    }
+    self.agreed("bincode_frames").then_some(Codec::Bincode)
+  }
+
+  /// Switches the host's frames to `codec`, once Thunder has been told.
+  pub fn use_codec(&self, codec: Codec) {
+    *self.codec.write().unwrap() = codec;
+  }
+
+  /// Whether Thunder's hello named `feature`, for features that need Thunder
+  /// to take part. False before the hello.
+  pub fn agreed(&self, feature: &str) -> bool {
+    match &*self.features.read().unwrap() {
+      Some(features) => features.iter().any(|f| f == feature),
+      None => false
+    }
+  }
+
+  /// The compression algorithm agreed, if any.
+  pub fn compression(&self) -> Option<Algorithm> {
+    Algorithm::ALL.into_iter().find(|a| a.built_in() && self.agreed(a.feature()))
+  }
+
+  /// Whether `feature` may be used. Everything is allowed until Thunder's
+  /// hello, as older bridges never send one.
+  pub fn supports(&self, feature: &str) -> bool {
+    match &*self.features.read().unwrap() {
+      Some(features) => features.iter().any(|f| f == feature),
+      None => true
+    }
+  }
+}
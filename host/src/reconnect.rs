@@ -0,0 +1,92 @@
+/*
+ * Copyright 2022 Comcast Cable Communications Management, LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+//! Getting the connection to Thunder back after it drops. Each attempt
+//! waits twice as long as the one before, up to a maximum, and the host
+//! gives up after a set number of attempts in a row.
+use std::time::Duration;
+
+use log::{info, warn};
+
+/// When and how often to try again, from the environment:
+/// THUNDER_RS_RECONNECT_ATTEMPTS (default 10, 0 never reconnects),
+/// THUNDER_RS_RECONNECT_INITIAL_MS (default 100) and
+/// THUNDER_RS_RECONNECT_MAX_MS (default 10000).
+#[derive(Debug, Clone, Copy)]
+pub struct Policy {
+  pub attempts: u32,
+  pub initial: Duration,
+  pub max: Duration
+}
+
+impl Default for Policy {
+  fn default() -> Self {
+    Policy {
+      attempts: 10,
+      initial: Duration::from_millis(100),
+      max: Duration::from_secs(10)
+    }
+  }
+}
+
+fn env_u64(name: &str) -> Option<u64> {
+  let s = std::env::var(name).ok()?;
+  match s.parse() {
+    Ok(n) => Some(n),
+    Err(_) => {
+      warn!("RUST REMOTE: ignoring invalid {}={}", name, s);
+      None
+    }
+  }
+}
+
+impl Policy {
+  pub fn from_env() -> Self {
+    let default = Policy::default();
+    Policy {
+      attempts: env_u64("THUNDER_RS_RECONNECT_ATTEMPTS").map(|n| n as u32).unwrap_or(default.attempts),
+      initial: env_u64("THUNDER_RS_RECONNECT_INITIAL_MS").map(Duration::from_millis).unwrap_or(default.initial),
+      max: env_u64("THUNDER_RS_RECONNECT_MAX_MS").map(Duration::from_millis).unwrap_or(default.max)
+    }
+  }
+
+  pub fn enabled(&self) -> bool {
+    self.attempts > 0
+  }
+
+  /// Calls `connect` until it succeeds, backing off between attempts.
+  /// Fails with the last error once every attempt has failed.
+  pub fn run<T, F>(&self, mut connect: F) -> Result<T, String>
+    where F: FnMut() -> Result<T, String>
+  {
+    let mut delay = self.initial;
+    let mut last = String::from("reconnecting is disabled");
+    for attempt in 1..=self.attempts {
+      std::thread::sleep(delay);
+      info!("RUST REMOTE: reconnecting, attempt {} of {}", attempt, self.attempts);
+      match connect() {
+        Ok(t) => return Ok(t),
+        Err(e) => {
+          warn!("RUST REMOTE: reconnect attempt {} failed: {}", attempt, e);
+          last = e;
+        }
+      }
+      delay = (delay * 2).min(self.max);
+    }
+    Err(format!("gave up after {} attempts: {}", self.attempts, last))
+  }
+}
@@ -0,0 +1,304 @@
+/*
+ * Copyright 2022 Comcast Cable Communications Management, LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+//! Traffic recorded on a device and played back to a plugin, for
+//! regression tests against real traffic. `--record=FILE` writes every
+//! frame to and from Thunder to FILE, a line of JSON each with the frame as
+//! the wire crate serializes it and the microseconds since recording
+//! started:
+//!
+//! ```text
+//! {"payloads":true}
+//! {"at_us":1520,"in":{"Attach":{"channel":3,"attach":true}}}
+//! {"at_us":1985,"out":{"channel":3,"plugin":0,"kind":"Text",...}}
+//! ```
+//!
+//! The file is only readable by the host's user. Security tokens are never
+//! recorded, and payloads only with THUNDER_RS_LOG_PAYLOADS=1, as they can
+//! carry anything a client sent; without them a recording shows the shape
+//! of the traffic but can't be replayed.
+//!
+//! `--replay=FILE` stands in for Thunder instead. It sends the plugins
+//! what was recorded coming in, at the pace it came, compares what they
+//! send each channel with what was recorded going out, in order, and says
+//! how many responses matched once they've gone quiet. The host then stops,
+//! with status 1 if any didn't. Thunder's hello, heartbeats and exit
+//! aren't replayed, so a replay is in legacy frames and uncompressed
+//! whatever was agreed when it was recorded; control messages aren't
+//! compared.
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, LineWriter, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+use serde_json::{json, Value};
+use thunder_rs_wire::{Command, Kind, Response};
+
+use crate::CONTROL_CHANNEL;
+
+/// How long the plugins have to be quiet after the last replayed command
+/// for the replay to be over
+const SETTLE: Duration = Duration::from_millis(500);
+
+/// What a recorded token is replaced with
+const REDACTED: &str = "<redacted>";
+
+struct Recording {
+  started: Instant,
+  payloads: bool,
+  file: Mutex<LineWriter<File>>
+}
+
+static RECORDING: OnceLock<Recording> = OnceLock::new();
+
+/// Set when a replay's responses didn't all match
+static MISMATCHED: AtomicBool = AtomicBool::new(false);
+
+/// Records from now on to `path`, replacing what's there.
+pub fn start(path: &Path) -> Result<(), String> {
+  let file = create(path).map_err(|e| format!("failed to create {}: {}", path.display(), e))?;
+  let payloads = thunder_rs::logging::log_payloads();
+  let mut file = LineWriter::new(file);
+  writeln!(file, "{}", json!({ "payloads": payloads }))
+    .map_err(|e| format!("failed to write {}: {}", path.display(), e))?;
+  let recording = Recording { started: Instant::now(), payloads, file: Mutex::new(file) };
+  if RECORDING.set(recording).is_err() {
+    return Err(String::from("already recording"));
+  }
+  info!("RUST REMOTE: recording traffic to {}{}", path.display(),
+    if payloads { "" } else { ", without payloads" });
+  Ok(())
+}
+
+/// A new file at `path` only the host's user can read. What was there is
+/// removed rather than truncated, so its permissions don't carry over.
+fn create(path: &Path) -> io::Result<File> {
+  match fs::remove_file(path) {
+    Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+    _ => ()
+  }
+  let mut options = OpenOptions::new();
+  options.write(true).create_new(true);
+  #[cfg(unix)]
+  std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+  options.open(path)
+}
+
+/// Records a frame from Thunder.
+pub fn inbound(command: &Command) {
+  write("in", |payloads| serde_json::to_value(redact(command.clone(), payloads)));
+}
+
+/// Records a frame to Thunder, as it was before any compression.
+pub fn outbound(plugin: u32, channel: u32, kind: Kind, payload: &[u8]) {
+  write("out", |payloads| {
+    let payload = if payloads { payload.to_vec() } else { Vec::new() };
+    serde_json::to_value(Response { channel, plugin, kind, compressed: false, payload })
+  });
+}
+
+/// `command` without its token, and without its payload unless `payloads`.
+fn redact(command: Command, payloads: bool) -> Command {
+  let token = |token: String| if token.is_empty() { token } else { String::from(REDACTED) };
+  let text = |text: String| if payloads { text } else { String::new() };
+  let bytes = |data: Vec<u8>| if payloads { data } else { Vec::new() };
+  match command {
+    Command::Invoke { channel, token: t, json } => Command::Invoke { channel, token: token(t), json: text(json) },
+    Command::InvokeBinary { channel, token: t, data } => Command::InvokeBinary { channel, token: token(t), data: bytes(data) },
+    Command::InvokeRaw { channel, token: t, data } => Command::InvokeRaw { channel, token: token(t), data: bytes(data) },
+    Command::WebRequest { id, channel, token: t, method, path, headers, body } => Command::WebRequest {
+      id, channel, token: token(t), method, path, headers: text(headers), body: body.map(bytes)
+    },
+    Command::WebBody { id, data } => Command::WebBody { id, data: bytes(data) },
+    Command::CallResult { id, json } => Command::CallResult { id, json: text(json) },
+    Command::ConfigChanged { json } => Command::ConfigChanged { json: text(json) },
+    Command::Config { json } => Command::Config { json: text(json) },
+    Command::Hello { version, json } => Command::Hello { version, json: text(json) },
+    Command::Custom { id, data } => Command::Custom { id, data: bytes(data) },
+    Command::Route { plugin, command } => Command::Route { plugin, command: Box::new(redact(*command, payloads)) },
+    command => command
+  }
+}
+
+fn write(direction: &str, frame: impl FnOnce(bool) -> serde_json::Result<Value>) {
+  let Some(recording) = RECORDING.get() else { return };
+  let line = match frame(recording.payloads) {
+    Ok(frame) => json!({ "at_us": recording.started.elapsed().as_micros() as u64, direction: frame }),
+    Err(e) => return warn!("RUST REMOTE: failed to record a frame: {}", e)
+  };
+  if let Err(e) = writeln!(recording.file.lock().unwrap(), "{}", line) {
+    warn!("RUST REMOTE: failed to record a frame: {}", e);
+  }
+}
+
+/// Whether a replay found responses other than those recorded.
+pub fn mismatched() -> bool {
+  MISMATCHED.load(Ordering::Acquire)
+}
+
+/// A recording, split into what to send and what to expect.
+struct Session {
+  commands: Vec<(Duration, Command)>,
+  responses: Vec<Response>
+}
+
+fn load(path: &Path) -> Result<Session, String> {
+  let file = File::open(path).map_err(|e| format!("failed to open {}: {}", path.display(), e))?;
+  let mut session = Session { commands: Vec::new(), responses: Vec::new() };
+  for (n, line) in BufReader::new(file).lines().enumerate() {
+    let line = line.map_err(|e| e.to_string())?;
+    if line.trim().is_empty() {
+      continue;
+    }
+    let bad = |e: &dyn std::fmt::Display| format!("{}:{}: {}", path.display(), n + 1, e);
+    let mut entry: Value = serde_json::from_str(&line).map_err(|e| bad(&e))?;
+    if let Some(payloads) = entry.get("payloads") {
+      if payloads != true {
+        return Err(bad(&"recorded without payloads, set THUNDER_RS_LOG_PAYLOADS=1 to record them"));
+      }
+      continue;
+    }
+    let at = Duration::from_micros(entry["at_us"].as_u64().ok_or_else(|| bad(&"no at_us"))?);
+    if let Some(command) = entry.get_mut("in").map(Value::take) {
+      let command: Command = serde_json::from_value(command).map_err(|e| bad(&e))?;
+      // The replay ends with an exit of its own
+      if !matches!(command, Command::Hello { .. } | Command::Ping { .. } | Command::Pong { .. } | Command::Exit) {
+        session.commands.push((at, command));
+      }
+    } else if let Some(response) = entry.get_mut("out").map(Value::take) {
+      let response: Response = serde_json::from_value(response).map_err(|e| bad(&e))?;
+      if response.channel != CONTROL_CHANNEL {
+        session.responses.push(response);
+      }
+    } else {
+      return Err(bad(&"neither in nor out"));
+    }
+  }
+  Ok(session)
+}
+
+#[cfg(unix)]
+mod imp {
+  use std::collections::{HashMap, VecDeque};
+  use std::os::unix::net::UnixStream;
+  use std::sync::{Arc, Mutex};
+  use std::time::Instant;
+
+  use log::{info, warn};
+  use thunder_rs_wire::{legacy, Command, Response};
+
+  use super::{load, Session, MISMATCHED, SETTLE};
+  use crate::transport::Connection;
+  use crate::CONTROL_CHANNEL;
+
+  /// How the responses compare with those recorded.
+  #[derive(Default)]
+  struct Tally {
+    /// Those recorded and not yet seen, by channel
+    expected: HashMap<u32, VecDeque<Response>>,
+    matched: usize,
+    differed: usize,
+    unexpected: usize,
+    last: Option<Instant>
+  }
+
+  /// Plays `path` back, returning the host's end of the frame stream.
+  pub fn replay(path: &std::path::Path) -> Result<Connection, String> {
+    let Session { commands, responses } = load(path)?;
+    info!("RUST REMOTE: replaying {} commands from {}, expecting {} responses",
+      commands.len(), path.display(), responses.len());
+    let mut tally = Tally::default();
+    for response in responses {
+      tally.expected.entry(response.channel).or_default().push_back(response);
+    }
+    let tally = Arc::new(Mutex::new(tally));
+
+    let (host, mut thunder) = UnixStream::pair().map_err(|e| e.to_string())?;
+    let mut responses = thunder.try_clone().map_err(|e| e.to_string())?;
+    let compared = tally.clone();
+    std::thread::spawn(move || {
+      while let Ok(response) = legacy::read_response(&mut responses) {
+        if response.channel != CONTROL_CHANNEL {
+          compare(&mut compared.lock().unwrap(), response);
+        }
+      }
+    });
+    std::thread::spawn(move || {
+      let started = Instant::now();
+      for (at, command) in commands {
+        if let Some(wait) = at.checked_sub(started.elapsed()) {
+          std::thread::sleep(wait);
+        }
+        if let Err(e) = std::io::Write::write_all(&mut thunder, &legacy::encode_command(&command)) {
+          return warn!("RUST REMOTE: replay stopped: {}", e);
+        }
+      }
+      // Until the plugins have had their say
+      loop {
+        let quiet = tally.lock().unwrap().last.map_or(started.elapsed(), |last| last.elapsed());
+        match SETTLE.checked_sub(quiet) {
+          Some(wait) if !wait.is_zero() => std::thread::sleep(wait),
+          _ => break
+        }
+      }
+      report(&tally.lock().unwrap());
+      let _ = std::io::Write::write_all(&mut thunder, &legacy::encode_command(&Command::Exit));
+    });
+    Connection::unix(host)
+  }
+
+  fn compare(tally: &mut Tally, response: Response) {
+    tally.last = Some(Instant::now());
+    match tally.expected.get_mut(&response.channel).and_then(VecDeque::pop_front) {
+      Some(expected) if expected.kind == response.kind && expected.payload == response.payload => tally.matched += 1,
+      Some(expected) => {
+        tally.differed += 1;
+        warn!("RUST REMOTE: replay: channel {} got {} instead of {}", response.channel,
+          String::from_utf8_lossy(&response.payload), String::from_utf8_lossy(&expected.payload));
+      }
+      None => {
+        tally.unexpected += 1;
+        warn!("RUST REMOTE: replay: channel {} got unrecorded {}", response.channel,
+          String::from_utf8_lossy(&response.payload));
+      }
+    }
+  }
+
+  fn report(tally: &Tally) {
+    let missing: usize = tally.expected.values().map(VecDeque::len).sum();
+    let passed = tally.differed == 0 && tally.unexpected == 0 && missing == 0;
+    MISMATCHED.store(!passed, std::sync::atomic::Ordering::Release);
+    let summary = format!("{} matched, {} differed, {} unrecorded, {} missing",
+      tally.matched, tally.differed, tally.unexpected, missing);
+    if passed {
+      info!("RUST REMOTE: replay passed: {}", summary);
+    } else {
+      warn!("RUST REMOTE: replay failed: {}", summary);
+    }
+  }
+}
+
+#[cfg(unix)]
+pub use imp::replay;
+
+#[cfg(not(unix))]
+pub fn replay(_path: &Path) -> Result<crate::transport::Connection, String> {
+  Err(String::from("replay is only supported on unix"))
+}
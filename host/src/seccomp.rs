@@ -0,0 +1,203 @@
+/*
+ * Copyright 2022 Comcast Cable Communications Management, LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+//! Restricts the host to the system calls a plugin legitimately needs, once
+//! the library is loaded and the connection to Thunder is up. `--seccomp=`
+//! turns the filter on and says what happens to any other call: "kill" ends
+//! the process, "errno" fails the call with EPERM and "log" lets it through
+//! but has the kernel log it, for finding out what a plugin needs.
+//! `--seccomp-allow=` adds calls to the list, by name or number, e.g. execve
+//! for a plugin that starts programs.
+//!
+//! The filter covers every thread of the host and can't be lifted. Sockets
+//! stay allowed, for reconnecting and for plugins that talk to the network;
+//! ptrace doesn't, so with the filter on the minidump feature writes no
+//! minidumps. Only x86_64 and aarch64 are supported.
+use log::info;
+
+/// What happens to a system call that isn't on the list.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+  Kill,
+  Errno,
+  Log
+}
+
+impl Action {
+  pub fn parse(s: &str) -> Result<Action, String> {
+    match s {
+      "kill" => Ok(Action::Kill),
+      "errno" => Ok(Action::Errno),
+      "log" => Ok(Action::Log),
+      other => Err(format!("unknown action {}, expected kill, errno or log", other))
+    }
+  }
+}
+
+/// The filter from the command line; without an action none is applied.
+#[derive(Debug, Clone, Default)]
+pub struct Filter {
+  pub action: Option<Action>,
+  /// Calls allowed besides the built in list
+  pub allow: Vec<i64>
+}
+
+impl Filter {
+  /// Takes a system call from --seccomp-allow, by name or number.
+  pub fn parse_call(call: &str) -> Result<i64, String> {
+    call.parse::<i64>().ok()
+      .or_else(|| imp::number(call))
+      .ok_or_else(|| format!("unknown system call {}", call))
+  }
+
+  /// Installs the filter on every thread of the host. Does nothing without
+  /// an action.
+  pub fn apply(&self) -> Result<(), String> {
+    let action = match self.action {
+      Some(action) => action,
+      None => return Ok(())
+    };
+    let allowed = imp::apply(action, &self.allow)?;
+    info!("RUST REMOTE: seccomp filter on, {} system calls allowed, others {:?}", allowed, action);
+    Ok(())
+  }
+}
+
+#[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+mod imp {
+  use super::Action;
+
+  // From linux/audit.h, which libc doesn't have
+  #[cfg(target_arch = "x86_64")]
+  const AUDIT_ARCH: u32 = 0xc000_003e;
+  #[cfg(target_arch = "aarch64")]
+  const AUDIT_ARCH: u32 = 0xc000_00b7;
+
+  // Offsets into struct seccomp_data
+  const NR_OFFSET: u32 = 0;
+  const ARCH_OFFSET: u32 = 4;
+
+  macro_rules! syscalls {
+    ($($name:ident),* $(,)?) => { &[$((stringify!($name), libc::$name as i64)),*] }
+  }
+
+  /// What the host, std and a typical plugin use, on both architectures
+  const COMMON: &[(&str, i64)] = syscalls![
+    SYS_read, SYS_write, SYS_readv, SYS_writev, SYS_pread64, SYS_pwrite64,
+    SYS_openat, SYS_close, SYS_close_range, SYS_fstat, SYS_newfstatat, SYS_statx, SYS_lseek,
+    SYS_fcntl, SYS_ioctl, SYS_dup, SYS_dup3, SYS_pipe2, SYS_flock, SYS_ftruncate,
+    SYS_fsync, SYS_fdatasync, SYS_getcwd, SYS_getdents64, SYS_readlinkat, SYS_faccessat,
+    SYS_faccessat2, SYS_unlinkat, SYS_renameat, SYS_mkdirat, SYS_umask,
+    SYS_mmap, SYS_munmap, SYS_mprotect, SYS_mremap, SYS_madvise, SYS_brk, SYS_membarrier,
+    SYS_rt_sigaction, SYS_rt_sigprocmask, SYS_rt_sigreturn, SYS_rt_sigtimedwait, SYS_sigaltstack,
+    SYS_getpid, SYS_gettid, SYS_getppid, SYS_getuid, SYS_geteuid, SYS_getgid, SYS_getegid,
+    SYS_kill, SYS_tgkill, SYS_clone, SYS_clone3, SYS_exit, SYS_exit_group, SYS_wait4,
+    SYS_set_tid_address, SYS_set_robust_list, SYS_get_robust_list, SYS_rseq,
+    SYS_futex, SYS_sched_yield, SYS_sched_getaffinity, SYS_nanosleep, SYS_clock_nanosleep,
+    SYS_clock_gettime, SYS_clock_getres, SYS_gettimeofday, SYS_getrandom, SYS_restart_syscall,
+    SYS_getrlimit, SYS_prlimit64, SYS_uname, SYS_sysinfo, SYS_prctl,
+    SYS_epoll_create1, SYS_epoll_ctl, SYS_epoll_pwait, SYS_eventfd2, SYS_ppoll, SYS_pselect6,
+    SYS_timerfd_create, SYS_timerfd_settime,
+    SYS_socket, SYS_socketpair, SYS_connect, SYS_accept4, SYS_bind, SYS_listen, SYS_shutdown,
+    SYS_sendto, SYS_recvfrom, SYS_sendmsg, SYS_recvmsg, SYS_setsockopt, SYS_getsockopt,
+    SYS_getsockname, SYS_getpeername
+  ];
+
+  /// The older calls x86_64 still has and glibc still makes
+  #[cfg(target_arch = "x86_64")]
+  const ARCH: &[(&str, i64)] = syscalls![
+    SYS_open, SYS_stat, SYS_lstat, SYS_access, SYS_readlink, SYS_unlink, SYS_rename, SYS_mkdir,
+    SYS_pipe, SYS_dup2, SYS_poll, SYS_select, SYS_epoll_wait, SYS_epoll_create, SYS_accept,
+    SYS_getdents, SYS_arch_prctl, SYS_time
+  ];
+  #[cfg(target_arch = "aarch64")]
+  const ARCH: &[(&str, i64)] = &[];
+
+  /// The number of the call called `name`, with or without the SYS_ prefix.
+  pub fn number(name: &str) -> Option<i64> {
+    let name = name.strip_prefix("SYS_").unwrap_or(name);
+    COMMON.iter().chain(ARCH)
+      .find(|(sys, _)| sys.strip_prefix("SYS_") == Some(name))
+      .map(|(_, nr)| *nr)
+  }
+
+  fn stmt(code: u32, k: u32) -> libc::sock_filter {
+    libc::sock_filter { code: code as u16, jt: 0, jf: 0, k }
+  }
+
+  fn jump(code: u32, k: u32, jt: u8, jf: u8) -> libc::sock_filter {
+    libc::sock_filter { code: code as u16, jt, jf, k }
+  }
+
+  pub fn apply(action: Action, extra: &[i64]) -> Result<usize, String> {
+    let mut allowed: Vec<i64> = COMMON.iter().chain(ARCH).map(|(_, nr)| *nr).chain(extra.iter().copied()).collect();
+    allowed.sort_unstable();
+    allowed.dedup();
+
+    let otherwise = match action {
+      Action::Kill => libc::SECCOMP_RET_KILL_PROCESS,
+      Action::Errno => libc::SECCOMP_RET_ERRNO | libc::EPERM as u32,
+      Action::Log => libc::SECCOMP_RET_LOG
+    };
+    // Calls made with another architecture's numbers are killed whatever
+    // the action, as they'd get around the list
+    let mut program = vec![
+      stmt(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, ARCH_OFFSET),
+      jump(libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K, AUDIT_ARCH, 1, 0),
+      stmt(libc::BPF_RET | libc::BPF_K, libc::SECCOMP_RET_KILL_PROCESS),
+      stmt(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, NR_OFFSET)
+    ];
+    for nr in &allowed {
+      program.push(jump(libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K, *nr as u32, 0, 1));
+      program.push(stmt(libc::BPF_RET | libc::BPF_K, libc::SECCOMP_RET_ALLOW));
+    }
+    program.push(stmt(libc::BPF_RET | libc::BPF_K, otherwise));
+
+    let prog = libc::sock_fprog {
+      len: program.len() as u16,
+      filter: program.as_mut_ptr()
+    };
+    unsafe {
+      // Lets a process without CAP_SYS_ADMIN install a filter
+      if libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) != 0 {
+        return Err(format!("failed to set no_new_privs: {}", std::io::Error::last_os_error()));
+      }
+      let rc = libc::syscall(libc::SYS_seccomp, libc::SECCOMP_SET_MODE_FILTER,
+        libc::SECCOMP_FILTER_FLAG_TSYNC, &prog as *const libc::sock_fprog);
+      // A positive result is the thread that couldn't be brought along
+      if rc < 0 {
+        return Err(format!("failed to install seccomp filter: {}", std::io::Error::last_os_error()));
+      } else if rc > 0 {
+        return Err(format!("failed to install seccomp filter on thread {}", rc));
+      }
+    }
+    Ok(allowed.len())
+  }
+}
+
+#[cfg(not(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64"))))]
+mod imp {
+  use super::Action;
+
+  pub fn number(_name: &str) -> Option<i64> {
+    None
+  }
+
+  pub fn apply(_action: Action, _extra: &[i64]) -> Result<usize, String> {
+    Err(String::from("seccomp filters aren't supported on this platform"))
+  }
+}
@@ -0,0 +1,111 @@
+/*
+ * Copyright 2022 Comcast Cable Communications Management, LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+//! Turns SIGTERM and SIGINT into an orderly shutdown, and SIGHUP into a
+//! reload of the plugin libraries. The signals are blocked before any other
+//! thread starts, so every thread inherits the mask, and one thread waits
+//! for them with sigwait instead of a handler, where next to nothing is safe
+//! to do. Until the host is serving there's nothing to shut down in order,
+//! and a signal exits at once; there's nothing to reload either, and SIGHUP
+//! is ignored.
+use std::sync::Mutex;
+
+use log::{info, warn};
+
+type Handler = Box<dyn FnOnce(&'static str) + Send>;
+type ReloadHandler = Box<dyn Fn() + Send>;
+
+enum State {
+  Starting,
+  Serving(Handler),
+  Stopping
+}
+
+static STATE: Mutex<State> = Mutex::new(State::Starting);
+static RELOAD: Mutex<Option<ReloadHandler>> = Mutex::new(None);
+
+#[cfg(unix)]
+fn shutdown_set() -> libc::sigset_t {
+  unsafe {
+    let mut set: libc::sigset_t = std::mem::zeroed();
+    libc::sigemptyset(&mut set);
+    libc::sigaddset(&mut set, libc::SIGTERM);
+    libc::sigaddset(&mut set, libc::SIGINT);
+    libc::sigaddset(&mut set, libc::SIGHUP);
+    set
+  }
+}
+
+/// Blocks the shutdown signals in this thread and every thread it starts,
+/// and starts the thread waiting for them. Has to be called before any
+/// other thread is started.
+#[cfg(unix)]
+pub fn install() {
+  let set = shutdown_set();
+  let rc = unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, &set, std::ptr::null_mut()) };
+  if rc != 0 {
+    warn!("RUST REMOTE: failed to block shutdown signals: {}", std::io::Error::from_raw_os_error(rc));
+    return;
+  }
+  std::thread::spawn(move || loop {
+    let mut signal = 0;
+    if unsafe { libc::sigwait(&set, &mut signal) } != 0 {
+      return;
+    }
+    if signal == libc::SIGHUP {
+      match &*RELOAD.lock().unwrap() {
+        Some(f) => f(),
+        None => info!("RUST REMOTE: SIGHUP received while starting, ignored")
+      }
+      continue;
+    }
+    let name = if signal == libc::SIGTERM { "SIGTERM" } else { "SIGINT" };
+    let mut state = STATE.lock().unwrap();
+    match std::mem::replace(&mut *state, State::Stopping) {
+      State::Serving(f) => {
+        drop(state);
+        f(name);
+      }
+      State::Starting => {
+        info!("RUST REMOTE: {} received while starting, exiting", name);
+        std::process::exit(1);
+      }
+      State::Stopping => {
+        warn!("RUST REMOTE: second shutdown signal, exiting now");
+        std::process::exit(1);
+      }
+    }
+  });
+}
+
+#[cfg(not(unix))]
+pub fn install() { }
+
+/// Calls `f` with the signal's name when the first shutdown signal arrives
+/// from now on. A second one exits at once, for a shutdown that hangs.
+pub fn on_shutdown<F>(f: F)
+  where F: FnOnce(&'static str) + Send + 'static
+{
+  *STATE.lock().unwrap() = State::Serving(Box::new(f));
+}
+
+/// Calls `f` each time SIGHUP arrives from now on.
+pub fn on_reload<F>(f: F)
+  where F: Fn() + Send + 'static
+{
+  *RELOAD.lock().unwrap() = Some(Box::new(f));
+}
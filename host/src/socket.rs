@@ -0,0 +1,140 @@
+/*
+ * Copyright 2022 Comcast Cable Communications Management, LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+//! Socket options for the TCP connection to Thunder. Defaults favour low
+//! latency and noticing a dead peer within about a minute; each can be
+//! changed through the environment:
+//!
+//!   THUNDER_RS_TCP_NODELAY          1 or 0, default 1
+//!   THUNDER_RS_SNDBUF, _RCVBUF      bytes, default left to the kernel
+//!   THUNDER_RS_KEEPALIVE            1 or 0, default 1
+//!   THUNDER_RS_KEEPALIVE_IDLE_S     default 30
+//!   THUNDER_RS_KEEPALIVE_INTERVAL_S default 10
+//!   THUNDER_RS_KEEPALIVE_COUNT      default 3
+use std::io;
+use std::net::TcpStream;
+use std::str::FromStr;
+
+use log::{info, warn};
+
+#[derive(Debug, Clone, Copy)]
+pub struct SocketOptions {
+  pub nodelay: bool,
+  pub send_buffer: Option<u32>,
+  pub recv_buffer: Option<u32>,
+  pub keepalive: Option<Keepalive>
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Keepalive {
+  pub idle_s: u32,
+  pub interval_s: u32,
+  pub count: u32
+}
+
+fn env<T: FromStr>(name: &str) -> Option<T> {
+  let value = std::env::var(name).ok()?;
+  match value.parse() {
+    Ok(v) => Some(v),
+    Err(_) => {
+      warn!("RUST REMOTE: ignoring invalid {}={}", name, value);
+      None
+    }
+  }
+}
+
+fn env_flag(name: &str, default: bool) -> bool {
+  env::<u8>(name).map_or(default, |v| v != 0)
+}
+
+impl SocketOptions {
+  pub fn from_env() -> Self {
+    let keepalive = if env_flag("THUNDER_RS_KEEPALIVE", true) {
+      Some(Keepalive {
+        idle_s: env("THUNDER_RS_KEEPALIVE_IDLE_S").unwrap_or(30),
+        interval_s: env("THUNDER_RS_KEEPALIVE_INTERVAL_S").unwrap_or(10),
+        count: env("THUNDER_RS_KEEPALIVE_COUNT").unwrap_or(3)
+      })
+    } else {
+      None
+    };
+    SocketOptions {
+      nodelay: env_flag("THUNDER_RS_TCP_NODELAY", true),
+      send_buffer: env("THUNDER_RS_SNDBUF"),
+      recv_buffer: env("THUNDER_RS_RCVBUF"),
+      keepalive
+    }
+  }
+
+  /// Applies the options to `stream`. An option the platform rejects is
+  /// logged and skipped rather than failing the connection.
+  pub fn apply(&self, stream: &TcpStream) {
+    if let Err(e) = stream.set_nodelay(self.nodelay) {
+      warn!("RUST REMOTE: failed to set TCP_NODELAY: {}", e);
+    }
+    if let Err(e) = self.apply_platform(stream) {
+      warn!("RUST REMOTE: failed to set socket options: {}", e);
+    }
+    info!("RUST REMOTE: socket options {:?}", self);
+  }
+
+  #[cfg(unix)]
+  fn apply_platform(&self, stream: &TcpStream) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let fd = stream.as_raw_fd();
+    if let Some(size) = self.send_buffer {
+      setsockopt(fd, libc::SOL_SOCKET, libc::SO_SNDBUF, size as libc::c_int)?;
+    }
+    if let Some(size) = self.recv_buffer {
+      setsockopt(fd, libc::SOL_SOCKET, libc::SO_RCVBUF, size as libc::c_int)?;
+    }
+    match self.keepalive {
+      Some(k) => {
+        setsockopt(fd, libc::SOL_SOCKET, libc::SO_KEEPALIVE, 1)?;
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        setsockopt(fd, libc::IPPROTO_TCP, libc::TCP_KEEPIDLE, k.idle_s as libc::c_int)?;
+        #[cfg(any(target_os = "macos", target_os = "ios"))]
+        setsockopt(fd, libc::IPPROTO_TCP, libc::TCP_KEEPALIVE, k.idle_s as libc::c_int)?;
+        setsockopt(fd, libc::IPPROTO_TCP, libc::TCP_KEEPINTVL, k.interval_s as libc::c_int)?;
+        setsockopt(fd, libc::IPPROTO_TCP, libc::TCP_KEEPCNT, k.count as libc::c_int)?;
+      }
+      None => setsockopt(fd, libc::SOL_SOCKET, libc::SO_KEEPALIVE, 0)?
+    }
+    Ok(())
+  }
+
+  #[cfg(not(unix))]
+  fn apply_platform(&self, _stream: &TcpStream) -> io::Result<()> {
+    if self.send_buffer.is_some() || self.recv_buffer.is_some() || self.keepalive.is_some() {
+      warn!("RUST REMOTE: socket buffer and keepalive settings are only supported on unix");
+    }
+    Ok(())
+  }
+}
+
+#[cfg(unix)]
+fn setsockopt(fd: libc::c_int, level: libc::c_int, name: libc::c_int, value: libc::c_int) -> io::Result<()> {
+  let rc = unsafe {
+    libc::setsockopt(fd, level, name, &value as *const libc::c_int as *const libc::c_void,
+      std::mem::size_of::<libc::c_int>() as libc::socklen_t)
+  };
+  if rc < 0 {
+    return Err(io::Error::last_os_error());
+  }
+  Ok(())
+}
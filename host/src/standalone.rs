@@ -0,0 +1,229 @@
+/*
+ * Copyright 2022 Comcast Cable Communications Management, LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+//! Runs the plugins without Thunder, for developing and demoing them on a
+//! laptop. With `--standalone` the host listens for WebSocket clients
+//! itself, on 127.0.0.1:9998 unless `--standalone=` gives an address, and
+//! stands in for Thunder at the other end of the frame stream. Each client
+//! is a channel, attached to every plugin for as long as it's connected;
+//! its messages go to the plugins as JSON-RPC requests, and what a plugin
+//! sends to the channel, responses and events alike, goes back to it.
+//!
+//! A request goes to the plugin named at the start of its method, as in
+//! Thunder's "Callsign.1.method", or to the first one. There's no Thunder
+//! to call into, so framework calls fail; the rest of what the host tells
+//! Thunder is logged and dropped.
+//!
+//! Inside the host the stand-in is a Connection like any other, carrying
+//! legacy frames. Needs the standalone feature.
+
+#[cfg(all(feature = "standalone", unix))]
+mod imp {
+  use std::collections::HashMap;
+  use std::io::{self, ErrorKind};
+  use std::net::{SocketAddr, TcpListener, TcpStream};
+  use std::os::unix::net::UnixStream;
+  use std::sync::atomic::{AtomicU32, Ordering};
+  use std::sync::{mpsc, Arc, Mutex};
+  use std::time::Duration;
+
+  use log::{debug, info, warn};
+  use serde_json::{json, Value};
+  use thunder_rs_wire::{legacy, Command, Kind};
+  use tungstenite::Message;
+
+  use crate::transport::Connection;
+  use crate::CONTROL_CHANNEL;
+
+  /// How long a client's thread waits to hear from it before sending it
+  /// what's queued
+  const POLL: Duration = Duration::from_millis(20);
+
+  /// Thunder's end of the frame stream.
+  struct Thunder {
+    /// Where commands to the host are written
+    commands: Mutex<UnixStream>,
+    /// What's to be sent to each client, by channel
+    clients: Mutex<HashMap<u32, mpsc::Sender<Message>>>,
+    /// The plugins' names by id, from the host's hello
+    plugins: Mutex<Vec<String>>,
+    next_channel: AtomicU32
+  }
+
+  /// Listens for clients at `addr`, returning the host's end of the frame
+  /// stream.
+  pub fn serve(addr: SocketAddr) -> Result<Connection, String> {
+    let listener = TcpListener::bind(addr).map_err(|e| format!("failed to bind {}: {}", addr, e))?;
+    let (host, commands) = UnixStream::pair().map_err(|e| e.to_string())?;
+    let responses = commands.try_clone().map_err(|e| e.to_string())?;
+    let thunder = Arc::new(Thunder {
+      commands: Mutex::new(commands),
+      clients: Mutex::new(HashMap::new()),
+      plugins: Mutex::new(Vec::new()),
+      next_channel: AtomicU32::new(1)
+    });
+    let delivery = thunder.clone();
+    std::thread::spawn(move || delivery.deliver(responses));
+    std::thread::spawn(move || {
+      for stream in listener.incoming() {
+        match stream {
+          Ok(stream) => {
+            let thunder = thunder.clone();
+            std::thread::spawn(move || thunder.client(stream));
+          }
+          Err(e) => warn!("RUST REMOTE: standalone: failed to accept a client: {}", e)
+        }
+      }
+    });
+    info!("RUST REMOTE: standalone, serving JSON-RPC at ws://{}", addr);
+    Connection::unix(host)
+  }
+
+  impl Thunder {
+    /// Hands what the host sends to the clients it's for, until the host
+    /// stops.
+    fn deliver(&self, mut responses: UnixStream) {
+      while let Ok(response) = legacy::read_response(&mut responses) {
+        if response.channel == CONTROL_CHANNEL {
+          self.control(&response.payload);
+          continue;
+        }
+        let message = match response.kind {
+          Kind::Text => Message::Text(String::from_utf8_lossy(&response.payload).into_owned()),
+          Kind::Binary | Kind::Raw => Message::Binary(response.payload)
+        };
+        match self.clients.lock().unwrap().get(&response.channel) {
+          Some(client) => {
+            let _ = client.send(message);
+          }
+          None => debug!("RUST REMOTE: standalone: dropped a message for gone channel {}", response.channel)
+        }
+      }
+    }
+
+    /// Answers the host's control messages as far as it can without
+    /// Thunder.
+    fn control(&self, payload: &[u8]) {
+      let msg: Value = serde_json::from_slice(payload).unwrap_or_default();
+      match msg["command"].as_str() {
+        Some("hello") => {
+          let plugins: Vec<String> = msg["plugins"].as_array().into_iter().flatten()
+            .filter_map(|name| name.as_str().map(str::to_string))
+            .collect();
+          info!("RUST REMOTE: standalone, plugins {}", plugins.join(", "));
+          *self.plugins.lock().unwrap() = plugins;
+        }
+        Some("call") => {
+          let id = msg["id"].as_u64().unwrap_or_default() as u32;
+          let result = json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "error": { "code": -32601, "message": "no Thunder to call in standalone mode" }
+          });
+          self.send(&Command::CallResult { id, json: result.to_string() });
+        }
+        command => info!("RUST REMOTE: standalone, ignoring {} from the host", command.unwrap_or("a message"))
+      }
+    }
+
+    /// Writes `command` to the host.
+    fn send(&self, command: &Command) {
+      let frame = legacy::encode_command(command);
+      if let Err(e) = io::Write::write_all(&mut *self.commands.lock().unwrap(), &frame) {
+        warn!("RUST REMOTE: standalone: failed to write to the host: {}", e);
+      }
+    }
+
+    /// `command` for the plugin with id `plugin`.
+    fn routed(plugin: u32, command: Command) -> Command {
+      match plugin {
+        0 => command,
+        plugin => Command::Route { plugin, command: Box::new(command) }
+      }
+    }
+
+    /// The plugin a JSON-RPC request's method names, or the first.
+    fn plugin_for(&self, json: &str) -> u32 {
+      let request: Value = serde_json::from_str(json).unwrap_or_default();
+      let callsign = request["method"].as_str().and_then(|method| method.split('.').next());
+      self.plugins.lock().unwrap().iter()
+        .position(|name| Some(name.as_str()) == callsign)
+        .unwrap_or(0) as u32
+    }
+
+    fn attach(&self, channel: u32, attach: bool) {
+      let plugins = self.plugins.lock().unwrap().len().max(1) as u32;
+      for plugin in 0..plugins {
+        self.send(&Thunder::routed(plugin, Command::Attach { channel, attach }));
+      }
+    }
+
+    /// Serves one client, as a channel of its own, until it goes.
+    fn client(&self, stream: TcpStream) {
+      let peer = stream.peer_addr().map(|addr| addr.to_string()).unwrap_or_default();
+      let mut socket = match tungstenite::accept(stream) {
+        Ok(socket) => socket,
+        Err(e) => return warn!("RUST REMOTE: standalone: WebSocket handshake with {} failed: {}", peer, e)
+      };
+      if let Err(e) = socket.get_ref().set_read_timeout(Some(POLL)) {
+        return warn!("RUST REMOTE: standalone: {}", e);
+      }
+      let channel = self.next_channel.fetch_add(1, Ordering::Relaxed);
+      let (tx, rx) = mpsc::channel();
+      self.clients.lock().unwrap().insert(channel, tx);
+      info!("RUST REMOTE: standalone, client {} connected as channel {}", peer, channel);
+      self.attach(channel, true);
+
+      'serving: loop {
+        match socket.read() {
+          Ok(Message::Text(json)) => {
+            let plugin = self.plugin_for(&json);
+            self.send(&Thunder::routed(plugin, Command::Invoke { channel, token: String::new(), json }));
+          }
+          Ok(Message::Binary(data)) => {
+            self.send(&Command::InvokeBinary { channel, token: String::new(), data });
+          }
+          // Pings are answered, and a close echoed, by the socket itself
+          Ok(_) => (),
+          Err(tungstenite::Error::Io(e)) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => (),
+          Err(tungstenite::Error::ConnectionClosed) => break,
+          Err(e) => {
+            debug!("RUST REMOTE: standalone: client {}: {}", peer, e);
+            break;
+          }
+        }
+        while let Ok(message) = rx.try_recv() {
+          if socket.send(message).is_err() {
+            break 'serving;
+          }
+        }
+      }
+
+      self.clients.lock().unwrap().remove(&channel);
+      self.attach(channel, false);
+      info!("RUST REMOTE: standalone, client {} on channel {} gone", peer, channel);
+    }
+  }
+}
+
+#[cfg(all(feature = "standalone", unix))]
+pub use imp::serve;
+
+#[cfg(not(all(feature = "standalone", unix)))]
+pub fn serve(_addr: std::net::SocketAddr) -> Result<crate::transport::Connection, String> {
+  Err(String::from("built without standalone mode"))
+}
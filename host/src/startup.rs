@@ -0,0 +1,202 @@
+/*
+ * Copyright 2022 Comcast Cable Communications Management, LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+use std::sync::{mpsc, Mutex};
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+use serde_json::{json, Value};
+
+use crate::protocol::Session;
+use thunder_rs_wire::CONTROL_CHANNEL;
+
+/// Every report so far, sent to Thunder once the handshake is over
+static REPORTS: Mutex<Vec<Value>> = Mutex::new(Vec::new());
+
+/// The steps the host goes through before it starts serving requests. Each
+/// one runs under its own watchdog so a hung plugin constructor or an
+/// unreachable Thunder endpoint is reported by name instead of hanging. The
+/// handshake is waited for under its timeout too, but doesn't end the host.
+#[derive(Debug, Clone, Copy)]
+pub enum Phase {
+  LoadLibrary,
+  Connect,
+  Accept,
+  Configure,
+  ResolveSymbol,
+  CreatePlugin,
+  Handshake
+}
+
+impl Phase {
+  pub fn name(&self) -> &'static str {
+    match self {
+      Phase::LoadLibrary => "load_library",
+      Phase::Connect => "connect",
+      Phase::Accept => "accept",
+      Phase::Configure => "configure",
+      Phase::ResolveSymbol => "resolve_symbol",
+      Phase::CreatePlugin => "create_plugin",
+      Phase::Handshake => "handshake"
+    }
+  }
+
+  fn env_var(&self) -> &'static str {
+    match self {
+      Phase::LoadLibrary => "THUNDER_RS_LOAD_LIBRARY_TIMEOUT_MS",
+      Phase::Connect => "THUNDER_RS_CONNECT_TIMEOUT_MS",
+      Phase::Accept => "THUNDER_RS_ACCEPT_TIMEOUT_MS",
+      Phase::Configure => "THUNDER_RS_CONFIGURE_TIMEOUT_MS",
+      Phase::ResolveSymbol => "THUNDER_RS_RESOLVE_SYMBOL_TIMEOUT_MS",
+      Phase::CreatePlugin => "THUNDER_RS_CREATE_PLUGIN_TIMEOUT_MS",
+      Phase::Handshake => "THUNDER_RS_HANDSHAKE_TIMEOUT_MS"
+    }
+  }
+
+  fn default_timeout(&self) -> Duration {
+    match self {
+      Phase::LoadLibrary => Duration::from_secs(5),
+      // matches the old behavior of 20 retries, 100ms apart
+      Phase::Connect => Duration::from_secs(2),
+      Phase::Accept => Duration::from_secs(30),
+      Phase::Configure => Duration::from_secs(5),
+      Phase::ResolveSymbol => Duration::from_secs(1),
+      Phase::CreatePlugin => Duration::from_secs(10),
+      Phase::Handshake => Duration::from_secs(2)
+    }
+  }
+
+  /// The timeout for this phase, overridable with a THUNDER_RS_*_TIMEOUT_MS
+  /// environment variable.
+  pub fn timeout(&self) -> Duration {
+    match std::env::var(self.env_var()) {
+      Ok(s) => match s.parse::<u64>() {
+        Ok(ms) => Duration::from_millis(ms),
+        Err(_) => {
+          warn!("RUST REMOTE: ignoring invalid {}={}", self.env_var(), s);
+          self.default_timeout()
+        }
+      },
+      Err(_) => self.default_timeout()
+    }
+  }
+}
+
+fn report(phase: Phase, status: &str, detail: String) {
+  info!("RUST REMOTE: startup phase={} status={} {}", phase.name(), status, detail);
+  let msg = json!({ "command": "startup", "phase": phase.name(), "status": status, "detail": detail });
+  REPORTS.lock().unwrap_or_else(|e| e.into_inner()).push(msg);
+}
+
+/// Sends a startup control message for each report, in order, if Thunder
+/// agreed to startup_reports. Called once the handshake is over, as nothing
+/// can be sent before the hello and nothing is known to be wanted until
+/// Thunder's.
+pub fn forward(tx: &thunder_rs::Responder, session: &Session) {
+  let reports = std::mem::take(&mut *REPORTS.lock().unwrap_or_else(|e| e.into_inner()));
+  if !session.agreed("startup_reports") {
+    return;
+  }
+  for msg in reports {
+    let _ = tx.send(thunder_rs::Message::new(CONTROL_CHANNEL, msg.to_string()));
+  }
+}
+
+/// Runs one startup phase on the current thread while a watchdog thread waits
+/// for it to finish. If the phase fails or doesn't complete within its timeout
+/// the process exits with the phase name in the log. The closure is handed the
+/// timeout so phases that retry internally can stay within it.
+pub fn run<T, F>(phase: Phase, f: F) -> T
+  where F: FnOnce(Duration) -> Result<T, String>
+{
+  let timeout = phase.timeout();
+  let start = Instant::now();
+  report(phase, "started", format!("timeout_ms={}", timeout.as_millis()));
+
+  let (done_tx, done_rx) = mpsc::channel::<()>();
+  let watchdog = std::thread::spawn(move || {
+    if let Err(mpsc::RecvTimeoutError::Timeout) = done_rx.recv_timeout(timeout) {
+      report(phase, "timeout", format!("timeout_ms={}", timeout.as_millis()));
+      std::process::exit(1);
+    }
+  });
+
+  let result = f(timeout);
+  drop(done_tx);
+  let _ = watchdog.join();
+
+  let elapsed_ms = start.elapsed().as_millis();
+  match result {
+    Ok(t) => {
+      report(phase, "done", format!("elapsed_ms={}", elapsed_ms));
+      t
+    }
+    Err(e) => {
+      report(phase, "failed", format!("elapsed_ms={} error=\"{}\"", elapsed_ms, e));
+      std::process::exit(1);
+    }
+  }
+}
+
+/// Waits for Thunder's answer to the hello, which the reader sends on
+/// `answered` with its first frame: true for a hello, false for anything
+/// else. Bridges that predate the handshake never answer, so unlike the
+/// other phases running out of time is reported and the host carries on.
+pub fn handshake(answered: &mpsc::Receiver<bool>) -> bool {
+  let phase = Phase::Handshake;
+  let timeout = phase.timeout();
+  let start = Instant::now();
+  report(phase, "started", format!("timeout_ms={}", timeout.as_millis()));
+  match answered.recv_timeout(timeout) {
+    Ok(hello) => {
+      report(phase, "done", format!("elapsed_ms={} answered={}", start.elapsed().as_millis(), hello));
+      hello
+    }
+    // The reader stopped before Thunder sent anything
+    Err(mpsc::RecvTimeoutError::Disconnected) => {
+      report(phase, "done", format!("elapsed_ms={} answered=false", start.elapsed().as_millis()));
+      false
+    }
+    Err(mpsc::RecvTimeoutError::Timeout) => {
+      report(phase, "timeout", format!("timeout_ms={} answered=false", timeout.as_millis()));
+      false
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn handshake_reports_whether_thunder_answered() {
+    let (tx, rx) = mpsc::channel();
+    tx.send(true).unwrap();
+    assert!(handshake(&rx));
+    tx.send(false).unwrap();
+    assert!(!handshake(&rx));
+  }
+
+  #[test]
+  fn handshake_ends_when_the_reader_stops() {
+    let (tx, rx) = mpsc::channel::<bool>();
+    drop(tx);
+    let start = Instant::now();
+    assert!(!handshake(&rx));
+    assert!(start.elapsed() < Phase::Handshake.timeout());
+  }
+}
@@ -0,0 +1,188 @@
+/*
+ * Copyright 2022 Comcast Cable Communications Management, LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+//! The host's own metrics: requests from Thunder by command and how long
+//! the main loop took over each, bytes read from and written to Thunder,
+//! reconnects and plugin panics. `--metrics=address:port` serves them to
+//! Prometheus over HTTP, at /metrics, along with each plugin's own metrics
+//! labelled with its name.
+//!
+//! With workers, a request's time is only that taken to hand it to one;
+//! without, it's the plugin's time too.
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+use thunder_rs::metrics::{Counter, Exposition, Histogram};
+
+/// How long a scrape has to send its request, and to take the answer
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The most of a scrape's request line and headers read before answering
+const MAX_REQUEST: u64 = 8 * 1024;
+
+#[derive(Default)]
+struct Stats {
+  requests: Mutex<BTreeMap<&'static str, (Counter, Histogram)>>,
+  bytes_in: Counter,
+  bytes_out: Counter,
+  reconnects: Counter,
+  /// By plugin name
+  panics: Mutex<BTreeMap<String, Counter>>,
+  /// Each plugin's name and metrics, by id
+  plugins: Mutex<Vec<(String, thunder_rs::Metrics)>>
+}
+
+fn stats() -> &'static Stats {
+  static STATS: OnceLock<Stats> = OnceLock::new();
+  STATS.get_or_init(Stats::default)
+}
+
+/// Counts a request of `command` the main loop took `elapsed` over.
+pub fn request(command: &'static str, elapsed: Duration) {
+  let mut requests = stats().requests.lock().unwrap();
+  let (count, latency) = requests.entry(command).or_default();
+  count.inc();
+  latency.observe(elapsed);
+}
+
+pub fn reconnected() {
+  stats().reconnects.inc();
+}
+
+pub fn panicked(plugin: &str) {
+  stats().panics.lock().unwrap().entry(plugin.to_string()).or_default().inc();
+}
+
+/// Serves plugin `id`'s metrics from now on, under `name`.
+pub fn set_plugin(id: usize, name: &str, metrics: thunder_rs::Metrics) {
+  let mut plugins = stats().plugins.lock().unwrap();
+  if plugins.len() <= id {
+    plugins.resize_with(id + 1, || (String::new(), thunder_rs::Metrics::new()));
+  }
+  plugins[id] = (name.to_string(), metrics);
+}
+
+/// Counts what's read through it as bytes from Thunder.
+pub struct CountingReader<R>(pub R);
+
+impl<R: Read> Read for CountingReader<R> {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    let n = self.0.read(buf)?;
+    stats().bytes_in.add(n as u64);
+    Ok(n)
+  }
+}
+
+/// Counts what's written through it as bytes to Thunder.
+pub struct CountingWriter<W>(pub W);
+
+impl<W: Write> Write for CountingWriter<W> {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    let n = self.0.write(buf)?;
+    stats().bytes_out.add(n as u64);
+    Ok(n)
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    self.0.flush()
+  }
+}
+
+/// Everything, in Prometheus' text format.
+pub fn render() -> String {
+  let stats = stats();
+  let mut out = Exposition::new();
+  for (command, (count, latency)) in stats.requests.lock().unwrap().iter() {
+    out.counter("thunder_rs_host_requests_total", "Requests from Thunder", &[("command", command)], count.get());
+    out.histogram("thunder_rs_host_request_duration_seconds", "Time the main loop took over a request",
+      &[("command", command)], latency);
+  }
+  out.counter("thunder_rs_host_received_bytes_total", "Bytes read from Thunder", &[], stats.bytes_in.get());
+  out.counter("thunder_rs_host_sent_bytes_total", "Bytes written to Thunder", &[], stats.bytes_out.get());
+  out.counter("thunder_rs_host_reconnects_total", "Connections to Thunder made again", &[], stats.reconnects.get());
+  for (plugin, panics) in stats.panics.lock().unwrap().iter() {
+    out.counter("thunder_rs_host_panics_total", "Plugin panics caught", &[("plugin", plugin)], panics.get());
+  }
+  for (name, metrics) in stats.plugins.lock().unwrap().iter() {
+    out.metrics("thunder_rs_plugin", &[("plugin", name)], metrics);
+  }
+  out.to_string()
+}
+
+/// Listens for Prometheus at `addr`.
+pub fn bind(addr: SocketAddr) -> Result<TcpListener, String> {
+  let listener = TcpListener::bind(addr).map_err(|e| format!("failed to listen on {}: {}", addr, e))?;
+  info!("RUST REMOTE: serving metrics at http://{}/metrics", addr);
+  Ok(listener)
+}
+
+/// Answers scrapes on `listener`, one at a time, on a thread of its own.
+pub fn serve(listener: TcpListener) {
+  std::thread::spawn(move || {
+    for stream in listener.incoming() {
+      let answered = stream.and_then(|stream| {
+        stream.set_write_timeout(Some(READ_TIMEOUT))?;
+        answer(stream)
+      });
+      if let Err(e) = answered {
+        warn!("RUST REMOTE: metrics scrape failed: {}", e);
+      }
+    }
+  });
+}
+
+/// Reads from a scrape until `deadline`, however slowly it trickles in.
+struct Deadline {
+  stream: TcpStream,
+  deadline: Instant
+}
+
+impl Read for Deadline {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    let left = self.deadline.saturating_duration_since(Instant::now());
+    if left.is_zero() {
+      return Err(io::Error::new(io::ErrorKind::TimedOut, "scrape took too long to send its request"));
+    }
+    self.stream.set_read_timeout(Some(left))?;
+    self.stream.read(buf)
+  }
+}
+
+fn answer(mut stream: TcpStream) -> io::Result<()> {
+  let deadline = Deadline { stream: stream.try_clone()?, deadline: Instant::now() + READ_TIMEOUT };
+  // Past MAX_REQUEST it reads as the end of the request
+  let mut reader = BufReader::new(deadline.take(MAX_REQUEST));
+  let mut request = String::new();
+  reader.read_line(&mut request)?;
+  // The headers aren't needed, but have to be read before answering
+  let mut header = String::new();
+  while reader.read_line(&mut header)? > 2 {
+    header.clear();
+  }
+  let mut parts = request.split_whitespace();
+  let (status, body) = match (parts.next(), parts.next()) {
+    (Some("GET"), Some("/metrics")) => ("200 OK", render()),
+    (Some("GET"), _) => ("404 Not Found", String::from("try /metrics\n")),
+    _ => ("405 Method Not Allowed", String::new())
+  };
+  write!(stream, "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+    status, body.len(), body)
+}
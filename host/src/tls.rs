@@ -0,0 +1,235 @@
+/*
+ * Copyright 2022 Comcast Cable Communications Management, LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+//! TLS on the TCP link to Thunder, with rustls. It's turned on from the
+//! environment:
+//!
+//!   THUNDER_RS_TLS_CA           CA certificates (PEM) the peer is checked
+//!                               against
+//!   THUNDER_RS_TLS_CERT/_KEY    the host's own certificate chain and key
+//!   THUNDER_RS_TLS_SERVER_NAME  the name Thunder's certificate must carry,
+//!                               by default the address argument
+//!
+//! Dialing out needs the CA, and with a certificate too the host
+//! authenticates itself (mTLS). Listening needs the certificate, and with
+//! the CA too clients have to present a certificate it signed.
+//!
+//! A rustls connection can't be split between the reader and the writer
+//! threads, so both share it behind a lock, and the reader waits for
+//! socket data without holding it.
+use std::path::PathBuf;
+
+/// Where the certificates are, from the environment. None when TLS is off.
+#[derive(Debug, Clone)]
+#[cfg_attr(not(feature = "tls"), allow(dead_code))]
+pub struct Settings {
+  pub ca: Option<PathBuf>,
+  pub cert: Option<PathBuf>,
+  pub key: Option<PathBuf>,
+  pub server_name: Option<String>
+}
+
+impl Settings {
+  pub fn from_env() -> Result<Option<Self>, String> {
+    let path = |name: &str| std::env::var_os(name).map(PathBuf::from);
+    let settings = Settings {
+      ca: path("THUNDER_RS_TLS_CA"),
+      cert: path("THUNDER_RS_TLS_CERT"),
+      key: path("THUNDER_RS_TLS_KEY"),
+      server_name: std::env::var("THUNDER_RS_TLS_SERVER_NAME").ok()
+    };
+    if settings.ca.is_none() && settings.cert.is_none() {
+      return Ok(None);
+    }
+    if settings.cert.is_some() != settings.key.is_some() {
+      return Err(String::from("THUNDER_RS_TLS_CERT and THUNDER_RS_TLS_KEY go together"));
+    }
+    if cfg!(not(feature = "tls")) {
+      return Err(String::from("TLS is configured, but the host was built without the tls feature"));
+    }
+    Ok(Some(settings))
+  }
+}
+
+#[cfg(feature = "tls")]
+mod imp {
+  use std::io::{self, Read, Write};
+  use std::net::{Shutdown, TcpStream};
+  use std::path::Path;
+  use std::sync::{Arc, Mutex};
+
+  use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+  use rustls::server::WebPkiClientVerifier;
+  use rustls::{ClientConfig, ClientConnection, RootCertStore, ServerConfig, ServerConnection};
+
+  use super::Settings;
+  use crate::transport::Connection;
+
+  fn certs(path: &Path) -> Result<Vec<CertificateDer<'static>>, String> {
+    let file = std::fs::File::open(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    rustls_pemfile::certs(&mut io::BufReader::new(file))
+      .collect::<Result<_, _>>()
+      .map_err(|e| format!("{}: {}", path.display(), e))
+  }
+
+  fn key(path: &Path) -> Result<PrivateKeyDer<'static>, String> {
+    let file = std::fs::File::open(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    rustls_pemfile::private_key(&mut io::BufReader::new(file))
+      .map_err(|e| format!("{}: {}", path.display(), e))?
+      .ok_or_else(|| format!("{}: no private key", path.display()))
+  }
+
+  fn roots(path: &Path) -> Result<RootCertStore, String> {
+    let mut roots = RootCertStore::empty();
+    for cert in certs(path)? {
+      roots.add(cert).map_err(|e| format!("{}: {}", path.display(), e))?;
+    }
+    Ok(roots)
+  }
+
+  /// The host as the TLS client, when it dials out. `host` is the address
+  /// argument, the server name unless the settings name another.
+  pub fn client(stream: TcpStream, host: &str, settings: &Settings) -> Result<Connection, String> {
+    let ca = settings.ca.as_ref()
+      .ok_or("THUNDER_RS_TLS_CA is needed to check Thunder's certificate")?;
+    let builder = ClientConfig::builder().with_root_certificates(roots(ca)?);
+    let config = match (&settings.cert, &settings.key) {
+      (Some(cert), Some(key)) => builder.with_client_auth_cert(certs(cert)?, self::key(key)?)
+        .map_err(|e| e.to_string())?,
+      _ => builder.with_no_client_auth()
+    };
+    let name = settings.server_name.as_deref().unwrap_or(host);
+    let name = ServerName::try_from(name.to_string()).map_err(|e| format!("server name {}: {}", name, e))?;
+    let conn = ClientConnection::new(Arc::new(config), name).map_err(|e| e.to_string())?;
+    handshake(stream, rustls::Connection::Client(conn))
+  }
+
+  /// The host as the TLS server, when it listens.
+  pub fn server(stream: TcpStream, settings: &Settings) -> Result<Connection, String> {
+    let (cert, key) = match (&settings.cert, &settings.key) {
+      (Some(cert), Some(key)) => (cert, key),
+      _ => return Err(String::from("THUNDER_RS_TLS_CERT and THUNDER_RS_TLS_KEY are needed to listen with TLS"))
+    };
+    let builder = match &settings.ca {
+      Some(ca) => ServerConfig::builder().with_client_cert_verifier(
+        WebPkiClientVerifier::builder(Arc::new(roots(ca)?)).build().map_err(|e| e.to_string())?),
+      None => ServerConfig::builder().with_no_client_auth()
+    };
+    let config = builder.with_single_cert(certs(cert)?, self::key(key)?)
+      .map_err(|e| e.to_string())?;
+    let conn = ServerConnection::new(Arc::new(config)).map_err(|e| e.to_string())?;
+    handshake(stream, rustls::Connection::Server(conn))
+  }
+
+  struct Shared {
+    conn: Mutex<rustls::Connection>,
+    tcp: TcpStream
+  }
+
+  impl Shared {
+    /// Sends whatever TLS records are waiting. Called with the lock held.
+    fn flush(&self, conn: &mut rustls::Connection) -> io::Result<()> {
+      while conn.wants_write() {
+        conn.write_tls(&mut &self.tcp)?;
+      }
+      Ok(())
+    }
+  }
+
+  fn handshake(mut tcp: TcpStream, mut conn: rustls::Connection) -> Result<Connection, String> {
+    while conn.is_handshaking() {
+      conn.complete_io(&mut tcp).map_err(|e| format!("TLS handshake failed: {}", e))?;
+    }
+    log::info!("RUST REMOTE: TLS established, {:?}", conn.negotiated_cipher_suite().map(|s| s.suite()));
+    let reader = tcp.try_clone().map_err(|e| e.to_string())?;
+    let shared = Arc::new(Shared { conn: Mutex::new(conn), tcp });
+    let closer = shared.clone();
+    Ok(Connection::new(
+      Box::new(TlsReader { shared: shared.clone(), tcp: reader, pending: Vec::new() }),
+      Box::new(TlsWriter { shared }),
+      Box::new(move || {
+        let _ = closer.tcp.shutdown(Shutdown::Both);
+      })
+    ))
+  }
+
+  struct TlsReader {
+    shared: Arc<Shared>,
+    tcp: TcpStream,
+    // Bytes off the socket rustls hasn't taken yet
+    pending: Vec<u8>
+  }
+
+  impl Read for TlsReader {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+      loop {
+        {
+          let mut conn = self.shared.conn.lock().unwrap();
+          if !self.pending.is_empty() {
+            let used = conn.read_tls(&mut &self.pending[..])?;
+            self.pending.drain(..used);
+            conn.process_new_packets().map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            self.shared.flush(&mut conn)?;
+          }
+          match conn.reader().read(out) {
+            Ok(n) => return Ok(n),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => (),
+            Err(e) => return Err(e)
+          }
+        }
+        let mut buf = [0; 16 * 1024];
+        let n = self.tcp.read(&mut buf)?;
+        if n == 0 {
+          return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed without close_notify"));
+        }
+        self.pending.extend_from_slice(&buf[..n]);
+      }
+    }
+  }
+
+  struct TlsWriter {
+    shared: Arc<Shared>
+  }
+
+  impl Write for TlsWriter {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+      let mut conn = self.shared.conn.lock().unwrap();
+      let n = conn.writer().write(data)?;
+      self.shared.flush(&mut conn)?;
+      Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+      let mut conn = self.shared.conn.lock().unwrap();
+      self.shared.flush(&mut conn)
+    }
+  }
+}
+
+#[cfg(feature = "tls")]
+pub use imp::{client, server};
+
+// Settings::from_env never returns settings without the feature
+#[cfg(not(feature = "tls"))]
+pub fn client(_stream: std::net::TcpStream, _host: &str, _settings: &Settings) -> Result<crate::transport::Connection, String> {
+  Err(String::from("built without TLS"))
+}
+
+#[cfg(not(feature = "tls"))]
+pub fn server(_stream: std::net::TcpStream, _settings: &Settings) -> Result<crate::transport::Connection, String> {
+  Err(String::from("built without TLS"))
+}
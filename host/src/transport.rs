@@ -0,0 +1,473 @@
+/*
+ * Copyright 2022 Comcast Cable Communications Management, LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{IpAddr, Shutdown, TcpListener, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::{thread, time};
+
+use log::{info, warn};
+
+use crate::stats;
+
+/// Both directions of the link to Thunder, whatever carries it. The frame
+/// protocol on top is the same for every transport.
+pub struct Connection {
+  reader: Box<dyn Read + Send>,
+  writer: Box<dyn Write + Send>,
+  closer: Closer
+}
+
+/// Takes a connection down so both its reader and its writer stop.
+pub type Closer = Box<dyn Fn() + Send>;
+
+impl Connection {
+  /// For transports layered over another, like TLS or gRPC.
+  #[cfg_attr(not(any(feature = "tls", feature = "grpc")), allow(dead_code))]
+  pub fn new(reader: Box<dyn Read + Send>, writer: Box<dyn Write + Send>, closer: Closer) -> Self {
+    Connection { reader, writer, closer }
+  }
+
+  pub fn tcp(stream: TcpStream) -> Result<Self, String> {
+    crate::socket::SocketOptions::from_env().apply(&stream);
+    let reader = stream.try_clone().map_err(|e| e.to_string())?;
+    let writer = stream.try_clone().map_err(|e| e.to_string())?;
+    Ok(Connection {
+      reader: Box::new(reader),
+      writer: Box::new(writer),
+      closer: Box::new(move || {
+        let _ = stream.shutdown(Shutdown::Both);
+      })
+    })
+  }
+
+  #[cfg(unix)]
+  pub fn unix(stream: UnixStream) -> Result<Self, String> {
+    let reader = stream.try_clone().map_err(|e| e.to_string())?;
+    let writer = stream.try_clone().map_err(|e| e.to_string())?;
+    Ok(Connection {
+      reader: Box::new(reader),
+      writer: Box::new(writer),
+      closer: Box::new(move || {
+        let _ = stream.shutdown(Shutdown::Both);
+      })
+    })
+  }
+
+  #[cfg(target_os = "linux")]
+  pub fn vsock(stream: std::fs::File) -> Result<Self, String> {
+    let reader = stream.try_clone().map_err(|e| e.to_string())?;
+    let writer = stream.try_clone().map_err(|e| e.to_string())?;
+    Ok(Connection {
+      reader: Box::new(reader),
+      writer: Box::new(writer),
+      closer: Box::new(move || crate::vsock::shutdown(&stream))
+    })
+  }
+
+  /// Frames over the host's own stdin and stdout, for launchers that hand
+  /// the host pipes instead of a socket. Anything else writing to stdout,
+  /// such as a plugin's println!, is sent to stderr so it can't corrupt the
+  /// frame stream.
+  #[cfg(unix)]
+  pub fn stdio() -> Result<Self, String> {
+    use std::os::fd::AsFd;
+
+    let frames = io::stdout().as_fd().try_clone_to_owned().map_err(|e| e.to_string())?;
+    io::stdout().flush().map_err(|e| e.to_string())?;
+    if unsafe { libc::dup2(libc::STDERR_FILENO, libc::STDOUT_FILENO) } < 0 {
+      return Err(format!("failed to redirect stdout: {}", io::Error::last_os_error()));
+    }
+    Ok(Connection {
+      reader: Box::new(io::stdin()),
+      writer: Box::new(std::fs::File::from(frames)),
+      // Nothing can unblock a read on stdin. The host stops without it once
+      // it's done, or once the pipes break, exiting with status 1 then
+      closer: Box::new(|| ())
+    })
+  }
+
+  #[cfg(not(unix))]
+  pub fn stdio() -> Result<Self, String> {
+    Err(String::from("stdin/stdout transport is only supported on unix"))
+  }
+
+  /// The connection's halves, counting the bytes through them for the
+  /// host's metrics.
+  pub fn split(self) -> (Box<dyn Read + Send>, Box<dyn Write + Send>, Closer) {
+    (Box::new(stats::CountingReader(self.reader)), Box::new(stats::CountingWriter(self.writer)), self.closer)
+  }
+}
+
+/// The writing half of the current connection. It outlives the connection
+/// so the thread delivering responses carries on over a reconnect.
+pub struct Outbound {
+  writer: Mutex<Option<Box<dyn Write + Send>>>,
+  // Separate from the writer so a connection can be closed while a write
+  // to it is blocked
+  closer: Mutex<Option<Closer>>
+}
+
+impl Outbound {
+  pub fn new(writer: Box<dyn Write + Send>, closer: Closer) -> Self {
+    Outbound {
+      writer: Mutex::new(Some(writer)),
+      closer: Mutex::new(Some(closer))
+    }
+  }
+
+  /// Writes with `f` to the current connection. Ok(false) means there is
+  /// none, and nothing was written.
+  pub fn write<F>(&self, f: F) -> io::Result<bool>
+    where F: FnOnce(&mut Box<dyn Write + Send>) -> io::Result<()>
+  {
+    match self.writer.lock().unwrap().as_mut() {
+      Some(writer) => f(writer).map(|_| true),
+      None => Ok(false)
+    }
+  }
+
+  /// Takes the current connection down, ending its reader too.
+  pub fn close(&self) {
+    if let Some(close) = self.closer.lock().unwrap().take() {
+      close();
+    }
+    if let Ok(mut writer) = self.writer.try_lock() {
+      *writer = None;
+    }
+  }
+
+  /// Whether there's a connection, without waiting for a write to it.
+  pub fn connected(&self) -> bool {
+    self.closer.lock().unwrap().is_some()
+  }
+
+  /// Switches to a new connection.
+  pub fn replace(&self, writer: Box<dyn Write + Send>, closer: Closer) {
+    *self.writer.lock().unwrap() = Some(writer);
+    *self.closer.lock().unwrap() = Some(closer);
+  }
+}
+
+/// A connection Thunder or a service manager handed over as file
+/// descriptor `fd`, instead of the host dialing out. The socket can be
+/// connected already, or listening, in which case one connection is
+/// accepted from it (systemd's Accept=no).
+#[cfg(unix)]
+pub fn inherited(fd: i32) -> Result<Connection, String> {
+  use std::os::fd::{FromRawFd, OwnedFd};
+
+  if unsafe { libc::fcntl(fd, libc::F_GETFD) } < 0 {
+    return Err(format!("fd {} is not open", fd));
+  }
+  // Nothing the plugin starts should inherit it in turn
+  unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+  let owned = unsafe { OwnedFd::from_raw_fd(fd) };
+
+  let mut addr: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+  let mut len = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+  if unsafe { libc::getsockname(fd, &mut addr as *mut _ as *mut libc::sockaddr, &mut len) } < 0 {
+    return Err(format!("fd {} is not a socket: {}", fd, io::Error::last_os_error()));
+  }
+  let mut listening: libc::c_int = 0;
+  let mut opt_len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+  unsafe {
+    libc::getsockopt(fd, libc::SOL_SOCKET, libc::SO_ACCEPTCONN,
+      &mut listening as *mut _ as *mut libc::c_void, &mut opt_len);
+  }
+
+  let accept_err = |e: io::Error| format!("failed to accept on fd {}: {}", fd, e);
+  match addr.ss_family as libc::c_int {
+    libc::AF_INET | libc::AF_INET6 if listening != 0 => {
+      let (stream, peer) = TcpListener::from(owned).accept().map_err(accept_err)?;
+      info!("RUST REMOTE: rust remote accepted connection from {} on fd {}", peer, fd);
+      Connection::tcp(stream)
+    }
+    libc::AF_INET | libc::AF_INET6 => Connection::tcp(TcpStream::from(owned)),
+    libc::AF_UNIX if listening != 0 => {
+      let (stream, _) = UnixListener::from(owned).accept().map_err(accept_err)?;
+      info!("RUST REMOTE: rust remote accepted connection on fd {}", fd);
+      Connection::unix(stream)
+    }
+    libc::AF_UNIX => Connection::unix(UnixStream::from(owned)),
+    #[cfg(target_os = "linux")]
+    libc::AF_VSOCK if listening == 0 => Connection::vsock(std::fs::File::from(owned)),
+    family => Err(format!("fd {} is a socket of family {} the host can't use", fd, family))
+  }
+}
+
+#[cfg(not(unix))]
+pub fn inherited(_fd: i32) -> Result<Connection, String> {
+  Err(String::from("inherited sockets are only supported on unix"))
+}
+
+/// Where Thunder's end of the bridge is, from the address and port
+/// arguments. An address containing a '/' is the path of a Unix domain
+/// socket, and the port is ignored. "vsock:<cid>" is a vsock port on
+/// that CID; "vsock:any" only makes sense with --listen. TLS only applies
+/// to TCP.
+#[derive(Debug, Clone)]
+pub enum Endpoint {
+  Tcp { host: String, addr: String, tls: Option<crate::tls::Settings> },
+  Unix(PathBuf),
+  Vsock { cid: u32, port: u32 }
+}
+
+impl Endpoint {
+  pub fn parse(address: &str, port: &str, tls: Option<crate::tls::Settings>) -> Result<Self, String> {
+    if tls.is_some() && (address.starts_with("vsock:") || address.contains('/')) {
+      warn!("RUST REMOTE: TLS is only used over TCP, ignoring it for {}", address);
+    }
+    if let Some(cid) = address.strip_prefix("vsock:") {
+      let cid = match cid {
+        // VMADDR_CID_ANY
+        "any" => u32::MAX,
+        cid => cid.parse().map_err(|_| format!("invalid vsock CID {}", cid))?
+      };
+      let port = port.parse().map_err(|_| format!("invalid vsock port {}", port))?;
+      Ok(Endpoint::Vsock { cid, port })
+    } else if address.contains('/') {
+      Ok(Endpoint::Unix(PathBuf::from(address)))
+    } else {
+      Ok(Endpoint::Tcp { host: address.to_string(), addr: format!("{}:{}", address, port), tls })
+    }
+  }
+
+  /// Dials out, retrying until `timeout`.
+  pub fn connect(&self, timeout: time::Duration) -> Result<Connection, String> {
+    match self {
+      Endpoint::Tcp { host, addr, tls } => connect_stream(addr.clone(), timeout).and_then(|stream| match tls {
+        Some(tls) => {
+          crate::socket::SocketOptions::from_env().apply(&stream);
+          crate::tls::client(stream, host, tls)
+        }
+        None => Connection::tcp(stream)
+      }),
+      Endpoint::Unix(path) => connect_unix(path, timeout),
+      Endpoint::Vsock { cid, port } => connect_vsock(*cid, *port, timeout)
+    }
+  }
+
+  /// Waits for Thunder to connect in.
+  pub fn accept(&self, allow: &[IpAddr]) -> Result<Connection, String> {
+    match self {
+      Endpoint::Tcp { addr, tls, .. } => accept_stream(addr.clone(), allow).and_then(|stream| match tls {
+        Some(tls) => {
+          crate::socket::SocketOptions::from_env().apply(&stream);
+          crate::tls::server(stream, tls)
+        }
+        None => Connection::tcp(stream)
+      }),
+      Endpoint::Unix(path) => {
+        if !allow.is_empty() {
+          warn!("RUST REMOTE: --allow has no effect on a unix socket");
+        }
+        accept_unix(path)
+      }
+      Endpoint::Vsock { cid, port } => {
+        if !allow.is_empty() {
+          warn!("RUST REMOTE: --allow has no effect on vsock");
+        }
+        accept_vsock(*cid, *port)
+      }
+    }
+  }
+}
+
+#[cfg(unix)]
+fn connect_unix(path: &Path, timeout: time::Duration) -> Result<Connection, String> {
+  let deadline = time::Instant::now() + timeout;
+  loop {
+    info!("RUST REMOTE: rust remote trying connect {}", path.display());
+    match UnixStream::connect(path) {
+      Ok(stream) => {
+        info!("RUST REMOTE: rust remote connected to {}", path.display());
+        return Connection::unix(stream);
+      }
+      Err(error) => {
+        warn!("RUST REMOTE: rust remote failed to connect to {}, error:{:?}", path.display(), error);
+        if time::Instant::now() + time::Duration::from_millis(100) >= deadline {
+          return Err(format!("failed to connect to {}: {}", path.display(), error));
+        }
+        thread::sleep(time::Duration::from_millis(100));
+      }
+    }
+  }
+}
+
+/// Like accept_stream, on a Unix domain socket. A socket file left behind
+/// by an earlier host is replaced.
+#[cfg(unix)]
+fn accept_unix(path: &Path) -> Result<Connection, String> {
+  use std::os::unix::fs::FileTypeExt;
+
+  if std::fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_socket()) {
+    let _ = std::fs::remove_file(path);
+  }
+  let listener = UnixListener::bind(path)
+    .map_err(|e| format!("failed to listen on {}: {}", path.display(), e))?;
+  info!("RUST REMOTE: rust remote listening on {}", path.display());
+  let (stream, _) = listener.accept()
+    .map_err(|e| format!("failed to accept on {}: {}", path.display(), e))?;
+  // Nothing else may connect, and the path is free for the next listener
+  let _ = std::fs::remove_file(path);
+  info!("RUST REMOTE: rust remote accepted connection on {}", path.display());
+  Connection::unix(stream)
+}
+
+#[cfg(not(unix))]
+fn connect_unix(_path: &Path, _timeout: time::Duration) -> Result<Connection, String> {
+  Err(String::from("unix sockets are only supported on unix"))
+}
+
+#[cfg(not(unix))]
+fn accept_unix(_path: &Path) -> Result<Connection, String> {
+  Err(String::from("unix sockets are only supported on unix"))
+}
+
+#[cfg(target_os = "linux")]
+fn connect_vsock(cid: u32, port: u32, timeout: time::Duration) -> Result<Connection, String> {
+  let deadline = time::Instant::now() + timeout;
+  loop {
+    info!("RUST REMOTE: rust remote trying connect vsock {}:{}", cid, port);
+    match crate::vsock::connect(cid, port) {
+      Ok(stream) => {
+        info!("RUST REMOTE: rust remote connected to vsock {}:{}", cid, port);
+        return Connection::vsock(stream);
+      }
+      Err(error) => {
+        warn!("RUST REMOTE: rust remote failed to connect to vsock {}:{}, error:{:?}", cid, port, error);
+        if time::Instant::now() + time::Duration::from_millis(100) >= deadline {
+          return Err(format!("failed to connect to vsock {}:{}: {}", cid, port, error));
+        }
+        thread::sleep(time::Duration::from_millis(100));
+      }
+    }
+  }
+}
+
+#[cfg(target_os = "linux")]
+fn accept_vsock(cid: u32, port: u32) -> Result<Connection, String> {
+  info!("RUST REMOTE: rust remote listening on vsock {}:{}", cid, port);
+  let (stream, peer) = crate::vsock::accept(cid, port)
+    .map_err(|e| format!("failed to accept on vsock port {}: {}", port, e))?;
+  info!("RUST REMOTE: rust remote accepted connection from CID {}", peer);
+  Connection::vsock(stream)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn connect_vsock(_cid: u32, _port: u32, _timeout: time::Duration) -> Result<Connection, String> {
+  Err(String::from("vsock is only supported on linux"))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn accept_vsock(_cid: u32, _port: u32) -> Result<Connection, String> {
+  Err(String::from("vsock is only supported on linux"))
+}
+
+/// Dials out to the Thunder side of the bridge, retrying until `timeout`
+/// since Thunder may not be listening yet when the host starts.
+pub fn connect_stream(addr: String, timeout: time::Duration) -> Result<TcpStream, String> {
+
+  let deadline = time::Instant::now() + timeout;
+
+  let stream = loop {
+
+    info!("RUST REMOTE: rust remote trying connect {}", addr);
+    
+    match TcpStream::connect(&addr) {
+      Ok(stream) => {
+        info!("RUST REMOTE: rust remote connected to {}", addr);
+        break stream
+      },
+      Err(error) => {
+        warn!("RUST REMOTE: rust remote failed to connec to {}, error:{:?}", addr, error);
+        if time::Instant::now() + time::Duration::from_millis(100) >= deadline {
+          return Err(format!("failed to connect to {}: {}", addr, error));
+        }
+        thread::sleep(time::Duration::from_millis(100));
+        continue;
+      }
+    }
+  };
+
+  Ok(stream)
+}
+
+// How many times in a row a write may make no progress because the socket
+// is busy before the connection is treated as broken.
+const MAX_WRITE_RETRIES: u32 = 50;
+const MAX_WRITE_BACKOFF: time::Duration = time::Duration::from_millis(100);
+
+/// Writes all of `buf`, picking up after short writes and retrying with
+/// backoff while the socket reports it's busy. An error means the
+/// connection is unusable.
+pub fn write_with_retry(stream: &mut impl Write, buf: &[u8]) -> io::Result<()> {
+  let mut written = 0;
+  let mut retries = 0;
+  let mut backoff = time::Duration::from_millis(1);
+
+  while written < buf.len() {
+    match stream.write(&buf[written..]) {
+      Ok(0) => return Err(io::Error::new(ErrorKind::WriteZero, "connection accepted no data")),
+      Ok(n) => {
+        written += n;
+        retries = 0;
+        backoff = time::Duration::from_millis(1);
+      }
+      Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+      Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+        retries += 1;
+        if retries > MAX_WRITE_RETRIES {
+          return Err(e);
+        }
+        warn!("RUST REMOTE: write would block, retrying in {:?}", backoff);
+        thread::sleep(backoff);
+        backoff = (backoff * 2).min(MAX_WRITE_BACKOFF);
+      }
+      Err(e) => return Err(e)
+    }
+  }
+  Ok(())
+}
+
+/// Binds `addr` and waits for the Thunder side to connect in, for
+/// deployments where the host can't initiate connections. Peers not in
+/// `allow` (when it isn't empty) are turned away. Only one connection is
+/// ever accepted; the listener is closed as soon as it has been.
+pub fn accept_stream(addr: String, allow: &[IpAddr]) -> Result<TcpStream, String> {
+  let listener = TcpListener::bind(&addr)
+    .map_err(|e| format!("failed to listen on {}: {}", addr, e))?;
+  info!("RUST REMOTE: rust remote listening on {}", addr);
+
+  loop {
+    let (stream, peer) = listener.accept()
+      .map_err(|e| format!("failed to accept on {}: {}", addr, e))?;
+
+    if !allow.is_empty() && !allow.contains(&peer.ip()) {
+      warn!("RUST REMOTE: rejecting connection from {}, not in allow list", peer);
+      drop(stream);
+      continue;
+    }
+
+    info!("RUST REMOTE: rust remote accepted connection from {}", peer);
+    return Ok(stream);
+  }
+}
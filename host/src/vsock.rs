@@ -0,0 +1,93 @@
+/*
+ * Copyright 2022 Comcast Cable Communications Management, LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+//! AF_VSOCK sockets, for a host running in a VM or an isolated container
+//! while Thunder runs outside it. The standard library has no vsock
+//! support, so sockets are made with libc and used through File, which
+//! reads and writes any fd.
+use std::fs::File;
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+
+fn address(cid: u32, port: u32) -> libc::sockaddr_vm {
+  let mut addr: libc::sockaddr_vm = unsafe { std::mem::zeroed() };
+  addr.svm_family = libc::AF_VSOCK as libc::sa_family_t;
+  addr.svm_cid = cid;
+  addr.svm_port = port;
+  addr
+}
+
+fn socket() -> io::Result<OwnedFd> {
+  let fd = unsafe { libc::socket(libc::AF_VSOCK, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, 0) };
+  if fd < 0 {
+    return Err(io::Error::last_os_error());
+  }
+  Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+fn check(rc: libc::c_int) -> io::Result<()> {
+  if rc < 0 {
+    Err(io::Error::last_os_error())
+  } else {
+    Ok(())
+  }
+}
+
+pub fn connect(cid: u32, port: u32) -> io::Result<File> {
+  let fd = socket()?;
+  let addr = address(cid, port);
+  check(unsafe {
+    libc::connect(fd.as_raw_fd(), &addr as *const _ as *const libc::sockaddr,
+      std::mem::size_of::<libc::sockaddr_vm>() as libc::socklen_t)
+  })?;
+  Ok(File::from(fd))
+}
+
+/// Waits for one connection on `port`, returning it with the peer's CID.
+/// The listening socket is closed once it has been accepted.
+pub fn accept(cid: u32, port: u32) -> io::Result<(File, u32)> {
+  let listener = socket()?;
+  let addr = address(cid, port);
+  check(unsafe {
+    libc::bind(listener.as_raw_fd(), &addr as *const _ as *const libc::sockaddr,
+      std::mem::size_of::<libc::sockaddr_vm>() as libc::socklen_t)
+  })?;
+  check(unsafe { libc::listen(listener.as_raw_fd(), 1) })?;
+
+  let mut peer = address(0, 0);
+  let mut len = std::mem::size_of::<libc::sockaddr_vm>() as libc::socklen_t;
+  let fd = loop {
+    let fd = unsafe {
+      libc::accept4(listener.as_raw_fd(), &mut peer as *mut _ as *mut libc::sockaddr, &mut len, libc::SOCK_CLOEXEC)
+    };
+    if fd >= 0 {
+      break fd;
+    }
+    let e = io::Error::last_os_error();
+    if e.kind() != io::ErrorKind::Interrupted {
+      return Err(e);
+    }
+  };
+  Ok((File::from(unsafe { OwnedFd::from_raw_fd(fd) }), peer.svm_cid))
+}
+
+/// Shuts both directions of a vsock connection down.
+pub fn shutdown(stream: &File) {
+  unsafe {
+    libc::shutdown(stream.as_raw_fd(), libc::SHUT_RDWR);
+  }
+}
@@ -0,0 +1,137 @@
+/*
+ * Copyright 2022 Comcast Cable Communications Management, LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+//! Debug aid behind the `lifetime-audit` feature: every RequestContext
+//! handle is recorded with the backtrace of where it was created or cloned,
+//! so handles kept long after their client went away, which keep the
+//! channel's state and buffers alive, can be traced back to their origin.
+//!
+//! Handles whose channel has been disconnected for longer than
+//! THUNDER_RS_AUDIT_GRACE_MS (default 60s) are reported when another
+//! channel disconnects and when the journal is dumped; every handle still
+//! alive when the plugin is destroyed is reported then.
+use std::backtrace::Backtrace;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock, Weak};
+use std::time::{Duration, Instant};
+
+use log::warn;
+
+use crate::channel::ChannelState;
+
+const DEFAULT_GRACE: Duration = Duration::from_secs(60);
+
+struct Entry {
+  channel: u32,
+  state: Weak<ChannelState>,
+  origin: Backtrace,
+  dead_since: Option<Instant>,
+  reported: bool
+}
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+fn registry() -> &'static Mutex<HashMap<u64, Entry>> {
+  static REGISTRY: OnceLock<Mutex<HashMap<u64, Entry>>> = OnceLock::new();
+  REGISTRY.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+fn grace() -> Duration {
+  std::env::var("THUNDER_RS_AUDIT_GRACE_MS").ok()
+    .and_then(|s| s.parse::<u64>().ok())
+    .map(Duration::from_millis)
+    .unwrap_or(DEFAULT_GRACE)
+}
+
+/// Held by each RequestContext; registers on creation and clone and
+/// unregisters on drop.
+pub(crate) struct Tracked {
+  id: u64,
+  channel: u32,
+  state: Weak<ChannelState>
+}
+
+impl Tracked {
+  pub(crate) fn new(channel: u32, state: &Arc<ChannelState>) -> Self {
+    Tracked::register(channel, Arc::downgrade(state))
+  }
+
+  fn register(channel: u32, state: Weak<ChannelState>) -> Self {
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    registry().lock().unwrap().insert(id, Entry {
+      channel,
+      state: state.clone(),
+      origin: Backtrace::force_capture(),
+      dead_since: None,
+      reported: false
+    });
+    Tracked { id, channel, state }
+  }
+}
+
+impl Clone for Tracked {
+  fn clone(&self) -> Self {
+    Tracked::register(self.channel, self.state.clone())
+  }
+}
+
+impl Drop for Tracked {
+  fn drop(&mut self) {
+    registry().lock().unwrap().remove(&self.id);
+  }
+}
+
+/// Reports handles whose channel has been gone for longer than the grace
+/// period. Each is reported once. Returns how many were reported.
+pub fn check() -> usize {
+  let grace = grace();
+  let now = Instant::now();
+  let mut reported = 0;
+  for entry in registry().lock().unwrap().values_mut() {
+    let alive = entry.state.upgrade().is_some_and(|s| s.is_alive());
+    if alive || entry.reported {
+      continue;
+    }
+    let dead_since = *entry.dead_since.get_or_insert(now);
+    if now.duration_since(dead_since) >= grace {
+      entry.reported = true;
+      reported += 1;
+      warn!("context for channel {} still alive {:?} after disconnect, created at:\n{}",
+        entry.channel, now.duration_since(dead_since), entry.origin);
+    }
+  }
+  reported
+}
+
+/// Stands in for a destroyed plugin so the contexts it held are released
+/// before report_live() runs.
+pub(crate) struct Retired;
+
+impl crate::Plugin for Retired {
+  fn on_message(&mut self, _json: String, _ctx: crate::RequestContext) { }
+}
+
+/// Reports every handle still alive, for when the plugin is going away and
+/// none should be. Returns how many there were.
+pub fn report_live() -> usize {
+  let registry = registry().lock().unwrap();
+  for entry in registry.values() {
+    warn!("context for channel {} outlived the plugin, created at:\n{}", entry.channel, entry.origin);
+  }
+  registry.len()
+}
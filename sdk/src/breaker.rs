@@ -0,0 +1,249 @@
+/*
+ * Copyright 2022 Comcast Cable Communications Management, LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+//! Stops calling a dependency that keeps failing and probes it now and then
+//! until it recovers, so a dead cloud service or hardware daemon makes
+//! requests fail fast instead of piling up behind timeouts.
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+
+use crate::jsonrpc;
+use crate::metrics::{Counter, Gauge, Metrics};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BreakerState {
+  /// Calls go through and their outcomes are counted.
+  Closed,
+  /// Calls are refused until the open period is over.
+  Open,
+  /// A limited number of probe calls are let through to test recovery.
+  HalfOpen
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct BreakerConfig {
+  /// How many recent calls the failure rate is computed over.
+  pub window: usize,
+  /// Failure rate (0.0 to 1.0) over the window that opens the breaker.
+  pub failure_rate: f64,
+  /// The breaker doesn't open before the window has this many calls.
+  pub min_calls: usize,
+  /// How long the breaker stays open before probing.
+  pub open_for: Duration,
+  /// Probe calls allowed at once while half-open.
+  pub probes: u32
+}
+
+impl Default for BreakerConfig {
+  fn default() -> Self {
+    BreakerConfig {
+      window: 20,
+      failure_rate: 0.5,
+      min_calls: 5,
+      open_for: Duration::from_secs(30),
+      probes: 1
+    }
+  }
+}
+
+/// Why a call through the breaker failed.
+#[derive(Debug)]
+pub enum BreakerError<E> {
+  /// The breaker is open; the call wasn't made.
+  Open,
+  /// The call was made and failed.
+  Failed(E)
+}
+
+impl<E: fmt::Display> fmt::Display for BreakerError<E> {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      BreakerError::Open => write!(f, "circuit breaker is open"),
+      BreakerError::Failed(e) => e.fmt(f)
+    }
+  }
+}
+
+impl<E: fmt::Debug + fmt::Display> std::error::Error for BreakerError<E> { }
+
+impl From<BreakerError<jsonrpc::Error>> for jsonrpc::Error {
+  fn from(e: BreakerError<jsonrpc::Error>) -> Self {
+    match e {
+      BreakerError::Open => jsonrpc::Error::new(jsonrpc::ERROR_UNAVAILABLE, "Service unavailable, try again later"),
+      BreakerError::Failed(e) => e
+    }
+  }
+}
+
+struct BreakerMetrics {
+  state: Gauge,
+  opened: Counter,
+  rejected: Counter
+}
+
+struct Inner {
+  state: BreakerState,
+  outcomes: VecDeque<bool>,
+  opened_at: Option<Instant>,
+  probes_in_flight: u32
+}
+
+/// Share one breaker (e.g. in an Arc) between every caller of the same
+/// dependency.
+pub struct CircuitBreaker {
+  name: String,
+  config: BreakerConfig,
+  inner: Mutex<Inner>,
+  metrics: Option<BreakerMetrics>
+}
+
+impl CircuitBreaker {
+  pub fn new(name: &str, config: BreakerConfig) -> Self {
+    CircuitBreaker {
+      name: name.to_string(),
+      config,
+      inner: Mutex::new(Inner {
+        state: BreakerState::Closed,
+        outcomes: VecDeque::new(),
+        opened_at: None,
+        probes_in_flight: 0
+      }),
+      metrics: None
+    }
+  }
+
+  /// Records the breaker in `metrics`: "<name>.state" (0 closed, 1 open,
+  /// 2 half-open), "<name>.opened" and "<name>.rejected".
+  pub fn with_metrics(mut self, metrics: &Metrics) -> Self {
+    self.metrics = Some(BreakerMetrics {
+      state: metrics.gauge(&format!("{}.state", self.name)),
+      opened: metrics.counter(&format!("{}.opened", self.name)),
+      rejected: metrics.counter(&format!("{}.rejected", self.name))
+    });
+    self
+  }
+
+  pub fn state(&self) -> BreakerState {
+    let mut inner = self.inner.lock().unwrap();
+    self.refresh(&mut inner);
+    inner.state
+  }
+
+  /// Runs `f` unless the breaker is open, and counts its outcome.
+  pub fn call<T, E, F>(&self, f: F) -> Result<T, BreakerError<E>>
+    where F: FnOnce() -> Result<T, E>
+  {
+    if !self.allow() {
+      return Err(BreakerError::Open);
+    }
+    let result = f();
+    self.record(result.is_ok());
+    result.map_err(BreakerError::Failed)
+  }
+
+  /// Asks to make a call. Callers that get true must report the outcome
+  /// with record(). call() does both.
+  pub fn allow(&self) -> bool {
+    let mut inner = self.inner.lock().unwrap();
+    self.refresh(&mut inner);
+    let allowed = match inner.state {
+      BreakerState::Closed => true,
+      BreakerState::Open => false,
+      BreakerState::HalfOpen => {
+        if inner.probes_in_flight < self.config.probes {
+          inner.probes_in_flight += 1;
+          true
+        } else {
+          false
+        }
+      }
+    };
+    if !allowed {
+      if let Some(m) = &self.metrics {
+        m.rejected.inc();
+      }
+    }
+    allowed
+  }
+
+  pub fn record(&self, success: bool) {
+    let mut inner = self.inner.lock().unwrap();
+    match inner.state {
+      BreakerState::HalfOpen => {
+        inner.probes_in_flight = inner.probes_in_flight.saturating_sub(1);
+        if success {
+          info!("{}: circuit closed, dependency recovered", self.name);
+          inner.state = BreakerState::Closed;
+          inner.outcomes.clear();
+          self.record_state(BreakerState::Closed);
+        } else {
+          self.open(&mut inner);
+        }
+      }
+      BreakerState::Closed => {
+        inner.outcomes.push_back(success);
+        while inner.outcomes.len() > self.config.window {
+          inner.outcomes.pop_front();
+        }
+        let calls = inner.outcomes.len();
+        let failures = inner.outcomes.iter().filter(|ok| !**ok).count();
+        if calls >= self.config.min_calls && failures as f64 / calls as f64 >= self.config.failure_rate {
+          self.open(&mut inner);
+        }
+      }
+      // A call let through before the breaker opened
+      BreakerState::Open => { }
+    }
+  }
+
+  fn open(&self, inner: &mut Inner) {
+    warn!("{}: circuit open for {:?}", self.name, self.config.open_for);
+    inner.state = BreakerState::Open;
+    inner.opened_at = Some(Instant::now());
+    inner.outcomes.clear();
+    inner.probes_in_flight = 0;
+    self.record_state(BreakerState::Open);
+    if let Some(m) = &self.metrics {
+      m.opened.inc();
+    }
+  }
+
+  fn record_state(&self, state: BreakerState) {
+    if let Some(m) = &self.metrics {
+      m.state.set(match state {
+        BreakerState::Closed => 0,
+        BreakerState::Open => 1,
+        BreakerState::HalfOpen => 2
+      });
+    }
+  }
+
+  fn refresh(&self, inner: &mut Inner) {
+    if inner.state == BreakerState::Open {
+      if let Some(opened_at) = inner.opened_at {
+        if opened_at.elapsed() >= self.config.open_for {
+          inner.state = BreakerState::HalfOpen;
+          self.record_state(BreakerState::HalfOpen);
+        }
+      }
+    }
+  }
+}
@@ -0,0 +1,285 @@
+/*
+ * Copyright 2022 Comcast Cable Communications Management, LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::{Codec, Features, Framework, Paths, RequestContext, Responder, Timers};
+
+/// How long a channel that never attached is kept after its last activity.
+/// Nothing tells us when such a client (e.g. HTTP) has gone.
+const UNATTACHED_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Counters the SDK keeps for each connected channel.
+#[derive(Debug, Clone, Copy)]
+pub struct ChannelStats {
+  pub requests_received: u64,
+  pub responses_sent: u64,
+  pub events_delivered: u64,
+  pub bytes_received: u64,
+  pub bytes_sent: u64,
+  /// When a message last went either way
+  pub last_activity: Instant
+}
+
+/// How a client reaches Thunder.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ChannelType {
+  #[default]
+  Unknown,
+  WebSocket,
+  Http
+}
+
+impl ChannelType {
+  /// The value bridges pass for each type: 1 WebSocket, 2 HTTP, anything
+  /// else unknown.
+  pub fn from_raw(raw: u32) -> Self {
+    match raw {
+      1 => ChannelType::WebSocket,
+      2 => ChannelType::Http,
+      _ => ChannelType::Unknown
+    }
+  }
+}
+
+/// What the bridge reported about the client on a channel. Bridges that
+/// predate this report nothing, so every field may be missing.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Peer {
+  /// The client's address, e.g. "192.168.1.20:51234"
+  pub remote_address: Option<String>,
+  /// The Origin header the client sent, for browser based clients
+  pub origin: Option<String>,
+  pub channel_type: ChannelType
+}
+
+pub(crate) struct ChannelState {
+  alive: AtomicBool,
+  // False for a channel that sent requests without attaching first
+  attached: bool,
+  peer: Mutex<Arc<Peer>>,
+  requests_received: AtomicU64,
+  responses_sent: AtomicU64,
+  events_delivered: AtomicU64,
+  bytes_received: AtomicU64,
+  bytes_sent: AtomicU64,
+  last_activity: Mutex<Instant>
+}
+
+impl ChannelState {
+  fn new(attached: bool) -> Self {
+    ChannelState {
+      alive: AtomicBool::new(true),
+      attached,
+      peer: Mutex::new(Arc::new(Peer::default())),
+      requests_received: AtomicU64::new(0),
+      responses_sent: AtomicU64::new(0),
+      events_delivered: AtomicU64::new(0),
+      bytes_received: AtomicU64::new(0),
+      bytes_sent: AtomicU64::new(0),
+      last_activity: Mutex::new(Instant::now())
+    }
+  }
+
+  pub(crate) fn is_alive(&self) -> bool {
+    self.alive.load(Ordering::Acquire)
+  }
+
+  pub(crate) fn peer(&self) -> Arc<Peer> {
+    self.peer.lock().unwrap().clone()
+  }
+
+  fn received(&self, len: usize) {
+    self.requests_received.fetch_add(1, Ordering::Relaxed);
+    self.bytes_received.fetch_add(len as u64, Ordering::Relaxed);
+    *self.last_activity.lock().unwrap() = Instant::now();
+  }
+
+  /// Counts a message sent to the client, as an event or as a response.
+  pub(crate) fn sent(&self, len: usize, event: bool) {
+    if event {
+      self.events_delivered.fetch_add(1, Ordering::Relaxed);
+    } else {
+      self.responses_sent.fetch_add(1, Ordering::Relaxed);
+    }
+    self.bytes_sent.fetch_add(len as u64, Ordering::Relaxed);
+    *self.last_activity.lock().unwrap() = Instant::now();
+  }
+
+  pub(crate) fn stats(&self) -> ChannelStats {
+    ChannelStats {
+      requests_received: self.requests_received.load(Ordering::Relaxed),
+      responses_sent: self.responses_sent.load(Ordering::Relaxed),
+      events_delivered: self.events_delivered.load(Ordering::Relaxed),
+      bytes_received: self.bytes_received.load(Ordering::Relaxed),
+      bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+      last_activity: *self.last_activity.lock().unwrap()
+    }
+  }
+}
+
+/// Tracks which channels are still connected so that contexts handed to the
+/// plugin can tell when their client has gone away.
+#[derive(Default)]
+pub struct Channels {
+  live: HashMap<u32, Arc<ChannelState>>,
+  framework: Framework,
+  timers: Timers,
+  features: Features,
+  paths: Arc<Paths>,
+  codec: Codec
+}
+
+impl Channels {
+  pub fn new() -> Self {
+    Channels::default()
+  }
+
+  /// Sets the framework handle given to every context created from now on.
+  pub fn set_framework(&mut self, framework: Framework) {
+    self.framework = framework;
+  }
+
+  /// Sets the feature flags given to every context created from now on.
+  pub fn set_features(&mut self, features: Features) {
+    self.features = features;
+  }
+
+  /// Sets the directories given to every context created from now on.
+  pub fn set_paths(&mut self, paths: Paths) {
+    self.paths = Arc::new(paths);
+  }
+
+  /// Sets the payload codec given to every context created from now on.
+  pub fn set_codec(&mut self, codec: Codec) {
+    self.codec = codec;
+  }
+
+  pub fn connect(&mut self, channel: u32) {
+    self.live.insert(channel, Arc::new(ChannelState::new(true)));
+  }
+
+  pub fn disconnect(&mut self, channel: u32) {
+    if let Some(state) = self.live.remove(&channel) {
+      state.alive.store(false, Ordering::Release);
+    }
+    #[cfg(feature = "lifetime-audit")]
+    crate::audit::check();
+  }
+
+  /// Stops every timer, dropping their callbacks, and gives contexts created
+  /// from now on a new set. For when the plugin's library is unloaded.
+  pub fn reset_timers(&mut self) {
+    self.timers.shutdown();
+    self.timers = Timers::new();
+  }
+
+  /// Records what the bridge reported about the client on `channel`.
+  pub fn set_peer(&mut self, channel: u32, peer: Peer) {
+    let state = self.state(channel);
+    let mut current = state.peer.lock().unwrap();
+    if **current != peer {
+      *current = Arc::new(peer);
+    }
+  }
+
+  /// Disconnects every channel, returning them in order.
+  pub fn disconnect_all(&mut self) -> Vec<u32> {
+    let mut channels: Vec<u32> = self.live.drain()
+      .map(|(channel, state)| {
+        state.alive.store(false, Ordering::Release);
+        channel
+      })
+      .collect();
+    channels.sort_unstable();
+    channels
+  }
+
+  /// Creates the context for a request arriving on `channel`. Channels that
+  /// send requests without attaching first (e.g. HTTP) are treated as live.
+  pub fn context(&mut self, channel: u32, auth_token: String, responder: Responder) -> RequestContext {
+    let state = self.state(channel);
+    RequestContext {
+      channel,
+      auth_token,
+      responder,
+      framework: self.framework.clone(),
+      timers: self.timers.clone(),
+      features: self.features.clone(),
+      paths: self.paths.clone(),
+      codec: self.codec,
+      claims: Default::default(),
+      #[cfg(feature = "lifetime-audit")]
+      _audit: crate::audit::Tracked::new(channel, &state),
+      state
+    }
+  }
+
+  /// The state of `channel`, created for one that hasn't attached. Creating
+  /// one also forgets channels that never attached and have been idle for
+  /// UNATTACHED_TIMEOUT.
+  fn state(&mut self, channel: u32) -> Arc<ChannelState> {
+    if let Some(state) = self.live.get(&channel) {
+      return state.clone();
+    }
+    self.live.retain(|_, state| {
+      state.attached || state.last_activity.lock().unwrap().elapsed() < UNATTACHED_TIMEOUT
+    });
+    let state = Arc::new(ChannelState::new(false));
+    self.live.insert(channel, state.clone());
+    state
+  }
+
+  /// Like context(), and counts a `len` byte request in the channel's stats.
+  pub fn receive(&mut self, channel: u32, auth_token: String, responder: Responder, len: usize) -> RequestContext {
+    let ctx = self.context(channel, auth_token, responder);
+    ctx.state.received(len);
+    ctx
+  }
+
+  /// Stats for every connected channel, ordered by channel.
+  pub fn stats(&self) -> Vec<(u32, ChannelStats)> {
+    let mut stats: Vec<(u32, ChannelStats)> = self.live.iter()
+      .map(|(channel, state)| (*channel, state.stats()))
+      .collect();
+    stats.sort_by_key(|(channel, _)| *channel);
+    stats
+  }
+
+  /// Adds the stats of every channel to the journal, so they're part of the
+  /// next dump.
+  pub fn record_stats(&self) {
+    #[cfg(feature = "lifetime-audit")]
+    crate::audit::check();
+    for (channel, s) in self.stats() {
+      crate::journal::record(crate::journal::Source::Plugin, "channel_stats", Some(channel),
+        format!("requests={} responses={} events={} bytes_in={} bytes_out={} idle_ms={}",
+          s.requests_received, s.responses_sent, s.events_delivered, s.bytes_received,
+          s.bytes_sent, s.last_activity.elapsed().as_millis()));
+    }
+  }
+}
+
+impl Drop for Channels {
+  /// Channels live as long as the plugin, so this is where its timers stop.
+  fn drop(&mut self) {
+    self.timers.shutdown();
+  }
+}
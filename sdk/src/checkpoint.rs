@@ -0,0 +1,255 @@
+/*
+ * Copyright 2022 Comcast Cable Communications Management, LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+//! Small recovery blobs that let a stateful plugin pick up where it left off
+//! after a panic restart or a host crash.
+use std::fs;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::{Mutex, MutexGuard, TryLockError};
+use std::time::{Duration, Instant};
+
+use log::{debug, warn};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+/// The last state a previous instance of the plugin checkpointed.
+#[derive(Debug, Clone)]
+pub struct Checkpoint {
+  data: Vec<u8>
+}
+
+impl Checkpoint {
+  pub fn decode<T: DeserializeOwned>(&self) -> Result<T, serde_json::Error> {
+    serde_json::from_slice(&self.data)
+  }
+}
+
+/// Writes checkpoints to one file, at most once per interval. Workers only
+/// hold the state lock long enough to swap in their bytes; the file is
+/// written under a lock of its own, and a checkpoint that finds a write
+/// under way leaves its state pending rather than waiting. Pending state,
+/// like any checkpoint made within the interval, is written by the first
+/// checkpoint after the interval or by flush. Without a path checkpoints are
+/// dropped and there's nothing to restore.
+#[derive(Debug)]
+pub(crate) struct Checkpointer {
+  path: Option<PathBuf>,
+  interval: Duration,
+  state: Mutex<State>,
+  writer: Mutex<()>
+}
+
+#[derive(Debug, Default)]
+struct State {
+  last_write: Option<Instant>,
+  pending: Option<Vec<u8>>
+}
+
+impl State {
+  fn due(&self, interval: Duration) -> bool {
+    self.pending.is_some() && !matches!(self.last_write, Some(t) if t.elapsed() < interval)
+  }
+}
+
+impl Checkpointer {
+  pub(crate) fn new(path: Option<PathBuf>, interval: Duration) -> Self {
+    Checkpointer {
+      path,
+      interval,
+      state: Mutex::new(State::default()),
+      writer: Mutex::new(())
+    }
+  }
+
+  pub(crate) fn checkpoint(&self, state: &impl Serialize) -> io::Result<()> {
+    if self.path.is_none() {
+      return Ok(());
+    }
+    let data = serde_json::to_vec(state)?;
+    let due = {
+      let mut state = self.lock_state();
+      state.pending = Some(data);
+      state.due(self.interval)
+    };
+    if !due {
+      return Ok(());
+    }
+    match self.writer.try_lock() {
+      Ok(_writing) => self.write_due(),
+      // Left pending; the write under way has just restarted the interval
+      Err(TryLockError::WouldBlock) => Ok(()),
+      Err(TryLockError::Poisoned(e)) => {
+        let _writing = e.into_inner();
+        self.write_due()
+      }
+    }
+  }
+
+  pub(crate) fn flush(&self) -> io::Result<()> {
+    let _writing = self.writer.lock().unwrap_or_else(|e| e.into_inner());
+    let data = match self.lock_state().pending.take() {
+      Some(data) => data,
+      None => return Ok(())
+    };
+    self.write(data)
+  }
+
+  /// Writes pending state while the interval allows, which with an interval
+  /// of zero takes whatever was left pending during a write. Called holding
+  /// `writer`.
+  fn write_due(&self) -> io::Result<()> {
+    loop {
+      let data = {
+        let mut state = self.lock_state();
+        if !state.due(self.interval) {
+          return Ok(());
+        }
+        state.pending.take().unwrap()
+      };
+      self.write(data)?;
+    }
+  }
+
+  /// Called holding `writer`.
+  fn write(&self, data: Vec<u8>) -> io::Result<()> {
+    let Some(path) = &self.path else { return Ok(()) };
+    // Write next to the real file and rename so a crash mid-write never
+    // leaves a torn checkpoint behind. Only the plugin's user may read it
+    let tmp = path.with_extension("tmp");
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(&tmp)?;
+    file.write_all(&data)?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)?;
+    self.lock_state().last_write = Some(Instant::now());
+    debug!("wrote {} byte checkpoint to {}", data.len(), path.display());
+    Ok(())
+  }
+
+  fn lock_state(&self) -> MutexGuard<'_, State> {
+    self.state.lock().unwrap_or_else(|e| e.into_inner())
+  }
+
+  pub(crate) fn restore(&self) -> Option<Checkpoint> {
+    let path = self.path.as_ref()?;
+    match fs::read(path) {
+      Ok(data) => Some(Checkpoint { data }),
+      Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+      Err(e) => {
+        warn!("failed to read checkpoint {}: {}", path.display(), e);
+        None
+      }
+    }
+  }
+}
+
+impl Drop for Checkpointer {
+  fn drop(&mut self) {
+    if let Err(e) = self.flush() {
+      warn!("failed to write checkpoint: {}", e);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::sync::Arc;
+
+  fn path(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("thunder_rs-checkpoint-{}-{}", std::process::id(), name));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir.join("plugin.checkpoint")
+  }
+
+  #[test]
+  fn writes_at_most_once_per_interval() {
+    let path = path("interval");
+    let checkpoints = Checkpointer::new(Some(path.clone()), Duration::from_secs(60));
+    checkpoints.checkpoint(&1).unwrap();
+    checkpoints.checkpoint(&2).unwrap();
+    assert_eq!(checkpoints.restore().unwrap().decode::<u32>().unwrap(), 1);
+    checkpoints.flush().unwrap();
+    assert_eq!(checkpoints.restore().unwrap().decode::<u32>().unwrap(), 2);
+  }
+
+  #[test]
+  fn checkpoint_during_a_write_waits_for_the_next() {
+    let path = path("busy");
+    let checkpoints = Checkpointer::new(Some(path.clone()), Duration::from_secs(60));
+    let writing = checkpoints.writer.lock().unwrap();
+    checkpoints.checkpoint(&1).unwrap();
+    drop(writing);
+    assert!(checkpoints.restore().is_none());
+    checkpoints.flush().unwrap();
+    assert_eq!(checkpoints.restore().unwrap().decode::<u32>().unwrap(), 1);
+  }
+
+  #[test]
+  fn pending_state_is_written_on_drop() {
+    let path = path("drop");
+    let checkpoints = Checkpointer::new(Some(path.clone()), Duration::from_secs(60));
+    checkpoints.checkpoint(&"first").unwrap();
+    checkpoints.checkpoint(&"last").unwrap();
+    drop(checkpoints);
+    let restored = Checkpointer::new(Some(path), Duration::ZERO).restore().unwrap();
+    assert_eq!(restored.decode::<String>().unwrap(), "last");
+  }
+
+  #[test]
+  fn workers_checkpoint_side_by_side() {
+    let path = path("workers");
+    let checkpoints = Arc::new(Checkpointer::new(Some(path.clone()), Duration::ZERO));
+    let threads: Vec<_> = (0..8u32)
+      .map(|worker| {
+        let checkpoints = checkpoints.clone();
+        std::thread::spawn(move || {
+          for i in 0..50u32 {
+            checkpoints.checkpoint(&(worker, i)).unwrap();
+          }
+        })
+      })
+      .collect();
+    for thread in threads {
+      thread.join().unwrap();
+    }
+    checkpoints.flush().unwrap();
+    // Whichever came last, the file holds a whole checkpoint
+    let (worker, i) = checkpoints.restore().unwrap().decode::<(u32, u32)>().unwrap();
+    assert!(worker < 8 && i < 50);
+    assert!(!path.with_extension("tmp").exists());
+  }
+
+  #[test]
+  fn nothing_to_restore_without_a_checkpoint() {
+    let checkpoints = Checkpointer::new(Some(path("none")), Duration::ZERO);
+    assert!(checkpoints.restore().is_none());
+  }
+
+  #[test]
+  fn nothing_is_kept_without_a_path() {
+    let checkpoints = Checkpointer::new(None, Duration::ZERO);
+    checkpoints.checkpoint(&1).unwrap();
+    checkpoints.flush().unwrap();
+    assert!(checkpoints.restore().is_none());
+  }
+}
@@ -0,0 +1,149 @@
+/*
+ * Copyright 2022 Comcast Cable Communications Management, LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+//! Binary payload codecs for plugins that move a lot of data. A plugin
+//! lists the codecs it accepts in its metadata flags:
+//!
+//!   thunder_rs::export_plugin!("Sensors", (1,0,0), sensors_init,
+//!     thunder_rs::codec::FLAG_CBOR);
+//!
+//! A bridge that supports one of them selects it with
+//! wpe_rust_plugin_set_codec and from then on carries payloads as binary
+//! frames in that codec instead of JSON text. They arrive in
+//! on_binary_message; decode them with `decode(ctx.codec(), data)` and
+//! answer with RequestContext::send_encoded.
+use std::fmt;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::SendError;
+
+/// Metadata flag: the plugin accepts CBOR payloads
+pub const FLAG_CBOR: u32 = 1 << 0;
+/// Metadata flag: the plugin accepts MessagePack payloads
+pub const FLAG_MSGPACK: u32 = 1 << 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Codec {
+  /// JSON text, what every bridge speaks
+  #[default]
+  Json,
+  Cbor,
+  MessagePack
+}
+
+impl Codec {
+  /// The value bridges pass to wpe_rust_plugin_set_codec: 0 JSON, 1 CBOR,
+  /// 2 MessagePack.
+  pub fn from_raw(raw: u32) -> Option<Self> {
+    match raw {
+      0 => Some(Codec::Json),
+      1 => Some(Codec::Cbor),
+      2 => Some(Codec::MessagePack),
+      _ => None
+    }
+  }
+
+  pub fn name(self) -> &'static str {
+    match self {
+      Codec::Json => "json",
+      Codec::Cbor => "cbor",
+      Codec::MessagePack => "msgpack"
+    }
+  }
+
+  /// The metadata flag a plugin sets to accept this codec. JSON needs none.
+  pub fn flag(self) -> u32 {
+    match self {
+      Codec::Json => 0,
+      Codec::Cbor => FLAG_CBOR,
+      Codec::MessagePack => FLAG_MSGPACK
+    }
+  }
+
+  /// Whether the SDK was built with this codec, i.e. with the "cbor" or
+  /// "msgpack" feature.
+  pub fn is_available(self) -> bool {
+    match self {
+      Codec::Json => true,
+      Codec::Cbor => cfg!(feature = "cbor"),
+      Codec::MessagePack => cfg!(feature = "msgpack")
+    }
+  }
+
+  /// Whether payloads in this codec go as binary frames.
+  pub fn is_binary(self) -> bool {
+    self != Codec::Json
+  }
+}
+
+#[derive(Debug)]
+pub enum Error {
+  /// The SDK was built without the codec's feature.
+  Unavailable(Codec),
+  Encode(String),
+  Decode(String),
+  Send(SendError)
+}
+
+impl fmt::Display for Error {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Error::Unavailable(codec) => write!(f, "the SDK was built without {} support", codec.name()),
+      Error::Encode(e) => write!(f, "failed to encode payload: {}", e),
+      Error::Decode(e) => write!(f, "failed to decode payload: {}", e),
+      Error::Send(e) => write!(f, "failed to send payload: {}", e)
+    }
+  }
+}
+
+impl std::error::Error for Error { }
+
+impl From<SendError> for Error {
+  fn from(e: SendError) -> Self {
+    Error::Send(e)
+  }
+}
+
+pub fn encode<T: Serialize + ?Sized>(codec: Codec, value: &T) -> Result<Vec<u8>, Error> {
+  match codec {
+    Codec::Json => serde_json::to_vec(value).map_err(|e| Error::Encode(e.to_string())),
+    #[cfg(feature = "cbor")]
+    Codec::Cbor => {
+      let mut data = Vec::new();
+      ciborium::into_writer(value, &mut data).map_err(|e| Error::Encode(e.to_string()))?;
+      Ok(data)
+    }
+    #[cfg(feature = "msgpack")]
+    Codec::MessagePack => rmp_serde::to_vec_named(value).map_err(|e| Error::Encode(e.to_string())),
+    #[allow(unreachable_patterns)]
+    _ => Err(Error::Unavailable(codec))
+  }
+}
+
+pub fn decode<T: DeserializeOwned>(codec: Codec, data: &[u8]) -> Result<T, Error> {
+  match codec {
+    Codec::Json => serde_json::from_slice(data).map_err(|e| Error::Decode(e.to_string())),
+    #[cfg(feature = "cbor")]
+    Codec::Cbor => ciborium::from_reader(data).map_err(|e| Error::Decode(e.to_string())),
+    #[cfg(feature = "msgpack")]
+    Codec::MessagePack => rmp_serde::from_slice(data).map_err(|e| Error::Decode(e.to_string())),
+    #[allow(unreachable_patterns)]
+    _ => Err(Error::Unavailable(codec))
+  }
+}
@@ -0,0 +1,309 @@
+/*
+ * Copyright 2022 Comcast Cable Communications Management, LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+
+use crate::events::Events;
+use crate::jsonrpc;
+use crate::router::Router;
+
+mod spec;
+
+pub use spec::{rust_from_spec, rust_from_spec_file};
+
+/// Generates a TypeScript client for the methods registered on `router`, for
+/// UIs talking to the plugin over Thunder's websocket. Parameter and result
+/// types are inferred from the examples attached to each route; methods
+/// without examples get `unknown`.
+///
+/// The generated `create<Callsign>Client` takes the function that performs
+/// the actual JSON-RPC call, so it works with whichever websocket client the
+/// UI already uses. `<Callsign>Events` maps each event declared on `events`
+/// to the type of its params, from the schema it was declared with.
+pub fn typescript(callsign: &str, router: &Router, events: &Events) -> String {
+  let ident = identifier(callsign);
+  let mut methods: BTreeMap<String, (Vec<String>, Vec<String>)> = router.methods().into_iter()
+    .map(|m| (m, (Vec::new(), Vec::new())))
+    .collect();
+
+  // Aliases of methods that don't exist can't be called, so leave them out
+  let aliases: Vec<(String, String)> = router.aliases().into_iter()
+    .filter(|(_, method)| methods.contains_key(method))
+    .collect();
+
+  for (method, example) in router.examples() {
+    let (params, results) = methods.get_mut(&method).unwrap();
+    push_unique(params, ts_type(&example.params));
+    if let Ok(result) = &example.response {
+      push_unique(results, ts_type(result));
+    }
+  }
+
+  let mut out = String::new();
+  let _ = writeln!(out, "// Generated by thunder_rs::codegen from the {} router. Do not edit.", js_string(callsign));
+  let _ = writeln!(out);
+  let _ = writeln!(out, "export type {}Call = (method: string, params?: unknown) => Promise<unknown>;", ident);
+  let _ = writeln!(out);
+  let _ = writeln!(out, "export interface {}Client {{", ident);
+  for (method, (params, results)) in &methods {
+    let _ = writeln!(out, "  {}(params?: {}): Promise<{}>;", property(method), union(params), union(results));
+  }
+  for (old, method) in &aliases {
+    let (params, results) = &methods[method];
+    let _ = writeln!(out, "  /** @deprecated Use {} instead. */", method);
+    let _ = writeln!(out, "  {}(params?: {}): Promise<{}>;", property(old), union(params), union(results));
+  }
+  let _ = writeln!(out, "}}");
+  let _ = writeln!(out);
+  let _ = writeln!(out, "export function create{}Client(call: {}Call): {}Client {{", ident, ident, ident);
+  let _ = writeln!(out, "  return {{");
+  for (method, (_, results)) in &methods {
+    let _ = writeln!(out, "    {}: (params) => call({}, params) as Promise<{}>,",
+      property(method), js_string(&format!("{}.{}", callsign, method)), union(results));
+  }
+  for (old, method) in &aliases {
+    let _ = writeln!(out, "    {}: (params) => call({}, params) as Promise<{}>,",
+      property(old), js_string(&format!("{}.{}", callsign, method)), union(&methods[method].1));
+  }
+  let _ = writeln!(out, "  }};");
+  let _ = writeln!(out, "}}");
+
+  // Clients subscribe through register and get each event as a
+  // notification carrying its params
+  let _ = writeln!(out);
+  let _ = writeln!(out, "export interface {}Events {{", ident);
+  for (event, doc) in events.declared() {
+    if !doc.summary.is_empty() {
+      let _ = writeln!(out, "  /** {} */", doc.summary.replace("*/", "*\\/"));
+    }
+    let _ = writeln!(out, "  {}: {};", property(&event), ts_schema_type(&doc.params));
+  }
+  let _ = writeln!(out, "}}");
+  let _ = writeln!(out);
+  let _ = writeln!(out, "export type {}EventName = keyof {}Events;", ident, ident);
+  let _ = writeln!(out, "export type {}EventHandler<E extends {}EventName> = (params: {}Events[E]) => void;",
+    ident, ident, ident);
+  out
+}
+
+/// Describes the plugin in the JSON format of Thunder's interface
+/// definitions (ThunderInterfaces), for the documentation and tooling that
+/// consume them.
+///
+/// Params come from the schema attached with Route::schema or, failing
+/// that, are inferred from the route's examples, as are results. Deprecated
+/// aliases show up as the "alt" name of the method they point to. Versioned
+/// methods are described at the version clients reach without naming one.
+pub fn interface(callsign: &str, router: &Router, events: &Events) -> Value {
+  let aliases = router.aliases();
+  let mut methods = Map::new();
+  let mut properties = Map::new();
+
+  for (name, route) in router.unversioned_routes() {
+    // Thunder defines these for every plugin with events
+    if name == "register" || name == "unregister" {
+      continue;
+    }
+    let mut doc = Map::new();
+    doc.insert(String::from("summary"), json!(route.summary_text().unwrap_or_default()));
+    let examples = route.examples_list();
+    let params = route.params_schema().cloned()
+      .or_else(|| examples.first().filter(|e| !e.params.is_null()).map(|e| schema_of(&e.params)));
+    if let Some(params) = params {
+      doc.insert(String::from("params"), params);
+    }
+    if let Some(result) = examples.iter().find_map(|e| e.response.as_ref().ok()) {
+      doc.insert(String::from("result"), schema_of(result));
+    }
+    if let Some((old, _)) = aliases.iter().find(|(_, method)| *method == name) {
+      doc.insert(String::from("alt"), json!(old));
+      doc.insert(String::from("altisdeprecated"), json!(true));
+    }
+    match route.property_access() {
+      Some(writable) => {
+        if !writable {
+          doc.insert(String::from("readonly"), json!(true));
+        }
+        properties.insert(name, Value::Object(doc));
+      }
+      None => {
+        methods.insert(name, Value::Object(doc));
+      }
+    }
+  }
+
+  let events: Map<String, Value> = events.declared().into_iter()
+    .map(|(event, doc)| (event, json!({ "summary": doc.summary, "params": doc.params })))
+    .collect();
+
+  json!({
+    "$schema": "interface.schema.json",
+    "jsonrpc": "2.0",
+    "info": {
+      "title": format!("{} API", callsign),
+      "class": callsign,
+      "description": format!("{} JSON-RPC interface", callsign)
+    },
+    "methods": methods,
+    "properties": properties,
+    "events": events
+  })
+}
+
+/// Deserializes params for a generated binding, answering INVALID_PARAMS
+/// when they don't fit.
+pub fn from_params<T: DeserializeOwned>(params: Value) -> Result<T, jsonrpc::Error> {
+  serde_json::from_value(params).map_err(|e| jsonrpc::Error::invalid_params(&e.to_string()))
+}
+
+/// Serializes a generated binding's result.
+pub fn to_result<T: Serialize>(result: Result<T, jsonrpc::Error>) -> Result<Value, jsonrpc::Error> {
+  let value = result?;
+  serde_json::to_value(value).map_err(|e| jsonrpc::Error::new(jsonrpc::INTERNAL_ERROR, &e.to_string()))
+}
+
+/// A JSON Schema that `value` satisfies, with `value` as its example.
+fn schema_of(value: &Value) -> Value {
+  let mut schema = match value {
+    Value::Null => json!({ "type": "null" }),
+    Value::Bool(_) => json!({ "type": "boolean" }),
+    Value::Number(n) if n.is_f64() => json!({ "type": "number" }),
+    Value::Number(_) => json!({ "type": "integer" }),
+    Value::String(_) => json!({ "type": "string" }),
+    Value::Array(items) => match items.first() {
+      Some(item) => json!({ "type": "array", "items": schema_of(item) }),
+      None => json!({ "type": "array" })
+    },
+    Value::Object(fields) => {
+      let properties: Map<String, Value> = fields.iter()
+        .map(|(k, v)| (k.clone(), schema_of(v)))
+        .collect();
+      let required: Vec<&String> = fields.keys().collect();
+      json!({ "type": "object", "properties": properties, "required": required })
+    }
+  };
+  if !value.is_object() && !value.is_array() {
+    schema["example"] = value.clone();
+  }
+  schema
+}
+
+fn push_unique(types: &mut Vec<String>, t: String) {
+  if !types.contains(&t) {
+    types.push(t);
+  }
+}
+
+fn union(types: &[String]) -> String {
+  if types.is_empty() {
+    String::from("unknown")
+  } else {
+    types.join(" | ")
+  }
+}
+
+fn ts_type(value: &Value) -> String {
+  match value {
+    Value::Null => String::from("null"),
+    Value::Bool(_) => String::from("boolean"),
+    Value::Number(_) => String::from("number"),
+    Value::String(_) => String::from("string"),
+    Value::Array(items) => {
+      let mut types = Vec::new();
+      for item in items {
+        push_unique(&mut types, ts_type(item));
+      }
+      match types.len() {
+        0 => String::from("unknown[]"),
+        1 => format!("{}[]", types[0]),
+        _ => format!("({})[]", types.join(" | "))
+      }
+    }
+    Value::Object(fields) => {
+      let fields: Vec<String> = fields.iter()
+        .map(|(k, v)| format!("{}: {}", property(k), ts_type(v)))
+        .collect();
+      format!("{{ {} }}", fields.join("; "))
+    }
+  }
+}
+
+/// The TypeScript type of values matching the JSON Schema `schema`.
+fn ts_schema_type(schema: &Value) -> String {
+  if let Some(values) = schema["enum"].as_array() {
+    let types: Vec<String> = values.iter().map(|v| v.to_string()).collect();
+    return union(&types);
+  }
+  match schema["type"].as_str() {
+    Some("null") => String::from("null"),
+    Some("boolean") => String::from("boolean"),
+    Some("integer") | Some("number") => String::from("number"),
+    Some("string") => String::from("string"),
+    Some("array") => match schema.get("items") {
+      Some(items) => format!("({})[]", ts_schema_type(items)),
+      None => String::from("unknown[]")
+    },
+    Some("object") => match schema["properties"].as_object() {
+      Some(properties) => {
+        let required: Vec<&str> = schema["required"].as_array()
+          .map(|r| r.iter().filter_map(Value::as_str).collect())
+          .unwrap_or_default();
+        let fields: Vec<String> = properties.iter()
+          .map(|(k, v)| format!("{}{}: {}", property(k),
+            if required.contains(&k.as_str()) { "" } else { "?" }, ts_schema_type(v)))
+          .collect();
+        format!("{{ {} }}", fields.join("; "))
+      }
+      None => String::from("Record<string, unknown>")
+    },
+    _ => String::from("unknown")
+  }
+}
+
+/// `text` as a JavaScript string literal.
+fn js_string(text: &str) -> String {
+  // JSON doesn't escape the two line separators JavaScript ends lines on
+  serde_json::to_string(text).unwrap()
+    .replace('\u{2028}', "\\u2028")
+    .replace('\u{2029}', "\\u2029")
+}
+
+fn property(name: &str) -> String {
+  let plain = name.chars().next().is_some_and(|c| !c.is_ascii_digit())
+    && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '$');
+  if plain {
+    name.to_string()
+  } else {
+    js_string(name)
+  }
+}
+
+fn identifier(callsign: &str) -> String {
+  let ident: String = callsign.chars()
+    .filter(|c| c.is_ascii_alphanumeric() || *c == '_')
+    .collect();
+  match ident.chars().next() {
+    Some(c) if c.is_ascii_digit() => format!("_{}", ident),
+    Some(_) => ident,
+    None => String::from("Plugin")
+  }
+}
@@ -0,0 +1,447 @@
+/*
+ * Copyright 2022 Comcast Cable Communications Management, LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+//! Rust bindings generated from a Thunder interface definition, the JSON
+//! specs kept in ThunderInterfaces, so a plugin can implement an interface
+//! that already exists with its params, results and events typed.
+//!
+//! The generated code derives serde's Serialize and Deserialize, so the
+//! plugin needs serde (with "derive") and serde_json as dependencies.
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+use std::path::Path;
+
+use serde_json::{Map, Value};
+
+/// Generates the bindings for `spec` and writes them to `output`, for use
+/// from a build script:
+///
+///   thunder_rs::codegen::rust_from_spec_file("interfaces/Calc.json",
+///     Path::new(&std::env::var("OUT_DIR").unwrap()).join("calc.rs")).unwrap();
+///
+/// and in the plugin `include!(concat!(env!("OUT_DIR"), "/calc.rs"));`.
+pub fn rust_from_spec_file(spec: impl AsRef<Path>, output: impl AsRef<Path>) -> Result<(), String> {
+  let spec = spec.as_ref();
+  println!("cargo:rerun-if-changed={}", spec.display());
+  let text = std::fs::read_to_string(spec)
+    .map_err(|e| format!("can't read {}: {}", spec.display(), e))?;
+  let spec_json: Value = serde_json::from_str(&text)
+    .map_err(|e| format!("{} is not valid JSON: {}", spec.display(), e))?;
+  let code = rust_from_spec(&spec_json)?;
+  std::fs::write(output.as_ref(), code)
+    .map_err(|e| format!("can't write {}: {}", output.as_ref().display(), e))
+}
+
+/// Generates Rust bindings for a Thunder interface definition:
+///
+/// - a struct for each method's params and result and each event's params,
+///   and for the definitions they refer to
+/// - a trait named after the interface's class, with a function per method
+///   and a getter (and setter, unless read-only) per property
+/// - `register(router, plugin)` routing every method and property to the
+///   trait, with params deserialized and results serialized
+/// - an `emit_<event>` function per event taking its typed params, failing
+///   if they can't be converted to JSON
+///
+/// References outside the spec's own "definitions" become serde_json::Value,
+/// and a field holding the struct it's in, directly or not, is boxed.
+pub fn rust_from_spec(spec: &Value) -> Result<String, String> {
+  let spec = spec.as_object().ok_or_else(|| String::from("spec must be an object"))?;
+  let class = spec.get("info").and_then(|i| i["class"].as_str())
+    .ok_or_else(|| String::from("spec has no info.class"))?;
+  let mut gen = Generator {
+    definitions: spec.get("definitions").and_then(Value::as_object).cloned().unwrap_or_default(),
+    items: String::new(),
+    named: BTreeSet::new(),
+    resolved: BTreeMap::new(),
+    building: BTreeSet::new()
+  };
+
+  let mut functions = Vec::new();
+  for (name, method) in entries(spec, "methods") {
+    let params = match method.get("params") {
+      Some(schema) => Some(gen.rust_type(schema, &format!("{}Params", camel(name)))?),
+      None => None
+    };
+    let result = gen.result_type(method.get("result"), &format!("{}Result", camel(name)))?;
+    functions.push(Function::Method {
+      name: name.clone(),
+      summary: summary(method),
+      params,
+      result,
+      alt: method["alt"].as_str().map(String::from)
+    });
+  }
+  for (name, property) in entries(spec, "properties") {
+    let value = match property.get("params") {
+      Some(schema) => gen.rust_type(schema, &camel(name))?,
+      None => String::from("serde_json::Value")
+    };
+    functions.push(Function::Property {
+      name: name.clone(),
+      summary: summary(property),
+      value,
+      readonly: property["readonly"].as_bool().unwrap_or(false),
+      indexed: property.get("index").is_some()
+    });
+  }
+  let mut events = Vec::new();
+  for (name, event) in entries(spec, "events") {
+    let params = match event.get("params") {
+      Some(schema) => gen.rust_type(schema, &format!("{}Params", camel(name)))?,
+      None => String::from("()")
+    };
+    events.push((name.clone(), summary(event), params));
+  }
+
+  let mut out = String::new();
+  let _ = writeln!(out, "// Generated by thunder_rs::codegen from the {} interface. Do not edit.", class);
+  let _ = writeln!(out);
+  out.push_str(&gen.items);
+
+  let trait_name = camel(class);
+  let _ = writeln!(out, "pub trait {}: Send + Sync {{", trait_name);
+  for f in &functions {
+    f.write_signature(&mut out);
+  }
+  let _ = writeln!(out, "}}");
+  let _ = writeln!(out);
+
+  let _ = writeln!(out, "/// Routes the {} interface to `plugin`.", class);
+  let _ = writeln!(out, "pub fn register(router: &mut thunder_rs::router::Router, plugin: std::sync::Arc<dyn {}>) {{", trait_name);
+  for f in &functions {
+    f.write_route(&mut out);
+  }
+  let _ = writeln!(out, "}}");
+
+  for (name, summary, params) in &events {
+    let _ = writeln!(out);
+    if let Some(summary) = summary {
+      let _ = writeln!(out, "/// {}", summary);
+    }
+    let _ = writeln!(out, "pub fn emit_{}(events: &thunder_rs::events::Events, params: &{}) -> Result<usize, serde_json::Error> {{",
+      snake(name), params);
+    let _ = writeln!(out, "  Ok(events.emit({:?}, serde_json::to_value(params)?))", name);
+    let _ = writeln!(out, "}}");
+  }
+  Ok(out)
+}
+
+enum Function {
+  Method {
+    name: String,
+    summary: Option<String>,
+    params: Option<String>,
+    result: String,
+    alt: Option<String>
+  },
+  Property {
+    name: String,
+    summary: Option<String>,
+    value: String,
+    readonly: bool,
+    indexed: bool
+  }
+}
+
+const CTX: &str = "ctx: &thunder_rs::RequestContext";
+const ERROR: &str = "thunder_rs::jsonrpc::Error";
+
+impl Function {
+  fn write_signature(&self, out: &mut String) {
+    match self {
+      Function::Method { name, summary, params, result, .. } => {
+        write_doc(out, summary);
+        let params = params.as_ref().map(|p| format!("params: {}, ", p)).unwrap_or_default();
+        let _ = writeln!(out, "  fn {}(&self, {}{}) -> Result<{}, {}>;", ident(&snake(name)), params, CTX, result, ERROR);
+      }
+      Function::Property { name, summary, value, readonly, indexed } => {
+        let index = if *indexed { "index: Option<&str>, " } else { "" };
+        write_doc(out, summary);
+        let _ = writeln!(out, "  fn {}(&self, {}{}) -> Result<{}, {}>;", ident(&snake(name)), index, CTX, value, ERROR);
+        if !readonly {
+          let _ = writeln!(out, "  fn set_{}(&self, {}value: {}, {}) -> Result<(), {}>;", snake(name), index, value, CTX, ERROR);
+        }
+      }
+    }
+  }
+
+  fn write_route(&self, out: &mut String) {
+    let _ = writeln!(out, "  let p = plugin.clone();");
+    match self {
+      Function::Method { name, params, alt, .. } => {
+        let call = match params {
+          Some(_) => format!("p.{}(thunder_rs::codegen::from_params(params)?, ctx)", ident(&snake(name))),
+          None => format!("p.{}(ctx)", ident(&snake(name)))
+        };
+        let _ = writeln!(out, "  router.register({:?}, move |{}params, ctx| thunder_rs::codegen::to_result({}));", name,
+          if params.is_some() { "" } else { "_" }, call);
+        if let Some(alt) = alt {
+          let _ = writeln!(out, "  router.alias({:?}, {:?});", alt, name);
+        }
+      }
+      Function::Property { name, readonly, indexed, .. } => {
+        let (index, arg) = if *indexed { ("index", "index, ") } else { ("_index", "") };
+        let get = format!("move |{}, ctx| thunder_rs::codegen::to_result(p.{}({}ctx))", index, ident(&snake(name)), arg);
+        if *readonly {
+          let _ = writeln!(out, "  router.property_readonly({:?}, {});", name, get);
+        } else {
+          let _ = writeln!(out, "  let s = plugin.clone();");
+          let _ = writeln!(out, "  router.property({:?}, {},", name, get);
+          let _ = writeln!(out, "    move |{}, value, ctx| s.set_{}({}thunder_rs::codegen::from_params(value)?, ctx));", index, snake(name), arg);
+        }
+      }
+    }
+  }
+}
+
+fn write_doc(out: &mut String, summary: &Option<String>) {
+  if let Some(summary) = summary {
+    let _ = writeln!(out, "  /// {}", summary);
+  }
+}
+
+struct Generator {
+  definitions: Map<String, Value>,
+  items: String,
+  named: BTreeSet<String>,
+  resolved: BTreeMap<String, String>,
+  // Structs whose fields are being generated
+  building: BTreeSet<String>
+}
+
+impl Generator {
+  fn result_type(&mut self, schema: Option<&Value>, hint: &str) -> Result<String, String> {
+    match schema {
+      None => Ok(String::from("()")),
+      Some(s) if s["type"] == "null" => Ok(String::from("()")),
+      Some(s) if s["$ref"].as_str().is_some_and(|r| r.ends_with("/void")) => Ok(String::from("()")),
+      Some(s) => self.rust_type(s, hint)
+    }
+  }
+
+  /// The Rust type for `schema`, emitting structs and enums for it as
+  /// needed. `hint` names the type if one has to be made.
+  fn rust_type(&mut self, schema: &Value, hint: &str) -> Result<String, String> {
+    if let Some(reference) = schema["$ref"].as_str() {
+      return self.reference(reference);
+    }
+    let t = match schema["type"].as_str() {
+      Some("boolean") => String::from("bool"),
+      Some("integer") => integer(schema),
+      Some("number") => String::from("f64"),
+      Some("string") => match schema["enum"].as_array() {
+        Some(values) => self.string_enum(hint, values)?,
+        None => String::from("String")
+      },
+      Some("array") => {
+        let item = match schema.get("items") {
+          Some(items) => self.rust_type(items, &singular(hint))?,
+          None => String::from("serde_json::Value")
+        };
+        format!("Vec<{}>", item)
+      }
+      Some("object") if schema.get("properties").is_some() => self.object(hint, schema)?,
+      Some("null") => String::from("()"),
+      _ => String::from("serde_json::Value")
+    };
+    Ok(t)
+  }
+
+  fn reference(&mut self, reference: &str) -> Result<String, String> {
+    let name = match reference.strip_prefix("#/definitions/") {
+      Some(name) => name,
+      None => return Ok(String::from("serde_json::Value"))
+    };
+    if let Some(t) = self.resolved.get(name) {
+      return Ok(t.clone());
+    }
+    let schema = self.definitions.get(name).cloned()
+      .ok_or_else(|| format!("{} refers to a missing definition", reference))?;
+    // Recorded before generating so recursive definitions terminate
+    let hint = camel(name);
+    if schema["type"] == "object" && schema.get("properties").is_some() {
+      let struct_name = self.claim(&hint);
+      self.resolved.insert(name.to_string(), struct_name.clone());
+      return self.object_named(struct_name, &schema);
+    }
+    self.resolved.insert(name.to_string(), String::from("serde_json::Value"));
+    let t = self.rust_type(&schema, &hint)?;
+    self.resolved.insert(name.to_string(), t.clone());
+    Ok(t)
+  }
+
+  fn object(&mut self, hint: &str, schema: &Value) -> Result<String, String> {
+    let name = self.claim(hint);
+    self.object_named(name, schema)
+  }
+
+  fn object_named(&mut self, name: String, schema: &Value) -> Result<String, String> {
+    self.building.insert(name.clone());
+    let required: Vec<&str> = schema["required"].as_array()
+      .map(|r| r.iter().filter_map(Value::as_str).collect())
+      .unwrap_or_default();
+    let mut fields = String::new();
+    if let Some(properties) = schema["properties"].as_object() {
+      for (field, field_schema) in properties {
+        let t = self.rust_type(field_schema, &format!("{}{}", name, camel(field)))?;
+        // A struct still being built is one this field is inside of, which
+        // would make it infinitely big
+        let t = if self.building.contains(&t) { format!("Box<{}>", t) } else { t };
+        if let Some(description) = field_schema["description"].as_str() {
+          let _ = writeln!(fields, "  /// {}", description);
+        }
+        if snake(field) != *field {
+          let _ = writeln!(fields, "  #[serde(rename = {:?})]", field);
+        }
+        if required.contains(&field.as_str()) {
+          let _ = writeln!(fields, "  pub {}: {},", ident(&snake(field)), t);
+        } else {
+          let _ = writeln!(fields, "  #[serde(default, skip_serializing_if = \"Option::is_none\")]");
+          let _ = writeln!(fields, "  pub {}: Option<{}>,", ident(&snake(field)), t);
+        }
+      }
+    }
+    self.building.remove(&name);
+    let _ = writeln!(self.items, "#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]");
+    let _ = writeln!(self.items, "pub struct {} {{", name);
+    self.items.push_str(&fields);
+    let _ = writeln!(self.items, "}}");
+    let _ = writeln!(self.items);
+    Ok(name)
+  }
+
+  fn string_enum(&mut self, hint: &str, values: &[Value]) -> Result<String, String> {
+    let name = self.claim(hint);
+    let _ = writeln!(self.items, "#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]");
+    let _ = writeln!(self.items, "pub enum {} {{", name);
+    for value in values {
+      let value = value.as_str()
+        .ok_or_else(|| format!("{} has a non-string enum value {}", name, value))?;
+      let _ = writeln!(self.items, "  #[serde(rename = {:?})]", value);
+      let _ = writeln!(self.items, "  {},", variant(value));
+    }
+    let _ = writeln!(self.items, "}}");
+    let _ = writeln!(self.items);
+    Ok(name)
+  }
+
+  /// `hint`, or `hint` with a number after it if that's taken.
+  fn claim(&mut self, hint: &str) -> String {
+    let mut name = hint.to_string();
+    let mut n = 2;
+    while !self.named.insert(name.clone()) {
+      name = format!("{}{}", hint, n);
+      n += 1;
+    }
+    name
+  }
+}
+
+fn entries<'a>(spec: &'a Map<String, Value>, key: &str) -> Vec<(&'a String, &'a Value)> {
+  spec.get(key).and_then(Value::as_object)
+    .map(|m| m.iter().collect())
+    .unwrap_or_default()
+}
+
+fn summary(entry: &Value) -> Option<String> {
+  entry["summary"].as_str().filter(|s| !s.is_empty()).map(String::from)
+}
+
+fn integer(schema: &Value) -> String {
+  let signed = schema["signed"].as_bool().unwrap_or(true);
+  match schema["size"].as_u64() {
+    Some(size @ (8 | 16 | 32 | 64)) => format!("{}{}", if signed { "i" } else { "u" }, size),
+    _ => String::from("i64")
+  }
+}
+
+fn words(name: &str) -> Vec<String> {
+  let mut words = Vec::new();
+  let mut word = String::new();
+  let mut prev_lower = false;
+  for c in name.chars() {
+    if !c.is_ascii_alphanumeric() {
+      if !word.is_empty() {
+        words.push(std::mem::take(&mut word));
+      }
+      prev_lower = false;
+      continue;
+    }
+    if c.is_ascii_uppercase() && prev_lower {
+      words.push(std::mem::take(&mut word));
+    }
+    prev_lower = c.is_ascii_lowercase() || c.is_ascii_digit();
+    word.push(c);
+  }
+  if !word.is_empty() {
+    words.push(word);
+  }
+  words
+}
+
+fn camel(name: &str) -> String {
+  words(name).iter()
+    .map(|w| {
+      let mut chars = w.chars();
+      match chars.next() {
+        Some(first) => first.to_ascii_uppercase().to_string() + &chars.as_str().to_ascii_lowercase(),
+        None => String::new()
+      }
+    })
+    .collect()
+}
+
+fn snake(name: &str) -> String {
+  let snake = words(name).iter().map(|w| w.to_ascii_lowercase()).collect::<Vec<_>>().join("_");
+  match snake.chars().next() {
+    Some(c) if c.is_ascii_digit() => format!("_{}", snake),
+    Some(_) => snake,
+    None => String::from("_")
+  }
+}
+
+fn variant(value: &str) -> String {
+  match camel(value) {
+    v if v.is_empty() => String::from("Empty"),
+    v if v.starts_with(|c: char| c.is_ascii_digit()) => format!("V{}", v),
+    v => v
+  }
+}
+
+fn singular(hint: &str) -> String {
+  match hint.strip_suffix('s') {
+    Some(s) if !s.is_empty() => s.to_string(),
+    _ => format!("{}Item", hint)
+  }
+}
+
+const KEYWORDS: &[&str] = &["as", "async", "await", "break", "const", "continue", "crate", "dyn",
+  "else", "enum", "extern", "false", "fn", "for", "if", "impl", "in", "let", "loop", "match", "mod",
+  "move", "mut", "pub", "ref", "return", "static", "struct", "trait", "true", "type", "unsafe",
+  "use", "where", "while", "abstract", "become", "box", "do", "final", "macro", "override", "priv",
+  "try", "typeof", "unsized", "virtual", "yield", "gen"];
+
+fn ident(name: &str) -> String {
+  if ["self", "super", "crate"].contains(&name) {
+    format!("{}_", name)
+  } else if KEYWORDS.contains(&name) {
+    format!("r#{}", name)
+  } else {
+    name.to_string()
+  }
+}
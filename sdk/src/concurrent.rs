@@ -0,0 +1,71 @@
+/*
+ * Copyright 2022 Comcast Cable Communications Management, LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+//! An alternative to Plugin for stateless plugins whose handlers take
+//! `&self`, so the SDK can run requests side by side on its workers instead
+//! of one at a time behind the plugin's lock.
+use std::sync::Arc;
+
+use crate::{Plugin, RequestContext};
+
+pub trait ConcurrentPlugin: Send + Sync {
+  /// May be called from several threads at once, for the same channel as
+  /// well as for different ones, so responses can go out in a different
+  /// order than the requests came in.
+  fn on_message(&self, json: String, ctx: RequestContext);
+
+  fn on_client_connect(&self, _channel: u32) { }
+
+  fn on_client_disconnect(&self, _channel: u32) { }
+
+  /// Threads requests are spread over. Defaults to one per CPU.
+  fn workers(&self) -> usize {
+    std::thread::available_parallelism().map_or(1, |n| n.get())
+  }
+}
+
+/// Adapts a ConcurrentPlugin so it can be returned from the create function
+/// given to export_plugin!.
+pub struct Concurrent<P>(Arc<P>);
+
+impl<P: ConcurrentPlugin + 'static> Concurrent<P> {
+  pub fn boxed(plugin: P) -> Box<dyn Plugin> {
+    Box::new(Concurrent(Arc::new(plugin)))
+  }
+}
+
+impl<P: ConcurrentPlugin + 'static> Plugin for Concurrent<P> {
+  fn on_message(&mut self, json: String, ctx: RequestContext) {
+    self.0.on_message(json, ctx);
+  }
+
+  fn on_client_connect(&mut self, channel: u32) {
+    self.0.on_client_connect(channel);
+  }
+
+  fn on_client_disconnect(&mut self, channel: u32) {
+    self.0.on_client_disconnect(channel);
+  }
+
+  fn workers(&self) -> usize {
+    self.0.workers()
+  }
+
+  fn concurrent(&self) -> Option<Arc<dyn ConcurrentPlugin>> {
+    Some(self.0.clone())
+  }
+}
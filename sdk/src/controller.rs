@@ -0,0 +1,116 @@
+/*
+ * Copyright 2022 Comcast Cable Communications Management, LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+use serde_json::{json, Value};
+
+use crate::framework::Framework;
+use crate::jsonrpc;
+
+const CALLSIGN: &str = "Controller";
+
+/// The lifecycle state Thunder reports for a plugin.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PluginState {
+  Unavailable,
+  Deactivated,
+  Deactivation,
+  Activated,
+  Activation,
+  Precondition,
+  Hibernated,
+  Destroyed,
+  Suspended,
+  Resumed,
+  Other(String)
+}
+
+impl PluginState {
+  fn parse(s: &str) -> Self {
+    match s.to_ascii_lowercase().as_str() {
+      "unavailable" => PluginState::Unavailable,
+      "deactivated" => PluginState::Deactivated,
+      "deactivation" => PluginState::Deactivation,
+      "activated" => PluginState::Activated,
+      "activation" => PluginState::Activation,
+      "precondition" => PluginState::Precondition,
+      "hibernated" => PluginState::Hibernated,
+      "destroyed" => PluginState::Destroyed,
+      "suspended" => PluginState::Suspended,
+      "resumed" => PluginState::Resumed,
+      _ => PluginState::Other(s.to_string())
+    }
+  }
+}
+
+/// One entry of Controller's status answer.
+#[derive(Debug, Clone)]
+pub struct PluginStatus {
+  pub callsign: String,
+  pub classname: String,
+  pub state: PluginState,
+  pub autostart: bool
+}
+
+impl PluginStatus {
+  fn from_value(v: &Value) -> Result<Self, jsonrpc::Error> {
+    let field = |name: &str| v[name].as_str().map(String::from).ok_or_else(|| {
+      jsonrpc::Error::new(jsonrpc::INTERNAL_ERROR, &format!("Controller status is missing {}", name))
+    });
+    Ok(PluginStatus {
+      callsign: field("callsign")?,
+      classname: v["classname"].as_str().unwrap_or_default().to_string(),
+      state: PluginState::parse(&field("state")?),
+      autostart: v["autostart"].as_bool().unwrap_or(false)
+    })
+  }
+}
+
+/// A typed client for Thunder's Controller plugin, for plugins that manage
+/// the lifecycle of their siblings.
+#[derive(Clone)]
+pub struct Controller {
+  framework: Framework
+}
+
+impl Controller {
+  pub fn new(framework: Framework) -> Self {
+    Controller { framework }
+  }
+
+  pub fn activate(&self, callsign: &str) -> Result<(), jsonrpc::Error> {
+    self.framework.call(CALLSIGN, "activate", json!({ "callsign": callsign }))?;
+    Ok(())
+  }
+
+  pub fn deactivate(&self, callsign: &str) -> Result<(), jsonrpc::Error> {
+    self.framework.call(CALLSIGN, "deactivate", json!({ "callsign": callsign }))?;
+    Ok(())
+  }
+
+  pub fn status(&self, callsign: &str) -> Result<PluginStatus, jsonrpc::Error> {
+    let res = self.framework.call(CALLSIGN, &format!("status@{}", callsign), Value::Null)?;
+    // Controller answers with a list even when asked about one callsign
+    let entry = match &res {
+      Value::Array(entries) => entries.first(),
+      other => Some(other)
+    };
+    match entry {
+      Some(v) => PluginStatus::from_value(v),
+      None => Err(jsonrpc::Error::new(jsonrpc::ERROR_UNAVAILABLE, &format!("Unknown callsign {}", callsign)))
+    }
+  }
+}
@@ -0,0 +1,318 @@
+/*
+ * Copyright 2022 Comcast Cable Communications Management, LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+//! Thunder style event subscriptions. Clients subscribe with
+//!
+//!   {"method":"Callsign.1.register","params":{"event":"location","id":"client.events"}}
+//!
+//! and receive each emitted event as a notification named "<id>.<event>".
+//! Events can be restricted to tokens carrying a claim, so privileged events
+//! can live next to public ones in the same plugin.
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex, OnceLock, Weak};
+
+use log::{debug, warn};
+use serde_json::{json, Value};
+
+use crate::router::Router;
+use crate::{jsonrpc, token, Message, RequestContext};
+
+struct Subscriber {
+  id: String,
+  ctx: RequestContext
+}
+
+/// What an event is for and what it carries, for codegen::interface.
+#[derive(Debug, Clone)]
+pub struct EventDoc {
+  pub summary: String,
+  pub params: Value
+}
+
+#[derive(Default)]
+struct Inner {
+  required: HashMap<String, Vec<String>>,
+  subscribers: HashMap<String, Vec<Subscriber>>,
+  declared: BTreeMap<String, EventDoc>
+}
+
+impl Inner {
+  fn allowed(&self, event: &str, ctx: &RequestContext) -> bool {
+    match self.required.get(event) {
+      Some(claims) => claims.iter().all(|c| token::has_claim(&ctx.auth_token, c)),
+      None => true
+    }
+  }
+}
+
+/// Every Events made in this library, so a reset can prune them all.
+fn all() -> &'static Mutex<Vec<Weak<Mutex<Inner>>>> {
+  static ALL: OnceLock<Mutex<Vec<Weak<Mutex<Inner>>>>> = OnceLock::new();
+  ALL.get_or_init(Default::default)
+}
+
+/// Runs prune() on every Events made in this library, returning how many
+/// subscriptions went. Called by the SDK once all clients have been dropped
+/// at once; a host reaches it through ServiceMetadata::prune_events.
+pub fn prune_all() -> usize {
+  let live: Vec<Arc<Mutex<Inner>>> = {
+    let mut all = all().lock().unwrap();
+    all.retain(|inner| inner.strong_count() > 0);
+    all.iter().filter_map(Weak::upgrade).collect()
+  };
+  live.into_iter().map(|inner| Events { inner }.prune()).sum()
+}
+
+/// Tracks event subscriptions and sends events to the subscribed clients.
+#[derive(Clone)]
+pub struct Events {
+  inner: Arc<Mutex<Inner>>
+}
+
+impl Default for Events {
+  fn default() -> Self {
+    let inner = Arc::new(Mutex::new(Inner::default()));
+    all().lock().unwrap().push(Arc::downgrade(&inner));
+    Events { inner }
+  }
+}
+
+impl Events {
+  pub fn new() -> Self {
+    Events::default()
+  }
+
+  /// Only clients whose token carries `claim` may subscribe to `event` or
+  /// receive it. Calling this more than once requires all the claims.
+  pub fn require_claim(&self, event: &str, claim: &str) {
+    self.inner.lock().unwrap().required.entry(event.to_string())
+      .or_default()
+      .push(claim.to_string());
+  }
+
+  /// Documents `event`, with `params` the JSON Schema of what it carries.
+  /// Only used by generated interface descriptions; undeclared events can
+  /// still be emitted.
+  pub fn declare(&self, event: &str, summary: &str, params: Value) {
+    self.inner.lock().unwrap().declared.insert(event.to_string(), EventDoc {
+      summary: summary.to_string(),
+      params
+    });
+  }
+
+  /// Declared events, ordered by name.
+  pub fn declared(&self) -> Vec<(String, EventDoc)> {
+    self.inner.lock().unwrap().declared.iter()
+      .map(|(event, doc)| (event.clone(), doc.clone()))
+      .collect()
+  }
+
+  /// Adds the "register" and "unregister" methods to `router`.
+  pub fn attach(&self, router: &mut Router) {
+    let events = self.clone();
+    router.register("register", move |params, ctx| {
+      let (event, id) = subscription(&params)?;
+      events.subscribe(event, id, ctx)?;
+      Ok(json!(0))
+    });
+    let events = self.clone();
+    router.register("unregister", move |params, ctx| {
+      let (event, id) = subscription(&params)?;
+      events.unsubscribe(event, id, ctx.channel);
+      Ok(json!(0))
+    });
+  }
+
+  pub fn subscribe(&self, event: &str, id: &str, ctx: &RequestContext) -> Result<(), jsonrpc::Error> {
+    let mut inner = self.inner.lock().unwrap();
+    if !inner.allowed(event, ctx) {
+      warn!("channel {} may not subscribe to {}", ctx.channel, event);
+      return Err(jsonrpc::Error::new(jsonrpc::ACCESS_DENIED,
+        &format!("Not allowed to subscribe to {}", event)));
+    }
+    let subscribers = inner.subscribers.entry(event.to_string()).or_default();
+    subscribers.retain(|s| !(s.ctx.channel == ctx.channel && s.id == id));
+    subscribers.push(Subscriber {
+      id: id.to_string(),
+      ctx: ctx.clone()
+    });
+    Ok(())
+  }
+
+  pub fn unsubscribe(&self, event: &str, id: &str, channel: u32) {
+    if let Some(subscribers) = self.inner.lock().unwrap().subscribers.get_mut(event) {
+      subscribers.retain(|s| !(s.ctx.channel == channel && s.id == id));
+    }
+  }
+
+  /// Drops the subscriptions of clients that have disconnected. emit() does
+  /// this as it goes for the event it sends; this clears every event. The
+  /// SDK runs it through prune_all() when all clients are dropped at once.
+  pub fn prune(&self) -> usize {
+    let mut inner = self.inner.lock().unwrap();
+    let mut pruned = 0;
+    for subscribers in inner.subscribers.values_mut() {
+      let before = subscribers.len();
+      subscribers.retain(|s| s.ctx.is_connected());
+      pruned += before - subscribers.len();
+    }
+    inner.subscribers.retain(|_, subscribers| !subscribers.is_empty());
+    pruned
+  }
+
+  /// Sends `event` to every subscriber still connected and permitted to see
+  /// it. Returns how many clients it went to. The subscriptions aren't held
+  /// while sending, so a full queue can't keep others from subscribing.
+  pub fn emit(&self, event: &str, params: Value) -> usize {
+    let targets: Vec<(String, RequestContext)> = {
+      let mut inner = self.inner.lock().unwrap();
+      let Some(subscribers) = inner.subscribers.get_mut(event) else { return 0 };
+      subscribers.retain(|s| s.ctx.is_connected());
+      inner.subscribers[event].iter()
+        .filter(|s| {
+          let allowed = inner.allowed(event, &s.ctx);
+          if !allowed {
+            debug!("not sending {} to channel {}", event, s.ctx.channel);
+          }
+          allowed
+        })
+        .map(|s| (s.id.clone(), s.ctx.clone()))
+        .collect()
+    };
+
+    let mut sent = 0;
+    for (id, ctx) in targets {
+      let msg = jsonrpc::notification(&format!("{}.{}", id, event), &params);
+      match ctx.send_message(Message::new(ctx.channel, msg), true) {
+        Ok(()) => sent += 1,
+        Err(e) => warn!("failed to send {} to channel {}: {}", event, ctx.channel, e)
+      }
+    }
+    sent
+  }
+}
+
+fn subscription(params: &Value) -> Result<(&str, &str), jsonrpc::Error> {
+  let event = params["event"].as_str()
+    .ok_or_else(|| jsonrpc::Error::invalid_params("Missing event"))?;
+  let id = params["id"].as_str()
+    .ok_or_else(|| jsonrpc::Error::invalid_params("Missing id"))?;
+  Ok((event, id))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+  use base64::Engine;
+  use std::time::Duration;
+
+  use crate::responder::{self, OverflowPolicy, QueueLimit};
+  use crate::Channels;
+
+  #[test]
+  fn emit_doesnt_hold_the_subscriptions_while_sending() {
+    let mut channels = Channels::new();
+    let (tx, rx) = responder::queue(QueueLimit { capacity: 1, policy: OverflowPolicy::Block });
+    channels.connect(1);
+    let events = Events::new();
+    events.subscribe("changed", "client", &channels.context(1, String::new(), tx.clone())).unwrap();
+
+    // The first event fills the queue, so the second blocks in emit
+    let emitter = {
+      let events = events.clone();
+      std::thread::spawn(move || {
+        events.emit("changed", json!(1));
+        events.emit("changed", json!(2))
+      })
+    };
+    std::thread::sleep(Duration::from_millis(50));
+    let (done_tx, done_rx) = std::sync::mpsc::channel();
+    {
+      let events = events.clone();
+      let ctx = channels.context(2, String::new(), tx);
+      std::thread::spawn(move || done_tx.send(events.subscribe("changed", "other", &ctx)).unwrap());
+    }
+    assert!(done_rx.recv_timeout(Duration::from_secs(5)).unwrap().is_ok());
+
+    assert!(rx.recv_timeout(Duration::from_secs(5)).is_some());
+    assert!(emitter.join().unwrap() >= 1);
+  }
+
+  #[test]
+  fn claims_are_required_to_subscribe() {
+    let mut channels = Channels::new();
+    let (tx, _rx) = responder::queue(QueueLimit::default());
+    let events = Events::new();
+    events.require_claim("secret", "admin");
+    let err = events.subscribe("secret", "client", &channels.context(1, String::new(), tx.clone())).unwrap_err();
+    assert_eq!(err.code, jsonrpc::ACCESS_DENIED);
+
+    let admin = format!("header.{}.signature", URL_SAFE_NO_PAD.encode(r#"{"admin":true}"#));
+    events.subscribe("secret", "client", &channels.context(2, admin, tx)).unwrap();
+    assert_eq!(events.emit("secret", json!(null)), 1);
+  }
+
+  #[test]
+  fn events_go_only_to_connected_subscribers() {
+    let mut channels = Channels::new();
+    let (tx, rx) = responder::queue(QueueLimit::default());
+    let events = Events::new();
+    for channel in 1..=3 {
+      channels.connect(channel);
+      events.subscribe("changed", "client", &channels.context(channel, String::new(), tx.clone())).unwrap();
+    }
+    events.unsubscribe("changed", "client", 1);
+    channels.disconnect(2);
+    assert_eq!(events.emit("changed", json!(1)), 1);
+    let sent = rx.try_recv().unwrap();
+    assert_eq!(sent.channel, 3);
+    assert!(rx.try_recv().is_none());
+  }
+
+  #[test]
+  fn disconnected_subscribers_are_pruned() {
+    let mut channels = Channels::new();
+    let (tx, _rx) = responder::queue(QueueLimit::default());
+    let events = Events::new();
+    for channel in 1..=3 {
+      channels.connect(channel);
+      events.subscribe("changed", "client", &channels.context(channel, String::new(), tx.clone())).unwrap();
+    }
+    channels.disconnect(2);
+    assert_eq!(events.prune(), 1);
+    assert_eq!(events.emit("changed", json!(null)), 2);
+    channels.disconnect_all();
+    assert_eq!(events.prune(), 2);
+    assert_eq!(events.emit("changed", json!(null)), 0);
+  }
+
+  #[test]
+  fn prune_all_reaches_every_events() {
+    let mut channels = Channels::new();
+    let (tx, _rx) = responder::queue(QueueLimit::default());
+    let (first, second) = (Events::new(), Events::new());
+    for (channel, events) in [(1, &first), (2, &second)] {
+      channels.connect(channel);
+      events.subscribe("changed", "client", &channels.context(channel, String::new(), tx.clone())).unwrap();
+    }
+    channels.disconnect_all();
+    // Other tests' subscriptions may go too
+    assert!(prune_all() >= 2);
+    assert_eq!(first.prune() + second.prune(), 0);
+  }
+}
@@ -0,0 +1,198 @@
+/*
+ * Copyright 2022 Comcast Cable Communications Management, LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+//! Named on/off switches for dark-launching behaviour. Defaults come from
+//! the "features" object of the plugin's config and can be overridden at
+//! runtime through the methods attach() adds:
+//!
+//!   {"method":"Callsign.1.setFeature","params":{"name":"newParser","enabled":true}}
+//!
+//! Handlers check a flag with ctx.feature("newParser").
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::{Arc, Mutex};
+
+use log::{info, warn};
+use serde_json::{json, Value};
+
+use crate::router::Router;
+use crate::{jsonrpc, token};
+
+type Listener = Arc<dyn Fn(&str, bool) + Send + Sync>;
+
+#[derive(Default)]
+struct Inner {
+  defaults: HashMap<String, bool>,
+  overrides: HashMap<String, bool>,
+  listeners: Vec<Listener>,
+  claim: Option<String>
+}
+
+impl Inner {
+  fn is_enabled(&self, name: &str) -> bool {
+    self.overrides.get(name).or_else(|| self.defaults.get(name)).copied().unwrap_or(false)
+  }
+}
+
+#[derive(Clone, Default)]
+pub struct Features {
+  inner: Arc<Mutex<Inner>>
+}
+
+impl Features {
+  pub fn new() -> Self {
+    Features::default()
+  }
+
+  /// Takes the defaults from `config["features"]`, an object of booleans.
+  pub fn from_config(config: &Value) -> Self {
+    let features = Features::new();
+    features.inner.lock().unwrap().defaults = defaults(config);
+    features
+  }
+
+  /// Replaces the defaults with those in updated config. Overrides stay, and
+  /// listeners hear about every feature that flips.
+  pub fn load_config(&self, config: &Value) {
+    let mut inner = self.inner.lock().unwrap();
+    let defaults = defaults(config);
+    let names: BTreeSet<String> = inner.defaults.keys().chain(defaults.keys()).cloned().collect();
+    let before: Vec<bool> = names.iter().map(|n| inner.is_enabled(n)).collect();
+    inner.defaults = defaults;
+    let changed: Vec<(String, bool)> = names.into_iter().zip(before)
+      .filter(|(name, before)| inner.is_enabled(name) != *before)
+      .map(|(name, before)| (name, !before))
+      .collect();
+    let listeners = inner.listeners.clone();
+    drop(inner);
+    for (name, enabled) in changed {
+      info!("feature {} is now {}", name, if enabled { "on" } else { "off" });
+      for listener in &listeners {
+        listener(&name, enabled);
+      }
+    }
+  }
+
+  /// Unknown features are off.
+  pub fn is_enabled(&self, name: &str) -> bool {
+    self.inner.lock().unwrap().is_enabled(name)
+  }
+
+  /// Overrides the default for `name` until reset.
+  pub fn set(&self, name: &str, enabled: bool) {
+    self.update(name, |inner| { inner.overrides.insert(name.to_string(), enabled); });
+  }
+
+  /// Drops the override for `name`, going back to its default.
+  pub fn reset(&self, name: &str) {
+    self.update(name, |inner| { inner.overrides.remove(name); });
+  }
+
+  /// Every known feature and whether it's on.
+  pub fn list(&self) -> BTreeMap<String, bool> {
+    let inner = self.inner.lock().unwrap();
+    inner.defaults.keys().chain(inner.overrides.keys())
+      .map(|name| (name.clone(), inner.is_enabled(name)))
+      .collect()
+  }
+
+  /// Calls `f` with the name and new state whenever a feature changes.
+  pub fn on_change<F>(&self, f: F)
+    where F: Fn(&str, bool) + Send + Sync + 'static
+  {
+    self.inner.lock().unwrap().listeners.push(Arc::new(f));
+  }
+
+  /// Drops every listener, before the library that registered them is
+  /// unloaded.
+  pub fn clear_listeners(&self) {
+    self.inner.lock().unwrap().listeners.clear();
+  }
+
+  /// Only tokens carrying `claim` may change features through the methods
+  /// attach() adds.
+  pub fn require_claim(&self, claim: &str) {
+    self.inner.lock().unwrap().claim = Some(claim.to_string());
+  }
+
+  /// Adds the "features", "setFeature" and "resetFeature" methods to `router`.
+  pub fn attach(&self, router: &mut Router) {
+    let features = self.clone();
+    router.register("features", move |_params, _ctx| {
+      Ok(json!(features.list()))
+    });
+    let features = self.clone();
+    router.register("setFeature", move |params, ctx| {
+      features.check_claim(&ctx.auth_token)?;
+      let name = feature_name(&params)?;
+      let enabled = params["enabled"].as_bool()
+        .ok_or_else(|| jsonrpc::Error::invalid_params("Missing enabled"))?;
+      features.set(name, enabled);
+      Ok(json!(0))
+    });
+    let features = self.clone();
+    router.register("resetFeature", move |params, ctx| {
+      features.check_claim(&ctx.auth_token)?;
+      features.reset(feature_name(&params)?);
+      Ok(json!(0))
+    });
+  }
+
+  fn check_claim(&self, auth_token: &str) -> Result<(), jsonrpc::Error> {
+    match &self.inner.lock().unwrap().claim {
+      Some(claim) if !token::has_claim(auth_token, claim) => {
+        Err(jsonrpc::Error::new(jsonrpc::ACCESS_DENIED, "Not allowed to change features"))
+      }
+      _ => Ok(())
+    }
+  }
+
+  fn update<F>(&self, name: &str, f: F)
+    where F: FnOnce(&mut Inner)
+  {
+    let mut inner = self.inner.lock().unwrap();
+    let before = inner.is_enabled(name);
+    f(&mut inner);
+    let after = inner.is_enabled(name);
+    if before == after {
+      return;
+    }
+    info!("feature {} is now {}", name, if after { "on" } else { "off" });
+    // Listeners are called unlocked so they can look at other features
+    let listeners = inner.listeners.clone();
+    drop(inner);
+    for listener in listeners {
+      listener(name, after);
+    }
+  }
+}
+
+fn defaults(config: &Value) -> HashMap<String, bool> {
+  let mut defaults = HashMap::new();
+  if let Some(map) = config.get("features").and_then(Value::as_object) {
+    for (name, enabled) in map {
+      match enabled.as_bool() {
+        Some(enabled) => { defaults.insert(name.clone(), enabled); }
+        None => warn!("ignoring feature {}, not a boolean", name)
+      }
+    }
+  }
+  defaults
+}
+
+fn feature_name(params: &Value) -> Result<&str, jsonrpc::Error> {
+  params["name"].as_str().ok_or_else(|| jsonrpc::Error::invalid_params("Missing name"))
+}
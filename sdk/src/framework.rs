@@ -0,0 +1,235 @@
+/*
+ * Copyright 2022 Comcast Cable Communications Management, LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use log::{error, warn};
+use serde_json::Value;
+
+use crate::controller::Controller;
+use crate::jsonrpc;
+use crate::subsystem::Subsystem;
+use crate::warning::Warning;
+
+/// Frame ids set aside for vendor extensions to the bridge. Frames in this
+/// range are passed through to Plugin::on_custom_frame and
+/// Framework::send_custom_frame untouched.
+pub const CUSTOM_FRAME_IDS: std::ops::RangeInclusive<u32> = 0x1000_0000..=0x1fff_ffff;
+
+/// How a Framework gets a call out to Thunder. The SDK provides one for the
+/// in-process bridge and the remote host provides one that writes frames.
+pub trait FrameworkLink: Send + Sync {
+  fn send_call(&self, id: u32, callsign: &str, method: &str, params: &Value) -> Result<(), String>;
+
+  /// Tells Thunder that a subsystem this plugin is responsible for changed.
+  fn set_subsystem(&self, subsystem: Subsystem, _active: bool) -> Result<(), String> {
+    Err(format!("can't set {}, not supported by this bridge", subsystem.name()))
+  }
+
+  /// Sends a vendor frame to the bridge.
+  fn send_custom(&self, id: u32, _data: &[u8]) -> Result<(), String> {
+    Err(format!("can't send custom frame {:#x}, not supported by this bridge", id))
+  }
+
+  /// Asks Thunder to deactivate the plugin as failed.
+  fn report_failure(&self, _reason: &str) -> Result<(), String> {
+    Err(String::from("can't report failure, not supported by this bridge"))
+  }
+
+  /// Passes a warning on to Thunder's WarningReporting.
+  fn report_warning(&self, _warning: &Warning, _message: &str) -> Result<(), String> {
+    Err(String::from("can't report warnings, not supported by this bridge"))
+  }
+}
+
+type Completion = mpsc::Sender<Result<Value, jsonrpc::Error>>;
+type Pending = mpsc::Receiver<Result<Value, jsonrpc::Error>>;
+
+struct Inner {
+  link: Option<Box<dyn FrameworkLink>>,
+  next_id: AtomicU32,
+  pending: Mutex<HashMap<u32, Completion>>,
+  subsystems: Mutex<HashMap<Subsystem, bool>>,
+  timeout: Duration
+}
+
+/// A handle for calling JSON-RPC methods on other Thunder plugins, e.g.
+/// DeviceInfo. Available from RequestContext::framework().
+#[derive(Clone)]
+pub struct Framework {
+  inner: Arc<Inner>
+}
+
+impl Default for Framework {
+  fn default() -> Self {
+    Framework::build(None)
+  }
+}
+
+impl Framework {
+  pub fn new(link: impl FrameworkLink + 'static) -> Self {
+    Framework::build(Some(Box::new(link)))
+  }
+
+  /// A framework that isn't connected to Thunder. Every call fails with
+  /// ERROR_UNAVAILABLE.
+  pub fn unavailable() -> Self {
+    Framework::default()
+  }
+
+  fn build(link: Option<Box<dyn FrameworkLink>>) -> Self {
+    Framework {
+      inner: Arc::new(Inner {
+        link,
+        next_id: AtomicU32::new(1),
+        pending: Mutex::new(HashMap::new()),
+        subsystems: Mutex::new(HashMap::new()),
+        timeout: Duration::from_secs(5)
+      })
+    }
+  }
+
+  /// Calls `method` on the plugin registered as `callsign` and waits for the
+  /// result, failing with ERROR_TIMEDOUT if Thunder doesn't answer in time.
+  pub fn call(&self, callsign: &str, method: &str, params: Value) -> Result<Value, jsonrpc::Error> {
+    let (id, rx) = self.start(callsign, method, &params)?;
+    self.wait(id, rx)
+  }
+
+  /// Like call() but doesn't block; `done` runs with the result on a
+  /// separate thread.
+  pub fn call_with<F>(&self, callsign: &str, method: &str, params: Value, done: F)
+    where F: FnOnce(Result<Value, jsonrpc::Error>) + Send + 'static
+  {
+    match self.start(callsign, method, &params) {
+      Ok((id, rx)) => {
+        let framework = self.clone();
+        std::thread::spawn(move || done(framework.wait(id, rx)));
+      }
+      Err(e) => done(Err(e))
+    }
+  }
+
+  fn start(&self, callsign: &str, method: &str, params: &Value)
+    -> Result<(u32, Pending), jsonrpc::Error>
+  {
+    let link = self.inner.link.as_ref()
+      .ok_or_else(|| jsonrpc::Error::new(jsonrpc::ERROR_UNAVAILABLE, "Not connected to Thunder"))?;
+
+    let id = self.inner.next_id.fetch_add(1, Ordering::Relaxed);
+    let (tx, rx) = mpsc::channel();
+    self.inner.pending.lock().unwrap().insert(id, tx);
+
+    if let Err(e) = link.send_call(id, callsign, method, params) {
+      self.inner.pending.lock().unwrap().remove(&id);
+      return Err(jsonrpc::Error::new(jsonrpc::ERROR_UNAVAILABLE, &e));
+    }
+    Ok((id, rx))
+  }
+
+  fn wait(&self, id: u32, rx: Pending) -> Result<Value, jsonrpc::Error> {
+    rx.recv_timeout(self.inner.timeout).unwrap_or_else(|_| {
+      self.inner.pending.lock().unwrap().remove(&id);
+      Err(jsonrpc::Error::new(jsonrpc::ERROR_TIMEDOUT, "Call to Thunder timed out"))
+    })
+  }
+
+  /// Whether Thunder last reported `subsystem` as up. Subsystems Thunder
+  /// hasn't reported yet count as down.
+  pub fn is_active(&self, subsystem: Subsystem) -> bool {
+    self.inner.subsystems.lock().unwrap().get(&subsystem).copied().unwrap_or(false)
+  }
+
+  /// Marks a subsystem this plugin provides as up or down.
+  pub fn set_subsystem(&self, subsystem: Subsystem, active: bool) -> Result<(), String> {
+    let link = self.inner.link.as_ref()
+      .ok_or_else(|| String::from("Not connected to Thunder"))?;
+    link.set_subsystem(subsystem, active)
+  }
+
+  /// Sends a vendor frame to the bridge. `id` must be in CUSTOM_FRAME_IDS.
+  pub fn send_custom_frame(&self, id: u32, data: &[u8]) -> Result<(), String> {
+    if !CUSTOM_FRAME_IDS.contains(&id) {
+      return Err(format!("{:#x} is not a custom frame id", id));
+    }
+    let link = self.inner.link.as_ref()
+      .ok_or_else(|| String::from("Not connected to Thunder"))?;
+    link.send_custom(id, data)
+  }
+
+  /// Tells Thunder the plugin is unhealthy and can't recover by itself.
+  /// Thunder deactivates it with reason FAILURE, and activates it again if
+  /// its restart policy says so. Requests keep arriving until that happens.
+  pub fn report_failure(&self, reason: &str) -> Result<(), String> {
+    let link = self.inner.link.as_ref()
+      .ok_or_else(|| String::from("Not connected to Thunder"))?;
+    error!("reporting failure to Thunder: {}", reason);
+    crate::journal::record(crate::journal::Source::Plugin, "report_failure", None, reason.to_string());
+    link.report_failure(reason)
+  }
+
+  /// Raises a health warning through Thunder's WarningReporting, where it's
+  /// counted along with those from C++ plugins, e.g.
+  ///
+  ///   framework.report_warning(&Warning::TooLongInvoke, "scan took 4200ms")
+  pub fn report_warning(&self, warning: &Warning, message: &str) -> Result<(), String> {
+    let link = self.inner.link.as_ref()
+      .ok_or_else(|| String::from("Not connected to Thunder"))?;
+    warn!("{}: {}", warning.category(), message);
+    crate::journal::record(crate::journal::Source::Plugin, "report_warning", None,
+      format!("{} {}", warning.category(), message));
+    link.report_warning(warning, message)
+  }
+
+  /// Records a subsystem change reported by Thunder. Returns the subsystem
+  /// if `id` is known and the state actually changed.
+  pub fn subsystem_changed(&self, id: u32, active: bool) -> Option<Subsystem> {
+    let subsystem = match Subsystem::from_id(id) {
+      Some(s) => s,
+      None => {
+        warn!("ignoring change of unknown subsystem {}", id);
+        return None;
+      }
+    };
+    let previous = self.inner.subsystems.lock().unwrap().insert(subsystem, active);
+    if previous == Some(active) {
+      None
+    } else {
+      Some(subsystem)
+    }
+  }
+
+  /// A typed client for Thunder's Controller plugin.
+  pub fn controller(&self) -> Controller {
+    Controller::new(self.clone())
+  }
+
+  /// Delivers Thunder's answer to the call with `id`. `response` is the
+  /// JSON-RPC response object.
+  pub fn complete(&self, id: u32, response: &str) {
+    match self.inner.pending.lock().unwrap().remove(&id) {
+      Some(tx) => {
+        let _ = tx.send(jsonrpc::parse_response(response));
+      }
+      None => warn!("result for unknown or expired call {}", id)
+    }
+  }
+}
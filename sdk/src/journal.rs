@@ -0,0 +1,129 @@
+/*
+ * Copyright 2022 Comcast Cable Communications Management, LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+//! A bounded, process-wide journal of what went through the bridge. The SDK
+//! records plugin callbacks and the host records wire frames into the same
+//! ring buffer, so one dump shows how the two interleaved.
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const DEFAULT_CAPACITY: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+  /// A frame read from or written to the host's connection to Thunder
+  Wire,
+  /// A call into or a message out of the plugin
+  Plugin
+}
+
+#[derive(Debug, Clone)]
+pub struct Entry {
+  pub at: SystemTime,
+  pub source: Source,
+  pub event: &'static str,
+  pub channel: Option<u32>,
+  pub detail: String
+}
+
+impl fmt::Display for Entry {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let at = self.at.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let source = match self.source {
+      Source::Wire => "wire",
+      Source::Plugin => "plugin"
+    };
+    write!(f, "{}.{:06} {} {}", at.as_secs(), at.subsec_micros(), source, self.event)?;
+    if let Some(channel) = self.channel {
+      write!(f, " channel={}", channel)?;
+    }
+    if !self.detail.is_empty() {
+      write!(f, " {}", self.detail)?;
+    }
+    Ok(())
+  }
+}
+
+struct Journal {
+  capacity: usize,
+  entries: VecDeque<Entry>
+}
+
+fn journal() -> &'static Mutex<Journal> {
+  static JOURNAL: OnceLock<Mutex<Journal>> = OnceLock::new();
+  JOURNAL.get_or_init(|| Mutex::new(Journal {
+    capacity: DEFAULT_CAPACITY,
+    entries: VecDeque::new()
+  }))
+}
+
+/// Changes how many entries are kept. The oldest are discarded first.
+pub fn set_capacity(capacity: usize) {
+  let mut j = journal().lock().unwrap();
+  j.capacity = capacity;
+  while j.entries.len() > capacity {
+    j.entries.pop_front();
+  }
+}
+
+pub fn record(source: Source, event: &'static str, channel: Option<u32>, detail: String) {
+  let mut j = journal().lock().unwrap();
+  if j.capacity == 0 {
+    return;
+  }
+  if j.entries.len() >= j.capacity {
+    j.entries.pop_front();
+  }
+  j.entries.push_back(Entry {
+    at: SystemTime::now(),
+    source,
+    event,
+    channel,
+    detail
+  });
+}
+
+pub fn snapshot() -> Vec<Entry> {
+  journal().lock().unwrap().entries.iter().cloned().collect()
+}
+
+pub fn dump(w: &mut dyn Write) -> io::Result<()> {
+  for entry in snapshot() {
+    writeln!(w, "{}", entry)?;
+  }
+  Ok(())
+}
+
+pub fn dump_to_file(path: &str) -> io::Result<()> {
+  let mut f = File::create(path)?;
+  dump(&mut f)
+}
+
+/// Dumps to a new journal-<pid>-<unix millis>.log in `dir` and returns its
+/// path. For requests from a peer, which don't get to pick the file.
+pub fn dump_to_dir(dir: &Path) -> io::Result<PathBuf> {
+  let millis = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis()).unwrap_or(0);
+  let path = dir.join(format!("journal-{}-{}.log", std::process::id(), millis));
+  let mut f = File::options().write(true).create_new(true).open(&path)?;
+  dump(&mut f)?;
+  Ok(path)
+}
@@ -0,0 +1,156 @@
+/*
+ * Copyright 2022 Comcast Cable Communications Management, LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+use std::fmt;
+
+use serde_json::{json, Value};
+
+// Error codes reserved by the JSON-RPC 2.0 spec
+pub const PARSE_ERROR: i32 = -32700;
+pub const INVALID_REQUEST: i32 = -32600;
+pub const METHOD_NOT_FOUND: i32 = -32601;
+pub const INVALID_PARAMS: i32 = -32602;
+pub const INTERNAL_ERROR: i32 = -32603;
+
+// Thunder Core::ERROR_* codes, which Thunder reports as JSON-RPC error codes
+pub const ERROR_UNAVAILABLE: i32 = 2;
+pub const ERROR_TIMEDOUT: i32 = 11;
+pub const ERROR_INPROGRESS: i32 = 12;
+
+// Server defined error codes (-32000 to -32099)
+pub const ACCESS_DENIED: i32 = -32004;
+pub const TOO_MANY_REQUESTS: i32 = -32005;
+
+/// The error object of a JSON-RPC response. Build it with new(), or a
+/// shorthand like invalid_params(), and with_data(); it's non_exhaustive so
+/// members the spec allows can be added without breaking plugins.
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub struct Error {
+  pub code: i32,
+  pub message: String,
+  pub data: Option<Value>
+}
+
+impl Error {
+  pub fn new(code: i32, message: &str) -> Self {
+    Error {
+      code,
+      message: message.to_string(),
+      data: None
+    }
+  }
+
+  /// Attaches details for the client, sent as the error's "data" member.
+  pub fn with_data(mut self, data: Value) -> Self {
+    self.data = Some(data);
+    self
+  }
+
+  pub fn invalid_params(message: &str) -> Self {
+    Error::new(INVALID_PARAMS, message)
+  }
+
+  pub fn to_value(&self) -> Value {
+    let mut value = json!({
+      "code": self.code,
+      "message": self.message
+    });
+    if let Some(data) = &self.data {
+      value["data"] = data.clone();
+    }
+    value
+  }
+}
+
+impl fmt::Display for Error {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{} ({})", self.message, self.code)
+  }
+}
+
+impl std::error::Error for Error { }
+
+/// Parses a JSON-RPC message. Built with the "simd-json" feature this uses
+/// simd-json, which parses in place and so works on a copy of `json`.
+pub fn parse(json: &str) -> Result<Value, String> {
+  #[cfg(feature = "simd-json")]
+  {
+    let mut buf = json.as_bytes().to_vec();
+    simd_json::serde::from_slice(&mut buf).map_err(|e| e.to_string())
+  }
+  #[cfg(not(feature = "simd-json"))]
+  serde_json::from_str(json).map_err(|e| e.to_string())
+}
+
+/// Pulls the "id" out of a JSON-RPC request. Returns None if the request
+/// doesn't parse or is a notification.
+pub fn request_id(json: &str) -> Option<Value> {
+  let req = parse(json).ok()?;
+  match req.get("id") {
+    Some(Value::Null) | None => None,
+    Some(id) => Some(id.clone())
+  }
+}
+
+/// Pulls the "method" out of a JSON-RPC request.
+pub fn request_method(json: &str) -> Option<String> {
+  let req = parse(json).ok()?;
+  req.get("method")?.as_str().map(String::from)
+}
+
+/// Turns a JSON-RPC response into the result or error it carries.
+pub fn parse_response(json: &str) -> Result<Value, Error> {
+  let res = parse(json)
+    .map_err(|e| Error::new(PARSE_ERROR, &e))?;
+  match res.get("error") {
+    Some(err) => Err(Error {
+      code: err["code"].as_i64().unwrap_or(INTERNAL_ERROR as i64) as i32,
+      message: err["message"].as_str().unwrap_or_default().to_string(),
+      data: err.get("data").cloned()
+    }),
+    None => Ok(res.get("result").cloned().unwrap_or(Value::Null))
+  }
+}
+
+pub fn notification(method: &str, params: &Value) -> String {
+  json!({
+    "jsonrpc": "2.0",
+    "method": method,
+    "params": params
+  }).to_string()
+}
+
+pub fn error_response(id: &Value, code: i32, message: &str) -> String {
+  response(id, &Err(Error::new(code, message)))
+}
+
+pub fn response(id: &Value, result: &Result<Value, Error>) -> String {
+  let res = match result {
+    Ok(value) => json!({
+      "jsonrpc": "2.0",
+      "id": id,
+      "result": value
+    }),
+    Err(e) => json!({
+      "jsonrpc": "2.0",
+      "id": id,
+      "error": e.to_value()
+    })
+  };
+  res.to_string()
+}
@@ -0,0 +1,118 @@
+/*
+ * Copyright 2022 Comcast Cable Communications Management, LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use log::error;
+
+use crate::jsonrpc;
+
+enum State<T> {
+  Uninit,
+  Pending(Instant),
+  Ready(Arc<T>),
+  Failed(String)
+}
+
+struct Inner<T> {
+  init: Box<dyn Fn() -> Result<T, String> + Send + Sync>,
+  timeout: Duration,
+  state: Mutex<State<T>>
+}
+
+/// A resource that is expensive to set up (a decoder session, a connection
+/// to a hardware daemon) and is only created the first time a method needs
+/// it. Initialization runs once on a background thread; until it finishes
+/// callers get ERROR_INPROGRESS, and if it fails or takes longer than the
+/// timeout they get ERROR_UNAVAILABLE.
+pub struct LazyResource<T> {
+  inner: Arc<Inner<T>>
+}
+
+impl<T> Clone for LazyResource<T> {
+  fn clone(&self) -> Self {
+    LazyResource { inner: self.inner.clone() }
+  }
+}
+
+impl<T: Send + Sync + 'static> LazyResource<T> {
+  pub fn new<F>(timeout: Duration, init: F) -> Self
+    where F: Fn() -> Result<T, String> + Send + Sync + 'static
+  {
+    LazyResource {
+      inner: Arc::new(Inner {
+        init: Box::new(init),
+        timeout,
+        state: Mutex::new(State::Uninit)
+      })
+    }
+  }
+
+  /// Returns the resource if it's ready, starting initialization on first use.
+  pub fn get(&self) -> Result<Arc<T>, jsonrpc::Error> {
+    let mut state = self.inner.state.lock().unwrap();
+    match &*state {
+      State::Ready(t) => Ok(t.clone()),
+      State::Failed(e) => Err(unavailable(e)),
+      State::Pending(started) => {
+        if started.elapsed() > self.inner.timeout {
+          let e = String::from("initialization timed out");
+          *state = State::Failed(e.clone());
+          Err(unavailable(&e))
+        }
+        else {
+          Err(in_progress())
+        }
+      }
+      State::Uninit => {
+        *state = State::Pending(Instant::now());
+        let inner = self.inner.clone();
+        std::thread::spawn(move || {
+          let result = catch_unwind(AssertUnwindSafe(|| (inner.init)()))
+            .unwrap_or_else(|_| Err(String::from("initialization panicked")));
+          let mut state = inner.state.lock().unwrap();
+          *state = match result {
+            Ok(t) => State::Ready(Arc::new(t)),
+            Err(e) => {
+              error!("lazy resource initialization failed: {}", e);
+              State::Failed(e)
+            }
+          };
+        });
+        Err(in_progress())
+      }
+    }
+  }
+
+  /// Forgets a ready or failed resource so the next use initializes it again.
+  pub fn reset(&self) {
+    let mut state = self.inner.state.lock().unwrap();
+    if !matches!(*state, State::Pending(_)) {
+      *state = State::Uninit;
+    }
+  }
+}
+
+fn in_progress() -> jsonrpc::Error {
+  jsonrpc::Error::new(jsonrpc::ERROR_INPROGRESS, "Resource is initializing")
+}
+
+fn unavailable(reason: &str) -> jsonrpc::Error {
+  jsonrpc::Error::new(jsonrpc::ERROR_UNAVAILABLE, &format!("Resource unavailable: {}", reason))
+}
@@ -15,66 +15,678 @@
  *
  * SPDX-License-Identifier: Apache-2.0
  */
+// The wpe_rust_plugin_* entry points take raw pointers owned by the C++ side
+// of the bridge and are never called from Rust.
+#![allow(clippy::not_unsafe_ptr_arg_deref)]
+
+use std::borrow::Cow;
 use std::ffi::CStr;
 use std::ffi::CString;
+use std::fmt;
 use std::os::raw::c_char;
-use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use log::{debug, error, warn};
+
+pub use bytes::Bytes;
+
+#[cfg(feature = "lifetime-audit")]
+pub mod audit;
+pub mod breaker;
+pub mod channel;
+pub mod checkpoint;
+pub mod codec;
+pub mod codegen;
+pub mod concurrent;
+pub mod controller;
+pub mod events;
+pub mod features;
+pub mod framework;
+pub mod journal;
+pub mod jsonrpc;
+pub mod lazy;
+pub mod logging;
+pub mod metrics;
+pub mod paths;
+pub mod progress;
+pub mod rate_limit;
+pub mod registry;
+pub mod responder;
+pub mod response;
+pub mod router;
+pub mod scope;
+pub mod secrets;
+pub mod stream;
+pub mod subsystem;
+pub mod supervisor;
+pub mod telemetry;
+pub mod timers;
+pub mod testing;
+mod token;
+pub mod warning;
+pub mod web;
+pub mod workers;
+
+pub use breaker::{BreakerConfig, CircuitBreaker};
+pub use channel::{ChannelStats, ChannelType, Channels, Peer};
+use channel::ChannelState;
+pub use checkpoint::Checkpoint;
+pub use codec::Codec;
+pub use concurrent::{Concurrent, ConcurrentPlugin};
+pub use controller::Controller;
+pub use events::Events;
+pub use features::Features;
+pub use framework::{Framework, FrameworkLink};
+pub use lazy::LazyResource;
+pub use metrics::Metrics;
+pub use paths::Paths;
+pub use progress::ProgressReporter;
+pub use rate_limit::RateLimit;
+pub use responder::{OverflowPolicy, QueueLimit, Responder};
+pub use response::ResponseBuilder;
+pub use router::Router;
+pub use scope::{PluginScope, StopToken};
+pub use stream::ResponseStream;
+pub use subsystem::Subsystem;
+pub use supervisor::{PanicAction, RestartPolicy, Supervisor};
+pub use timers::{TimerHandle, Timers};
+pub use token::TokenClaims;
+pub use warning::Warning;
+pub use web::{UploadSink, WebHandler, WebRequest, WebResponse};
+use rate_limit::RateLimiter;
+use workers::WorkerPool;
 
 type SendToFunction = unsafe extern "C" fn (u32, *const c_char, u32);
+type SendBinaryFunction = unsafe extern "C" fn (u32, *const u8, u32, u32);
+type CallFunction = unsafe extern "C" fn (u32, u32, *const c_char, *const c_char, *const c_char);
+type SubsystemFunction = unsafe extern "C" fn (u32, u32, u32);
+type CustomFunction = unsafe extern "C" fn (u32, u32, *const u8, u32);
+type FailureFunction = unsafe extern "C" fn (u32, *const c_char);
+type WarningFunction = unsafe extern "C" fn (u32, *const c_char, *const c_char);
+type WebResponseFunction = unsafe extern "C" fn (*mut std::os::raw::c_void, u32, *const c_char, *const u8, u32);
+type WebChunkFunction = unsafe extern "C" fn (*mut std::os::raw::c_void, *const u8, u32);
 
-#[derive(Debug)]
+/// Handed to the plugin's create function. Fields are added as the SDK grows,
+/// each with a default, so code building one should end the literal with
+/// `..Default::default()`. Its layout is part of the ABI, so a new field
+/// also bumps ABI_VERSION.
+#[derive(Clone, Default)]
 pub struct PluginConfig {
-  pub auth_token: String
+  pub auth_token: String,
+  pub scope: PluginScope,
+  /// The plugin's JSON config with its secret references resolved, or Null
+  /// if it has none. See secrets.
+  pub config: serde_json::Value,
+  /// The plugin's feature flags, with defaults from `config`.
+  pub features: Features,
+  /// Where the plugin records metrics for Thunder to collect.
+  pub metrics: Metrics,
+  /// The plugin's persistent, volatile and data directories.
+  pub paths: Paths
+}
+
+impl fmt::Debug for PluginConfig {
+  // The token and config may hold secrets, so only their shape is shown
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let config = match &self.config {
+      serde_json::Value::Object(map) => format!("{{{} keys}}", map.len()),
+      serde_json::Value::Null => String::from("null"),
+      _ => String::from("<value>")
+    };
+    f.debug_struct("PluginConfig")
+      .field("auth_token", &if self.auth_token.is_empty() { "" } else { "<redacted>" })
+      .field("scope", &self.scope)
+      .field("config", &config)
+      .field("paths", &self.paths)
+      .finish()
+  }
 }
 
-pub trait Plugin {
+pub trait Plugin: Send {
   fn on_message(&mut self, json: String, ctx: RequestContext);
-  fn on_client_connect(&mut self, channel: u32);
-  fn on_client_disconnect(&mut self, channel: u32);
+
+  /// Like on_message, but `json` is borrowed for the length of the call
+  /// instead of copied into a String. Called in place of on_message when
+  /// borrow_messages returns true.
+  fn on_message_str(&mut self, json: &str, ctx: RequestContext) {
+    self.on_message(json.to_string(), ctx);
+  }
+
+  /// Opts in to on_message_str, which saves copying every request when the
+  /// plugin runs on the caller's thread: the request is then read straight
+  /// from the bridge's buffer. With workers it's still copied once to hand
+  /// it over.
+  fn borrow_messages(&self) -> bool {
+    false
+  }
+
+  /// Called when a client attaches. Plugins that don't track clients can
+  /// leave this out; channel state is kept by the SDK either way.
+  fn on_client_connect(&mut self, _channel: u32) { }
+
+  fn on_client_disconnect(&mut self, _channel: u32) { }
+
+  /// Called when every client is dropped at once, e.g. because Thunder
+  /// restarted, instead of on_client_disconnect for each. The default calls
+  /// on_client_disconnect for each of `channels`.
+  fn on_all_clients_disconnected(&mut self, channels: &[u32], reason: &str) {
+    debug!("all clients disconnected: {}", reason);
+    for channel in channels {
+      self.on_client_disconnect(*channel);
+    }
+  }
+
+  /// Called once after the plugin is created with the state last saved via
+  /// PluginScope::checkpoint, if a previous instance left one.
+  fn on_init(&mut self, _checkpoint: Option<Checkpoint>) { }
+
+  /// Called for binary WebSocket frames. Plugins that only speak JSON-RPC
+  /// can ignore these.
+  fn on_binary_message(&mut self, data: &[u8], ctx: RequestContext) {
+    debug!("ignoring {} byte binary message on channel {}", data.len(), ctx.channel);
+  }
+
+  /// Called with whatever arrived from a client on a raw socket channel,
+  /// for plugins bridging protocols that aren't JSON-RPC. There's no
+  /// framing, so a message may be split over calls or several may arrive
+  /// in one. Answer with ctx.send_raw.
+  fn on_raw_data(&mut self, data: &[u8], ctx: RequestContext) {
+    debug!("ignoring {} bytes of raw data on channel {}", data.len(), ctx.channel);
+  }
+
+  /// Per-channel request rate enforced by the SDK before on_message is
+  /// called. Requests over the limit are answered with a JSON-RPC error;
+  /// binary frames and raw data count too, and over the limit are dropped
+  /// silently, as there's no request id to answer.
+  fn rate_limit(&self) -> Option<RateLimit> {
+    None
+  }
+
+  /// Bounds the queue of messages waiting to be delivered to Thunder and
+  /// decides what happens when it's full.
+  fn queue_limit(&self) -> QueueLimit {
+    QueueLimit::default()
+  }
+
+  /// Number of worker threads the SDK runs on_message and the other channel
+  /// callbacks on, so the caller's thread isn't held up by a slow handler.
+  /// Callbacks for one channel stay in order. 0 calls the plugin directly
+  /// on the caller's thread.
+  fn workers(&self) -> usize {
+    0
+  }
+
+  /// What the SDK does when the plugin keeps panicking.
+  fn restart_policy(&self) -> RestartPolicy {
+    RestartPolicy::default()
+  }
+
+  /// Memory figures reported to Thunder's Monitor plugin. Plugins that don't
+  /// track their usage return None and Monitor shows nothing for them.
+  fn memory_usage(&self) -> Option<MemoryUsage> {
+    None
+  }
+
+  /// Called when Thunder reports a subsystem going up or down. The current
+  /// state is also available from Framework::is_active.
+  fn on_subsystem_change(&mut self, subsystem: Subsystem, active: bool) {
+    debug!("subsystem {} is now {}", subsystem.name(), if active { "up" } else { "down" });
+  }
+
+  /// Called when Thunder pushes new configuration to the running plugin.
+  /// Feature flag defaults have already been updated from it, and plugins
+  /// restarted by the supervisor from now on are created with it.
+  fn on_config_changed(&mut self, config: &serde_json::Value) {
+    debug!("ignoring config change to {}", config);
+  }
+
+  /// Called once before the plugin is destroyed, e.g. when the host is told
+  /// to stop. Messages sent from here are still delivered.
+  fn on_shutdown(&mut self) { }
+
+  /// Called for vendor frames (ids in framework::CUSTOM_FRAME_IDS) from a
+  /// bridge extension.
+  fn on_custom_frame(&mut self, id: u32, data: &[u8]) {
+    debug!("ignoring custom frame {:#x} of {} bytes", id, data.len());
+  }
+
+  /// The handler to call without holding the plugin's lock. Only
+  /// Concurrent overrides this.
+  fn concurrent(&self) -> Option<Arc<dyn ConcurrentPlugin>> {
+    None
+  }
+
+  /// Serves plain HTTP requests Thunder routes to the plugin. Plugins
+  /// without one answer them with 501.
+  fn web_handler(&self) -> Option<Arc<dyn WebHandler>> {
+    None
+  }
+}
+
+/// Memory use in bytes, as Thunder's IMemory interface reports it.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MemoryUsage {
+  pub resident: u64,
+  pub allocated: u64,
+  pub shared: u64
+}
+
+/// Makes the SDK's logger the `log` backend for the plugin and every
+/// library it uses, so their lines reach Thunder's tracing once the bridge
+/// registers its callback (stdout until then). The SDK does this itself
+/// before creating the plugin; calling it earlier, or again, is harmless.
+/// Does nothing if another logger was installed first.
+pub fn init_logging() {
+  logging::init();
+}
+
+/// Calls `plugin.on_message`, and if the handler panics answers the request
+/// with a JSON-RPC internal error so the client isn't left waiting. The
+/// panic is handed back to the caller to log.
+pub fn invoke_message(plugin: &mut dyn Plugin, json: String, ctx: RequestContext) -> std::thread::Result<()> {
+  guard_message(Cow::Owned(json), ctx, |json, ctx| deliver(plugin, json, ctx))
+}
+
+/// Like invoke_message, for a request the caller keeps.
+pub fn invoke_message_str(plugin: &mut dyn Plugin, json: &str, ctx: RequestContext) -> std::thread::Result<()> {
+  guard_message(Cow::Borrowed(json), ctx, |json, ctx| deliver(plugin, json, ctx))
+}
+
+/// Like invoke_message, for a ConcurrentPlugin's handler called without the
+/// plugin's lock.
+pub fn invoke_concurrent(handler: &dyn ConcurrentPlugin, json: String, ctx: RequestContext) -> std::thread::Result<()> {
+  guard_message(Cow::Owned(json), ctx, |json, ctx| handler.on_message(json.into_owned(), ctx))
+}
+
+/// Like invoke_message, catching a panic with `catch`. A host calling a
+/// plugin library passes the library's ServiceMetadata::catch_panic.
+pub fn invoke_message_in(catch: CatchPanic, plugin: &mut dyn Plugin, json: String, ctx: RequestContext) -> Result<(), String> {
+  guard_in(catch, Cow::Owned(json), ctx, |json, ctx| deliver(plugin, json, ctx))
+}
+
+/// Like invoke_concurrent, catching a panic with `catch`.
+pub fn invoke_concurrent_in(catch: CatchPanic, handler: &dyn ConcurrentPlugin, json: String, ctx: RequestContext) -> Result<(), String> {
+  guard_in(catch, Cow::Owned(json), ctx, |json, ctx| handler.on_message(json.into_owned(), ctx))
+}
+
+/// Hands a request to on_message_str or on_message, whichever the plugin
+/// asked for, copying it only if it has to.
+pub(crate) fn deliver(plugin: &mut dyn Plugin, json: Cow<str>, ctx: RequestContext) {
+  if plugin.borrow_messages() {
+    plugin.on_message_str(&json, ctx);
+  } else {
+    plugin.on_message(json.into_owned(), ctx);
+  }
 }
 
+fn guard_message<'a, F>(json: Cow<'a, str>, ctx: RequestContext, f: F) -> std::thread::Result<()>
+  where F: FnOnce(Cow<'a, str>, RequestContext)
+{
+  let mut payload = None;
+  let catch = |run: &mut dyn FnMut()| {
+    std::panic::catch_unwind(std::panic::AssertUnwindSafe(run)).map_err(|cause| {
+      let msg = panic_message(cause.as_ref()).to_string();
+      payload = Some(cause);
+      msg
+    })
+  };
+  let _ = guard_in(catch, json, ctx, f);
+  payload.map_or(Ok(()), Err)
+}
+
+/// Delivers a request inside `catch`, answering it with a JSON-RPC internal
+/// error if the handler panics.
+fn guard_in<'a, C, F>(catch: C, json: Cow<'a, str>, ctx: RequestContext, f: F) -> Result<(), String>
+  where C: FnOnce(&mut dyn FnMut()) -> Result<(), String>,
+        F: FnOnce(Cow<'a, str>, RequestContext)
+{
+  let id = jsonrpc::request_id(&json);
+  #[cfg(feature = "tracing")]
+  let _span = tracing::info_span!("on_message",
+    channel = ctx.channel,
+    method = jsonrpc::request_method(&json).unwrap_or_default(),
+    id = %id.as_ref().map(serde_json::Value::to_string).unwrap_or_default()
+  ).entered();
+  let reply = ctx.clone();
+  let mut call = Some((f, json, ctx));
+  let result = catch(&mut || {
+    if let Some((f, json, ctx)) = call.take() {
+      f(json, ctx);
+    }
+  });
+  if let (Err(cause), Some(id)) = (&result, id) {
+    let msg = jsonrpc::error_response(&id, jsonrpc::INTERNAL_ERROR,
+      &format!("Internal error: {}", cause));
+    if let Err(e) = reply.send(msg) {
+      warn!("failed to report panic to channel {}: {}", reply.channel, e);
+    }
+  }
+  result
+}
+
+fn panic_message(cause: &(dyn std::any::Any + Send)) -> &str {
+  if let Some(s) = cause.downcast_ref::<&str>() {
+    s
+  } else if let Some(s) = cause.downcast_ref::<String>() {
+    s
+  } else {
+    "handler panicked"
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageKind {
+  /// A text frame, normally a JSON-RPC message
+  Text,
+  /// A binary WebSocket frame
+  Binary,
+  /// Bytes for a client on a raw socket channel, sent without framing
+  Raw
+}
+
+/// A payload on its way to a client. The data is usually JSON text but is
+/// carried as bytes so large payloads aren't validated or copied again on
+/// the way out.
 pub struct Message {
   pub channel: u32,
-  pub data: String
+  pub data: Bytes,
+  pub kind: MessageKind
+}
+
+impl Message {
+  pub fn new(channel: u32, data: impl Into<Bytes>) -> Self {
+    Message {
+      channel,
+      data: data.into(),
+      kind: MessageKind::Text
+    }
+  }
+
+  pub fn binary(channel: u32, data: impl Into<Bytes>) -> Self {
+    Message {
+      channel,
+      data: data.into(),
+      kind: MessageKind::Binary
+    }
+  }
+
+  pub fn raw(channel: u32, data: impl Into<Bytes>) -> Self {
+    Message {
+      channel,
+      data: data.into(),
+      kind: MessageKind::Raw
+    }
+  }
+
+  /// The payload as text, if it's valid UTF-8.
+  pub fn as_str(&self) -> Option<&str> {
+    std::str::from_utf8(&self.data).ok()
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SendError {
+  /// The client on this channel has disconnected. Anything further sent to
+  /// it would be dropped.
+  ChannelClosed(u32),
+  /// The plugin is being torn down and is no longer delivering messages.
+  Shutdown,
+  /// The responder queue is full and its policy is OverflowPolicy::Error.
+  QueueFull
+}
+
+impl fmt::Display for SendError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      SendError::ChannelClosed(channel) => write!(f, "channel {} is closed", channel),
+      SendError::Shutdown => write!(f, "plugin is shutting down"),
+      SendError::QueueFull => write!(f, "responder queue is full")
+    }
+  }
 }
 
+impl std::error::Error for SendError { }
+
 #[derive(Clone)]
 pub struct RequestContext {
   pub channel: u32,
   pub auth_token: String,
-  pub responder: Sender<Message>
+  pub responder: Responder,
+  state: Arc<ChannelState>,
+  framework: Framework,
+  timers: Timers,
+  features: Features,
+  paths: Arc<Paths>,
+  codec: Codec,
+  claims: Arc<std::sync::OnceLock<Option<TokenClaims>>>,
+  #[cfg(feature = "lifetime-audit")]
+  _audit: audit::Tracked
 }
 
 impl RequestContext {
-  pub fn send(&self, json: String) {
-    let m = Message {
-      channel: self.channel,
-      data: json
-    };
-    let _result = self.responder.send(m);
-    // TODO: check result and report any problems
+  /// A handle for calling other Thunder plugins.
+  pub fn framework(&self) -> &Framework {
+    &self.framework
+  }
+
+  /// The plugin's timers. They're cancelled when the plugin is destroyed.
+  pub fn timers(&self) -> &Timers {
+    &self.timers
+  }
+
+  /// Whether the feature flag `name` is on.
+  pub fn feature(&self, name: &str) -> bool {
+    self.features.is_enabled(name)
+  }
+
+  pub fn features(&self) -> &Features {
+    &self.features
+  }
+
+  /// The plugin's directories, e.g. ctx.paths().persistent().
+  pub fn paths(&self) -> &Paths {
+    &self.paths
+  }
+
+  /// The payload codec the bridge selected, Codec::Json unless the plugin
+  /// offered another in its metadata flags.
+  pub fn codec(&self) -> Codec {
+    self.codec
+  }
+
+  /// Returns false once the client that sent this request has disconnected,
+  /// so long running work on its behalf can be abandoned.
+  pub fn is_connected(&self) -> bool {
+    self.state.is_alive()
+  }
+
+  /// The claims in the client's security token, decoded the first time
+  /// they're asked for. None without a token or if it can't be decoded.
+  pub fn token_claims(&self) -> Option<&TokenClaims> {
+    self.claims.get_or_init(|| TokenClaims::parse(&self.auth_token)).as_ref()
+  }
+
+  /// Who the client is, as far as the bridge reported it.
+  pub fn peer(&self) -> Arc<Peer> {
+    self.state.peer()
+  }
+
+  /// Traffic counters for this request's channel.
+  pub fn channel_stats(&self) -> ChannelStats {
+    self.state.stats()
+  }
+
+  pub fn send(&self, json: String) -> Result<(), SendError> {
+    self.send_bytes(json)
+  }
+
+  /// Sends an already serialized payload without going through String.
+  pub fn send_bytes(&self, data: impl Into<Bytes>) -> Result<(), SendError> {
+    self.send_message(Message::new(self.channel, data), false)
+  }
+
+  pub(crate) fn send_message(&self, msg: Message, event: bool) -> Result<(), SendError> {
+    if !self.is_connected() {
+      return Err(SendError::ChannelClosed(self.channel));
+    }
+    let len = msg.data.len();
+    self.responder.send(msg)?;
+    self.state.sent(len, event);
+    Ok(())
+  }
+
+  /// Sends a binary WebSocket frame to the client.
+  pub fn send_binary(&self, data: impl Into<Bytes>) -> Result<(), SendError> {
+    self.send_message(Message::binary(self.channel, data), false)
+  }
+
+  /// Sends bytes to a client on a raw socket channel, as they are.
+  pub fn send_raw(&self, data: impl Into<Bytes>) -> Result<(), SendError> {
+    self.send_message(Message::raw(self.channel, data), false)
+  }
+
+  /// Serializes `value` with the selected codec and sends it, as a binary
+  /// frame unless the codec is JSON.
+  pub fn send_encoded<T: serde::Serialize + ?Sized>(&self, value: &T) -> Result<(), codec::Error> {
+    let data = codec::encode(self.codec, value)?;
+    if self.codec.is_binary() {
+      self.send_binary(data)?;
+    } else {
+      self.send_bytes(data)?;
+    }
+    Ok(())
   }
 }
 
+/// Bumped whenever a change to the SDK means a plugin built against one
+/// version can't be driven by a bridge or host built against another, e.g.
+/// a change to the Plugin trait or the layout of these structs.
+pub const ABI_VERSION: u32 = 10;
+
+/// abi_version comes first and the layout is fixed, so a loader can read
+/// it before trusting anything else in the struct.
+#[repr(C)]
 pub struct ServiceMetadata {
+  pub abi_version: u32,
   pub name: &'static str,
   pub version: (u32, u32, u32),
-  pub create: fn (conf: PluginConfig) -> Box<dyn Plugin>
+  pub create: fn (conf: PluginConfig) -> Box<dyn Plugin>,
+  /// What the plugin offers bridges beyond JSON text, e.g.
+  /// codec::FLAG_CBOR
+  pub flags: u32,
+  /// The library's own catch_panic, for a host calling into it
+  pub catch_panic: CatchPanic,
+  /// The library's own events::prune_all, for a host that has dropped
+  /// every client
+  pub prune_events: fn() -> usize
+}
+
+/// Runs a closure, catching a panic in it as its message.
+pub type CatchPanic = fn(&mut dyn FnMut()) -> Result<(), String>;
+
+/// Runs `f`, catching a panic in it. Every library exports its own in
+/// ServiceMetadata: a panic in a plugin loaded by a Rust host can only be
+/// caught by the std the plugin was built with, as the host's sees it as a
+/// foreign exception and aborts.
+pub fn catch_panic(f: &mut dyn FnMut()) -> Result<(), String> {
+  std::panic::catch_unwind(std::panic::AssertUnwindSafe(f))
+    .map_err(|cause| panic_message(cause.as_ref()).to_string())
+}
+
+/// Every plugin in a library exported with export_plugins!.
+#[repr(C)]
+pub struct ServiceList {
+  pub abi_version: u32,
+  pub services: &'static [ServiceMetadata]
+}
+
+impl ServiceMetadata {
+  /// Err with a description of the mismatch if the plugin was built
+  /// against an SDK this one can't drive.
+  pub fn check_abi(&self) -> Result<(), String> {
+    check_abi(self.abi_version)
+  }
+}
+
+impl ServiceList {
+  pub fn check_abi(&self) -> Result<(), String> {
+    check_abi(self.abi_version)
+  }
+
+  pub fn find(&self, name: &str) -> Option<&'static ServiceMetadata> {
+    self.services.iter().find(|s| s.name == name)
+  }
+
+  pub fn names(&self) -> Vec<&'static str> {
+    self.services.iter().map(|s| s.name).collect()
+  }
 }
 
 #[macro_export]
 macro_rules! export_plugin {
-  ($name:expr, $version:expr,  $create:expr) => {
+  ($name:expr, $version:expr,  $create:expr $(, $flags:expr)?) => {
     #[no_mangle]
     pub static thunder_service_metadata : $crate::ServiceMetadata =
       $crate::ServiceMetadata {
+        abi_version: $crate::ABI_VERSION,
         name: $name,
         version: $version,
-        create: $create
+        create: $create,
+        flags: 0 $(| $flags)?,
+        catch_panic: $crate::catch_panic,
+        prune_events: $crate::events::prune_all
       };
   };
 }
 
+/// Exports several plugins from one library, each as (name, version,
+/// create), optionally followed by metadata flags. Bridges pick one by name, either through the exported
+/// thunder_service_list or by calling wpe_rust_plugin_find.
+///
+///   thunder_rs::export_plugins![
+///     ("Player", (1,0,0), player_init),
+///     ("PlayerDiagnostics", (1,0,0), diagnostics_init)
+///   ];
+#[macro_export]
+macro_rules! export_plugins {
+  ($(($name:expr, $version:expr, $create:expr $(, $flags:expr)?)),+ $(,)?) => {
+    #[no_mangle]
+    pub static thunder_service_list : $crate::ServiceList = $crate::ServiceList {
+      abi_version: $crate::ABI_VERSION,
+      services: &[$(
+        $crate::ServiceMetadata {
+          abi_version: $crate::ABI_VERSION,
+          name: $name,
+          version: $version,
+          create: $create,
+          flags: 0 $(| $flags)?,
+          catch_panic: $crate::catch_panic,
+          prune_events: $crate::events::prune_all
+        }
+      ),+]
+    };
+
+    /// The metadata of the plugin called `name`, to pass to
+    /// wpe_rust_plugin_create, or null if the library has no such plugin.
+    #[no_mangle]
+    pub extern "C" fn wpe_rust_plugin_find(name: *const ::std::os::raw::c_char)
+      -> *const $crate::ServiceMetadata
+    {
+      if name.is_null() {
+        return ::std::ptr::null();
+      }
+      let name = unsafe{ ::std::ffi::CStr::from_ptr(name) }.to_string_lossy();
+      match thunder_service_list.find(&name) {
+        Some(metadata) => metadata,
+        None => ::std::ptr::null()
+      }
+    }
+  };
+}
+
 //===============================================================================
 // Internal code only below here
 //===============================================================================
@@ -85,6 +697,106 @@ pub struct CRequestContext {
   auth_token: *const c_char
 }
 
+/// The request context passed by pointer to the _v2 entry points. `size`
+/// is the size of the struct the bridge was built with, so fields can be
+/// added at the end: fields past `size` are treated as not reported.
+#[repr(C)]
+pub struct CRequestContextV2 {
+  size: u32,
+  channel: u32,
+  auth_token: *const c_char,
+  remote_address: *const c_char,
+  origin: *const c_char,
+  /// 1 WebSocket, 2 HTTP, 0 unknown
+  channel_type: u32
+}
+
+/// An HTTP request, passed by pointer to wpe_rust_plugin_web_request.
+/// `size` is the size of the struct the bridge was built with, so fields
+/// can be added at the end as in CRequestContextV2.
+#[repr(C)]
+pub struct CWebRequest {
+  size: u32,
+  method: *const c_char,
+  path: *const c_char,
+  /// "Name: value" lines, each ending in CRLF
+  headers: *const c_char,
+  body: *const u8,
+  body_len: u32
+}
+
+impl CWebRequest {
+  /// The request, with any invalid UTF-8 from the client replaced.
+  fn to_request(&self) -> WebRequest {
+    let body = if self.body.is_null() {
+      Vec::new()
+    } else {
+      unsafe{ std::slice::from_raw_parts(self.body, self.body_len as usize) }.to_vec()
+    };
+    WebRequest {
+      method: cstr_to_string_lossy(self.method),
+      path: cstr_to_string_lossy(self.path),
+      headers: web::parse_headers(&cstr_to_string_lossy(self.headers)),
+      body
+    }
+  }
+}
+
+macro_rules! v2_field {
+  ($ctx:expr, $field:ident) => {
+    ($ctx.size as usize) >= std::mem::offset_of!(CRequestContextV2, $field)
+      + std::mem::size_of_val(&$ctx.$field)
+  };
+}
+
+impl CRequestContextV2 {
+  /// The v1 context and the peer, reading only the fields the bridge set.
+  fn split(&self) -> (CRequestContext, Peer) {
+    let text = |present: bool, s: *const c_char| {
+      if present && !s.is_null() { Some(cstr_to_string(s)) } else { None }
+    };
+    let ctx = CRequestContext {
+      channel: self.channel,
+      auth_token: if v2_field!(self, auth_token) { self.auth_token } else { std::ptr::null() }
+    };
+    let peer = Peer {
+      remote_address: text(v2_field!(self, remote_address), self.remote_address),
+      origin: text(v2_field!(self, origin), self.origin),
+      channel_type: if v2_field!(self, channel_type) {
+        ChannelType::from_raw(self.channel_type)
+      } else {
+        ChannelType::Unknown
+      }
+    };
+    (ctx, peer)
+  }
+}
+
+fn check_abi(abi_version: u32) -> Result<(), String> {
+  if abi_version == ABI_VERSION {
+    Ok(())
+  } else {
+    Err(format!("plugin was built for SDK ABI {}, this SDK has ABI {}", abi_version, ABI_VERSION))
+  }
+}
+
+/// Borrows a string from the bridge without copying it.
+fn cstr_to_str<'a>(s: *const c_char) -> &'a str {
+  if s.is_null() {
+    ""
+  } else {
+    unsafe{ CStr::from_ptr(s) }.to_str().unwrap()
+  }
+}
+
+fn cstr_to_string_lossy(s: *const c_char) -> String {
+  if s.is_null() {
+    String::new()
+  } else {
+    unsafe{ CStr::from_ptr(s) }.to_string_lossy().into_owned()
+  }
+}
+
 fn cstr_to_string(s : *const c_char) -> String {
   if s.is_null() {
     String::new()
@@ -99,66 +811,556 @@ fn cstr_to_string(s : *const c_char) -> String {
 
 pub struct CPlugin {
   pub name: String,
-  pub plugin: Box<dyn Plugin>,
-  sender: std::sync::mpsc::Sender<Message>
+  pub plugin: Arc<Mutex<Box<dyn Plugin>>>,
+  sender: Responder,
+  send_binary: Arc<Mutex<Option<SendBinaryFunction>>>,
+  send_raw: Arc<Mutex<Option<SendBinaryFunction>>>,
+  call_func: Arc<Mutex<Option<CallFunction>>>,
+  subsystem_func: Arc<Mutex<Option<SubsystemFunction>>>,
+  custom_func: Arc<Mutex<Option<CustomFunction>>>,
+  failure_func: Arc<Mutex<Option<FailureFunction>>>,
+  warning_func: Arc<Mutex<Option<WarningFunction>>>,
+  framework: Framework,
+  scope: PluginScope,
+  delivery: Option<JoinHandle<()>>,
+  supervisor: Arc<Mutex<Supervisor>>,
+  // The factory's, read without waiting for the state's lock
+  metrics: Metrics,
+  // Set for ConcurrentPlugins, whose requests are spread over the workers
+  concurrent: bool,
+  // Codecs the plugin offered in its metadata flags
+  codec_flags: u32,
+  plugin_ctx: u32,
+  state: Mutex<State>
+}
+
+/// What the bridge's calls change. Every entry point borrows the CPlugin
+/// shared, as call_result, memory and metrics come from other threads while
+/// an invoke is running, so this is behind a lock instead.
+struct State {
+  workers: Option<WorkerPool>,
+  channels: Channels,
+  rate_limiter: Option<RateLimiter>,
+  // Shared with calls already queued on the workers, and replaced whole
+  // when the config changes
+  factory: Arc<Factory>,
+  dispatched: u32
+}
+
+/// Everything needed to create another instance of the plugin.
+#[derive(Clone)]
+struct Factory {
+  create: fn (conf: PluginConfig) -> Box<dyn Plugin>,
+  auth_token: String,
+  scope: PluginScope,
+  config: serde_json::Value,
+  features: Features,
+  metrics: Metrics,
+  paths: Paths
+}
+
+impl Factory {
+  fn create(&self) -> Box<dyn Plugin> {
+    let config = PluginConfig {
+      auth_token: self.auth_token.clone(),
+      scope: self.scope.clone(),
+      config: self.config.clone(),
+      features: self.features.clone(),
+      metrics: self.metrics.clone(),
+      paths: self.paths.clone()
+    };
+    let mut plugin = (self.create)(config);
+    plugin.on_init(self.scope.restore());
+    plugin
+  }
+}
+
+// How long destroy waits for queued messages to reach Thunder before
+// dropping them.
+const DEFAULT_DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+
+impl Drop for CPlugin {
+  fn drop(&mut self) {
+    // Lets callbacks already queued on the workers finish before the plugin
+    // is told to shut down
+    self.state.get_mut().unwrap_or_else(|e| e.into_inner()).workers = None;
+    journal::record(journal::Source::Plugin, "on_shutdown", None, String::new());
+    let plugin_ctx = self.plugin_ctx;
+    let mut plugin = self.plugin.lock().unwrap_or_else(|e| e.into_inner());
+    if let Err(cause) = catch_panic(&mut || logging::with_plugin(plugin_ctx, || plugin.on_shutdown())) {
+      error!("Error calling on_shutdown: {}", cause);
+    }
+    drop(plugin);
+    self.scope.shutdown();
+
+    self.sender.close();
+    if let Some(delivery) = self.delivery.take() {
+      let timeout = std::env::var("THUNDER_RS_DRAIN_TIMEOUT_MS").ok()
+        .and_then(|s| s.parse::<u64>().ok())
+        .map(Duration::from_millis)
+        .unwrap_or(DEFAULT_DRAIN_TIMEOUT);
+      let deadline = Instant::now() + timeout;
+      while !delivery.is_finished() && Instant::now() < deadline {
+        std::thread::sleep(Duration::from_millis(5));
+      }
+      if !delivery.is_finished() {
+        let dropped = self.sender.discard();
+        warn!("{}: dropped {} undelivered messages on destroy", self.name, dropped);
+      }
+      let _ = delivery.join();
+    }
+    #[cfg(feature = "lifetime-audit")]
+    {
+      // Contexts the plugin keeps in its own fields go with it
+      if let Ok(mut plugin) = self.plugin.lock() {
+        *plugin = Box::new(audit::Retired);
+      }
+      audit::report_live();
+    }
+    // Thunder's trace callback may not outlive the plugin; the others in the
+    // library keep theirs
+    logging::set_trace_func(self.plugin_ctx, None);
+  }
+}
+
+/// Sends framework calls through the callback the bridge registered with
+/// wpe_rust_plugin_set_call_func.
+struct FfiLink {
+  call_func: Arc<Mutex<Option<CallFunction>>>,
+  subsystem_func: Arc<Mutex<Option<SubsystemFunction>>>,
+  custom_func: Arc<Mutex<Option<CustomFunction>>>,
+  failure_func: Arc<Mutex<Option<FailureFunction>>>,
+  warning_func: Arc<Mutex<Option<WarningFunction>>>,
+  plugin_ctx: u32
+}
+
+impl FrameworkLink for FfiLink {
+  fn send_call(&self, id: u32, callsign: &str, method: &str, params: &serde_json::Value) -> Result<(), String> {
+    let call_func = (*self.call_func.lock().unwrap())
+      .ok_or_else(|| String::from("bridge does not support outbound calls"))?;
+    let callsign = CString::new(callsign).map_err(|e| e.to_string())?;
+    let method = CString::new(method).map_err(|e| e.to_string())?;
+    let params = CString::new(params.to_string()).map_err(|e| e.to_string())?;
+    unsafe {
+      call_func(self.plugin_ctx, id, callsign.as_ptr(), method.as_ptr(), params.as_ptr());
+    }
+    Ok(())
+  }
+
+  fn set_subsystem(&self, subsystem: Subsystem, active: bool) -> Result<(), String> {
+    let subsystem_func = (*self.subsystem_func.lock().unwrap())
+      .ok_or_else(|| String::from("bridge does not support setting subsystems"))?;
+    unsafe {
+      subsystem_func(self.plugin_ctx, subsystem.id(), active as u32);
+    }
+    Ok(())
+  }
+
+  fn send_custom(&self, id: u32, data: &[u8]) -> Result<(), String> {
+    let custom_func = (*self.custom_func.lock().unwrap())
+      .ok_or_else(|| String::from("bridge does not support custom frames"))?;
+    unsafe {
+      custom_func(self.plugin_ctx, id, data.as_ptr(), data.len() as u32);
+    }
+    Ok(())
+  }
+
+  fn report_failure(&self, reason: &str) -> Result<(), String> {
+    let failure_func = (*self.failure_func.lock().unwrap())
+      .ok_or_else(|| String::from("bridge does not support failure reports"))?;
+    let reason = CString::new(reason).map_err(|e| e.to_string())?;
+    unsafe {
+      failure_func(self.plugin_ctx, reason.as_ptr());
+    }
+    Ok(())
+  }
+
+  fn report_warning(&self, warning: &Warning, message: &str) -> Result<(), String> {
+    let warning_func = (*self.warning_func.lock().unwrap())
+      .ok_or_else(|| String::from("bridge does not support warning reports"))?;
+    let category = CString::new(warning.category()).map_err(|e| e.to_string())?;
+    let message = CString::new(message).map_err(|e| e.to_string())?;
+    unsafe {
+      warning_func(self.plugin_ctx, category.as_ptr(), message.as_ptr());
+    }
+    Ok(())
+  }
+}
+
+/// Runs `f` against the plugin, applying the restart policy if it panics.
+fn call_plugin<F>(plugin: &Mutex<Box<dyn Plugin>>, supervisor: &Mutex<Supervisor>, factory: &Factory,
+  channel: u32, f: F)
+  where F: FnOnce(&mut dyn Plugin)
+{
+  // A panicking callback poisons the lock; carry on with the plugin as is
+  // unless the restart policy says otherwise
+  let mut plugin = plugin.lock().unwrap_or_else(|e| e.into_inner());
+  if supervisor.lock().unwrap().is_quarantined() {
+    return;
+  }
+  let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| f(plugin.as_mut())));
+  if let Err(cause) = result {
+    error!("Error calling plugin on channel {}: {:?}", channel, cause);
+    let action = supervisor.lock().unwrap().record_panic();
+    if action == Some(PanicAction::Restart) {
+      *plugin = factory.create();
+    }
+  }
 }
 
 impl CPlugin {
-  fn on_incoming_message(&mut self, json_req: *const c_char, ctx: CRequestContext) {
-    let req = cstr_to_string(json_req);
-    let req_ctx = RequestContext {
-      channel: ctx.channel,
-      auth_token: cstr_to_string(ctx.auth_token),
-      responder: self.sender.clone()
+  fn state(&self) -> MutexGuard<'_, State> {
+    self.state.lock().unwrap_or_else(|e| e.into_inner())
+  }
+
+  /// Runs `f` against the plugin, on the worker owning `channel` when there's
+  /// a pool and right here otherwise.
+  fn run<F>(&self, state: &State, channel: u32, f: F)
+    where F: FnOnce(&mut dyn Plugin) + Send + 'static
+  {
+    match &state.workers {
+      Some(pool) => {
+        let plugin = self.plugin.clone();
+        let supervisor = self.supervisor.clone();
+        let factory = state.factory.clone();
+        let plugin_ctx = self.plugin_ctx;
+        pool.submit(channel, move || logging::with_plugin(plugin_ctx,
+          || call_plugin(&plugin, &supervisor, &factory, channel, f)));
+      }
+      None => call_plugin(&self.plugin, &self.supervisor, &state.factory, channel, f)
+    }
+  }
+
+  /// Runs a ConcurrentPlugin's on_message without holding the plugin's lock,
+  /// on the next worker in turn rather than the one owning the channel.
+  /// Without workers it runs right here, once `state` is released.
+  fn run_concurrent(&self, mut state: MutexGuard<'_, State>, json: String, ctx: RequestContext) {
+    let plugin = self.plugin.clone();
+    let supervisor = self.supervisor.clone();
+    let factory = state.factory.clone();
+    let channel = ctx.channel;
+    let plugin_ctx = self.plugin_ctx;
+    let handle = move || {
+      // Locked only to pick up the current instance, which a restart replaces
+      let handler = {
+        let plugin = plugin.lock().unwrap_or_else(|e| e.into_inner());
+        if supervisor.lock().unwrap().is_quarantined() {
+          return;
+        }
+        plugin.concurrent()
+      };
+      let Some(handler) = handler else { return };
+      if let Err(cause) = invoke_concurrent(handler.as_ref(), json, ctx) {
+        error!("Error calling plugin on channel {}: {:?}", channel, cause);
+        let action = supervisor.lock().unwrap().record_panic();
+        if action == Some(PanicAction::Restart) {
+          *plugin.lock().unwrap_or_else(|e| e.into_inner()) = factory.create();
+        }
+      }
     };
-    println!("dispatch from thunder");
-    self.plugin.on_message(req, req_ctx);
+    let call = move || logging::with_plugin(plugin_ctx, handle);
+    state.dispatched = state.dispatched.wrapping_add(1);
+    match &state.workers {
+      Some(pool) => pool.submit(state.dispatched, call),
+      None => {
+        drop(state);
+        call()
+      }
+    }
+  }
+
+  /// The web handler and the context for an HTTP request. The handler is
+  /// taken like concurrent(), so it runs without the plugin's lock.
+  fn web_context(&self, req: &WebRequest, ctx: CRequestContext) -> (Option<Arc<dyn WebHandler>>, RequestContext) {
+    let req_ctx = self.state().channels.receive(ctx.channel, cstr_to_string(ctx.auth_token),
+      self.sender.clone(), req.body.len());
+    journal::record(journal::Source::Plugin, "on_web_request", Some(ctx.channel),
+      format!("{} {}", req.method, req.path));
+    let handler = self.plugin.lock().unwrap_or_else(|e| e.into_inner()).web_handler();
+    (handler, req_ctx)
+  }
+
+  fn on_incoming_message(&self, json_req: *const c_char, ctx: CRequestContext) {
+    let mut state = self.state();
+    let req = cstr_to_str(json_req);
+    let req_ctx = state.channels.receive(ctx.channel, cstr_to_string(ctx.auth_token),
+      self.sender.clone(), req.len());
+    if self.supervisor.lock().unwrap().is_quarantined() {
+      if let Some(id) = jsonrpc::request_id(req) {
+        let _ = req_ctx.send(jsonrpc::error_response(&id, jsonrpc::ERROR_UNAVAILABLE,
+          &format!("{} is quarantined after repeated failures", self.name)));
+      }
+      return;
+    }
+    if let Some(limiter) = &mut state.rate_limiter {
+      if !limiter.admit(req, &req_ctx) {
+        return;
+      }
+    }
+    debug!("dispatch from thunder");
+    journal::record(journal::Source::Plugin, "on_message", Some(ctx.channel),
+      format!("len={}", req.len()));
+    if self.concurrent {
+      self.run_concurrent(state, req.to_string(), req_ctx);
+      return;
+    }
+    if state.workers.is_none() {
+      // Running here, so the request can stay in the bridge's buffer
+      let factory = state.factory.clone();
+      drop(state);
+      call_plugin(&self.plugin, &self.supervisor, &factory, ctx.channel, |p| {
+        if let Err(cause) = invoke_message_str(p, req, req_ctx) {
+          std::panic::resume_unwind(cause);
+        }
+      });
+      return;
+    }
+    let req = req.to_string();
+    self.run(&state, ctx.channel, move |p| {
+      if let Err(cause) = invoke_message(p, req, req_ctx) {
+        std::panic::resume_unwind(cause);
+      }
+    });
   }
-  fn on_client_connect(&mut self, channel: u32) {
-    self.plugin.on_client_connect(channel);
+  fn on_incoming_binary(&self, data: &[u8], ctx: CRequestContext) {
+    let mut state = self.state();
+    let req_ctx = state.channels.receive(ctx.channel, cstr_to_string(ctx.auth_token),
+      self.sender.clone(), data.len());
+    if let Some(limiter) = &mut state.rate_limiter {
+      if !limiter.admit_frame(ctx.channel) {
+        return;
+      }
+    }
+    journal::record(journal::Source::Plugin, "on_binary_message", Some(ctx.channel),
+      format!("len={}", data.len()));
+    let data = data.to_vec();
+    self.run(&state, ctx.channel, move |p| p.on_binary_message(&data, req_ctx));
   }
-  fn on_client_disconnect(&mut self, channel: u32) {
-    self.plugin.on_client_disconnect(channel);
+  fn on_incoming_raw(&self, data: &[u8], ctx: CRequestContext) {
+    let mut state = self.state();
+    let req_ctx = state.channels.receive(ctx.channel, cstr_to_string(ctx.auth_token),
+      self.sender.clone(), data.len());
+    if let Some(limiter) = &mut state.rate_limiter {
+      if !limiter.admit_frame(ctx.channel) {
+        return;
+      }
+    }
+    journal::record(journal::Source::Plugin, "on_raw_data", Some(ctx.channel),
+      format!("len={}", data.len()));
+    let data = data.to_vec();
+    self.run(&state, ctx.channel, move |p| p.on_raw_data(&data, req_ctx));
+  }
+  fn on_client_connect(&self, channel: u32) {
+    let mut state = self.state();
+    state.channels.connect(channel);
+    journal::record(journal::Source::Plugin, "on_client_connect", Some(channel), String::new());
+    self.run(&state, channel, move |p| p.on_client_connect(channel));
+  }
+  fn on_client_disconnect(&self, channel: u32) {
+    let mut state = self.state();
+    state.channels.disconnect(channel);
+    if let Some(limiter) = &mut state.rate_limiter {
+      limiter.forget(channel);
+    }
+    journal::record(journal::Source::Plugin, "on_client_disconnect", Some(channel), String::new());
+    self.run(&state, channel, move |p| p.on_client_disconnect(channel));
+  }
+  fn on_reset(&self, reason: String) {
+    let mut state = self.state();
+    let channels = state.channels.disconnect_all();
+    if let Some(limiter) = &mut state.rate_limiter {
+      limiter.forget_all();
+    }
+    let pruned = events::prune_all();
+    journal::record(journal::Source::Plugin, "on_all_clients_disconnected", None,
+      format!("channels={} subscriptions={} reason={}", channels.len(), pruned, reason));
+    // Messages already queued for other workers see their contexts as
+    // disconnected
+    self.run(&state, 0, move |p| p.on_all_clients_disconnected(&channels, &reason));
+  }
+  fn on_config_changed(&self, json: String) {
+    let mut state = self.state();
+    let config = match secrets::parse_config(&json) {
+      Ok(config) => config,
+      Err(e) => {
+        error!("{}: ignoring config change: {}", self.name, e);
+        return;
+      }
+    };
+    journal::record(journal::Source::Plugin, "on_config_changed", None, format!("len={}", json.len()));
+    let factory = Arc::make_mut(&mut state.factory);
+    factory.features.load_config(&config);
+    factory.config = config.clone();
+    self.run(&state, 0, move |p| p.on_config_changed(&config));
+  }
+  fn on_custom_frame(&self, id: u32, data: &[u8]) {
+    let state = self.state();
+    journal::record(journal::Source::Plugin, "on_custom_frame", None,
+      format!("id={:#x} len={}", id, data.len()));
+    let data = data.to_vec();
+    self.run(&state, 0, move |p| p.on_custom_frame(id, &data));
+  }
+  fn on_subsystem_change(&self, id: u32, active: bool) {
+    if let Some(subsystem) = self.framework.subsystem_changed(id, active) {
+      journal::record(journal::Source::Plugin, "on_subsystem_change", None,
+        format!("subsystem={} active={}", subsystem.name(), active));
+      self.plugin.lock().unwrap_or_else(|e| e.into_inner()).on_subsystem_change(subsystem, active);
+    }
   }
 }
 
+/// The SDK ABI the library was built with, for bridges to check before
+/// calling anything else. Bridges built for another ABI must not load it.
 #[no_mangle]
-pub extern fn wpe_rust_plugin_create(_name: *const c_char, send_func: SendToFunction,
+pub extern "C" fn wpe_rust_plugin_abi_version() -> u32 {
+  ABI_VERSION
+}
+
+/// Creates the plugin described by `meta_data`. Returns null if it was built
+/// for another SDK ABI.
+#[no_mangle]
+pub extern "C" fn wpe_rust_plugin_create(callsign: *const c_char, send_func: SendToFunction,
   plugin_ctx: u32, auth_token: *const c_char, meta_data: *mut ServiceMetadata) -> *mut CPlugin
 {
   assert!(!meta_data.is_null());
   assert!(!auth_token.is_null());
 
-  let config = PluginConfig {
-    auth_token: cstr_to_string(auth_token)
-  };
+  logging::init();
 
   let service_metadata = unsafe{ &*meta_data };
-  let plugin: Box<dyn Plugin> = (service_metadata.create)(config);
+  if let Err(e) = service_metadata.check_abi() {
+    error!("refusing to create plugin: {}", e);
+    return std::ptr::null_mut();
+  }
+  let callsign = match cstr_to_string_lossy(callsign) {
+    callsign if callsign.is_empty() => service_metadata.name.to_string(),
+    callsign => callsign
+  };
+  let config = secrets::load_config(&callsign).unwrap_or_else(|e| {
+    error!("{}: {}", service_metadata.name, e);
+    serde_json::Value::Null
+  });
+  let features = Features::from_config(&config);
+  let paths = Paths::from_config(&config);
+  let scope = PluginScope::new(service_metadata.name, &paths);
+  let factory = Factory {
+    create: service_metadata.create,
+    auth_token: cstr_to_string(auth_token),
+    scope: scope.clone(),
+    config,
+    features: features.clone(),
+    metrics: Metrics::new(),
+    paths: paths.clone()
+  };
+
+  let plugin: Box<dyn Plugin> = factory.create();
   let name: String = service_metadata.name.to_string();
+  telemetry::start(&name, &factory.config, &factory.metrics, &scope);
+  let supervisor = Supervisor::new(&name, plugin.restart_policy());
 
-  let (tx, rx) = std::sync::mpsc::channel::<Message>();
+  let concurrent = plugin.concurrent().is_some();
+  let rate_limiter = plugin.rate_limit().map(RateLimiter::new);
+  let workers = match plugin.workers() {
+    0 => None,
+    n => Some(WorkerPool::new(n))
+  };
 
-  let c_plugin: Box<CPlugin> = Box::new(CPlugin {
-    name: name,
-    plugin: plugin,
-    sender: tx
+  let (tx, rx) = responder::queue(plugin.queue_limit());
+  let send_binary = Arc::new(Mutex::new(None::<SendBinaryFunction>));
+  let delivery_binary = send_binary.clone();
+  let send_raw = Arc::new(Mutex::new(None::<SendBinaryFunction>));
+  let delivery_raw = send_raw.clone();
+  let call_func = Arc::new(Mutex::new(None::<CallFunction>));
+  let subsystem_func = Arc::new(Mutex::new(None::<SubsystemFunction>));
+  let custom_func = Arc::new(Mutex::new(None::<CustomFunction>));
+  let failure_func = Arc::new(Mutex::new(None::<FailureFunction>));
+  let warning_func = Arc::new(Mutex::new(None::<WarningFunction>));
+  let framework = Framework::new(FfiLink {
+    call_func: call_func.clone(),
+    subsystem_func: subsystem_func.clone(),
+    custom_func: custom_func.clone(),
+    failure_func: failure_func.clone(),
+    warning_func: warning_func.clone(),
+    plugin_ctx
   });
 
-  std::thread::spawn(move || {
-    while let Ok(m) = rx.recv() {
-      let c_str = CString::new(m.data).unwrap();
-      unsafe {
-        send_func(m.channel, c_str.as_ptr(), plugin_ctx);
+  let mut channels = Channels::new();
+  channels.set_framework(framework.clone());
+  channels.set_features(features);
+  channels.set_paths(paths);
+
+  let delivery = std::thread::spawn(move || {
+    while let Some(m) = rx.recv() {
+      journal::record(journal::Source::Plugin, "send", Some(m.channel), format!("len={}", m.data.len()));
+      match m.kind {
+        MessageKind::Text => {
+          // The bridge takes a C string, which would end at a NUL
+          match CString::new(Vec::from(m.data)) {
+            Ok(c_str) => unsafe {
+              send_func(m.channel, c_str.as_ptr(), plugin_ctx);
+            },
+            Err(e) => {
+              error!("dropping message for channel {}, NUL at byte {}", m.channel, e.nul_position());
+            }
+          }
+        }
+        MessageKind::Binary => {
+          match *delivery_binary.lock().unwrap() {
+            Some(send_binary_func) => unsafe {
+              send_binary_func(m.channel, m.data.as_ptr(), m.data.len() as u32, plugin_ctx);
+            },
+            None => {
+              warn!("dropping binary message for channel {}, no binary send function", m.channel);
+            }
+          }
+        }
+        MessageKind::Raw => {
+          match *delivery_raw.lock().unwrap() {
+            Some(send_raw_func) => unsafe {
+              send_raw_func(m.channel, m.data.as_ptr(), m.data.len() as u32, plugin_ctx);
+            },
+            None => {
+              warn!("dropping raw data for channel {}, no raw send function", m.channel);
+            }
+          }
+        }
       }
     }
   });
 
+  let c_plugin: Box<CPlugin> = Box::new(CPlugin {
+    name,
+    plugin: Arc::new(Mutex::new(plugin)),
+    sender: tx,
+    send_binary,
+    send_raw,
+    call_func,
+    subsystem_func,
+    custom_func,
+    failure_func,
+    warning_func,
+    framework,
+    scope,
+    delivery: Some(delivery),
+    supervisor: Arc::new(Mutex::new(supervisor)),
+    metrics: factory.metrics.clone(),
+    concurrent,
+    codec_flags: service_metadata.flags,
+    plugin_ctx,
+    state: Mutex::new(State {
+      workers,
+      channels,
+      rate_limiter,
+      factory: Arc::new(factory),
+      dispatched: 0
+    })
+  });
+
   Box::into_raw(c_plugin)
 }
 
 #[no_mangle]
-pub extern fn wpe_rust_plugin_destroy(ptr: *mut CPlugin) {
+pub extern "C" fn wpe_rust_plugin_destroy(ptr: *mut CPlugin) {
   assert!(!ptr.is_null());
 
   unsafe {
@@ -167,7 +1369,7 @@ pub extern fn wpe_rust_plugin_destroy(ptr: *mut CPlugin) {
 }
 
 #[no_mangle]
-pub extern fn wpe_rust_plugin_init(_ptr: *mut CPlugin, _json: *const c_char) {
+pub extern "C" fn wpe_rust_plugin_init(_ptr: *mut CPlugin, _json: *const c_char) {
   // assert!(!ptr.is_null());
 
   // XXX: Create + Init doesn't seem to fit the Rust style. wpe_rust_plugin_create 
@@ -178,56 +1380,512 @@ pub extern fn wpe_rust_plugin_init(_ptr: *mut CPlugin, _json: *const c_char) {
 }
 
 #[no_mangle]
-pub extern fn wpe_rust_plugin_invoke(ptr: *mut CPlugin, json_req: *const c_char, req_ctx: CRequestContext) {
+pub extern "C" fn wpe_rust_plugin_invoke(ptr: *mut CPlugin, json_req: *const c_char, req_ctx: CRequestContext) {
   assert!(!ptr.is_null());
   assert!(!json_req.is_null());
 
-  let plugin = unsafe{ &mut *ptr };
+  let plugin = unsafe{ &*ptr };
   let uncaught_error = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
-    plugin.on_incoming_message(json_req, req_ctx);
+    logging::with_plugin(plugin.plugin_ctx, || plugin.on_incoming_message(json_req, req_ctx));
   }));
 
-  match uncaught_error {
-    Err(cause) => {
-      println!("Error calling on_incoming_message");
-      println!("{:?}", cause);
-    }
-    Ok(_) => { }
+  if let Err(cause) = uncaught_error {
+    error!("Error calling on_incoming_message: {:?}", cause);
+  }
+}
+
+/// Like wpe_rust_plugin_invoke with the extensible context, which also
+/// carries who the client is.
+#[no_mangle]
+pub extern "C" fn wpe_rust_plugin_invoke_v2(ptr: *mut CPlugin, json_req: *const c_char,
+  req_ctx: *const CRequestContextV2)
+{
+  assert!(!ptr.is_null());
+  assert!(!req_ctx.is_null());
+
+  let (req_ctx, peer) = unsafe{ &*req_ctx }.split();
+  let plugin = unsafe{ &*ptr };
+  plugin.state().channels.set_peer(req_ctx.channel, peer);
+  wpe_rust_plugin_invoke(ptr, json_req, req_ctx);
+}
+
+/// Writes the bridge journal to `path`.
+#[no_mangle]
+pub extern "C" fn wpe_rust_plugin_dump_journal(ptr: *mut CPlugin, path: *const c_char) {
+  assert!(!ptr.is_null());
+  assert!(!path.is_null());
+
+  let plugin = unsafe{ &*ptr };
+  plugin.state().channels.record_stats();
+  let path = cstr_to_string(path);
+  if let Err(e) = journal::dump_to_file(&path) {
+    error!("failed to dump journal to {}: {}", path, e);
+  }
+}
+
+/// Registers the callback used for RequestContext::framework() calls. It's
+/// called with (plugin_ctx, call_id, callsign, method, params) and the
+/// answer must be delivered with wpe_rust_plugin_call_result, possibly from
+/// inside the callback.
+#[no_mangle]
+pub extern "C" fn wpe_rust_plugin_set_call_func(ptr: *mut CPlugin, call_func: CallFunction) {
+  assert!(!ptr.is_null());
+
+  let plugin = unsafe{ &*ptr };
+  *plugin.call_func.lock().unwrap() = Some(call_func);
+}
+
+/// Registers Thunder's trace callback; from now on the SDK's and the
+/// plugin's log lines go to it instead of stdout. See logging::TraceFunction.
+#[no_mangle]
+pub extern "C" fn wpe_rust_plugin_set_trace_func(ptr: *mut CPlugin, trace_func: logging::TraceFunction) {
+  assert!(!ptr.is_null());
+
+  let plugin = unsafe{ &*ptr };
+  logging::set_trace_func(plugin.plugin_ctx, Some(trace_func));
+}
+
+/// Delivers the JSON-RPC response for an outbound call.
+#[no_mangle]
+pub extern "C" fn wpe_rust_plugin_call_result(ptr: *mut CPlugin, call_id: u32, json_res: *const c_char) {
+  assert!(!ptr.is_null());
+
+  // May come from inside a callback while an invoke is running, which is
+  // fine as neither borrows the plugin mutably
+  let plugin = unsafe{ &*ptr };
+  plugin.framework.complete(call_id, &cstr_to_string(json_res));
+}
+
+/// Registers the callback used for Framework::set_subsystem. It's called with
+/// (plugin_ctx, subsystem id, active).
+#[no_mangle]
+pub extern "C" fn wpe_rust_plugin_set_subsystem_func(ptr: *mut CPlugin, subsystem_func: SubsystemFunction) {
+  assert!(!ptr.is_null());
+
+  let plugin = unsafe{ &*ptr };
+  *plugin.subsystem_func.lock().unwrap() = Some(subsystem_func);
+}
+
+/// Reports the state of a subsystem. Bridges should call this for every
+/// subsystem once after creating the plugin and again on each change.
+#[no_mangle]
+pub extern "C" fn wpe_rust_plugin_subsystem_changed(ptr: *mut CPlugin, subsystem: u32, active: u32) {
+  assert!(!ptr.is_null());
+
+  let plugin = unsafe{ &*ptr };
+  let uncaught_error = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+    logging::with_plugin(plugin.plugin_ctx, || plugin.on_subsystem_change(subsystem, active != 0));
+  }));
+
+  if let Err(cause) = uncaught_error {
+    error!("Error calling on_subsystem_change: {:?}", cause);
   }
 }
 
+/// Registers the callback used for Framework::send_custom_frame. It's called
+/// with (plugin_ctx, id, data, len).
+#[no_mangle]
+pub extern "C" fn wpe_rust_plugin_set_custom_func(ptr: *mut CPlugin, custom_func: CustomFunction) {
+  assert!(!ptr.is_null());
+
+  let plugin = unsafe{ &*ptr };
+  *plugin.custom_func.lock().unwrap() = Some(custom_func);
+}
+
+/// Registers the callback used for Framework::report_failure. It's called
+/// with (plugin_ctx, reason); the bridge should have Thunder deactivate the
+/// plugin with reason FAILURE, which restarts it if its config says so.
+#[no_mangle]
+pub extern "C" fn wpe_rust_plugin_set_failure_func(ptr: *mut CPlugin, failure_func: FailureFunction) {
+  assert!(!ptr.is_null());
+
+  let plugin = unsafe{ &*ptr };
+  *plugin.failure_func.lock().unwrap() = Some(failure_func);
+}
+
+/// Registers the callback used for Framework::report_warning. It's called
+/// with (plugin_ctx, category, message) for the bridge to pass on to
+/// Thunder's WarningReporting.
+#[no_mangle]
+pub extern "C" fn wpe_rust_plugin_set_warning_func(ptr: *mut CPlugin, warning_func: WarningFunction) {
+  assert!(!ptr.is_null());
+
+  let plugin = unsafe{ &*ptr };
+  *plugin.warning_func.lock().unwrap() = Some(warning_func);
+}
+
+/// Serves an HTTP request. `respond` is called once, before this returns,
+/// with (respond_arg, status, headers, body, body_len); headers are
+/// formatted as in the request and only valid during the call.
 #[no_mangle]
-pub extern fn wpe_rust_plugin_on_client_connect(ptr: *mut CPlugin, channel: u32) {
+pub extern "C" fn wpe_rust_plugin_web_request(ptr: *mut CPlugin, req: *const CWebRequest,
+  req_ctx: CRequestContext, respond: WebResponseFunction, respond_arg: *mut std::os::raw::c_void)
+{
   assert!(!ptr.is_null());
+  assert!(!req.is_null());
 
-  let plugin = unsafe{ &mut *ptr };
+  let plugin = unsafe{ &*ptr };
   let uncaught_error = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
-    plugin.on_client_connect(channel);
+    let req = unsafe{ &*req }.to_request();
+    logging::with_plugin(plugin.plugin_ctx, || {
+      let (handler, ctx) = plugin.web_context(&req, req_ctx);
+      web::dispatch(handler, &req, &ctx).into_buffered()
+    })
+  }));
+
+  let res = uncaught_error.unwrap_or_else(|cause| {
+    error!("Error calling web_handler: {:?}", cause);
+    WebResponse::new(500)
+  });
+  respond_web(res, respond, None, respond_arg);
+}
+
+/// Like wpe_rust_plugin_web_request, but a streamed response body isn't
+/// read into memory: `respond` gets the status and headers with an empty
+/// body, then `chunk` is called with (respond_arg, data, len) for each
+/// piece and finally with len 0.
+#[no_mangle]
+pub extern "C" fn wpe_rust_plugin_web_request_streamed(ptr: *mut CPlugin, req: *const CWebRequest,
+  req_ctx: CRequestContext, respond: WebResponseFunction, chunk: WebChunkFunction,
+  respond_arg: *mut std::os::raw::c_void)
+{
+  assert!(!ptr.is_null());
+  assert!(!req.is_null());
+
+  let plugin = unsafe{ &*ptr };
+  let uncaught_error = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+    let req = unsafe{ &*req }.to_request();
+    logging::with_plugin(plugin.plugin_ctx, || {
+      let (handler, ctx) = plugin.web_context(&req, req_ctx);
+      web::dispatch(handler, &req, &ctx)
+    })
+  }));
+
+  let res = uncaught_error.unwrap_or_else(|cause| {
+    error!("Error calling web_handler: {:?}", cause);
+    WebResponse::new(500)
+  });
+  respond_web(res, respond, Some(chunk), respond_arg);
+}
+
+/// Starts a request whose body the bridge passes in chunks, for uploads
+/// too big to hold in memory; any body in `req` is taken as the first
+/// chunk. Feed it with wpe_rust_plugin_web_upload_chunk and end it with
+/// wpe_rust_plugin_web_upload_end, which frees it. Returns null if the
+/// plugin panicked starting it; the chunks are then ignored and the end
+/// answers 500.
+#[no_mangle]
+pub extern "C" fn wpe_rust_plugin_web_upload_begin(ptr: *mut CPlugin, req: *const CWebRequest,
+  req_ctx: CRequestContext) -> *mut web::Upload
+{
+  assert!(!ptr.is_null());
+  assert!(!req.is_null());
+
+  let plugin = unsafe{ &*ptr };
+  let uncaught_error = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+    let mut req = unsafe{ &*req }.to_request();
+    let first = std::mem::take(&mut req.body);
+    logging::with_plugin(plugin.plugin_ctx, || {
+      let (handler, ctx) = plugin.web_context(&req, req_ctx);
+      let mut upload = web::Upload::begin(handler, req, ctx);
+      if !first.is_empty() {
+        upload.write(&first);
+      }
+      upload
+    })
   }));
 
   match uncaught_error {
+    Ok(upload) => Box::into_raw(Box::new(upload)),
     Err(cause) => {
-      println!("Error calling on_client_connect");
-      println!("{:?}", cause);
+      error!("Error calling web_handler: {:?}", cause);
+      std::ptr::null_mut()
     }
-    Ok(_) => { }
   }
 }
 
 #[no_mangle]
-pub extern fn wpe_rust_plugin_on_client_disconnect(ptr: *mut CPlugin, channel: u32) {
+pub extern "C" fn wpe_rust_plugin_web_upload_chunk(upload: *mut web::Upload, data: *const u8, len: u32) {
+  if upload.is_null() || data.is_null() {
+    return;
+  }
+
+  let upload = unsafe{ &mut *upload };
+  let data = unsafe{ std::slice::from_raw_parts(data, len as usize) };
+  let uncaught_error = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+    upload.write(data);
+  }));
+
+  if let Err(cause) = uncaught_error {
+    error!("Error writing web upload: {:?}", cause);
+  }
+}
+
+/// Ends an upload and answers it as wpe_rust_plugin_web_request_streamed
+/// does.
+#[no_mangle]
+pub extern "C" fn wpe_rust_plugin_web_upload_end(upload: *mut web::Upload, respond: WebResponseFunction,
+  chunk: WebChunkFunction, respond_arg: *mut std::os::raw::c_void)
+{
+  if upload.is_null() {
+    respond_web(WebResponse::new(500), respond, Some(chunk), respond_arg);
+    return;
+  }
+
+  let upload = unsafe{ Box::from_raw(upload) };
+  let uncaught_error = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| upload.finish()));
+
+  let res = uncaught_error.unwrap_or_else(|cause| {
+    error!("Error finishing web upload: {:?}", cause);
+    WebResponse::new(500)
+  });
+  respond_web(res, respond, Some(chunk), respond_arg);
+}
+
+fn respond_web(res: WebResponse, respond: WebResponseFunction, chunk: Option<WebChunkFunction>,
+  respond_arg: *mut std::os::raw::c_void)
+{
+  // A header the bridge can't pass on as is fails the whole response
+  let (mut res, headers) = match web::format_headers(&res.headers) {
+    Ok(headers) => (res, headers),
+    Err(e) => {
+      error!("Error in web response: {}", e);
+      (WebResponse::new(500), String::new())
+    }
+  };
+  let headers = CString::new(headers).unwrap_or_default();
+  match chunk {
+    Some(chunk) if res.is_streamed() => {
+      unsafe {
+        respond(respond_arg, res.status as u32, headers.as_ptr(), std::ptr::null(), 0);
+      }
+      res.write_chunks(|data| unsafe { chunk(respond_arg, data.as_ptr(), data.len() as u32) });
+      unsafe {
+        chunk(respond_arg, std::ptr::null(), 0);
+      }
+    }
+    _ => {
+      let res = res.into_buffered();
+      unsafe {
+        respond(respond_arg, res.status as u32, headers.as_ptr(), res.body.as_ptr(), res.body.len() as u32);
+      }
+    }
+  }
+}
+
+/// Selects the codec payloads are carried in: 0 JSON, 1 CBOR, 2
+/// MessagePack. Returns 0 if the plugin didn't offer the codec in its
+/// metadata flags or the SDK was built without it, in which case JSON
+/// stays in use.
+#[no_mangle]
+pub extern "C" fn wpe_rust_plugin_set_codec(ptr: *mut CPlugin, codec: u32) -> u32 {
   assert!(!ptr.is_null());
 
-  let plugin = unsafe{ &mut *ptr };
+  let plugin = unsafe{ &*ptr };
+  let Some(codec) = Codec::from_raw(codec) else {
+    warn!("{}: unknown codec {}", plugin.name, codec);
+    return 0;
+  };
+  if codec.flag() & plugin.codec_flags != codec.flag() || !codec.is_available() {
+    warn!("{}: refusing codec {}", plugin.name, codec.name());
+    return 0;
+  }
+  debug!("{}: payloads are now {}", plugin.name, codec.name());
+  plugin.state().channels.set_codec(codec);
+  1
+}
+
+/// Delivers updated plugin configuration, a JSON document.
+#[no_mangle]
+pub extern "C" fn wpe_rust_plugin_config_changed(ptr: *mut CPlugin, json: *const c_char) {
+  assert!(!ptr.is_null());
+  assert!(!json.is_null());
+
+  let plugin = unsafe{ &*ptr };
+  let json = cstr_to_string(json);
   let uncaught_error = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
-    plugin.on_client_disconnect(channel);
+    logging::with_plugin(plugin.plugin_ctx, || plugin.on_config_changed(json));
+  }));
+
+  if let Err(cause) = uncaught_error {
+    error!("Error calling on_config_changed: {:?}", cause);
+  }
+}
+
+/// Delivers a vendor frame to the plugin. Ids outside
+/// framework::CUSTOM_FRAME_IDS are dropped.
+#[no_mangle]
+pub extern "C" fn wpe_rust_plugin_custom_frame(ptr: *mut CPlugin, id: u32, data: *const u8, len: u32) {
+  assert!(!ptr.is_null());
+
+  if !framework::CUSTOM_FRAME_IDS.contains(&id) {
+    warn!("dropping frame {:#x}, not a custom frame id", id);
+    return;
+  }
+  let plugin = unsafe{ &*ptr };
+  let data = if data.is_null() { &[][..] } else { unsafe { std::slice::from_raw_parts(data, len as usize) } };
+  logging::with_plugin(plugin.plugin_ctx, || plugin.on_custom_frame(id, data));
+}
+
+/// Fills `usage` with the plugin's memory figures. Returns 0 if the plugin
+/// doesn't report any, in which case `usage` is left untouched.
+#[no_mangle]
+pub extern "C" fn wpe_rust_plugin_memory(ptr: *mut CPlugin, usage: *mut MemoryUsage) -> u32 {
+  assert!(!ptr.is_null());
+  assert!(!usage.is_null());
+
+  // Monitor polls from a thread of its own, maybe while an invoke is
+  // running, so this waits its turn for the plugin's lock
+  let plugin = unsafe{ &*ptr };
+  let uncaught_error = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+    logging::with_plugin(plugin.plugin_ctx, || plugin.plugin.lock().unwrap_or_else(|e| e.into_inner()).memory_usage())
   }));
 
   match uncaught_error {
+    Ok(Some(m)) => {
+      unsafe{ *usage = m };
+      1
+    }
+    Ok(None) => 0,
     Err(cause) => {
-      println!("Error calling on_client_disconnect");
-      println!("{:?}", cause);
+      error!("Error calling memory_usage: {:?}", cause);
+      0
     }
-    Ok(_) => { }
+  }
+}
+
+/// Writes the plugin's metrics as JSON into `buf`, NUL terminated and cut
+/// short if `len` is too small. Returns the length the whole snapshot needs,
+/// not counting the NUL, so a caller can retry with a bigger buffer.
+#[no_mangle]
+pub extern "C" fn wpe_rust_plugin_metrics(ptr: *mut CPlugin, buf: *mut c_char, len: u32) -> u32 {
+  assert!(!ptr.is_null());
+
+  // Polled from a thread of its own, maybe while an invoke is running
+  let plugin = unsafe{ &*ptr };
+  let snapshot = plugin.metrics.snapshot().to_string();
+  if !buf.is_null() && len > 0 {
+    let n = snapshot.len().min(len as usize - 1);
+    unsafe {
+      std::ptr::copy_nonoverlapping(snapshot.as_ptr() as *const c_char, buf, n);
+      *buf.add(n) = 0;
+    }
+  }
+  snapshot.len() as u32
+}
+
+/// Registers the callback used to deliver binary frames. Bridges that never
+/// call this can still load the plugin, binary output is dropped.
+#[no_mangle]
+pub extern "C" fn wpe_rust_plugin_set_send_binary(ptr: *mut CPlugin, send_binary: SendBinaryFunction) {
+  assert!(!ptr.is_null());
+
+  let plugin = unsafe{ &*ptr };
+  *plugin.send_binary.lock().unwrap() = Some(send_binary);
+}
+
+/// Registers the callback used to deliver raw socket data, called like the
+/// binary one.
+#[no_mangle]
+pub extern "C" fn wpe_rust_plugin_set_send_raw(ptr: *mut CPlugin, send_raw: SendBinaryFunction) {
+  assert!(!ptr.is_null());
+
+  let plugin = unsafe{ &*ptr };
+  *plugin.send_raw.lock().unwrap() = Some(send_raw);
+}
+
+/// Delivers bytes from a client on a raw socket channel.
+#[no_mangle]
+pub extern "C" fn wpe_rust_plugin_invoke_raw(ptr: *mut CPlugin, data: *const u8, len: u32,
+  req_ctx: CRequestContext)
+{
+  assert!(!ptr.is_null());
+  assert!(!data.is_null() || len == 0);
+
+  let plugin = unsafe{ &*ptr };
+  let data: &[u8] = if len == 0 { &[] } else { unsafe{ std::slice::from_raw_parts(data, len as usize) } };
+  let uncaught_error = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+    logging::with_plugin(plugin.plugin_ctx, || plugin.on_incoming_raw(data, req_ctx));
+  }));
+
+  if let Err(cause) = uncaught_error {
+    error!("Error calling on_raw_data: {:?}", cause);
+  }
+}
+
+#[no_mangle]
+pub extern "C" fn wpe_rust_plugin_invoke_binary(ptr: *mut CPlugin, data: *const u8, len: u32,
+  req_ctx: CRequestContext)
+{
+  assert!(!ptr.is_null());
+  assert!(!data.is_null() || len == 0);
+
+  let plugin = unsafe{ &*ptr };
+  let data: &[u8] = if len == 0 { &[] } else { unsafe{ std::slice::from_raw_parts(data, len as usize) } };
+  let uncaught_error = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+    logging::with_plugin(plugin.plugin_ctx, || plugin.on_incoming_binary(data, req_ctx));
+  }));
+
+  if let Err(cause) = uncaught_error {
+    error!("Error calling on_binary_message: {:?}", cause);
+  }
+}
+
+/// Like wpe_rust_plugin_invoke_binary with the extensible context.
+#[no_mangle]
+pub extern "C" fn wpe_rust_plugin_invoke_binary_v2(ptr: *mut CPlugin, data: *const u8, len: u32,
+  req_ctx: *const CRequestContextV2)
+{
+  assert!(!ptr.is_null());
+  assert!(!req_ctx.is_null());
+
+  let (req_ctx, peer) = unsafe{ &*req_ctx }.split();
+  let plugin = unsafe{ &*ptr };
+  plugin.state().channels.set_peer(req_ctx.channel, peer);
+  wpe_rust_plugin_invoke_binary(ptr, data, len, req_ctx);
+}
+
+#[no_mangle]
+pub extern "C" fn wpe_rust_plugin_on_client_connect(ptr: *mut CPlugin, channel: u32) {
+  assert!(!ptr.is_null());
+
+  let plugin = unsafe{ &*ptr };
+  let uncaught_error = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+    logging::with_plugin(plugin.plugin_ctx, || plugin.on_client_connect(channel));
+  }));
+
+  if let Err(cause) = uncaught_error {
+    error!("Error calling on_client_connect: {:?}", cause);
+  }
+}
+
+#[no_mangle]
+pub extern "C" fn wpe_rust_plugin_on_client_disconnect(ptr: *mut CPlugin, channel: u32) {
+  assert!(!ptr.is_null());
+
+  let plugin = unsafe{ &*ptr };
+  let uncaught_error = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+    logging::with_plugin(plugin.plugin_ctx, || plugin.on_client_disconnect(channel));
+  }));
+
+  if let Err(cause) = uncaught_error {
+    error!("Error calling on_client_disconnect: {:?}", cause);
+  }
+}
+
+/// Drops every client at once, e.g. after Thunder restarted. `reason` is
+/// passed on to Plugin::on_all_clients_disconnected.
+#[no_mangle]
+pub extern "C" fn wpe_rust_plugin_reset(ptr: *mut CPlugin, reason: *const c_char) {
+  assert!(!ptr.is_null());
+
+  let plugin = unsafe{ &*ptr };
+  let reason = cstr_to_string(reason);
+  let uncaught_error = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+    logging::with_plugin(plugin.plugin_ctx, || plugin.on_reset(reason));
+  }));
+
+  if let Err(cause) = uncaught_error {
+    error!("Error calling on_all_clients_disconnected: {:?}", cause);
   }
 }
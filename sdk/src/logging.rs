@@ -0,0 +1,254 @@
+/*
+ * Copyright 2022 Comcast Cable Communications Management, LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+//! A minimal stdout backend for the `log` facade used throughout the SDK and
+//! host. The level is taken from THUNDER_RS_LOG (error, warn, info, debug,
+//! trace or off) and defaults to info. It may be followed by levels for
+//! single modules, e.g. `warn,thunder_rs::router=debug`. Building with the
+//! `production` feature compiles debug and trace logging out entirely.
+//!
+//! Request and response payloads are only logged with THUNDER_RS_LOG_PAYLOADS
+//! set to 1; otherwise lines show their length, as payload() does.
+//!
+//! When Thunder registers a trace callback (wpe_rust_plugin_set_trace_func),
+//! lines go to its Tracing/Messaging system instead of stdout. Each plugin in
+//! a library has its own: a line goes to the callback of the plugin whose
+//! code logged it, and lines from threads of the plugin's own go to the
+//! first one registered. A process that redirects its own stdout can point
+//! the logger elsewhere with set_output.
+use std::cell::Cell;
+use std::ffi::CString;
+use std::os::raw::c_char;
+use std::fmt;
+use std::io::Write;
+use std::sync::{Mutex, OnceLock, RwLock};
+
+use log::{LevelFilter, Log, Metadata, Record};
+
+/// Thunder's trace callback, called with (plugin_ctx, severity, category,
+/// file, line, message). Severity is 1 for errors through 5 for trace; the
+/// category is the module the line was logged from.
+pub type TraceFunction = unsafe extern "C" fn (u32, u32, *const c_char, *const c_char, u32, *const c_char);
+
+/// Each plugin's trace callback, by plugin_ctx, in the order registered
+static TRACE: Mutex<Vec<(u32, TraceFunction)>> = Mutex::new(Vec::new());
+
+thread_local! {
+  /// The plugin_ctx of the plugin running on this thread, if any
+  static CURRENT: Cell<Option<u32>> = const { Cell::new(None) };
+}
+
+/// Sets or, with None, clears the trace callback of the plugin `plugin_ctx`.
+pub(crate) fn set_trace_func(plugin_ctx: u32, trace_func: Option<TraceFunction>) {
+  let mut trace = TRACE.lock().unwrap_or_else(|e| e.into_inner());
+  trace.retain(|(ctx, _)| *ctx != plugin_ctx);
+  if let Some(trace_func) = trace_func {
+    trace.push((plugin_ctx, trace_func));
+  }
+}
+
+/// Runs `f` with what's logged on this thread going to the trace callback
+/// of the plugin `plugin_ctx`.
+pub(crate) fn with_plugin<T>(plugin_ctx: u32, f: impl FnOnce() -> T) -> T {
+  struct Restore(Option<u32>);
+  impl Drop for Restore {
+    fn drop(&mut self) {
+      CURRENT.with(|current| current.set(self.0));
+    }
+  }
+  let _restore = Restore(CURRENT.with(|current| current.replace(Some(plugin_ctx))));
+  f()
+}
+
+/// The callback a line logged on this thread goes to.
+fn trace_func() -> Option<(TraceFunction, u32)> {
+  let trace = TRACE.lock().unwrap_or_else(|e| e.into_inner());
+  let current = CURRENT.with(Cell::get);
+  trace.iter().find(|(ctx, _)| Some(*ctx) == current)
+    .or_else(|| trace.first())
+    .map(|(ctx, trace_func)| (*trace_func, *ctx))
+}
+
+fn to_cstring(s: &str) -> CString {
+  CString::new(s.replace('\0', "")).unwrap_or_default()
+}
+
+/// The level for everything, and levels for modules and what's below them.
+struct Filter {
+  level: LevelFilter,
+  targets: Vec<(String, LevelFilter)>
+}
+
+impl Filter {
+  /// Parses "level,target=level,...". Anything that isn't understood is
+  /// skipped, leaving the default.
+  fn parse(spec: &str) -> Filter {
+    let mut filter = Filter { level: LevelFilter::Info, targets: Vec::new() };
+    for directive in spec.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+      match directive.split_once('=') {
+        Some((target, level)) => {
+          if let Ok(level) = level.trim().parse() {
+            filter.targets.push((target.trim().to_string(), level));
+          }
+        }
+        None => {
+          if let Ok(level) = directive.parse() {
+            filter.level = level;
+          }
+        }
+      }
+    }
+    // The most specific module wins
+    filter.targets.sort_by_key(|(target, _)| std::cmp::Reverse(target.len()));
+    filter
+  }
+
+  fn level(&self, target: &str) -> LevelFilter {
+    self.targets.iter()
+      .find(|(prefix, _)| target == prefix
+        || target.strip_prefix(prefix.as_str()).is_some_and(|rest| rest.starts_with("::")))
+      .map_or(self.level, |(_, level)| *level)
+  }
+
+  /// The most verbose level anything is logged at.
+  fn max(&self) -> LevelFilter {
+    self.targets.iter().map(|(_, level)| *level).fold(self.level, std::cmp::max)
+  }
+}
+
+static FILTER: RwLock<Option<Filter>> = RwLock::new(None);
+
+static OUTPUT: Mutex<Option<Box<dyn Write + Send>>> = Mutex::new(None);
+
+/// Sends log lines to `writer` instead of stdout.
+pub fn set_output(writer: Box<dyn Write + Send>) {
+  *OUTPUT.lock().unwrap_or_else(|e| e.into_inner()) = Some(writer);
+}
+
+struct StdoutLogger;
+
+impl Log for StdoutLogger {
+  fn enabled(&self, metadata: &Metadata) -> bool {
+    if metadata.level() > log::max_level() {
+      return false;
+    }
+    match &*FILTER.read().unwrap_or_else(|e| e.into_inner()) {
+      Some(filter) => metadata.level() <= filter.level(metadata.target()),
+      None => true
+    }
+  }
+
+  fn log(&self, record: &Record) {
+    if !self.enabled(record.metadata()) {
+      return;
+    }
+    match trace_func() {
+      Some((trace_func, plugin_ctx)) => {
+        let category = to_cstring(record.target());
+        let file = to_cstring(record.file().unwrap_or(""));
+        let message = to_cstring(&record.args().to_string());
+        unsafe {
+          trace_func(plugin_ctx, record.level() as u32, category.as_ptr(), file.as_ptr(),
+            record.line().unwrap_or(0), message.as_ptr());
+        }
+      }
+      None => match &mut *OUTPUT.lock().unwrap_or_else(|e| e.into_inner()) {
+        Some(output) => {
+          let _ = writeln!(output, "{:<5} {}", record.level(), record.args());
+        }
+        None => println!("{:<5} {}", record.level(), record.args())
+      }
+    }
+  }
+
+  fn flush(&self) { }
+}
+
+static LOGGER: StdoutLogger = StdoutLogger;
+
+/// The level requested through THUNDER_RS_LOG, leaving out any levels
+/// given for single modules.
+pub fn env_level() -> LevelFilter {
+  Filter::parse(&std::env::var("THUNDER_RS_LOG").unwrap_or_default()).level
+}
+
+/// Installs the stdout logger unless the process already has a logger.
+pub fn init() {
+  if log::set_logger(&LOGGER).is_ok() {
+    set_levels(&std::env::var("THUNDER_RS_LOG").unwrap_or_default());
+  }
+}
+
+/// Changes what the stdout logger logs while running, taking the same
+/// "level,target=level" list as THUNDER_RS_LOG.
+pub fn set_levels(spec: &str) {
+  let filter = Filter::parse(spec);
+  log::set_max_level(filter.max());
+  *FILTER.write().unwrap_or_else(|e| e.into_inner()) = Some(filter);
+}
+
+/// Whether THUNDER_RS_LOG_PAYLOADS asks for payloads in the log.
+pub fn log_payloads() -> bool {
+  static PAYLOADS: OnceLock<bool> = OnceLock::new();
+  *PAYLOADS.get_or_init(|| std::env::var("THUNDER_RS_LOG_PAYLOADS").is_ok_and(|v| v == "1"))
+}
+
+/// A payload for a log line: the text itself if payloads are logged, its
+/// length otherwise.
+pub struct Payload<'a>(&'a [u8]);
+
+pub fn payload(data: &[u8]) -> Payload<'_> {
+  Payload(data)
+}
+
+impl fmt::Display for Payload<'_> {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    if log_payloads() {
+      write!(f, "{}", String::from_utf8_lossy(self.0))
+    } else {
+      write!(f, "<{} bytes>", self.0.len())
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  unsafe extern "C" fn ignore(_: u32, _: u32, _: *const c_char, _: *const c_char, _: u32, _: *const c_char) { }
+
+  fn routed_to() -> Option<u32> {
+    trace_func().map(|(_, ctx)| ctx)
+  }
+
+  #[test]
+  fn lines_go_to_the_plugin_that_logged_them() {
+    set_trace_func(1, Some(ignore));
+    set_trace_func(2, Some(ignore));
+    assert_eq!(with_plugin(2, routed_to), Some(2));
+    assert_eq!(with_plugin(1, || with_plugin(2, routed_to)), Some(2));
+    // Anything else goes to the first registered
+    assert_eq!(routed_to(), Some(1));
+    assert_eq!(with_plugin(3, routed_to), Some(1));
+
+    // Destroying one plugin leaves the other's callback
+    set_trace_func(1, None);
+    assert_eq!(routed_to(), Some(2));
+    set_trace_func(2, None);
+    assert_eq!(with_plugin(2, routed_to), None);
+  }
+}
@@ -0,0 +1,288 @@
+/*
+ * Copyright 2022 Comcast Cable Communications Management, LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+//! Counters, gauges and latency histograms a plugin records into, which
+//! Thunder can read as a JSON snapshot (wpe_rust_plugin_metrics, or the
+//! host's ID_METRICS command) without the plugin exposing them itself. A
+//! host can serve them to Prometheus too, written out by Exposition.
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use serde_json::{json, Value};
+
+/// Upper bounds of the histogram buckets, in milliseconds.
+const BUCKETS_MS: [u64; 12] = [1, 2, 5, 10, 25, 50, 100, 250, 500, 1000, 2500, 5000];
+
+#[derive(Clone, Default)]
+pub struct Counter(Arc<AtomicU64>);
+
+impl Counter {
+  pub fn inc(&self) {
+    self.add(1);
+  }
+
+  pub fn add(&self, n: u64) {
+    self.0.fetch_add(n, Ordering::Relaxed);
+  }
+
+  pub fn get(&self) -> u64 {
+    self.0.load(Ordering::Relaxed)
+  }
+}
+
+#[derive(Clone, Default)]
+pub struct Gauge(Arc<AtomicI64>);
+
+impl Gauge {
+  pub fn set(&self, value: i64) {
+    self.0.store(value, Ordering::Relaxed);
+  }
+
+  pub fn add(&self, n: i64) {
+    self.0.fetch_add(n, Ordering::Relaxed);
+  }
+
+  pub fn get(&self) -> i64 {
+    self.0.load(Ordering::Relaxed)
+  }
+}
+
+#[derive(Default)]
+struct HistogramInner {
+  // One more than BUCKETS_MS, for everything slower
+  buckets: [AtomicU64; BUCKETS_MS.len() + 1],
+  count: AtomicU64,
+  sum_us: AtomicU64
+}
+
+/// Latencies, counted into fixed buckets from 1ms to 5s.
+#[derive(Clone, Default)]
+pub struct Histogram(Arc<HistogramInner>);
+
+impl Histogram {
+  pub fn observe(&self, elapsed: Duration) {
+    let ms = elapsed.as_millis() as u64;
+    let bucket = BUCKETS_MS.iter().position(|b| ms <= *b).unwrap_or(BUCKETS_MS.len());
+    self.0.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+    self.0.count.fetch_add(1, Ordering::Relaxed);
+    self.0.sum_us.fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+  }
+
+  /// Observes the time until the returned guard is dropped.
+  pub fn start_timer(&self) -> Timer {
+    Timer { histogram: self.clone(), start: Instant::now() }
+  }
+
+  pub fn count(&self) -> u64 {
+    self.0.count.load(Ordering::Relaxed)
+  }
+
+  /// Buckets are listed in order as {"le_ms": bound, "count": n}; the last
+  /// one has a null bound and counts everything slower.
+  fn snapshot(&self) -> Value {
+    let buckets: Vec<Value> = self.0.buckets.iter().enumerate()
+      .map(|(i, n)| json!({
+        "le_ms": BUCKETS_MS.get(i),
+        "count": n.load(Ordering::Relaxed)
+      }))
+      .collect();
+    json!({
+      "count": self.count(),
+      "sum_ms": self.0.sum_us.load(Ordering::Relaxed) as f64 / 1000.0,
+      "buckets_ms": buckets
+    })
+  }
+}
+
+pub struct Timer {
+  histogram: Histogram,
+  start: Instant
+}
+
+impl Drop for Timer {
+  fn drop(&mut self) {
+    self.histogram.observe(self.start.elapsed());
+  }
+}
+
+/// The current value of one metric, as Metrics::read returns it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetricValue {
+  Counter(u64),
+  Gauge(i64),
+  /// Number of samples
+  Histogram(u64)
+}
+
+#[derive(Default)]
+struct Inner {
+  counters: BTreeMap<String, Counter>,
+  gauges: BTreeMap<String, Gauge>,
+  histograms: BTreeMap<String, Histogram>
+}
+
+/// The plugin's metrics, handed to it in PluginConfig. Asking for a metric
+/// that doesn't exist yet creates it, so handles can be fetched wherever
+/// they're needed or kept around to skip the lookup.
+#[derive(Clone, Default)]
+pub struct Metrics {
+  inner: Arc<Mutex<Inner>>
+}
+
+impl Metrics {
+  pub fn new() -> Self {
+    Metrics::default()
+  }
+
+  pub fn counter(&self, name: &str) -> Counter {
+    self.inner.lock().unwrap().counters.entry(name.to_string()).or_default().clone()
+  }
+
+  pub fn gauge(&self, name: &str) -> Gauge {
+    self.inner.lock().unwrap().gauges.entry(name.to_string()).or_default().clone()
+  }
+
+  pub fn histogram(&self, name: &str) -> Histogram {
+    self.inner.lock().unwrap().histograms.entry(name.to_string()).or_default().clone()
+  }
+
+  /// The value of the metric called `name`, if there is one.
+  pub fn read(&self, name: &str) -> Option<MetricValue> {
+    let inner = self.inner.lock().unwrap();
+    if let Some(c) = inner.counters.get(name) {
+      Some(MetricValue::Counter(c.get()))
+    } else if let Some(g) = inner.gauges.get(name) {
+      Some(MetricValue::Gauge(g.get()))
+    } else {
+      inner.histograms.get(name).map(|h| MetricValue::Histogram(h.count()))
+    }
+  }
+
+  /// Every metric's current value.
+  pub fn snapshot(&self) -> Value {
+    let inner = self.inner.lock().unwrap();
+    json!({
+      "counters": inner.counters.iter().map(|(k, c)| (k.clone(), json!(c.get()))).collect::<serde_json::Map<_, _>>(),
+      "gauges": inner.gauges.iter().map(|(k, g)| (k.clone(), json!(g.get()))).collect::<serde_json::Map<_, _>>(),
+      "histograms": inner.histograms.iter().map(|(k, h)| (k.clone(), h.snapshot())).collect::<serde_json::Map<_, _>>()
+    })
+  }
+}
+
+/// Metrics in Prometheus' text format. Samples of the same metric are
+/// written together, in whatever order they're added.
+#[derive(Default)]
+pub struct Exposition {
+  families: BTreeMap<String, Family>
+}
+
+struct Family {
+  kind: &'static str,
+  help: String,
+  samples: Vec<String>
+}
+
+impl Exposition {
+  pub fn new() -> Self {
+    Exposition::default()
+  }
+
+  pub fn counter(&mut self, name: &str, help: &str, labels: &[(&str, &str)], value: u64) {
+    self.family(name, "counter", help).push(format!("{}{} {}", name, label_set(labels, None), value));
+  }
+
+  pub fn gauge(&mut self, name: &str, help: &str, labels: &[(&str, &str)], value: i64) {
+    self.family(name, "gauge", help).push(format!("{}{} {}", name, label_set(labels, None), value));
+  }
+
+  /// A histogram, in seconds as Prometheus has it.
+  pub fn histogram(&mut self, name: &str, help: &str, labels: &[(&str, &str)], histogram: &Histogram) {
+    let inner = &histogram.0;
+    let samples = self.family(name, "histogram", help);
+    let mut cumulative = 0;
+    for (i, n) in inner.buckets.iter().enumerate() {
+      cumulative += n.load(Ordering::Relaxed);
+      let le = match BUCKETS_MS.get(i) {
+        Some(ms) => (*ms as f64 / 1000.0).to_string(),
+        None => String::from("+Inf")
+      };
+      samples.push(format!("{}_bucket{} {}", name, label_set(labels, Some(&le)), cumulative));
+    }
+    let sum = inner.sum_us.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+    samples.push(format!("{}_sum{} {}", name, label_set(labels, None), sum));
+    samples.push(format!("{}_count{} {}", name, label_set(labels, None), histogram.count()));
+  }
+
+  /// Every metric in `metrics`, named `prefix`_ and its name with what
+  /// Prometheus doesn't allow in a name replaced by '_'. Counters end in
+  /// _total and histograms in _seconds.
+  pub fn metrics(&mut self, prefix: &str, labels: &[(&str, &str)], metrics: &Metrics) {
+    let inner = metrics.inner.lock().unwrap();
+    for (name, counter) in &inner.counters {
+      let help = format!("Counter {}", name);
+      self.counter(&format!("{}_{}_total", prefix, sanitize(name)), &help, labels, counter.get());
+    }
+    for (name, gauge) in &inner.gauges {
+      let help = format!("Gauge {}", name);
+      self.gauge(&format!("{}_{}", prefix, sanitize(name)), &help, labels, gauge.get());
+    }
+    for (name, histogram) in &inner.histograms {
+      let help = format!("Histogram {}", name);
+      self.histogram(&format!("{}_{}_seconds", prefix, sanitize(name)), &help, labels, histogram);
+    }
+  }
+
+  fn family(&mut self, name: &str, kind: &'static str, help: &str) -> &mut Vec<String> {
+    &mut self.families.entry(name.to_string())
+      .or_insert_with(|| Family { kind, help: help.to_string(), samples: Vec::new() })
+      .samples
+  }
+}
+
+impl fmt::Display for Exposition {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    for (name, family) in &self.families {
+      writeln!(f, "# HELP {} {}", name, family.help.replace('\\', "\\\\").replace('\n', "\\n"))?;
+      writeln!(f, "# TYPE {} {}", name, family.kind)?;
+      for sample in &family.samples {
+        writeln!(f, "{}", sample)?;
+      }
+    }
+    Ok(())
+  }
+}
+
+fn sanitize(name: &str) -> String {
+  name.chars().map(|c| if c.is_ascii_alphanumeric() || c == '_' { c } else { '_' }).collect()
+}
+
+/// `labels`, and `le` for a histogram bucket, as {name="value",...}.
+fn label_set(labels: &[(&str, &str)], le: Option<&str>) -> String {
+  let labels: Vec<String> = labels.iter().copied()
+    .chain(le.map(|le| ("le", le)))
+    .map(|(name, value)| format!("{}=\"{}\"", name,
+      value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")))
+    .collect();
+  if labels.is_empty() {
+    String::new()
+  } else {
+    format!("{{{}}}", labels.join(","))
+  }
+}
@@ -0,0 +1,78 @@
+/*
+ * Copyright 2022 Comcast Cable Communications Management, LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+//! The directories Thunder gives each plugin. Bridges pass them in the
+//! plugin config as
+//!
+//!   {"paths": {"persistent": "/opt/persistent/Player/", "volatile": "/tmp/Player/", "data": "/usr/share/WPEFramework/Player/"}}
+//!
+//! and THUNDER_RS_PERSISTENT_PATH, THUNDER_RS_VOLATILE_PATH and
+//! THUNDER_RS_DATA_PATH stand in for any that are missing.
+use std::path::{Path, PathBuf};
+
+use serde_json::Value;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Paths {
+  persistent: Option<PathBuf>,
+  volatile: Option<PathBuf>,
+  data: Option<PathBuf>
+}
+
+impl Paths {
+  pub fn from_config(config: &Value) -> Self {
+    let path = |key: &str, var: &str| {
+      config["paths"][key].as_str().map(PathBuf::from)
+        .or_else(|| std::env::var_os(var).map(PathBuf::from))
+    };
+    Paths {
+      persistent: path("persistent", "THUNDER_RS_PERSISTENT_PATH"),
+      volatile: path("volatile", "THUNDER_RS_VOLATILE_PATH"),
+      data: path("data", "THUNDER_RS_DATA_PATH")
+    }
+  }
+
+  /// For state that has to survive a reboot.
+  pub fn persistent(&self) -> Option<&Path> {
+    self.persistent.as_deref()
+  }
+
+  /// For state that only has to last until the next reboot.
+  pub fn volatile(&self) -> Option<&Path> {
+    self.volatile.as_deref()
+  }
+
+  /// Read-only files installed with the plugin.
+  pub fn data(&self) -> Option<&Path> {
+    self.data.as_deref()
+  }
+
+  pub fn with_persistent(mut self, path: impl Into<PathBuf>) -> Self {
+    self.persistent = Some(path.into());
+    self
+  }
+
+  pub fn with_volatile(mut self, path: impl Into<PathBuf>) -> Self {
+    self.volatile = Some(path.into());
+    self
+  }
+
+  pub fn with_data(mut self, path: impl Into<PathBuf>) -> Self {
+    self.data = Some(path.into());
+    self
+  }
+}
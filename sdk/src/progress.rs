@@ -0,0 +1,113 @@
+/*
+ * Copyright 2022 Comcast Cable Communications Management, LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use serde_json::{json, Value};
+
+use crate::{jsonrpc, RequestContext, SendError};
+
+/// Method name of progress notifications.
+pub const PROGRESS_METHOD: &str = "progress";
+/// Method name of the notification that ends an operation.
+pub const COMPLETE_METHOD: &str = "complete";
+
+static NEXT_OPERATION: AtomicU64 = AtomicU64::new(1);
+
+/// Reports on a long running operation, e.g. a firmware update, to the
+/// client that started it. The handler answers the request straight away
+/// with the operation id and hands the reporter to whatever does the work,
+/// which sends
+///
+///   {"jsonrpc":"2.0","method":"progress","params":{"operation":n,"percent":40,"stage":"flashing"}}
+///
+/// as it goes and finally
+///
+///   {"jsonrpc":"2.0","method":"complete","params":{"operation":n,"result":...}}
+///
+/// with "error" instead of "result" if it failed. A reporter dropped before
+/// complete() or fail() reports the operation as abandoned.
+pub struct ProgressReporter {
+  ctx: RequestContext,
+  operation: u64,
+  last: Option<(u8, String)>,
+  done: bool
+}
+
+impl ProgressReporter {
+  pub fn new(ctx: &RequestContext) -> Self {
+    ProgressReporter {
+      ctx: ctx.clone(),
+      operation: NEXT_OPERATION.fetch_add(1, Ordering::Relaxed),
+      last: None,
+      done: false
+    }
+  }
+
+  /// The id carried by every notification, to return to the client.
+  pub fn operation(&self) -> u64 {
+    self.operation
+  }
+
+  pub fn context(&self) -> &RequestContext {
+    &self.ctx
+  }
+
+  /// Sends `percent`, capped at 100, and the current stage. Nothing is
+  /// sent if neither changed since the last report, so it's fine to call
+  /// this from a tight loop. Fails once the client has gone, which is the
+  /// cue to give up on work nobody is waiting for.
+  pub fn report(&mut self, percent: u8, stage: &str) -> Result<(), SendError> {
+    let percent = percent.min(100);
+    if self.last.as_ref().is_some_and(|(p, s)| *p == percent && s == stage) {
+      if !self.ctx.is_connected() {
+        return Err(SendError::ChannelClosed(self.ctx.channel));
+      }
+      return Ok(());
+    }
+    self.ctx.send(jsonrpc::notification(PROGRESS_METHOD, &json!({
+      "operation": self.operation,
+      "percent": percent,
+      "stage": stage
+    })))?;
+    self.last = Some((percent, stage.to_string()));
+    Ok(())
+  }
+
+  pub fn complete(mut self, result: Value) -> Result<(), SendError> {
+    self.done = true;
+    self.finish(json!({ "operation": self.operation, "result": result }))
+  }
+
+  pub fn fail(mut self, error: jsonrpc::Error) -> Result<(), SendError> {
+    self.done = true;
+    self.finish(json!({ "operation": self.operation, "error": error.to_value() }))
+  }
+
+  fn finish(&self, params: Value) -> Result<(), SendError> {
+    self.ctx.send(jsonrpc::notification(COMPLETE_METHOD, &params))
+  }
+}
+
+impl Drop for ProgressReporter {
+  fn drop(&mut self) {
+    if !self.done {
+      let err = jsonrpc::Error::new(jsonrpc::INTERNAL_ERROR, "Operation abandoned");
+      let _ = self.finish(json!({ "operation": self.operation, "error": err.to_value() }));
+    }
+  }
+}
@@ -0,0 +1,181 @@
+/*
+ * Copyright 2022 Comcast Cable Communications Management, LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+use std::collections::HashMap;
+use std::time::Instant;
+
+use log::warn;
+
+use crate::jsonrpc;
+use crate::RequestContext;
+
+/// Token bucket settings applied to each channel independently. A channel
+/// may burst up to `capacity` requests and then gets `refill_per_sec` more
+/// every second.
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimit {
+  pub capacity: u32,
+  pub refill_per_sec: u32
+}
+
+struct Bucket {
+  tokens: f64,
+  last_refill: Instant
+}
+
+pub struct RateLimiter {
+  limit: RateLimit,
+  buckets: HashMap<u32, Bucket>
+}
+
+impl RateLimiter {
+  pub fn new(limit: RateLimit) -> Self {
+    RateLimiter {
+      limit,
+      buckets: HashMap::new()
+    }
+  }
+
+  /// Takes a token from the channel's bucket. Returns false if the channel
+  /// is over its limit and the request should be rejected.
+  pub fn try_acquire(&mut self, channel: u32) -> bool {
+    let limit = self.limit;
+    let now = Instant::now();
+    let bucket = self.buckets.entry(channel).or_insert(Bucket {
+      tokens: limit.capacity as f64,
+      last_refill: now
+    });
+
+    let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+    bucket.tokens = (bucket.tokens + elapsed * limit.refill_per_sec as f64)
+      .min(limit.capacity as f64);
+    bucket.last_refill = now;
+
+    if bucket.tokens >= 1.0 {
+      bucket.tokens -= 1.0;
+      true
+    }
+    else {
+      false
+    }
+  }
+
+  /// Checks the limit for an incoming request, answering it with a JSON-RPC
+  /// error if the channel is over its limit. Returns true if the request
+  /// should be dispatched.
+  pub fn admit(&mut self, json: &str, ctx: &RequestContext) -> bool {
+    if self.try_acquire(ctx.channel) {
+      return true;
+    }
+    warn!("rate limit exceeded on channel {}", ctx.channel);
+    if let Some(id) = jsonrpc::request_id(json) {
+      let _ = ctx.send(jsonrpc::error_response(&id, jsonrpc::TOO_MANY_REQUESTS, "Too many requests"));
+    }
+    false
+  }
+
+  /// Checks the limit for a binary frame or raw data. Over the limit it's
+  /// dropped with only a warning in the log: unlike a request it has no id
+  /// an error could answer.
+  pub fn admit_frame(&mut self, channel: u32) -> bool {
+    if self.try_acquire(channel) {
+      return true;
+    }
+    warn!("rate limit exceeded on channel {}, dropping a frame", channel);
+    false
+  }
+
+  pub fn forget(&mut self, channel: u32) {
+    self.buckets.remove(&channel);
+  }
+
+  pub fn forget_all(&mut self) {
+    self.buckets.clear();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::time::Duration;
+
+  use crate::testing::FakeContext;
+
+  fn limiter(capacity: u32, refill_per_sec: u32) -> RateLimiter {
+    RateLimiter::new(RateLimit { capacity, refill_per_sec })
+  }
+
+  /// As if `secs` had gone by since the channel's bucket was last refilled.
+  fn wait(limiter: &mut RateLimiter, channel: u32, secs: u64) {
+    limiter.buckets.get_mut(&channel).unwrap().last_refill -= Duration::from_secs(secs);
+  }
+
+  #[test]
+  fn bursts_up_to_capacity() {
+    let mut limiter = limiter(3, 0);
+    assert!((0..3).all(|_| limiter.try_acquire(1)));
+    assert!(!limiter.try_acquire(1));
+  }
+
+  #[test]
+  fn refills_over_time_up_to_capacity() {
+    let mut limiter = limiter(2, 1);
+    assert!(limiter.try_acquire(1) && limiter.try_acquire(1));
+    assert!(!limiter.try_acquire(1));
+    wait(&mut limiter, 1, 1);
+    assert!(limiter.try_acquire(1));
+    assert!(!limiter.try_acquire(1));
+    // Never more than capacity, however long the channel was idle
+    wait(&mut limiter, 1, 60);
+    assert!(limiter.try_acquire(1) && limiter.try_acquire(1));
+    assert!(!limiter.try_acquire(1));
+  }
+
+  #[test]
+  fn channels_have_buckets_of_their_own() {
+    let mut limiter = limiter(1, 0);
+    assert!(limiter.try_acquire(1));
+    assert!(!limiter.try_acquire(1));
+    assert!(limiter.try_acquire(2));
+    assert!(!limiter.admit_frame(2));
+  }
+
+  #[test]
+  fn forgotten_channels_start_full() {
+    let mut limiter = limiter(1, 0);
+    assert!(limiter.try_acquire(1) && limiter.try_acquire(2));
+    limiter.forget(1);
+    assert!(limiter.try_acquire(1));
+    assert!(!limiter.try_acquire(2));
+    limiter.forget_all();
+    assert!(limiter.try_acquire(1) && limiter.try_acquire(2));
+  }
+
+  #[test]
+  fn requests_over_the_limit_are_answered() {
+    let mut limiter = limiter(1, 0);
+    let fake = FakeContext::builder().channel(4).build();
+    assert!(limiter.admit(r#"{"jsonrpc":"2.0","id":1,"method":"a"}"#, &fake.context()));
+    assert!(!limiter.admit(r#"{"jsonrpc":"2.0","id":2,"method":"a"}"#, &fake.context()));
+    // A notification has no id to answer
+    assert!(!limiter.admit(r#"{"jsonrpc":"2.0","method":"a"}"#, &fake.context()));
+    let sent = fake.sent_json();
+    assert_eq!(sent.len(), 1);
+    assert_eq!(sent[0]["id"], 2);
+    assert_eq!(sent[0]["error"]["code"], jsonrpc::TOO_MANY_REQUESTS);
+  }
+}
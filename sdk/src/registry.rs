@@ -0,0 +1,186 @@
+/*
+ * Copyright 2022 Comcast Cable Communications Management, LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+//! Lets plugins living in the same process image find each other by callsign
+//! and call each other's methods directly instead of going through Thunder.
+//!
+//! The registry belongs to the copy of the SDK linked into a library, so it
+//! only spans plugins built into the same library. Plugins in libraries of
+//! their own each see a registry of their own.
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock, RwLock};
+
+use log::warn;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::responder::{self, QueueLimit};
+use crate::router::{method_name, Router};
+use crate::{jsonrpc, Channels};
+
+/// What a plugin makes available to its siblings.
+#[derive(Debug, Clone, Default)]
+pub struct Exports {
+  /// Methods siblings may call. Everything else is refused.
+  pub methods: Vec<String>,
+  /// Callsigns allowed to look this plugin up. None allows everyone.
+  pub callers: Option<Vec<String>>
+}
+
+struct Entry {
+  // The registration the entry belongs to
+  id: u64,
+  router: Arc<Router>,
+  exports: Exports
+}
+
+fn entries() -> &'static RwLock<HashMap<String, Entry>> {
+  static ENTRIES: OnceLock<RwLock<HashMap<String, Entry>>> = OnceLock::new();
+  ENTRIES.get_or_init(|| RwLock::new(HashMap::new()))
+}
+
+/// Keeps a plugin registered. Dropping it (typically when the plugin itself
+/// is dropped) removes the entry, unless a later registration for the same
+/// callsign has replaced it.
+pub struct Registration {
+  callsign: String,
+  id: u64
+}
+
+impl Drop for Registration {
+  fn drop(&mut self) {
+    let mut entries = entries().write().unwrap();
+    if entries.get(&self.callsign).is_some_and(|entry| entry.id == self.id) {
+      entries.remove(&self.callsign);
+    }
+  }
+}
+
+/// Publishes `router` under `callsign`. An existing entry for the same
+/// callsign is replaced.
+pub fn register(callsign: &str, router: Arc<Router>, exports: Exports) -> Registration {
+  static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+  let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+  entries().write().unwrap().insert(callsign.to_string(), Entry { id, router, exports });
+  Registration {
+    callsign: callsign.to_string(),
+    id
+  }
+}
+
+/// Finds the plugin registered as `callsign` on behalf of `caller`. Returns
+/// None if there's no such plugin or `caller` isn't allowed to use it.
+pub fn lookup(callsign: &str, caller: &str) -> Option<Client> {
+  let entries = entries().read().unwrap();
+  let entry = entries.get(callsign)?;
+  if let Some(callers) = &entry.exports.callers {
+    if !callers.iter().any(|c| c == caller) {
+      warn!("{} is not allowed to use {}", caller, callsign);
+      return None;
+    }
+  }
+  Some(Client {
+    callsign: callsign.to_string(),
+    router: entry.router.clone(),
+    methods: entry.exports.methods.clone()
+  })
+}
+
+/// A handle for calling a sibling plugin, shaped like a remote JSON-RPC
+/// client so code can move between the two.
+#[derive(Clone)]
+pub struct Client {
+  callsign: String,
+  router: Arc<Router>,
+  methods: Vec<String>
+}
+
+impl Client {
+  pub fn callsign(&self) -> &str {
+    &self.callsign
+  }
+
+  pub fn call(&self, method: &str, params: Value) -> Result<Value, jsonrpc::Error> {
+    let name = method_name(method);
+    if !self.methods.iter().any(|m| m == name) {
+      return Err(jsonrpc::Error::new(jsonrpc::ACCESS_DENIED,
+        &format!("{} does not export {}", self.callsign, name)));
+    }
+
+    // Local calls have no client channel to answer on, so anything the
+    // handler sends outside its return value is dropped.
+    let (responder, _) = responder::queue(QueueLimit::default());
+    let ctx = Channels::new().context(0, String::new(), responder);
+    self.router.call(name, params, &ctx)
+  }
+
+  /// Same as call() with params and result converted through serde.
+  pub fn call_as<P, R>(&self, method: &str, params: &P) -> Result<R, jsonrpc::Error>
+    where P: Serialize, R: DeserializeOwned
+  {
+    let params = serde_json::to_value(params)
+      .map_err(|e| jsonrpc::Error::invalid_params(&e.to_string()))?;
+    let result = self.call(method, params)?;
+    serde_json::from_value(result)
+      .map_err(|e| jsonrpc::Error::new(jsonrpc::INTERNAL_ERROR, &e.to_string()))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn only_allowed_callers_find_a_plugin() {
+    let exports = Exports { methods: Vec::new(), callers: Some(vec![String::from("Friend")]) };
+    let _registration = register("RegistryTest.Private", Arc::new(Router::new()), exports);
+    assert!(lookup("RegistryTest.Private", "Friend").is_some());
+    assert!(lookup("RegistryTest.Private", "Stranger").is_none());
+  }
+
+  #[test]
+  fn only_exported_methods_can_be_called() {
+    let mut router = Router::new();
+    router.register("add", |params, _ctx| Ok(serde_json::json!(params[0].as_i64().unwrap_or(0) + 1)));
+    router.register("reset", |_params, _ctx| Ok(Value::Null));
+    let exports = Exports { methods: vec![String::from("add")], callers: None };
+    let _registration = register("RegistryTest.Calc", Arc::new(router), exports);
+    let calc = lookup("RegistryTest.Calc", "Caller").unwrap();
+    assert_eq!(calc.call("add", serde_json::json!([1])), Ok(serde_json::json!(2)));
+    assert_eq!(calc.call("reset", Value::Null).unwrap_err().code, jsonrpc::ACCESS_DENIED);
+  }
+
+  #[test]
+  fn an_old_registration_doesnt_remove_a_newer_one() {
+    let old = register("RegistryTest.Replaced", Arc::new(Router::new()), Exports::default());
+    let new = register("RegistryTest.Replaced", Arc::new(Router::new()), Exports::default());
+    drop(old);
+    assert!(lookup("RegistryTest.Replaced", "Caller").is_some());
+    drop(new);
+    assert!(lookup("RegistryTest.Replaced", "Caller").is_none());
+  }
+
+  #[test]
+  fn dropping_the_registration_removes_the_plugin() {
+    let registration = register("RegistryTest.Dropped", Arc::new(Router::new()), Exports::default());
+    assert!(lookup("RegistryTest.Dropped", "Caller").is_some());
+    drop(registration);
+    assert!(lookup("RegistryTest.Dropped", "Caller").is_none());
+  }
+}
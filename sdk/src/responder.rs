@@ -0,0 +1,317 @@
+/*
+ * Copyright 2022 Comcast Cable Communications Management, LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+use std::collections::VecDeque;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use log::warn;
+
+use crate::{Message, SendError};
+
+/// What happens when a plugin produces messages faster than the bridge can
+/// deliver them and the responder queue fills up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+  /// The sending thread waits until there's room.
+  Block,
+  /// The oldest queued message is discarded to make room.
+  DropOldest,
+  /// send() fails with SendError::QueueFull.
+  Error
+}
+
+/// A capacity below 1 is taken as 1, as nothing could ever be queued.
+#[derive(Debug, Clone, Copy)]
+pub struct QueueLimit {
+  pub capacity: usize,
+  pub policy: OverflowPolicy
+}
+
+impl Default for QueueLimit {
+  fn default() -> Self {
+    QueueLimit {
+      capacity: 1024,
+      policy: OverflowPolicy::Block
+    }
+  }
+}
+
+struct State {
+  messages: VecDeque<Message>,
+  senders: usize,
+  receiver_alive: bool,
+  closed: bool
+}
+
+struct Shared {
+  limit: QueueLimit,
+  state: Mutex<State>,
+  not_empty: Condvar,
+  not_full: Condvar
+}
+
+/// The sending half of the queue between the plugin and the thread that
+/// delivers messages to Thunder.
+pub struct Responder {
+  shared: Arc<Shared>
+}
+
+pub struct Receiver {
+  shared: Arc<Shared>
+}
+
+pub fn queue(mut limit: QueueLimit) -> (Responder, Receiver) {
+  if limit.capacity == 0 {
+    warn!("responder queue capacity of 0, using 1");
+    limit.capacity = 1;
+  }
+  let shared = Arc::new(Shared {
+    limit,
+    state: Mutex::new(State {
+      messages: VecDeque::new(),
+      senders: 1,
+      receiver_alive: true,
+      closed: false
+    }),
+    not_empty: Condvar::new(),
+    not_full: Condvar::new()
+  });
+  (Responder { shared: shared.clone() }, Receiver { shared })
+}
+
+impl Responder {
+  pub fn send(&self, m: Message) -> Result<(), SendError> {
+    let limit = self.shared.limit;
+    let mut state = self.shared.state.lock().unwrap();
+
+    while state.receiver_alive && !state.closed && state.messages.len() >= limit.capacity {
+      match limit.policy {
+        OverflowPolicy::Block => {
+          state = self.shared.not_full.wait(state).unwrap();
+        }
+        OverflowPolicy::DropOldest => {
+          if let Some(dropped) = state.messages.pop_front() {
+            warn!("responder queue full, dropping message for channel {}", dropped.channel);
+          }
+        }
+        OverflowPolicy::Error => {
+          return Err(SendError::QueueFull);
+        }
+      }
+    }
+
+    if !state.receiver_alive || state.closed {
+      return Err(SendError::Shutdown);
+    }
+
+    state.messages.push_back(m);
+    self.shared.not_empty.notify_one();
+    Ok(())
+  }
+
+  /// Like send, but fails with SendError::QueueFull when the queue is full
+  /// whatever its policy, so the caller never waits on the bridge.
+  pub fn try_send(&self, m: Message) -> Result<(), SendError> {
+    let mut state = self.shared.state.lock().unwrap();
+    if !state.receiver_alive || state.closed {
+      return Err(SendError::Shutdown);
+    }
+    if state.messages.len() >= self.shared.limit.capacity {
+      return Err(SendError::QueueFull);
+    }
+    state.messages.push_back(m);
+    self.shared.not_empty.notify_one();
+    Ok(())
+  }
+}
+
+impl Responder {
+  /// Stops the queue from taking new messages, for every clone of this
+  /// responder. What's already queued is still delivered and the receiver
+  /// then sees the end of the queue.
+  pub fn close(&self) {
+    self.shared.state.lock().unwrap().closed = true;
+    self.shared.not_empty.notify_all();
+    self.shared.not_full.notify_all();
+  }
+
+  /// The number of messages waiting to be delivered.
+  pub fn queued(&self) -> usize {
+    self.shared.state.lock().unwrap().messages.len()
+  }
+
+  /// Throws away messages still waiting to be delivered.
+  pub fn discard(&self) -> usize {
+    let mut state = self.shared.state.lock().unwrap();
+    let n = state.messages.len();
+    state.messages.clear();
+    self.shared.not_full.notify_all();
+    n
+  }
+}
+
+impl Clone for Responder {
+  fn clone(&self) -> Self {
+    self.shared.state.lock().unwrap().senders += 1;
+    Responder { shared: self.shared.clone() }
+  }
+}
+
+impl Drop for Responder {
+  fn drop(&mut self) {
+    let mut state = self.shared.state.lock().unwrap();
+    state.senders -= 1;
+    if state.senders == 0 {
+      self.shared.not_empty.notify_all();
+    }
+  }
+}
+
+impl Receiver {
+  /// Waits for the next message. Returns None once every Responder has been
+  /// dropped or the queue closed, and the queue is empty.
+  pub fn recv(&self) -> Option<Message> {
+    let mut state = self.shared.state.lock().unwrap();
+    loop {
+      if let Some(m) = state.messages.pop_front() {
+        self.shared.not_full.notify_one();
+        return Some(m);
+      }
+      if state.senders == 0 || state.closed {
+        return None;
+      }
+      state = self.shared.not_empty.wait(state).unwrap();
+    }
+  }
+
+  /// Like recv() but gives up after `timeout`.
+  pub fn recv_timeout(&self, timeout: Duration) -> Option<Message> {
+    let deadline = Instant::now() + timeout;
+    let mut state = self.shared.state.lock().unwrap();
+    loop {
+      if let Some(m) = state.messages.pop_front() {
+        self.shared.not_full.notify_one();
+        return Some(m);
+      }
+      let now = Instant::now();
+      if state.senders == 0 || state.closed || now >= deadline {
+        return None;
+      }
+      state = self.shared.not_empty.wait_timeout(state, deadline - now).unwrap().0;
+    }
+  }
+
+  pub fn try_recv(&self) -> Option<Message> {
+    let mut state = self.shared.state.lock().unwrap();
+    let m = state.messages.pop_front();
+    if m.is_some() {
+      self.shared.not_full.notify_one();
+    }
+    m
+  }
+}
+
+impl Drop for Receiver {
+  fn drop(&mut self) {
+    let mut state = self.shared.state.lock().unwrap();
+    state.receiver_alive = false;
+    state.messages.clear();
+    self.shared.not_full.notify_all();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn limit(capacity: usize, policy: OverflowPolicy) -> QueueLimit {
+    QueueLimit { capacity, policy }
+  }
+
+  fn text(m: Message) -> String {
+    String::from_utf8(m.data.to_vec()).unwrap()
+  }
+
+  #[test]
+  fn capacity_of_zero_holds_one() {
+    let (tx, rx) = queue(limit(0, OverflowPolicy::Error));
+    tx.send(Message::new(1, "a")).unwrap();
+    assert!(matches!(tx.send(Message::new(1, "b")), Err(SendError::QueueFull)));
+    assert_eq!(text(rx.try_recv().unwrap()), "a");
+  }
+
+  #[test]
+  fn drop_oldest_keeps_the_newest() {
+    let (tx, rx) = queue(limit(2, OverflowPolicy::DropOldest));
+    for m in ["a", "b", "c"] {
+      tx.send(Message::new(1, m)).unwrap();
+    }
+    assert_eq!(text(rx.try_recv().unwrap()), "b");
+    assert_eq!(text(rx.try_recv().unwrap()), "c");
+    assert!(rx.try_recv().is_none());
+  }
+
+  #[test]
+  fn error_refuses_when_full() {
+    let (tx, rx) = queue(limit(1, OverflowPolicy::Error));
+    tx.send(Message::new(1, "a")).unwrap();
+    assert!(matches!(tx.send(Message::new(1, "b")), Err(SendError::QueueFull)));
+    assert_eq!(text(rx.try_recv().unwrap()), "a");
+    drop(rx);
+    assert!(matches!(tx.send(Message::new(1, "c")), Err(SendError::Shutdown)));
+  }
+
+  #[test]
+  fn block_waits_for_room() {
+    let (tx, rx) = queue(limit(1, OverflowPolicy::Block));
+    tx.send(Message::new(1, "a")).unwrap();
+    let sender = std::thread::spawn(move || tx.send(Message::new(1, "b")));
+    assert_eq!(text(rx.recv_timeout(Duration::from_secs(5)).unwrap()), "a");
+    sender.join().unwrap().unwrap();
+    assert_eq!(text(rx.recv_timeout(Duration::from_secs(5)).unwrap()), "b");
+  }
+
+  #[test]
+  fn close_wakes_a_blocked_sender() {
+    let (tx, _rx) = queue(limit(1, OverflowPolicy::Block));
+    tx.send(Message::new(1, "a")).unwrap();
+    let closer = tx.clone();
+    let sender = std::thread::spawn(move || tx.send(Message::new(1, "b")));
+    std::thread::sleep(Duration::from_millis(50));
+    closer.close();
+    assert!(matches!(sender.join().unwrap(), Err(SendError::Shutdown)));
+  }
+
+  #[test]
+  fn try_send_never_blocks() {
+    let (tx, rx) = queue(limit(1, OverflowPolicy::Block));
+    tx.try_send(Message::new(1, "a")).unwrap();
+    assert!(matches!(tx.try_send(Message::new(1, "b")), Err(SendError::QueueFull)));
+    drop(rx);
+    assert!(matches!(tx.try_send(Message::new(1, "c")), Err(SendError::Shutdown)));
+  }
+
+  #[test]
+  fn receiver_sees_the_end_once_senders_are_gone() {
+    let (tx, rx) = queue(QueueLimit::default());
+    tx.send(Message::new(1, "a")).unwrap();
+    drop(tx);
+    assert_eq!(text(rx.recv().unwrap()), "a");
+    assert!(rx.recv().is_none());
+  }
+}
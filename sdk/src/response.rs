@@ -0,0 +1,90 @@
+/*
+ * Copyright 2022 Comcast Cable Communications Management, LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+use log::{debug, warn};
+use serde_json::Value;
+
+use crate::{jsonrpc, RequestContext, SendError};
+
+/// Answers one request. The request's id is taken when the builder is
+/// created, and replying consumes the builder, so a handler can't answer
+/// with the wrong id or answer twice. A builder dropped without replying
+/// logs a warning and answers with an internal error so the client isn't
+/// left waiting. Notifications (requests without an id) get no reply.
+pub struct ResponseBuilder {
+  ctx: RequestContext,
+  id: Option<Value>,
+  method: String,
+  done: bool
+}
+
+impl ResponseBuilder {
+  pub fn new(json: &str, ctx: RequestContext) -> Self {
+    ResponseBuilder {
+      ctx,
+      id: jsonrpc::request_id(json),
+      method: jsonrpc::request_method(json).unwrap_or_default(),
+      done: false
+    }
+  }
+
+  pub fn context(&self) -> &RequestContext {
+    &self.ctx
+  }
+
+  /// The request's id, None for a notification.
+  pub fn id(&self) -> Option<&Value> {
+    self.id.as_ref()
+  }
+
+  pub fn method(&self) -> &str {
+    &self.method
+  }
+
+  pub fn result(self, result: Value) -> Result<(), SendError> {
+    self.reply(Ok(result))
+  }
+
+  pub fn error(self, error: jsonrpc::Error) -> Result<(), SendError> {
+    self.reply(Err(error))
+  }
+
+  pub fn reply(mut self, result: Result<Value, jsonrpc::Error>) -> Result<(), SendError> {
+    self.done = true;
+    match &self.id {
+      Some(id) => self.ctx.send(jsonrpc::response(id, &result)),
+      None => {
+        debug!("not replying to notification {}", self.method);
+        Ok(())
+      }
+    }
+  }
+}
+
+impl Drop for ResponseBuilder {
+  fn drop(&mut self) {
+    // Unwinding, the panic's own error response goes out instead
+    if self.done || std::thread::panicking() {
+      return;
+    }
+    if let Some(id) = &self.id {
+      warn!("no response to {} (id {}) on channel {}", self.method, id, self.ctx.channel);
+      let err = jsonrpc::Error::new(jsonrpc::INTERNAL_ERROR, "Request was not answered");
+      let _ = self.ctx.send(jsonrpc::response(id, &Err(err)));
+    }
+  }
+}
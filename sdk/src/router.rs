@@ -0,0 +1,450 @@
+/*
+ * Copyright 2022 Comcast Cable Communications Management, LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use log::{debug, warn};
+use serde_json::Value;
+
+use crate::jsonrpc;
+use crate::lazy::LazyResource;
+use crate::stream::ResponseStream;
+use crate::RequestContext;
+
+pub type Handler = Box<dyn Fn(Value, &RequestContext) -> Result<Value, jsonrpc::Error> + Send + Sync>;
+pub type StreamHandler = Box<dyn Fn(Value, ResponseStream) + Send + Sync>;
+pub type Getter = Box<dyn Fn(Option<&str>, &RequestContext) -> Result<Value, jsonrpc::Error> + Send + Sync>;
+pub type Setter = Box<dyn Fn(Option<&str>, Value, &RequestContext) -> Result<(), jsonrpc::Error> + Send + Sync>;
+
+enum Target {
+  Call(Handler),
+  Stream(StreamHandler),
+  Property(Getter, Option<Setter>)
+}
+
+/// A published request/response pair for a method. MockHost::contract_test
+/// replays these against the live handler.
+#[derive(Debug, Clone)]
+pub struct Example {
+  pub params: Value,
+  pub response: Result<Value, jsonrpc::Error>
+}
+
+pub struct Route {
+  target: Target,
+  examples: Vec<Example>,
+  summary: Option<String>,
+  schema: Option<Value>,
+  #[cfg(feature = "schema")]
+  validator: Option<jsonschema::Validator>
+}
+
+impl Route {
+  fn new(target: Target) -> Self {
+    Route {
+      target,
+      examples: Vec::new(),
+      summary: None,
+      schema: None,
+      #[cfg(feature = "schema")]
+      validator: None
+    }
+  }
+
+  pub fn example(&mut self, params: Value, response: Result<Value, jsonrpc::Error>) -> &mut Self {
+    self.examples.push(Example { params, response });
+    self
+  }
+
+  /// A one line description, used in generated interface descriptions.
+  pub fn summary(&mut self, summary: &str) -> &mut Self {
+    self.summary = Some(summary.to_string());
+    self
+  }
+
+  pub(crate) fn summary_text(&self) -> Option<&str> {
+    self.summary.as_deref()
+  }
+
+  pub(crate) fn params_schema(&self) -> Option<&Value> {
+    self.schema.as_ref()
+  }
+
+  pub(crate) fn examples_list(&self) -> &[Example] {
+    &self.examples
+  }
+
+  /// Whether this is a property, and if so whether it can be set.
+  pub(crate) fn property_access(&self) -> Option<bool> {
+    match &self.target {
+      Target::Property(_, set) => Some(set.is_some()),
+      _ => None
+    }
+  }
+
+  /// Checks params against the JSON Schema `schema` before the handler
+  /// runs. Params that don't match are answered with INVALID_PARAMS, listing
+  /// every violation in the error's data. For properties only sets are
+  /// checked. Panics if `schema` isn't a valid schema.
+  #[cfg(feature = "schema")]
+  pub fn schema(&mut self, schema: &Value) -> &mut Self {
+    let validator = jsonschema::validator_for(schema)
+      .unwrap_or_else(|e| panic!("invalid params schema: {}", e));
+    self.validator = Some(validator);
+    self.schema = Some(schema.clone());
+    self
+  }
+
+  /// Like schema() with the schema derived from the params type `T`.
+  #[cfg(feature = "schemars")]
+  pub fn schema_for<T: schemars::JsonSchema>(&mut self) -> &mut Self {
+    let schema = serde_json::to_value(schemars::schema_for!(T)).unwrap();
+    self.schema(&schema)
+  }
+
+  #[cfg(feature = "schema")]
+  fn check(&self, params: &Value) -> Result<(), jsonrpc::Error> {
+    let validator = match &self.validator {
+      Some(v) => v,
+      None => return Ok(())
+    };
+    let violations: Vec<Value> = validator.iter_errors(params)
+      .map(|e| serde_json::json!({
+        "path": e.instance_path().to_string(),
+        "message": e.to_string()
+      }))
+      .collect();
+    match violations.first() {
+      None => Ok(()),
+      Some(first) => {
+        let message = format!("Invalid params: {}", first["message"].as_str().unwrap_or_default());
+        Err(jsonrpc::Error::invalid_params(&message).with_data(Value::Array(violations)))
+      }
+    }
+  }
+
+  #[cfg(not(feature = "schema"))]
+  fn check(&self, _params: &Value) -> Result<(), jsonrpc::Error> {
+    Ok(())
+  }
+}
+
+/// A legacy method name kept working for old clients.
+struct Alias {
+  target: String,
+  warned: AtomicBool
+}
+
+/// Dispatches JSON-RPC requests to handlers registered by method name. The
+/// callsign part of Thunder's designator is ignored, so "Calculator.1.add"
+/// and "add" both reach the "add" handler. The version part only matters for
+/// methods registered with register_versioned().
+#[derive(Default)]
+pub struct Router {
+  routes: HashMap<String, Route>,
+  versions: HashMap<String, BTreeMap<u32, Route>>,
+  aliases: HashMap<String, Alias>
+}
+
+impl Router {
+  pub fn new() -> Self {
+    Router::default()
+  }
+
+  pub fn register<F>(&mut self, method: &str, handler: F) -> &mut Route
+    where F: Fn(Value, &RequestContext) -> Result<Value, jsonrpc::Error> + Send + Sync + 'static
+  {
+    self.insert(method, Target::Call(Box::new(handler)))
+  }
+
+  /// Registers version `version` of a method. "Calculator.2.getInfo" and
+  /// "getInfo@2" reach version 2; calls without a version reach the lowest
+  /// one, so clients written against it keep working as versions are added.
+  /// A version nobody registered falls back to a plain register() of the
+  /// same name, if there is one.
+  pub fn register_versioned<F>(&mut self, method: &str, version: u32, handler: F) -> &mut Route
+    where F: Fn(Value, &RequestContext) -> Result<Value, jsonrpc::Error> + Send + Sync + 'static
+  {
+    let route = Route::new(Target::Call(Box::new(handler)));
+    let versions = self.versions.entry(method.to_string()).or_default();
+    versions.insert(version, route);
+    versions.get_mut(&version).unwrap()
+  }
+
+  /// Registers a method that answers through a ResponseStream, sending its
+  /// result in chunks. The handler may hand the stream off to another thread.
+  pub fn register_stream<F>(&mut self, method: &str, handler: F) -> &mut Route
+    where F: Fn(Value, ResponseStream) + Send + Sync + 'static
+  {
+    self.insert(method, Target::Stream(Box::new(handler)))
+  }
+
+  /// Registers a Thunder style property: a call without params reads it and
+  /// a call with params sets it to them, answering null. "volume@0" reaches
+  /// the same property with the index "0" passed to `get` and `set`.
+  pub fn property<G, S>(&mut self, name: &str, get: G, set: S) -> &mut Route
+    where G: Fn(Option<&str>, &RequestContext) -> Result<Value, jsonrpc::Error> + Send + Sync + 'static,
+          S: Fn(Option<&str>, Value, &RequestContext) -> Result<(), jsonrpc::Error> + Send + Sync + 'static
+  {
+    self.insert(name, Target::Property(Box::new(get), Some(Box::new(set))))
+  }
+
+  /// A property that can only be read; setting it is an error.
+  pub fn property_readonly<G>(&mut self, name: &str, get: G) -> &mut Route
+    where G: Fn(Option<&str>, &RequestContext) -> Result<Value, jsonrpc::Error> + Send + Sync + 'static
+  {
+    self.insert(name, Target::Property(Box::new(get), None))
+  }
+
+  fn insert(&mut self, method: &str, target: Target) -> &mut Route {
+    let route = Route::new(target);
+    self.routes.insert(method.to_string(), route);
+    self.routes.get_mut(method).unwrap()
+  }
+
+  /// Registers a method that needs `resource`. The handler only runs once
+  /// the resource is ready; until then the request is answered with
+  /// ERROR_INPROGRESS or ERROR_UNAVAILABLE.
+  pub fn register_lazy<T, F>(&mut self, method: &str, resource: LazyResource<T>, handler: F) -> &mut Route
+    where T: Send + Sync + 'static,
+          F: Fn(&T, Value, &RequestContext) -> Result<Value, jsonrpc::Error> + Send + Sync + 'static
+  {
+    self.register(method, move |params, ctx| {
+      let r = resource.get()?;
+      handler(&r, params, ctx)
+    })
+  }
+
+  /// Makes `old` another name for `method`. Calls through the old name are
+  /// logged as deprecated, loudly the first time and quietly after that.
+  pub fn alias(&mut self, old: &str, method: &str) {
+    self.aliases.insert(old.to_string(), Alias {
+      target: method.to_string(),
+      warned: AtomicBool::new(false)
+    });
+  }
+
+  /// Adds aliases from a config object mapping old names to new ones, e.g.
+  /// {"getValue": "value", "setValue": "value"}.
+  pub fn aliases_from_config(&mut self, config: &Value) -> Result<(), String> {
+    let map = config.as_object()
+      .ok_or_else(|| String::from("aliases must be an object"))?;
+    for (old, method) in map {
+      let method = method.as_str()
+        .ok_or_else(|| format!("alias {} must map to a method name", old))?;
+      self.alias(old, method);
+    }
+    Ok(())
+  }
+
+  /// Registered aliases as (old name, method), sorted by old name.
+  pub fn aliases(&self) -> Vec<(String, String)> {
+    let mut aliases: Vec<(String, String)> = self.aliases.iter()
+      .map(|(old, a)| (old.clone(), a.target.clone()))
+      .collect();
+    aliases.sort();
+    aliases
+  }
+
+  /// The route for `designator`, and the index when it names an element of
+  /// an indexed property ("volume@0").
+  fn route<'a>(&self, designator: &'a str) -> (Option<&Route>, Option<&'a str>) {
+    let (name, version) = split_version(designator);
+    if let Some(route) = self.lookup(name, version) {
+      return (Some(route), None);
+    }
+    // Split before taking the method name, indexes may contain dots
+    if let Some((base, suffix)) = designator.split_once('@') {
+      let (name, version) = split_version(base);
+      if version.is_none() && self.versions.contains_key(name) {
+        if let Ok(version) = suffix.parse::<u32>() {
+          return (self.lookup(name, Some(version)), None);
+        }
+      }
+      if let Some(route) = self.lookup(name, version) {
+        if let Target::Property(..) = route.target {
+          return (Some(route), Some(suffix));
+        }
+      }
+    }
+    (None, None)
+  }
+
+  fn lookup(&self, name: &str, version: Option<u32>) -> Option<&Route> {
+    if let Some(route) = self.find(name, version) {
+      return Some(route);
+    }
+    let alias = self.aliases.get(name)?;
+    if !alias.warned.swap(true, Ordering::Relaxed) {
+      warn!("{} is deprecated, use {}", name, alias.target);
+    } else {
+      debug!("deprecated {} called", name);
+    }
+    self.find(&alias.target, version)
+  }
+
+  fn find(&self, name: &str, version: Option<u32>) -> Option<&Route> {
+    self.versions.get(name)
+      .and_then(|versions| match version {
+        Some(version) => versions.get(&version),
+        None => versions.values().next()
+      })
+      .or_else(|| self.routes.get(name))
+  }
+
+  /// Every registered route with the name it's called by, "getInfo@2" for
+  /// versioned methods.
+  fn all_routes(&self) -> BTreeMap<String, &Route> {
+    let versioned = self.versions.iter()
+      .flat_map(|(m, versions)| versions.iter().map(move |(v, r)| (format!("{}@{}", m, v), r)));
+    self.routes.iter()
+      .map(|(m, r)| (m.clone(), r))
+      .chain(versioned)
+      .collect()
+  }
+
+  /// What clients calling without a version reach, by name: every route,
+  /// with versioned methods at their lowest version.
+  pub(crate) fn unversioned_routes(&self) -> BTreeMap<String, &Route> {
+    let lowest = self.versions.iter()
+      .filter_map(|(m, versions)| versions.values().next().map(|r| (m.clone(), r)));
+    self.routes.iter()
+      .map(|(m, r)| (m.clone(), r))
+      .chain(lowest)
+      .collect()
+  }
+
+  /// The registered method names, sorted. Each version of a versioned method
+  /// is listed as "method@version".
+  pub fn methods(&self) -> Vec<String> {
+    self.all_routes().into_keys().collect()
+  }
+
+  /// All registered examples as (method, example), ordered by method name.
+  pub fn examples(&self) -> Vec<(String, Example)> {
+    self.all_routes().into_iter()
+      .flat_map(|(m, r)| r.examples.iter().map(move |e| (m.clone(), e.clone())))
+      .collect()
+  }
+
+  /// Calls the handler for `designator` directly, without a JSON-RPC envelope.
+  pub fn call(&self, designator: &str, params: Value, ctx: &RequestContext) -> Result<Value, jsonrpc::Error> {
+    let (route, index) = self.route(designator);
+    Router::call_route(designator, route, index, params, ctx)
+  }
+
+  fn call_route(designator: &str, route: Option<&Route>, index: Option<&str>, params: Value, ctx: &RequestContext)
+    -> Result<Value, jsonrpc::Error>
+  {
+    let route = match route {
+      Some(route) => route,
+      None => return Err(jsonrpc::Error::new(jsonrpc::METHOD_NOT_FOUND,
+        &format!("Unknown method {}", designator)))
+    };
+    match &route.target {
+      Target::Call(handler) => {
+        route.check(&params)?;
+        handler(params, ctx)
+      }
+      Target::Property(get, set) => {
+        if is_empty_params(&params) {
+          return get(index, ctx);
+        }
+        match set {
+          Some(set) => {
+            route.check(&params)?;
+            set(index, params, ctx).map(|()| Value::Null)
+          }
+          None => Err(jsonrpc::Error::new(jsonrpc::INVALID_REQUEST,
+            &format!("{} is read-only", designator)))
+        }
+      }
+      Target::Stream(_) => Err(jsonrpc::Error::new(jsonrpc::INVALID_REQUEST,
+        &format!("{} streams its result and can't be called directly", designator)))
+    }
+  }
+
+  /// Runs the request through its handler and returns the response to send,
+  /// or None for notifications.
+  pub fn handle(&self, json: &str, ctx: &RequestContext) -> Option<String> {
+    let req = match jsonrpc::parse(json) {
+      Ok(req) => req,
+      Err(e) => {
+        return Some(jsonrpc::error_response(&Value::Null, jsonrpc::PARSE_ERROR, &e));
+      }
+    };
+
+    let id = req.get("id").cloned().unwrap_or(Value::Null);
+    let result = match req["method"].as_str() {
+      Some(designator) => {
+        let params = req.get("params").cloned().unwrap_or(Value::Null);
+        let (route, index) = self.route(designator);
+        if let Some(stream @ Route { target: Target::Stream(handler), .. }) = route {
+          if id.is_null() {
+            return None;
+          }
+          if let Err(e) = stream.check(&params) {
+            return Some(jsonrpc::response(&id, &Err(e)));
+          }
+          handler(params, ResponseStream::new(ctx.clone(), id));
+          return None;
+        }
+        Router::call_route(designator, route, index, params, ctx)
+      }
+      None => Err(jsonrpc::Error::new(jsonrpc::INVALID_REQUEST, "Missing method"))
+    };
+
+    if id.is_null() {
+      None
+    }
+    else {
+      Some(jsonrpc::response(&id, &result))
+    }
+  }
+
+  /// Handles the request and sends the response back on the context.
+  pub fn dispatch(&self, json: &str, ctx: &RequestContext) {
+    if let Some(res) = self.handle(json, ctx) {
+      if let Err(e) = ctx.send(res) {
+        warn!("failed to send response: {}", e);
+      }
+    }
+  }
+}
+
+/// Thunder clients read a property with no params, null, {} or [].
+fn is_empty_params(params: &Value) -> bool {
+  match params {
+    Value::Null => true,
+    Value::Object(map) => map.is_empty(),
+    Value::Array(items) => items.is_empty(),
+    _ => false
+  }
+}
+
+pub(crate) fn method_name(designator: &str) -> &str {
+  designator.rsplit('.').next().unwrap_or(designator)
+}
+
+/// The method name and, for "Callsign.2.method", the version.
+fn split_version(designator: &str) -> (&str, Option<u32>) {
+  let mut parts = designator.rsplit('.');
+  let name = parts.next().unwrap_or(designator);
+  let version = parts.next().and_then(|v| v.parse().ok());
+  (name, version)
+}
@@ -0,0 +1,146 @@
+/*
+ * Copyright 2022 Comcast Cable Communications Management, LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+use std::io;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use log::{error, warn};
+use serde::Serialize;
+
+use crate::checkpoint::{Checkpoint, Checkpointer};
+use crate::Paths;
+
+const DEFAULT_CHECKPOINT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Handed to background tasks so they can tell when the plugin is going
+/// away and they should return.
+#[derive(Debug, Clone)]
+pub struct StopToken {
+  stopped: Arc<AtomicBool>
+}
+
+impl StopToken {
+  pub fn is_stopped(&self) -> bool {
+    self.stopped.load(Ordering::Acquire)
+  }
+}
+
+/// Per-plugin services handed to the plugin through PluginConfig. Everything
+/// started through it is stopped when the plugin is destroyed.
+#[derive(Debug, Clone)]
+pub struct PluginScope {
+  checkpoints: Arc<Checkpointer>,
+  tasks: Arc<Mutex<Vec<JoinHandle<()>>>>,
+  stop: StopToken
+}
+
+/// A scope that doesn't keep checkpoints.
+impl Default for PluginScope {
+  fn default() -> Self {
+    PluginScope::with_checkpoints(None, DEFAULT_CHECKPOINT_INTERVAL)
+  }
+}
+
+impl PluginScope {
+  /// Checkpoints for `name` are kept in THUNDER_RS_CHECKPOINT_DIR, or the
+  /// plugin's persistent directory without it, and written at most once per
+  /// THUNDER_RS_CHECKPOINT_INTERVAL_MS. With neither, checkpoints aren't
+  /// kept: a shared directory like the temp one is neither private nor sure
+  /// to outlive the host.
+  pub fn new(name: &str, paths: &Paths) -> Self {
+    let dir = std::env::var_os("THUNDER_RS_CHECKPOINT_DIR")
+      .map(PathBuf::from)
+      .or_else(|| paths.persistent().map(PathBuf::from));
+    if dir.is_none() {
+      warn!("{} has no persistent directory or THUNDER_RS_CHECKPOINT_DIR, checkpoints won't be kept", name);
+    }
+    let interval = std::env::var("THUNDER_RS_CHECKPOINT_INTERVAL_MS").ok()
+      .and_then(|s| s.parse::<u64>().ok())
+      .map(Duration::from_millis)
+      .unwrap_or(DEFAULT_CHECKPOINT_INTERVAL);
+    PluginScope::with_checkpoints(dir.map(|dir| dir.join(format!("{}.checkpoint", name))), interval)
+  }
+
+  pub fn with_checkpoint_path(path: PathBuf, interval: Duration) -> Self {
+    PluginScope::with_checkpoints(Some(path), interval)
+  }
+
+  fn with_checkpoints(path: Option<PathBuf>, interval: Duration) -> Self {
+    PluginScope {
+      checkpoints: Arc::new(Checkpointer::new(path, interval)),
+      tasks: Arc::new(Mutex::new(Vec::new())),
+      stop: StopToken {
+        stopped: Arc::new(AtomicBool::new(false))
+      }
+    }
+  }
+
+  /// Records `state` as the one to recover from. It's written straight away
+  /// unless the last write was less than the interval ago, in which case it
+  /// goes out with the next checkpoint or flush() after that. Safe to call
+  /// from several workers at once; none waits on another's write.
+  pub fn checkpoint(&self, state: &impl Serialize) -> io::Result<()> {
+    self.checkpoints.checkpoint(state)
+  }
+
+  /// Writes a checkpoint held back by the interval, if there is one.
+  pub fn flush(&self) -> io::Result<()> {
+    self.checkpoints.flush()
+  }
+
+  /// The checkpoint left by a previous instance, if any.
+  pub fn restore(&self) -> Option<Checkpoint> {
+    self.checkpoints.restore()
+  }
+
+  /// Runs `f` on a new thread owned by the SDK. `f` should return soon after
+  /// its StopToken reports stopped; the plugin isn't torn down until it has.
+  pub fn spawn<F>(&self, f: F)
+    where F: FnOnce(StopToken) + Send + 'static
+  {
+    if self.stop.is_stopped() {
+      warn!("not spawning a task, the plugin is shutting down");
+      return;
+    }
+    let stop = self.stop.clone();
+    let handle = std::thread::spawn(move || {
+      if let Err(cause) = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| f(stop))) {
+        error!("Error in background task: {:?}", cause);
+      }
+    });
+    let mut tasks = self.tasks.lock().unwrap();
+    tasks.retain(|t| !t.is_finished());
+    tasks.push(handle);
+  }
+
+  /// Asks every task to stop, waits for them and writes any pending
+  /// checkpoint. Called by the SDK when the plugin is destroyed.
+  pub fn shutdown(&self) {
+    self.stop.stopped.store(true, Ordering::Release);
+    let tasks: Vec<JoinHandle<()>> = self.tasks.lock().unwrap().drain(..).collect();
+    for task in tasks {
+      let _ = task.join();
+    }
+    if let Err(e) = self.flush() {
+      warn!("failed to write checkpoint: {}", e);
+    }
+  }
+}
@@ -0,0 +1,194 @@
+/*
+ * Copyright 2022 Comcast Cable Communications Management, LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+//! Lets plugin config reference secrets as `"${secret:svc/apikey}"` instead
+//! of carrying them in plain JSON. References are resolved once, when the
+//! plugin is loaded, through the provider picked by
+//! THUNDER_RS_SECRET_PROVIDER (`env`, `file:<dir>` or `keystore`).
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+use log::info;
+use serde_json::Value;
+
+/// Where secret values come from.
+pub trait SecretProvider {
+  /// The value for `key`, or None if the provider doesn't have it.
+  fn secret(&self, key: &str) -> Result<Option<String>, String>;
+}
+
+/// Reads `svc/apikey` from THUNDER_RS_SECRET_SVC_APIKEY.
+pub struct EnvSecrets;
+
+impl SecretProvider for EnvSecrets {
+  fn secret(&self, key: &str) -> Result<Option<String>, String> {
+    let name: String = key.chars()
+      .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_uppercase() } else { '_' })
+      .collect();
+    Ok(std::env::var(format!("THUNDER_RS_SECRET_{}", name)).ok())
+  }
+}
+
+/// Reads `svc/apikey` from the file `<dir>/svc/apikey`, without its trailing
+/// newline.
+pub struct FileSecrets {
+  dir: PathBuf,
+  private_only: bool
+}
+
+impl FileSecrets {
+  pub fn new(dir: impl Into<PathBuf>) -> Self {
+    FileSecrets { dir: dir.into(), private_only: false }
+  }
+
+  /// Refuses secret files that other users can read.
+  pub fn private_only(mut self) -> Self {
+    self.private_only = true;
+    self
+  }
+}
+
+impl SecretProvider for FileSecrets {
+  fn secret(&self, key: &str) -> Result<Option<String>, String> {
+    let rel = Path::new(key);
+    if !rel.components().all(|c| matches!(c, Component::Normal(_))) {
+      return Err(format!("invalid secret name {}", key));
+    }
+    let path = self.dir.join(rel);
+    if !path.exists() {
+      return Ok(None);
+    }
+    if self.private_only {
+      check_private(&path)?;
+    }
+    let value = fs::read_to_string(&path)
+      .map_err(|e| format!("failed to read secret {}: {}", key, e))?;
+    Ok(Some(value.trim_end_matches(['\r', '\n']).to_string()))
+  }
+}
+
+#[cfg(unix)]
+fn check_private(path: &Path) -> Result<(), String> {
+  use std::os::unix::fs::PermissionsExt;
+
+  let mode = fs::metadata(path).map_err(|e| e.to_string())?.permissions().mode();
+  if mode & 0o077 != 0 {
+    return Err(format!("{} is readable by other users", path.display()));
+  }
+  Ok(())
+}
+
+#[cfg(not(unix))]
+fn check_private(_path: &Path) -> Result<(), String> {
+  Ok(())
+}
+
+// The encrypted, per-device partition on RDK images
+const KEYSTORE_DIR: &str = "/opt/secure/thunder_rs";
+
+/// The device keystore: secret files on the secure partition, which must
+/// only be readable by their owner.
+pub fn keystore() -> FileSecrets {
+  let dir = std::env::var("THUNDER_RS_KEYSTORE_DIR").unwrap_or_else(|_| KEYSTORE_DIR.to_string());
+  FileSecrets::new(dir).private_only()
+}
+
+/// The provider configured for this deployment. Defaults to `env`.
+pub fn from_env() -> Result<Box<dyn SecretProvider>, String> {
+  let spec = std::env::var("THUNDER_RS_SECRET_PROVIDER").unwrap_or_else(|_| String::from("env"));
+  match spec.as_str() {
+    "env" => Ok(Box::new(EnvSecrets)),
+    "keystore" => Ok(Box::new(keystore())),
+    s => match s.strip_prefix("file:") {
+      Some(dir) => Ok(Box::new(FileSecrets::new(dir))),
+      None => Err(format!("unknown secret provider {}", s))
+    }
+  }
+}
+
+/// Replaces every `${secret:key}` in the strings of `config`. A reference
+/// the provider can't resolve is an error, so a plugin never starts with a
+/// placeholder where its credentials should be.
+pub fn resolve(config: &mut Value, provider: &dyn SecretProvider) -> Result<(), String> {
+  match config {
+    Value::String(s) if s.contains("${secret:") => {
+      *s = resolve_str(s, provider)?;
+    }
+    Value::Array(items) => {
+      for item in items {
+        resolve(item, provider)?;
+      }
+    }
+    Value::Object(map) => {
+      for value in map.values_mut() {
+        resolve(value, provider)?;
+      }
+    }
+    _ => { }
+  }
+  Ok(())
+}
+
+fn resolve_str(s: &str, provider: &dyn SecretProvider) -> Result<String, String> {
+  let mut out = String::with_capacity(s.len());
+  let mut rest = s;
+  while let Some(start) = rest.find("${secret:") {
+    out.push_str(&rest[..start]);
+    let after = &rest[start + "${secret:".len()..];
+    let end = after.find('}')
+      .ok_or_else(|| format!("unterminated secret reference in {:?}", s))?;
+    let key = &after[..end];
+    let value = provider.secret(key)?
+      .ok_or_else(|| format!("secret {} not found", key))?;
+    out.push_str(&value);
+    rest = &after[end + 1..];
+  }
+  out.push_str(rest);
+  Ok(out)
+}
+
+/// The config of the plugin called `callsign` from
+/// THUNDER_RS_PLUGIN_CONFIG_<CALLSIGN> (JSON text, the callsign upper cased
+/// with anything but letters and digits as `_`), with its secrets resolved.
+/// THUNDER_RS_PLUGIN_CONFIG is read instead when that isn't set, for a
+/// process with a single plugin. Null when neither is.
+pub fn load_config(callsign: &str) -> Result<Value, String> {
+  let suffix: String = callsign.chars()
+    .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_uppercase() } else { '_' })
+    .collect();
+  let own = format!("THUNDER_RS_PLUGIN_CONFIG_{}", suffix);
+  let (var, text) = match std::env::var(&own) {
+    Ok(text) => (own.as_str(), text),
+    Err(_) => match std::env::var("THUNDER_RS_PLUGIN_CONFIG") {
+      Ok(text) => ("THUNDER_RS_PLUGIN_CONFIG", text),
+      Err(_) => return Ok(Value::Null)
+    }
+  };
+  let config = parse_config(&text)
+    .map_err(|e| format!("invalid {}: {}", var, e))?;
+  info!("loaded {}'s config from {}", callsign, var);
+  Ok(config)
+}
+
+/// Parses config pushed by Thunder and resolves its secret references with
+/// the provider chosen by THUNDER_RS_SECRET_PROVIDER.
+pub fn parse_config(text: &str) -> Result<Value, String> {
+  let mut config: Value = serde_json::from_str(text).map_err(|e| e.to_string())?;
+  let provider = from_env()?;
+  resolve(&mut config, provider.as_ref())?;
+  Ok(config)
+}
@@ -0,0 +1,95 @@
+/*
+ * Copyright 2022 Comcast Cable Communications Management, LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+use serde_json::{json, Value};
+
+use crate::{jsonrpc, RequestContext, SendError};
+
+/// Method name used for the partial result notifications of a stream.
+pub const PARTIAL_METHOD: &str = "partial";
+
+/// Lets a handler answer one request with a sequence of partial results
+/// followed by a final response, instead of building one huge result in
+/// memory. Each chunk goes out as a notification
+///
+///   {"jsonrpc":"2.0","method":"partial","params":{"id":<request id>,"seq":n,"result":chunk}}
+///
+/// and the request is completed by finish() or fail(). A stream dropped
+/// without either answers the request with an internal error.
+pub struct ResponseStream {
+  ctx: RequestContext,
+  id: Value,
+  seq: u64,
+  done: bool
+}
+
+impl ResponseStream {
+  pub fn new(ctx: RequestContext, id: Value) -> Self {
+    ResponseStream {
+      ctx,
+      id,
+      seq: 0,
+      done: false
+    }
+  }
+
+  pub fn context(&self) -> &RequestContext {
+    &self.ctx
+  }
+
+  /// Number of chunks sent so far.
+  pub fn sent(&self) -> u64 {
+    self.seq
+  }
+
+  pub fn send(&mut self, chunk: Value) -> Result<(), SendError> {
+    let note = json!({
+      "jsonrpc": "2.0",
+      "method": PARTIAL_METHOD,
+      "params": {
+        "id": self.id,
+        "seq": self.seq,
+        "result": chunk
+      }
+    });
+    self.ctx.send(note.to_string())?;
+    self.seq += 1;
+    Ok(())
+  }
+
+  /// Completes the request with `result` as the final response.
+  pub fn finish(mut self, result: Value) -> Result<(), SendError> {
+    self.done = true;
+    self.ctx.send(jsonrpc::response(&self.id, &Ok(result)))
+  }
+
+  /// Completes the request with an error, e.g. when producing a later chunk
+  /// failed.
+  pub fn fail(mut self, error: jsonrpc::Error) -> Result<(), SendError> {
+    self.done = true;
+    self.ctx.send(jsonrpc::response(&self.id, &Err(error)))
+  }
+}
+
+impl Drop for ResponseStream {
+  fn drop(&mut self) {
+    if !self.done {
+      let err = jsonrpc::Error::new(jsonrpc::INTERNAL_ERROR, "Response stream dropped without a result");
+      let _ = self.ctx.send(jsonrpc::response(&self.id, &Err(err)));
+    }
+  }
+}
@@ -0,0 +1,87 @@
+/*
+ * Copyright 2022 Comcast Cable Communications Management, LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+//! Thunder's ISubSystem flags: system-wide conditions such as network or
+//! time availability that plugins can wait on or be responsible for.
+
+/// A Thunder subsystem. The ids match Thunder's ISubSystem::subsystem enum.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Subsystem {
+  Platform,
+  Security,
+  Network,
+  Identifier,
+  Graphics,
+  Internet,
+  Location,
+  Time,
+  Provisioning,
+  Decryption,
+  WebSource,
+  Streaming,
+  Bluetooth,
+  Cryptography,
+  Installation
+}
+
+const ALL: [Subsystem; 15] = [
+  Subsystem::Platform,
+  Subsystem::Security,
+  Subsystem::Network,
+  Subsystem::Identifier,
+  Subsystem::Graphics,
+  Subsystem::Internet,
+  Subsystem::Location,
+  Subsystem::Time,
+  Subsystem::Provisioning,
+  Subsystem::Decryption,
+  Subsystem::WebSource,
+  Subsystem::Streaming,
+  Subsystem::Bluetooth,
+  Subsystem::Cryptography,
+  Subsystem::Installation
+];
+
+impl Subsystem {
+  pub fn from_id(id: u32) -> Option<Self> {
+    ALL.get(id as usize).copied()
+  }
+
+  pub fn id(&self) -> u32 {
+    ALL.iter().position(|s| s == self).unwrap() as u32
+  }
+
+  pub fn name(&self) -> &'static str {
+    match self {
+      Subsystem::Platform => "PLATFORM",
+      Subsystem::Security => "SECURITY",
+      Subsystem::Network => "NETWORK",
+      Subsystem::Identifier => "IDENTIFIER",
+      Subsystem::Graphics => "GRAPHICS",
+      Subsystem::Internet => "INTERNET",
+      Subsystem::Location => "LOCATION",
+      Subsystem::Time => "TIME",
+      Subsystem::Provisioning => "PROVISIONING",
+      Subsystem::Decryption => "DECRYPTION",
+      Subsystem::WebSource => "WEBSOURCE",
+      Subsystem::Streaming => "STREAMING",
+      Subsystem::Bluetooth => "BLUETOOTH",
+      Subsystem::Cryptography => "CRYPTOGRAPHY",
+      Subsystem::Installation => "INSTALLATION"
+    }
+  }
+}
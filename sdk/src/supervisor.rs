@@ -0,0 +1,118 @@
+/*
+ * Copyright 2022 Comcast Cable Communications Management, LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+//! Decides what to do about a plugin that keeps panicking.
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use log::error;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PanicAction {
+  /// Throw the instance away and create a fresh one through
+  /// ServiceMetadata::create. It gets the last checkpoint in on_init.
+  Restart,
+  /// Stop calling the plugin. Requests are answered with ERROR_UNAVAILABLE.
+  Quarantine
+}
+
+/// How many panics a plugin may have within `window` before `action` is
+/// taken.
+#[derive(Debug, Clone, Copy)]
+pub struct RestartPolicy {
+  pub max_panics: u32,
+  pub window: Duration,
+  pub action: PanicAction
+}
+
+impl Default for RestartPolicy {
+  fn default() -> Self {
+    RestartPolicy {
+      max_panics: 5,
+      window: Duration::from_secs(60),
+      action: PanicAction::Restart
+    }
+  }
+}
+
+/// Counts a plugin's panics against its RestartPolicy.
+pub struct Supervisor {
+  name: String,
+  policy: RestartPolicy,
+  panics: VecDeque<Instant>,
+  restarts: u32,
+  quarantined: bool
+}
+
+impl Supervisor {
+  pub fn new(name: &str, policy: RestartPolicy) -> Self {
+    Supervisor {
+      name: name.to_string(),
+      policy,
+      panics: VecDeque::new(),
+      restarts: 0,
+      quarantined: false
+    }
+  }
+
+  /// Records a panic. Returns the policy's action when this panic crosses
+  /// the threshold; the count starts over after that.
+  pub fn record_panic(&mut self) -> Option<PanicAction> {
+    let now = Instant::now();
+    self.panics.push_back(now);
+    while let Some(first) = self.panics.front() {
+      if now.duration_since(*first) > self.policy.window {
+        self.panics.pop_front();
+      } else {
+        break;
+      }
+    }
+    if (self.panics.len() as u32) < self.policy.max_panics {
+      return None;
+    }
+
+    self.panics.clear();
+    match self.policy.action {
+      PanicAction::Restart => {
+        self.restarts += 1;
+        error!("{} panicked {} times within {:?}, restarting (restart {})", self.name,
+          self.policy.max_panics, self.policy.window, self.restarts);
+      }
+      PanicAction::Quarantine => {
+        self.quarantined = true;
+        error!("{} panicked {} times within {:?}, quarantined", self.name,
+          self.policy.max_panics, self.policy.window);
+      }
+    }
+    Some(self.policy.action)
+  }
+
+  /// Stops calling the plugin whatever the policy, e.g. when it can't be
+  /// restarted.
+  pub fn quarantine(&mut self) {
+    self.quarantined = true;
+  }
+
+  pub fn is_quarantined(&self) -> bool {
+    self.quarantined
+  }
+
+  /// How many times the plugin has been restarted.
+  pub fn restarts(&self) -> u32 {
+    self.restarts
+  }
+}
@@ -0,0 +1,189 @@
+/*
+ * Copyright 2022 Comcast Cable Communications Management, LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+//! Pushes selected metrics to RDK's Telemetry 2.0 agent, so plugin health
+//! shows up in the operator's existing telemetry pipeline. Enabled by a
+//! "telemetry" object in the plugin's config:
+//!
+//!   "telemetry": {
+//!     "interval_s": 300,
+//!     "markers": { "requests": "RUST_MyPlugin_Requests_split" }
+//!   }
+//!
+//! Every interval each listed metric is reported under its marker: the
+//! increase since the last report for counters, the current value for
+//! gauges and the number of samples for histograms. Values go through
+//! libtelemetry_msgsender's t2_event_d when it can be loaded, and otherwise
+//! as "<marker>:<value>" lines appended to THUNDER_RS_T2_FILE (default
+//! /opt/logs/thunder_rs_t2.log) for a T2 grep marker to pick up.
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+use serde_json::Value;
+
+use crate::metrics::MetricValue;
+use crate::{Metrics, PluginScope};
+
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(300);
+const MIN_INTERVAL: Duration = Duration::from_secs(1);
+const DEFAULT_FILE: &str = "/opt/logs/thunder_rs_t2.log";
+
+pub struct TelemetryConfig {
+  pub interval: Duration,
+  /// (metric, marker) pairs
+  pub markers: Vec<(String, String)>
+}
+
+impl TelemetryConfig {
+  /// Reads `config["telemetry"]`. None if it's missing or lists no markers.
+  pub fn from_config(config: &Value) -> Option<Self> {
+    let telemetry = config.get("telemetry")?;
+    let markers: Vec<(String, String)> = telemetry.get("markers")?.as_object()?.iter()
+      .filter_map(|(metric, marker)| Some((metric.clone(), marker.as_str()?.to_string())))
+      .collect();
+    if markers.is_empty() {
+      return None;
+    }
+    let interval = match telemetry.get("interval_s").and_then(Value::as_u64) {
+      // Would report in a busy loop
+      Some(0) => {
+        warn!("telemetry interval_s of 0, reporting every {:?}", MIN_INTERVAL);
+        MIN_INTERVAL
+      }
+      Some(secs) => Duration::from_secs(secs),
+      None => DEFAULT_INTERVAL
+    };
+    Some(TelemetryConfig { interval, markers })
+  }
+}
+
+enum Sink {
+  #[cfg(unix)]
+  Library(T2Library),
+  File(PathBuf)
+}
+
+impl Sink {
+  fn open(component: &str) -> Self {
+    #[cfg(unix)]
+    if let Some(library) = T2Library::load(component) {
+      return Sink::Library(library);
+    }
+    #[cfg(not(unix))]
+    let _ = component;
+    let path = std::env::var("THUNDER_RS_T2_FILE").unwrap_or_else(|_| DEFAULT_FILE.to_string());
+    Sink::File(PathBuf::from(path))
+  }
+
+  fn report(&self, values: &[(String, i64)]) {
+    match self {
+      #[cfg(unix)]
+      Sink::Library(library) => {
+        for (marker, value) in values {
+          library.event(marker, *value);
+        }
+      }
+      Sink::File(path) => {
+        let lines: String = values.iter().map(|(marker, value)| format!("{}:{}\n", marker, value)).collect();
+        let written = std::fs::OpenOptions::new().create(true).append(true).open(path)
+          .and_then(|mut f| f.write_all(lines.as_bytes()));
+        if let Err(e) = written {
+          warn!("failed to write telemetry to {}: {}", path.display(), e);
+        }
+      }
+    }
+  }
+}
+
+#[cfg(unix)]
+type EventFunction = unsafe extern "C" fn (*const libc::c_char, libc::c_int) -> libc::c_int;
+
+#[cfg(unix)]
+struct T2Library {
+  event_d: EventFunction
+}
+
+#[cfg(unix)]
+impl T2Library {
+  fn load(component: &str) -> Option<Self> {
+    let handle = ["libtelemetry_msgsender.so.0", "libtelemetry_msgsender.so"].iter().find_map(|name| {
+      let name = CString::new(*name).ok()?;
+      let handle = unsafe { libc::dlopen(name.as_ptr(), libc::RTLD_NOW) };
+      (!handle.is_null()).then_some(handle)
+    })?;
+    let init = unsafe { libc::dlsym(handle, c"t2_init".as_ptr()) };
+    let event_d = unsafe { libc::dlsym(handle, c"t2_event_d".as_ptr()) };
+    if init.is_null() || event_d.is_null() {
+      warn!("libtelemetry_msgsender is missing t2_init or t2_event_d");
+      return None;
+    }
+    // The library stays loaded for the life of the process
+    let init = unsafe { std::mem::transmute::<*mut libc::c_void, unsafe extern "C" fn (*mut libc::c_char)>(init) };
+    let component = CString::new(component).ok()?;
+    unsafe { init(component.into_raw()) };
+    Some(T2Library { event_d: unsafe { std::mem::transmute::<*mut libc::c_void, EventFunction>(event_d) } })
+  }
+
+  fn event(&self, marker: &str, value: i64) {
+    if let Ok(marker) = CString::new(marker) {
+      unsafe { (self.event_d)(marker.as_ptr(), value.clamp(libc::c_int::MIN as i64, libc::c_int::MAX as i64) as libc::c_int) };
+    }
+  }
+}
+
+/// Starts reporting on a task owned by `scope`, if `config` asks for it.
+pub fn start(component: &str, config: &Value, metrics: &Metrics, scope: &PluginScope) {
+  let config = match TelemetryConfig::from_config(config) {
+    Some(c) => c,
+    None => return
+  };
+  let component = component.to_string();
+  let metrics = metrics.clone();
+  scope.spawn(move |stop| {
+    let sink = Sink::open(&component);
+    info!("reporting {} metrics to telemetry every {:?}", config.markers.len(), config.interval);
+    let mut last: HashMap<String, u64> = HashMap::new();
+    let mut next = Instant::now() + config.interval;
+    while !stop.is_stopped() {
+      if Instant::now() < next {
+        std::thread::sleep(Duration::from_millis(100));
+        continue;
+      }
+      next += config.interval;
+      let values: Vec<(String, i64)> = config.markers.iter()
+        .filter_map(|(metric, marker)| {
+          let value = match metrics.read(metric)? {
+            MetricValue::Counter(n) => {
+              let previous = last.insert(metric.clone(), n).unwrap_or(0);
+              n.saturating_sub(previous) as i64
+            }
+            MetricValue::Gauge(v) => v,
+            MetricValue::Histogram(count) => count as i64
+          };
+          Some((marker.clone(), value))
+        })
+        .collect();
+      if !values.is_empty() {
+        sink.report(&values);
+      }
+    }
+  });
+}
@@ -0,0 +1,273 @@
+/*
+ * Copyright 2022 Comcast Cable Communications Management, LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+//! Helpers for exercising a plugin without a running Thunder.
+use std::fmt;
+use std::time::Duration;
+
+use serde_json::{json, Value};
+
+use crate::router::Example;
+use crate::responder::{self, Receiver};
+use crate::{Channels, Codec, Features, Framework, Message, Paths, Plugin, RequestContext, Responder};
+
+/// Drives a plugin the way the bridge would: requests go in through
+/// on_message and whatever the plugin sends back is collected.
+pub struct MockHost {
+  plugin: Box<dyn Plugin>,
+  channels: Channels,
+  tx: Responder,
+  rx: Receiver,
+  timeout: Duration,
+  next_id: u64
+}
+
+/// An example whose live response didn't match the published one.
+#[derive(Debug)]
+pub struct ContractFailure {
+  pub method: String,
+  pub params: Value,
+  pub expected: Value,
+  pub actual: Option<Value>
+}
+
+impl fmt::Display for ContractFailure {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match &self.actual {
+      Some(actual) => write!(f, "{}({}): expected {} got {}", self.method, self.params,
+        self.expected, actual),
+      None => write!(f, "{}({}): expected {} got no response", self.method, self.params,
+        self.expected)
+    }
+  }
+}
+
+impl MockHost {
+  pub fn new(plugin: Box<dyn Plugin>) -> Self {
+    let (tx, rx) = responder::queue(plugin.queue_limit());
+    MockHost {
+      plugin,
+      channels: Channels::new(),
+      tx,
+      rx,
+      timeout: Duration::from_secs(1),
+      next_id: 1
+    }
+  }
+
+  /// How long to wait for the plugin to respond to a request.
+  pub fn with_timeout(mut self, timeout: Duration) -> Self {
+    self.timeout = timeout;
+    self
+  }
+
+  pub fn connect(&mut self, channel: u32) {
+    self.channels.connect(channel);
+    self.plugin.on_client_connect(channel);
+  }
+
+  pub fn disconnect(&mut self, channel: u32) {
+    self.channels.disconnect(channel);
+    self.plugin.on_client_disconnect(channel);
+  }
+
+  /// Drops every client at once, as when Thunder restarts.
+  pub fn reset(&mut self, reason: &str) {
+    let channels = self.channels.disconnect_all();
+    crate::events::prune_all();
+    self.plugin.on_all_clients_disconnected(&channels, reason);
+  }
+
+  /// Pushes new configuration, as Thunder would to a running plugin.
+  pub fn config_changed(&mut self, config: Value) {
+    self.plugin.on_config_changed(&config);
+  }
+
+  /// Tells the plugin it's about to be destroyed, as a stopping host would.
+  pub fn shutdown(&mut self) {
+    self.plugin.on_shutdown();
+  }
+
+  /// Sends a raw request and waits for the first message the plugin sends
+  /// back on that channel.
+  pub fn invoke(&mut self, channel: u32, json: &str) -> Option<String> {
+    while self.rx.try_recv().is_some() { }
+
+    let ctx = self.channels.receive(channel, String::new(), self.tx.clone(), json.len());
+    crate::deliver(self.plugin.as_mut(), std::borrow::Cow::Borrowed(json), ctx);
+
+    loop {
+      match self.rx.recv_timeout(self.timeout) {
+        Some(m) if m.channel == channel => return Some(String::from_utf8_lossy(&m.data).into_owned()),
+        Some(_) => continue,
+        None => return None
+      }
+    }
+  }
+
+  /// Calls `method` with `params` and returns the parsed response.
+  pub fn call(&mut self, channel: u32, method: &str, params: Value) -> Option<Value> {
+    let id = self.next_id;
+    self.next_id += 1;
+    let req = json!({
+      "jsonrpc": "2.0",
+      "id": id,
+      "method": method,
+      "params": params
+    });
+    let res = self.invoke(channel, &req.to_string())?;
+    serde_json::from_str(&res).ok()
+  }
+
+  /// Runs every example against the plugin and returns the ones whose
+  /// response differs from what was published.
+  pub fn contract_test(&mut self, examples: &[(String, Example)]) -> Vec<ContractFailure> {
+    let mut failures = Vec::new();
+    for (method, example) in examples {
+      let expected = match &example.response {
+        Ok(result) => json!({ "result": result }),
+        Err(e) => json!({ "error": e.to_value() })
+      };
+      let actual = self.call(0, method, example.params.clone())
+        .map(|mut res| {
+          if let Some(obj) = res.as_object_mut() {
+            obj.remove("jsonrpc");
+            obj.remove("id");
+          }
+          res
+        });
+      if actual.as_ref() != Some(&expected) {
+        failures.push(ContractFailure {
+          method: method.clone(),
+          params: example.params.clone(),
+          expected,
+          actual
+        });
+      }
+    }
+    failures
+  }
+}
+
+/// A RequestContext for calling a handler directly in a unit test, with
+/// everything sent through it captured. Events subscribed with the context
+/// are captured the same way.
+pub struct FakeContext {
+  channels: Channels,
+  ctx: RequestContext,
+  rx: Receiver
+}
+
+/// A fake context on channel 1 with no token.
+pub fn fake_context() -> FakeContext {
+  FakeContext::builder().build()
+}
+
+pub struct FakeContextBuilder {
+  channel: u32,
+  auth_token: String,
+  framework: Framework,
+  features: Features,
+  paths: Paths,
+  codec: Codec
+}
+
+impl FakeContextBuilder {
+  pub fn channel(mut self, channel: u32) -> Self {
+    self.channel = channel;
+    self
+  }
+
+  pub fn auth_token(mut self, auth_token: &str) -> Self {
+    self.auth_token = auth_token.to_string();
+    self
+  }
+
+  /// Lets framework calls made by the handler go to a test double.
+  pub fn framework(mut self, framework: Framework) -> Self {
+    self.framework = framework;
+    self
+  }
+
+  /// Feature flags the handler sees through ctx.feature().
+  pub fn features(mut self, features: Features) -> Self {
+    self.features = features;
+    self
+  }
+
+  /// Directories the handler sees through ctx.paths(), e.g. a temp dir.
+  pub fn paths(mut self, paths: Paths) -> Self {
+    self.paths = paths;
+    self
+  }
+
+  /// The codec the handler sees through ctx.codec(), as if a bridge had
+  /// selected it.
+  pub fn codec(mut self, codec: Codec) -> Self {
+    self.codec = codec;
+    self
+  }
+
+  pub fn build(self) -> FakeContext {
+    let (tx, rx) = responder::queue(Default::default());
+    let mut channels = Channels::new();
+    channels.set_framework(self.framework);
+    channels.set_features(self.features);
+    channels.set_paths(self.paths);
+    channels.set_codec(self.codec);
+    channels.connect(self.channel);
+    let ctx = channels.context(self.channel, self.auth_token, tx);
+    FakeContext { channels, ctx, rx }
+  }
+}
+
+impl FakeContext {
+  pub fn builder() -> FakeContextBuilder {
+    FakeContextBuilder {
+      channel: 1,
+      auth_token: String::new(),
+      framework: Framework::unavailable(),
+      features: Features::new(),
+      paths: Paths::default(),
+      codec: Codec::Json
+    }
+  }
+
+  /// The context to hand to the code under test. Every call returns a
+  /// handle on the same channel.
+  pub fn context(&self) -> RequestContext {
+    self.ctx.clone()
+  }
+
+  /// Everything sent since the last call, oldest first.
+  pub fn sent(&self) -> Vec<Message> {
+    std::iter::from_fn(|| self.rx.try_recv()).collect()
+  }
+
+  /// Like sent() but parsed as JSON, skipping binary and unparseable messages.
+  pub fn sent_json(&self) -> Vec<Value> {
+    self.sent().iter()
+      .filter_map(|m| m.as_str().and_then(|s| serde_json::from_str(s).ok()))
+      .collect()
+  }
+
+  /// Marks the channel disconnected, so is_connected() turns false and
+  /// further sends fail.
+  pub fn disconnect(&mut self) {
+    self.channels.disconnect(self.ctx.channel);
+  }
+}
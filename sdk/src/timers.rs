@@ -0,0 +1,193 @@
+/*
+ * Copyright 2022 Comcast Cable Communications Management, LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+//! One-shot and repeating timers run on a thread owned by the SDK, so
+//! plugins don't need their own threads for periodic work.
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use log::error;
+
+enum Task {
+  Once(Box<dyn FnOnce() + Send>),
+  Repeat(Duration, Box<dyn FnMut() + Send>)
+}
+
+#[derive(Default)]
+struct State {
+  next_id: u64,
+  queue: BTreeMap<(Instant, u64), Task>,
+  due: HashMap<u64, Instant>,
+  // The timer whose callback is running right now, and whether it was
+  // cancelled while it ran
+  running: Option<(u64, bool)>,
+  stopped: bool
+}
+
+#[derive(Default)]
+struct Inner {
+  state: Mutex<State>,
+  wakeup: Condvar,
+  thread: Mutex<Option<JoinHandle<()>>>
+}
+
+/// The plugin's timers, available from RequestContext::timers(). All of
+/// them are cancelled when the plugin is destroyed.
+#[derive(Clone, Default)]
+pub struct Timers {
+  inner: Arc<Inner>
+}
+
+/// Identifies a scheduled timer so it can be cancelled.
+#[derive(Clone)]
+pub struct TimerHandle {
+  id: u64,
+  inner: Arc<Inner>
+}
+
+impl TimerHandle {
+  pub fn cancel(&self) {
+    let mut state = self.inner.state.lock().unwrap();
+    if let Some(due) = state.due.remove(&self.id) {
+      state.queue.remove(&(due, self.id));
+    }
+    if let Some((id, cancelled)) = &mut state.running {
+      if *id == self.id {
+        *cancelled = true;
+      }
+    }
+  }
+}
+
+impl Timers {
+  pub fn new() -> Self {
+    Timers::default()
+  }
+
+  /// Runs `f` once after `delay`.
+  pub fn schedule<F>(&self, delay: Duration, f: F) -> TimerHandle
+    where F: FnOnce() + Send + 'static
+  {
+    self.insert(delay, Task::Once(Box::new(f)))
+  }
+
+  /// Runs `f` every `interval` until cancelled.
+  pub fn schedule_repeating<F>(&self, interval: Duration, f: F) -> TimerHandle
+    where F: FnMut() + Send + 'static
+  {
+    self.insert(interval, Task::Repeat(interval, Box::new(f)))
+  }
+
+  fn insert(&self, delay: Duration, task: Task) -> TimerHandle {
+    let mut state = self.inner.state.lock().unwrap();
+    state.next_id += 1;
+    let id = state.next_id;
+    if state.stopped {
+      error!("timer scheduled after shutdown, it will never run");
+    } else {
+      let due = Instant::now() + delay;
+      state.queue.insert((due, id), task);
+      state.due.insert(id, due);
+      self.start();
+      self.inner.wakeup.notify_one();
+    }
+    TimerHandle {
+      id,
+      inner: self.inner.clone()
+    }
+  }
+
+  fn start(&self) {
+    let mut thread = self.inner.thread.lock().unwrap();
+    if thread.is_none() {
+      let inner = self.inner.clone();
+      *thread = Some(std::thread::Builder::new()
+        .name(String::from("thunder-rs-timers"))
+        .spawn(move || run(&inner))
+        .expect("failed to spawn timer thread"));
+    }
+  }
+
+  /// Cancels every timer and waits for a callback that's running to finish.
+  pub fn shutdown(&self) {
+    {
+      let mut state = self.inner.state.lock().unwrap();
+      state.stopped = true;
+      state.queue.clear();
+      state.due.clear();
+    }
+    self.inner.wakeup.notify_one();
+    if let Some(thread) = self.inner.thread.lock().unwrap().take() {
+      let _ = thread.join();
+    }
+  }
+}
+
+fn run(inner: &Inner) {
+  let mut state = inner.state.lock().unwrap();
+  loop {
+    if state.stopped {
+      return;
+    }
+    let (due, id) = match state.queue.keys().next() {
+      Some(key) => *key,
+      None => {
+        state = inner.wakeup.wait(state).unwrap();
+        continue;
+      }
+    };
+    let now = Instant::now();
+    if due > now {
+      state = inner.wakeup.wait_timeout(state, due - now).unwrap().0;
+      continue;
+    }
+
+    let task = state.queue.remove(&(due, id)).unwrap();
+    state.due.remove(&id);
+    state.running = Some((id, false));
+    drop(state);
+
+    let again = match task {
+      Task::Once(f) => {
+        call(f);
+        None
+      }
+      Task::Repeat(interval, mut f) => {
+        call(&mut f);
+        Some((interval, f))
+      }
+    };
+
+    state = inner.state.lock().unwrap();
+    let cancelled = matches!(state.running.take(), Some((_, true)));
+    if let Some((interval, f)) = again {
+      if !cancelled && !state.stopped {
+        let next = due + interval;
+        state.queue.insert((next, id), Task::Repeat(interval, f));
+        state.due.insert(id, next);
+      }
+    }
+  }
+}
+
+fn call(f: impl FnOnce()) {
+  if let Err(cause) = std::panic::catch_unwind(std::panic::AssertUnwindSafe(f)) {
+    error!("Error calling timer callback: {:?}", cause);
+  }
+}
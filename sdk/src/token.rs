@@ -0,0 +1,152 @@
+/*
+ * Copyright 2022 Comcast Cable Communications Management, LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+//! Reading the Thunder security token handed to plugins with each request.
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use serde_json::Value;
+
+/// Decodes the payload of a JWT style token. The signature isn't checked;
+/// Thunder's SecurityAgent already did that before the request reached us.
+pub(crate) fn payload(token: &str) -> Option<Value> {
+  let part = token.split('.').nth(1)?;
+  let bytes = URL_SAFE_NO_PAD.decode(part.trim_end_matches('=')).ok()?;
+  serde_json::from_slice(&bytes).ok()
+}
+
+/// Whether the token carries `claim`, either as a top-level field set to
+/// true or as an entry in a "claims" list equal to it.
+pub(crate) fn has_claim(token: &str, claim: &str) -> bool {
+  match payload(token) {
+    Some(p) => payload_has_claim(&p, claim),
+    None => false
+  }
+}
+
+fn payload_has_claim(payload: &Value, claim: &str) -> bool {
+  if let Some(claims) = payload["claims"].as_array() {
+    if claims.iter().any(|c| c.as_str() == Some(claim)) {
+      return true;
+    }
+  }
+  payload.get(claim) == Some(&Value::Bool(true))
+}
+
+/// What a client's security token says about it. Thunder issues tokens to
+/// applications by URL, so `url` and `host` identify the application.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TokenClaims {
+  /// The URL the token was issued for
+  pub url: Option<String>,
+  /// The token's host claim, or the host part of `url` without it
+  pub host: Option<String>,
+  /// Entries of the token's "claims" list
+  pub claims: Vec<String>,
+  /// The whole payload, for claims not covered above
+  pub payload: Value
+}
+
+impl TokenClaims {
+  /// Decodes `token`, or None if it isn't a token.
+  pub fn parse(token: &str) -> Option<Self> {
+    let payload = payload(token)?;
+    let url = payload["url"].as_str().map(String::from);
+    let host = payload["host"].as_str().map(String::from)
+      .or_else(|| url.as_deref().and_then(url_host).map(String::from));
+    let claims = payload["claims"].as_array()
+      .map(|c| c.iter().filter_map(|c| c.as_str().map(String::from)).collect())
+      .unwrap_or_default();
+    Some(TokenClaims { url, host, claims, payload })
+  }
+
+  /// Same rules as Events::require_claim and Features::require_claim.
+  pub fn has(&self, claim: &str) -> bool {
+    payload_has_claim(&self.payload, claim)
+  }
+}
+
+/// The host of `url`, without scheme, credentials or port.
+fn url_host(url: &str) -> Option<&str> {
+  let rest = url.split_once("://").map_or(url, |(_, rest)| rest);
+  let authority = rest.split(['/', '?', '#']).next()?;
+  let host = authority.rsplit_once('@').map_or(authority, |(_, host)| host);
+  let host = match host.strip_prefix('[') {
+    Some(v6) => v6.split(']').next()?,
+    None => host.split(':').next()?
+  };
+  Some(host).filter(|h| !h.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn token(payload: Value) -> String {
+    format!("header.{}.signature", URL_SAFE_NO_PAD.encode(payload.to_string()))
+  }
+
+  #[test]
+  fn claims_come_from_the_list_or_a_field() {
+    let token = token(serde_json::json!({ "claims": ["camera"], "admin": true, "guest": false }));
+    assert!(has_claim(&token, "camera"));
+    assert!(has_claim(&token, "admin"));
+    assert!(!has_claim(&token, "guest"));
+    assert!(!has_claim(&token, "location"));
+  }
+
+  #[test]
+  fn claims_match_exactly() {
+    let token = token(serde_json::json!({
+      "claims": ["camera", "Location"],
+      "admin": true,
+      "debug": "yes",
+      "beta": 1,
+      "guest": false
+    }));
+    assert!(has_claim(&token, "camera"));
+    assert!(has_claim(&token, "Location"));
+    assert!(has_claim(&token, "admin"));
+    for claim in ["location", "camera ", "cam", "debug", "beta", "guest", "claims", ""] {
+      assert!(!has_claim(&token, claim), "{:?} matched", claim);
+    }
+  }
+
+  #[test]
+  fn anything_but_a_token_has_no_claims() {
+    assert!(!has_claim("", "admin"));
+    assert!(!has_claim("not a token", "admin"));
+    assert!(!has_claim("a.!!!.c", "admin"));
+  }
+
+  #[test]
+  fn host_comes_from_the_url_without_a_host_claim() {
+    let claims = TokenClaims::parse(&token(serde_json::json!({ "url": "https://user@app.example:8080/ui" }))).unwrap();
+    assert_eq!(claims.host.as_deref(), Some("app.example"));
+    let claims = TokenClaims::parse(&token(serde_json::json!({ "url": "http://[::1]:80/", "host": "other" }))).unwrap();
+    assert_eq!(claims.host.as_deref(), Some("other"));
+    assert_eq!(url_host("http://[::1]:80/"), Some("::1"));
+  }
+
+  #[test]
+  fn parsed_claims_keep_the_list_and_payload() {
+    let claims = TokenClaims::parse(&token(serde_json::json!({ "claims": ["camera", 5], "admin": true }))).unwrap();
+    assert_eq!(claims.claims, ["camera"]);
+    assert!(claims.has("camera") && claims.has("admin"));
+    assert_eq!(claims.url, None);
+    assert!(TokenClaims::parse("not a token").is_none());
+  }
+}
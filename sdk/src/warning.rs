@@ -0,0 +1,47 @@
+/*
+ * Copyright 2022 Comcast Cable Communications Management, LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+//! Categories for Thunder's WarningReporting, the health warnings plugins
+//! raise when something takes longer than it should.
+
+/// A warning category. The named ones match the categories Thunder's C++
+/// plugins report, so they're aggregated together; Custom is for anything
+/// plugin specific.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Warning {
+  /// A request took too long to handle
+  TooLongInvoke,
+  TooLongWaitingForLock,
+  /// Activation or deactivation took too long
+  TooLongPluginState,
+  /// Queued work took too long to finish
+  JobTooLongToFinish,
+  Custom(String)
+}
+
+impl Warning {
+  /// The category name Thunder files the warning under.
+  pub fn category(&self) -> &str {
+    match self {
+      Warning::TooLongInvoke => "TooLongInvokeRPC",
+      Warning::TooLongWaitingForLock => "TooLongWaitingForLock",
+      Warning::TooLongPluginState => "TooLongPluginState",
+      Warning::JobTooLongToFinish => "JobTooLongToFinish",
+      Warning::Custom(name) => name
+    }
+  }
+}
@@ -0,0 +1,371 @@
+/*
+ * Copyright 2022 Comcast Cable Communications Management, LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+//! Plain HTTP requests Thunder routes to the plugin under its callsign,
+//! e.g. GET /Service/Callsign/status, next to JSON-RPC. Plugins serve them
+//! by returning a WebHandler from Plugin::web_handler.
+//!
+//! Large bodies don't have to be held in memory: a handler can take an
+//! upload chunk by chunk through an UploadSink, and a response can stream
+//! its body from any Read, e.g. WebResponse::file.
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use log::error;
+use serde_json::Value;
+
+use crate::{CatchPanic, RequestContext};
+
+#[derive(Debug, Clone, Default)]
+pub struct WebRequest {
+  pub method: String,
+  /// The path below the plugin's callsign, with any query string
+  pub path: String,
+  pub headers: Vec<(String, String)>,
+  pub body: Vec<u8>
+}
+
+impl WebRequest {
+  /// The first header called `name`, ignoring case.
+  pub fn header(&self, name: &str) -> Option<&str> {
+    self.headers.iter()
+      .find(|(n, _)| n.eq_ignore_ascii_case(name))
+      .map(|(_, v)| v.as_str())
+  }
+
+  /// The body as text, if it's valid UTF-8.
+  pub fn body_str(&self) -> Option<&str> {
+    std::str::from_utf8(&self.body).ok()
+  }
+}
+
+/// How much of a streamed body is read and passed on at a time.
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+/// A response body read as it's sent.
+pub struct BodyStream(Box<dyn Read + Send>);
+
+impl fmt::Debug for BodyStream {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str("BodyStream")
+  }
+}
+
+#[derive(Debug)]
+pub struct WebResponse {
+  pub status: u16,
+  pub headers: Vec<(String, String)>,
+  pub body: Vec<u8>,
+  /// Sent after `body`, CHUNK_SIZE bytes at a time
+  pub stream: Option<BodyStream>
+}
+
+impl WebResponse {
+  pub fn new(status: u16) -> Self {
+    WebResponse { status, headers: Vec::new(), body: Vec::new(), stream: None }
+  }
+
+  /// Serves the file at `path`, read as it's sent, with its length as
+  /// Content-Length.
+  pub fn file(path: impl AsRef<Path>) -> io::Result<Self> {
+    let file = File::open(path)?;
+    let len = file.metadata()?.len();
+    Ok(WebResponse::ok()
+      .header("Content-Length", &len.to_string())
+      .stream(file))
+  }
+
+  pub fn ok() -> Self {
+    WebResponse::new(200)
+  }
+
+  pub fn not_found() -> Self {
+    WebResponse::new(404)
+  }
+
+  pub fn header(mut self, name: &str, value: &str) -> Self {
+    self.headers.push((name.to_string(), value.to_string()));
+    self
+  }
+
+  pub fn body(mut self, body: impl Into<Vec<u8>>) -> Self {
+    self.body = body.into();
+    self
+  }
+
+  /// Sets `value` as the body, with its Content-Type.
+  pub fn json(self, value: &Value) -> Self {
+    self.header("Content-Type", "application/json").body(value.to_string())
+  }
+
+  /// Streams the body from `reader` instead of holding it in memory.
+  pub fn stream(mut self, reader: impl Read + Send + 'static) -> Self {
+    self.stream = Some(BodyStream(Box::new(reader)));
+    self
+  }
+
+  pub fn is_streamed(&self) -> bool {
+    self.stream.is_some()
+  }
+
+  /// Passes the body to `f` a chunk at a time, `body` first and then the
+  /// stream. A stream that fails or panics part way through ends the body
+  /// early, as the status has gone out by then.
+  pub fn write_chunks(&mut self, f: impl FnMut(&[u8])) {
+    self.write_chunks_in(crate::catch_panic, f)
+  }
+
+  /// Like write_chunks, catching a panic in the stream with `catch`.
+  pub fn write_chunks_in(&mut self, catch: CatchPanic, mut f: impl FnMut(&[u8])) {
+    if !self.body.is_empty() {
+      f(&self.body);
+    }
+    let Some(BodyStream(reader)) = &mut self.stream else { return };
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    loop {
+      match catching(catch, || reader.read(&mut buf)) {
+        Ok(Ok(0)) => break,
+        Ok(Ok(n)) => f(&buf[..n]),
+        Ok(Err(e)) if e.kind() == io::ErrorKind::Interrupted => continue,
+        Ok(Err(e)) => {
+          error!("response body failed part way: {}", e);
+          break;
+        }
+        Err(cause) => {
+          error!("response body panicked part way: {}", cause);
+          break;
+        }
+      }
+    }
+  }
+
+  /// Reads a streamed body into `body`, for bridges that can't stream.
+  pub fn into_buffered(mut self) -> Self {
+    if self.stream.is_some() {
+      let mut body = Vec::new();
+      self.write_chunks(|chunk| body.extend_from_slice(chunk));
+      self.body = body;
+      self.stream = None;
+    }
+    self
+  }
+}
+
+/// Takes a request body chunk by chunk as it arrives.
+pub trait UploadSink: Send {
+  fn write(&mut self, chunk: &[u8]) -> io::Result<()>;
+
+  /// Called once the whole body has arrived, for the response.
+  fn finish(self: Box<Self>, ctx: &RequestContext) -> WebResponse;
+}
+
+/// An UploadSink writing the body to a file. Answers 201 once it's all on
+/// disk.
+pub struct FileUpload {
+  file: BufWriter<File>,
+  path: PathBuf
+}
+
+impl FileUpload {
+  pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+    Ok(FileUpload {
+      file: BufWriter::new(File::create(path.as_ref())?),
+      path: path.as_ref().to_path_buf()
+    })
+  }
+}
+
+impl UploadSink for FileUpload {
+  fn write(&mut self, chunk: &[u8]) -> io::Result<()> {
+    self.file.write_all(chunk)
+  }
+
+  fn finish(mut self: Box<Self>, _ctx: &RequestContext) -> WebResponse {
+    match self.file.flush() {
+      Ok(()) => WebResponse::new(201),
+      Err(e) => {
+        error!("failed to write upload to {}: {}", self.path.display(), e);
+        WebResponse::new(500)
+      }
+    }
+  }
+}
+
+/// Serves HTTP requests. Like ConcurrentPlugin it takes `&self`, as it's
+/// called on the bridge's thread without holding the plugin's lock.
+pub trait WebHandler: Send + Sync {
+  fn handle(&self, req: &WebRequest, ctx: &RequestContext) -> WebResponse;
+
+  /// Called instead of handle for a request whose body the bridge streams,
+  /// with `req.body` empty. Returning None collects the body and calls
+  /// handle as usual.
+  fn upload(&self, _req: &WebRequest, _ctx: &RequestContext) -> Option<Box<dyn UploadSink>> {
+    None
+  }
+}
+
+/// Runs `req` through the plugin's web handler. Plugins without one get
+/// 501, and a panicking handler 500.
+pub fn dispatch(handler: Option<Arc<dyn WebHandler>>, req: &WebRequest, ctx: &RequestContext) -> WebResponse {
+  dispatch_in(crate::catch_panic, handler, req, ctx)
+}
+
+/// Like dispatch, catching a panic with `catch`. A host calling a plugin
+/// library passes the library's ServiceMetadata::catch_panic.
+pub fn dispatch_in(catch: CatchPanic, handler: Option<Arc<dyn WebHandler>>, req: &WebRequest,
+  ctx: &RequestContext) -> WebResponse
+{
+  let Some(handler) = handler else {
+    return WebResponse::new(501);
+  };
+  catching(catch, || handler.handle(req, ctx))
+    .unwrap_or_else(|cause| {
+      error!("web handler panicked on {} {}: {}", req.method, req.path, cause);
+      WebResponse::new(500)
+    })
+}
+
+/// Runs `f` inside `catch`, returning what it returns.
+fn catching<T>(catch: CatchPanic, f: impl FnOnce() -> T) -> Result<T, String> {
+  let mut f = Some(f);
+  let mut out = None;
+  catch(&mut || {
+    if let Some(f) = f.take() {
+      out = Some(f());
+    }
+  })?;
+  out.ok_or_else(|| String::from("handler panicked"))
+}
+
+/// The most of a streamed body collected for a handler without an
+/// UploadSink. Larger bodies are answered with 413.
+pub const MAX_BUFFERED_BODY: usize = 16 * 1024 * 1024;
+
+/// A request whose body arrives in chunks, going to the handler's
+/// UploadSink if it has one and collected, up to MAX_BUFFERED_BODY,
+/// otherwise.
+pub struct Upload {
+  handler: Option<Arc<dyn WebHandler>>,
+  req: WebRequest,
+  ctx: RequestContext,
+  sink: Option<Box<dyn UploadSink>>,
+  catch: CatchPanic,
+  // The status to answer with once the body has failed
+  failed: Option<u16>
+}
+
+impl Upload {
+  pub fn begin(handler: Option<Arc<dyn WebHandler>>, req: WebRequest, ctx: RequestContext) -> Self {
+    Upload::begin_in(crate::catch_panic, handler, req, ctx)
+  }
+
+  /// Like begin, catching a panic in the handler or its sink with `catch`
+  /// from then on.
+  pub fn begin_in(catch: CatchPanic, handler: Option<Arc<dyn WebHandler>>, req: WebRequest,
+    ctx: RequestContext) -> Self
+  {
+    let sink = handler.as_ref().and_then(|handler| {
+      catching(catch, || handler.upload(&req, &ctx))
+        .unwrap_or_else(|cause| {
+          error!("web handler panicked starting upload to {}: {}", req.path, cause);
+          None
+        })
+    });
+    Upload { handler, req, ctx, sink, catch, failed: None }
+  }
+
+  /// The channel the request came in on.
+  pub fn channel(&self) -> u32 {
+    self.ctx.channel
+  }
+
+  pub fn write(&mut self, chunk: &[u8]) {
+    if self.failed.is_some() {
+      return;
+    }
+    let Some(sink) = &mut self.sink else {
+      if self.req.body.len() + chunk.len() > MAX_BUFFERED_BODY {
+        error!("body of upload to {} is over {} bytes and there's no sink for it",
+          self.req.path, MAX_BUFFERED_BODY);
+        self.req.body = Vec::new();
+        self.failed = Some(413);
+        return;
+      }
+      self.req.body.extend_from_slice(chunk);
+      return;
+    };
+    let result = catching(self.catch, || sink.write(chunk));
+    match result {
+      Ok(Ok(())) => (),
+      Ok(Err(e)) => {
+        error!("upload to {} failed: {}", self.req.path, e);
+        self.failed = Some(500);
+      }
+      Err(cause) => {
+        error!("web handler panicked during upload to {}: {}", self.req.path, cause);
+        self.failed = Some(500);
+      }
+    }
+  }
+
+  /// The response, once the whole body has been written. 500 if the sink
+  /// failed along the way, 413 if the body was too big to collect.
+  pub fn finish(self) -> WebResponse {
+    if let Some(status) = self.failed {
+      return WebResponse::new(status);
+    }
+    match self.sink {
+      Some(sink) => {
+        let ctx = &self.ctx;
+        catching(self.catch, || sink.finish(ctx))
+          .unwrap_or_else(|cause| {
+            error!("web handler panicked finishing upload to {}: {}", self.req.path, cause);
+            WebResponse::new(500)
+          })
+      }
+      None => dispatch_in(self.catch, self.handler, &self.req, &self.ctx)
+    }
+  }
+}
+
+/// Headers as bridges pass them across FFI: "Name: value" lines ending in
+/// CRLF.
+pub fn parse_headers(text: &str) -> Vec<(String, String)> {
+  text.split("\r\n")
+    .filter_map(|line| line.split_once(':'))
+    .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+    .collect()
+}
+
+/// Formats headers for bridges, failing on a name or value with a CR, LF
+/// or NUL in it, which would let it add headers of its own.
+pub fn format_headers(headers: &[(String, String)]) -> Result<String, String> {
+  check_headers(headers)?;
+  Ok(headers.iter().map(|(name, value)| format!("{}: {}\r\n", name, value)).collect())
+}
+
+/// Fails on a header name or value with a CR, LF or NUL in it.
+pub fn check_headers(headers: &[(String, String)]) -> Result<(), String> {
+  let bad = |s: &str| s.contains(['\r', '\n', '\0']);
+  match headers.iter().find(|(name, value)| bad(name) || bad(value)) {
+    Some((name, _)) => Err(format!("header {:?} has a line break or NUL in it", name)),
+    None => Ok(())
+  }
+}
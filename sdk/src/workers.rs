@@ -0,0 +1,125 @@
+/*
+ * Copyright 2022 Comcast Cable Communications Management, LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+//! A fixed pool of threads for running plugin callbacks off the caller's
+//! thread. Work is keyed by channel and every key always lands on the same
+//! worker, so messages from one client are handled in the order they came in.
+use std::sync::mpsc;
+use std::thread::{self, JoinHandle};
+
+use log::error;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+pub struct WorkerPool {
+  queues: Vec<mpsc::Sender<Job>>,
+  handles: Vec<JoinHandle<()>>
+}
+
+impl WorkerPool {
+  pub fn new(workers: usize) -> Self {
+    assert!(workers > 0, "a worker pool needs at least one worker");
+
+    let mut queues = Vec::with_capacity(workers);
+    let mut handles = Vec::with_capacity(workers);
+    for i in 0..workers {
+      let (tx, rx) = mpsc::channel::<Job>();
+      let handle = thread::Builder::new()
+        .name(format!("thunder-rs-worker-{}", i))
+        .spawn(move || {
+          while let Ok(job) = rx.recv() {
+            if let Err(cause) = std::panic::catch_unwind(std::panic::AssertUnwindSafe(job)) {
+              error!("Error in worker {}: {:?}", i, cause);
+            }
+          }
+        })
+        .expect("failed to spawn worker thread");
+      queues.push(tx);
+      handles.push(handle);
+    }
+
+    WorkerPool { queues, handles }
+  }
+
+  pub fn workers(&self) -> usize {
+    self.queues.len()
+  }
+
+  /// Queues `job` on the worker that owns `key`.
+  pub fn submit(&self, key: u32, job: impl FnOnce() + Send + 'static) {
+    let queue = &self.queues[key as usize % self.queues.len()];
+    if queue.send(Box::new(job)).is_err() {
+      error!("worker for key {} has stopped, dropping job", key);
+    }
+  }
+}
+
+impl Drop for WorkerPool {
+  /// Lets the workers finish what's queued and waits for them.
+  fn drop(&mut self) {
+    self.queues.clear();
+    for handle in self.handles.drain(..) {
+      let _ = handle.join();
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::sync::{Arc, Mutex};
+
+  #[test]
+  fn jobs_for_a_key_run_in_order() {
+    let pool = WorkerPool::new(4);
+    let seen: Arc<Mutex<Vec<(u32, u32)>>> = Arc::default();
+    for i in 0..100 {
+      for key in 0..8 {
+        let seen = seen.clone();
+        pool.submit(key, move || seen.lock().unwrap().push((key, i)));
+      }
+    }
+    drop(pool);
+    let seen = seen.lock().unwrap();
+    assert_eq!(seen.len(), 800);
+    for key in 0..8 {
+      let order: Vec<u32> = seen.iter().filter(|(k, _)| *k == key).map(|(_, i)| *i).collect();
+      assert_eq!(order, (0..100).collect::<Vec<_>>());
+    }
+  }
+
+  #[test]
+  fn a_panicking_job_doesnt_stop_its_worker() {
+    let pool = WorkerPool::new(1);
+    let (tx, rx) = mpsc::channel();
+    pool.submit(0, || panic!("job failed"));
+    pool.submit(0, move || tx.send(()).unwrap());
+    assert!(rx.recv_timeout(std::time::Duration::from_secs(5)).is_ok());
+  }
+
+  #[test]
+  fn keys_spread_over_the_workers() {
+    let pool = WorkerPool::new(2);
+    let (tx, rx) = mpsc::channel();
+    let (release, wait) = mpsc::channel::<()>();
+    // Worker 0 is held up, which mustn't hold up key 1
+    pool.submit(0, move || { let _ = wait.recv(); });
+    pool.submit(1, move || tx.send(()).unwrap());
+    assert!(rx.recv_timeout(std::time::Duration::from_secs(5)).is_ok());
+    release.send(()).unwrap();
+  }
+}
@@ -0,0 +1,39 @@
+/*
+ * Copyright 2022 Comcast Cable Communications Management, LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+fn main() {
+  #[cfg(feature = "protobuf")]
+  protobuf();
+}
+
+/// Generates the protobuf codec's messages, and with grpc the Host service,
+/// from proto/wire.proto, parsing it with protox so building doesn't need
+/// protoc.
+#[cfg(feature = "protobuf")]
+fn protobuf() {
+  println!("cargo:rerun-if-changed=proto/wire.proto");
+  let files = protox::compile(["proto/wire.proto"], ["proto"]).expect("proto/wire.proto compiles");
+  #[cfg(feature = "grpc")]
+  tonic_build::configure()
+    .compile_fds(files)
+    .expect("gRPC service generates");
+  #[cfg(not(feature = "grpc"))]
+  prost_build::Config::new()
+    .compile_fds(files)
+    .expect("protobuf messages generate");
+}
@@ -0,0 +1,166 @@
+/*
+ * Copyright 2022 Comcast Cable Communications Management, LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+//! Frames as a u32 big-endian length followed by the frame encoded with
+//! bincode's default, fixed width little-endian options. The length is
+//! checked against Limits before the frame is read, and a frame that is too
+//! long or doesn't decode is skipped whole, so the stream stays in step.
+use std::io::{self, Read};
+
+use crate::*;
+
+// Command::Route's variant index. A route's own variant and plugin take 8
+// bytes, and its command's variant follows.
+const ROUTE_VARIANT: u32 = 19;
+
+/// Reads the next command. As with legacy::read_command, a BadFrame was read
+/// whole and an error means the connection can't be read further.
+pub fn read_command(stream: &mut impl Read, limits: &Limits) -> io::Result<Result<Command, BadFrame>> {
+  let frame = match read_frame(stream, limits)? {
+    Ok(frame) => frame,
+    Err(problem) => return Ok(Err(BadFrame { command_id: 0, problem }))
+  };
+  // serde decodes a routed command recursively with no limit on the depth,
+  // so a route in a route is refused before the frame is decoded
+  if variant(&frame, 0) == Some(ROUTE_VARIANT) && variant(&frame, 8) == Some(ROUTE_VARIANT) {
+    return Ok(Err(BadFrame { command_id: ID_ROUTE, problem: String::from("routed frame is routed again") }));
+  }
+  let command: Command = match ::bincode::deserialize(&frame) {
+    Ok(command) => command,
+    Err(e) => return Ok(Err(BadFrame { command_id: 0, problem: format!("frame doesn't decode: {}", e) }))
+  };
+  Ok(check_limits(command, limits))
+}
+
+/// The command as a frame.
+pub fn encode_command(command: &Command) -> Vec<u8> {
+  frame(command)
+}
+
+/// The response as a frame.
+pub fn encode_response(response: &Response) -> Vec<u8> {
+  frame(response)
+}
+
+/// Reads the next response, for tools standing in for Thunder.
+pub fn read_response(stream: &mut impl Read) -> io::Result<Response> {
+  let mut len = [0; 4];
+  stream.read_exact(&mut len)?;
+  let mut frame = vec![0u8; u32::from_be_bytes(len) as usize];
+  stream.read_exact(&mut frame)?;
+  ::bincode::deserialize(&frame).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+fn frame<T: Serialize>(value: &T) -> Vec<u8> {
+  // Only the writer can fail, and a Vec doesn't
+  let encoded = ::bincode::serialize(value).expect("frame encodes");
+  let mut frame = Vec::with_capacity(4 + encoded.len());
+  frame.extend_from_slice(&(encoded.len() as u32).to_be_bytes());
+  frame.extend_from_slice(&encoded);
+  frame
+}
+
+/// The u32 variant index at `at` in an encoded frame.
+fn variant(frame: &[u8], at: usize) -> Option<u32> {
+  let bytes = frame.get(at..at + 4)?;
+  Some(u32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn invoke(token: &str, json: &str) -> Command {
+    Command::Invoke { channel: 7, token: token.to_string(), json: json.to_string() }
+  }
+
+  fn small() -> Limits {
+    Limits { max_token: 8, max_payload: 16 }
+  }
+
+  #[test]
+  fn reads_what_it_encodes() {
+    let command = Command::Route { plugin: 1, command: Box::new(invoke("token", "{\"id\":1}")) };
+    let frame = encode_command(&command);
+    assert_eq!(read_command(&mut frame.as_slice(), &Limits::default()).unwrap(), Ok(command));
+  }
+
+  #[test]
+  fn frame_over_the_limit_is_skipped_in_step() {
+    let max = 8 + 16 + FRAME_OVERHEAD;
+    let mut stream = encode_command(&invoke("token", &"x".repeat(max as usize)));
+    stream.extend(encode_command(&Command::Ping { seq: 1 }));
+    let mut stream = stream.as_slice();
+
+    let bad = read_command(&mut stream, &small()).unwrap().unwrap_err();
+    assert!(bad.problem.ends_with(&format!("over the limit of {}", max)), "{}", bad.problem);
+    assert_eq!(read_command(&mut stream, &small()).unwrap(), Ok(Command::Ping { seq: 1 }));
+  }
+
+  #[test]
+  fn token_over_its_limit_is_a_bad_frame() {
+    let frame = encode_command(&invoke("a token too long", "{}"));
+    let bad = read_command(&mut frame.as_slice(), &small()).unwrap().unwrap_err();
+    assert_eq!(bad.command_id, ID_INVOKE);
+    assert_eq!(bad.problem, "token of 16 bytes is over the limit of 8");
+  }
+
+  #[test]
+  fn frame_that_doesnt_decode_is_skipped_in_step() {
+    let mut stream = vec![0, 0, 0, 3, 0xff, 0xff, 0xff];
+    stream.extend(encode_command(&Command::Ping { seq: 1 }));
+    let mut stream = stream.as_slice();
+
+    let bad = read_command(&mut stream, &Limits::default()).unwrap().unwrap_err();
+    assert!(bad.problem.starts_with("frame doesn't decode"), "{}", bad.problem);
+    assert_eq!(read_command(&mut stream, &Limits::default()).unwrap(), Ok(Command::Ping { seq: 1 }));
+  }
+
+  #[test]
+  fn route_in_a_route_is_a_bad_frame() {
+    let inner = Command::Route { plugin: 1, command: Box::new(Command::Ping { seq: 1 }) };
+    let frame = encode_command(&Command::Route { plugin: 0, command: Box::new(inner) });
+    let bad = read_command(&mut frame.as_slice(), &Limits::default()).unwrap().unwrap_err();
+    assert_eq!(bad.problem, "routed frame is routed again");
+  }
+
+  #[test]
+  fn route_variant_is_the_one_encoded() {
+    let frame = ::bincode::serialize(&Command::Route { plugin: 1, command: Box::new(Command::Exit) }).unwrap();
+    assert_eq!(variant(&frame, 0), Some(ROUTE_VARIANT));
+    assert_eq!(variant(&frame, 8), Some(2));
+  }
+
+  #[test]
+  fn deep_chain_of_routes_is_refused_without_decoding() {
+    let mut encoded = Vec::new();
+    for _ in 0..200_000 {
+      encoded.extend_from_slice(&ROUTE_VARIANT.to_le_bytes());
+      encoded.extend_from_slice(&0u32.to_le_bytes());
+    }
+    encoded.extend_from_slice(&2u32.to_le_bytes());
+    let mut stream = (encoded.len() as u32).to_be_bytes().to_vec();
+    stream.extend(encoded);
+    stream.extend(encode_command(&Command::Ping { seq: 1 }));
+    let mut stream = stream.as_slice();
+
+    let bad = read_command(&mut stream, &Limits::default()).unwrap().unwrap_err();
+    assert_eq!(bad.command_id, ID_ROUTE);
+    assert_eq!(bad.problem, "routed frame is routed again");
+    assert_eq!(read_command(&mut stream, &Limits::default()).unwrap(), Ok(Command::Ping { seq: 1 }));
+  }
+}
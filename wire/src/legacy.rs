@@ -0,0 +1,490 @@
+/*
+ * Copyright 2022 Comcast Cable Communications Management, LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+//! The frame format every bridge speaks. Commands start with a u32 command
+//! id, followed by their fields; integers are big-endian, and variable
+//! length fields come after the u32 giving their length.
+//!
+//! Every read is exact, and length prefixed fields are checked against
+//! Limits before anything is allocated: a field over its limit, or text
+//! that isn't UTF-8, is skipped on the wire so the stream stays in step,
+//! and the frame is reported as bad instead of being handled.
+use std::io::{self, Read};
+
+use crate::*;
+
+/// Reads the next command. Frames that can't be handled but were read whole
+/// come back as a BadFrame; an error means the connection is gone or out of
+/// step and can't be read further.
+pub fn read_command(stream: &mut impl Read, limits: &Limits) -> io::Result<Result<Command, BadFrame>> {
+  read_frame(stream, limits, false)
+}
+
+/// Reads a frame, `routed` when it's the one a route frame carries.
+fn read_frame(stream: &mut impl Read, limits: &Limits, routed: bool) -> io::Result<Result<Command, BadFrame>> {
+  let mut frame = FrameReader::new(stream, limits);
+
+  let command_id = frame.u32("command_id")?;
+
+  let command = if command_id == ID_INVOKE {
+    let channel = frame.u32("channel")?;
+    let token_len = frame.u32("token_len")?;
+    let json_len = frame.u32("json_len")?;
+    let token = frame.token(token_len)?;
+    let json = frame.text("json", json_len)?;
+    Command::Invoke { channel, token, json }
+  } else if command_id == ID_ATTACH {
+    let channel = frame.u32("channel")?;
+    let attach = frame.u8("attach")? != 0;
+    Command::Attach { channel, attach }
+  } else if command_id == ID_INVOKE_BINARY || command_id == ID_INVOKE_RAW {
+    let channel = frame.u32("channel")?;
+    let token_len = frame.u32("token_len")?;
+    let data_len = frame.u32("data_len")?;
+    let token = frame.token(token_len)?;
+    let data = frame.bytes("data", data_len)?;
+    if command_id == ID_INVOKE_RAW {
+      Command::InvokeRaw { channel, token, data }
+    } else {
+      Command::InvokeBinary { channel, token, data }
+    }
+  } else if command_id == ID_DUMP_JOURNAL {
+    let path_len = frame.u32("path_len")?;
+    let path = frame.text("path", path_len)?;
+    Command::DumpJournal { path }
+  } else if command_id == ID_CALL_RESULT {
+    let id = frame.u32("call_id")?;
+    let json_len = frame.u32("json_len")?;
+    let json = frame.text("json", json_len)?;
+    Command::CallResult { id, json }
+  } else if command_id == ID_SUBSYSTEM {
+    let subsystem = frame.u32("subsystem")?;
+    let active = frame.u8("active")? != 0;
+    Command::Subsystem { subsystem, active }
+  } else if command_id == ID_MEMORY {
+    Command::Memory
+  } else if command_id == ID_RESET {
+    let reason_len = frame.u32("reason_len")?;
+    let reason = frame.lossy_text("reason", reason_len)?;
+    Command::Reset { reason }
+  } else if command_id == ID_METRICS {
+    Command::Metrics
+  } else if command_id == ID_CONFIG_CHANGED {
+    let json_len = frame.u32("json_len")?;
+    let json = frame.lossy_text("json", json_len)?;
+    Command::ConfigChanged { json }
+  } else if command_id == ID_PEER {
+    let channel = frame.u32("channel")?;
+    let channel_type = frame.u32("channel_type")?;
+    let address_len = frame.u32("address_len")?;
+    let address = frame.lossy_text("address", address_len)?;
+    let origin_len = frame.u32("origin_len")?;
+    let origin = frame.lossy_text("origin", origin_len)?;
+    Command::Peer { channel, channel_type, address, origin }
+  } else if command_id == ID_WEB_REQUEST {
+    let id = frame.u32("id")?;
+    let channel = frame.u32("channel")?;
+    let token_len = frame.u32("token_len")?;
+    let token = frame.token(token_len)?;
+    let method_len = frame.u32("method_len")?;
+    let method = frame.lossy_text("method", method_len)?;
+    let path_len = frame.u32("path_len")?;
+    let path = frame.lossy_text("path", path_len)?;
+    let headers_len = frame.u32("headers_len")?;
+    let headers = frame.lossy_text("headers", headers_len)?;
+    let body_len = frame.u32("body_len")?;
+    let body = if body_len == STREAMED_BODY { None } else { Some(frame.bytes("body", body_len)?) };
+    Command::WebRequest { id, channel, token, method, path, headers, body }
+  } else if command_id == ID_WEB_BODY {
+    let id = frame.u32("id")?;
+    let data_len = frame.u32("data_len")?;
+    let data = frame.bytes("data", data_len)?;
+    Command::WebBody { id, data }
+  } else if command_id == ID_HELLO {
+    let version = frame.u32("version")?;
+    let json_len = frame.u32("json_len")?;
+    let json = frame.text("json", json_len)?;
+    Command::Hello { version, json }
+  } else if command_id == ID_CONFIG {
+    let json_len = frame.u32("json_len")?;
+    let json = frame.text("json", json_len)?;
+    Command::Config { json }
+  } else if command_id == ID_PING {
+    Command::Ping { seq: frame.u32("seq")? }
+  } else if command_id == ID_PONG {
+    Command::Pong { seq: frame.u32("seq")? }
+  } else if command_id == ID_ROUTE {
+    // Routes could nest without end, and there's no telling where they stop
+    if routed {
+      return Err(io::Error::new(io::ErrorKind::InvalidData, "routed frame is routed again"));
+    }
+    let plugin = frame.u32("plugin")?;
+    // The routed frame follows whole, so a bad one doesn't lose the stream
+    return Ok(read_frame(stream, limits, true)?
+      .map(|command| Command::Route { plugin, command: Box::new(command) }));
+  } else if CUSTOM_IDS.contains(&command_id) {
+    let data_len = frame.u32("data_len")?;
+    let data = frame.bytes("data", data_len)?;
+    Command::Custom { id: command_id, data }
+  } else if command_id == ID_EXIT {
+    Command::Exit
+  } else {
+    // Without knowing the frame's shape there's no telling where the next
+    // one starts
+    return Err(io::Error::new(io::ErrorKind::InvalidData, format!("invalid command_id {}", command_id)));
+  };
+
+  Ok(match frame.problem() {
+    Some(problem) => Err(BadFrame { command_id, problem }),
+    None => Ok(command)
+  })
+}
+
+/// The command as a frame.
+pub fn encode_command(command: &Command) -> Vec<u8> {
+  let mut frame = Vec::new();
+  write_command(&mut frame, command);
+  frame
+}
+
+fn write_command(frame: &mut Vec<u8>, command: &Command) {
+  let u32 = |frame: &mut Vec<u8>, n: u32| frame.extend_from_slice(&n.to_be_bytes());
+  let field = |frame: &mut Vec<u8>, data: &[u8]| {
+    frame.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    frame.extend_from_slice(data);
+  };
+  match command {
+    Command::Invoke { channel, token, json } => {
+      u32(frame, ID_INVOKE);
+      u32(frame, *channel);
+      u32(frame, token.len() as u32);
+      u32(frame, json.len() as u32);
+      frame.extend_from_slice(token.as_bytes());
+      frame.extend_from_slice(json.as_bytes());
+    }
+    Command::Attach { channel, attach } => {
+      u32(frame, ID_ATTACH);
+      u32(frame, *channel);
+      frame.push(*attach as u8);
+    }
+    Command::Exit => u32(frame, ID_EXIT),
+    Command::InvokeBinary { channel, token, data } | Command::InvokeRaw { channel, token, data } => {
+      u32(frame, if matches!(command, Command::InvokeRaw { .. }) { ID_INVOKE_RAW } else { ID_INVOKE_BINARY });
+      u32(frame, *channel);
+      u32(frame, token.len() as u32);
+      u32(frame, data.len() as u32);
+      frame.extend_from_slice(token.as_bytes());
+      frame.extend_from_slice(data);
+    }
+    Command::DumpJournal { path } => {
+      u32(frame, ID_DUMP_JOURNAL);
+      field(frame, path.as_bytes());
+    }
+    Command::CallResult { id, json } => {
+      u32(frame, ID_CALL_RESULT);
+      u32(frame, *id);
+      field(frame, json.as_bytes());
+    }
+    Command::Subsystem { subsystem, active } => {
+      u32(frame, ID_SUBSYSTEM);
+      u32(frame, *subsystem);
+      frame.push(*active as u8);
+    }
+    Command::Memory => u32(frame, ID_MEMORY),
+    Command::Reset { reason } => {
+      u32(frame, ID_RESET);
+      field(frame, reason.as_bytes());
+    }
+    Command::Metrics => u32(frame, ID_METRICS),
+    Command::Peer { channel, channel_type, address, origin } => {
+      u32(frame, ID_PEER);
+      u32(frame, *channel);
+      u32(frame, *channel_type);
+      field(frame, address.as_bytes());
+      field(frame, origin.as_bytes());
+    }
+    Command::ConfigChanged { json } => {
+      u32(frame, ID_CONFIG_CHANGED);
+      field(frame, json.as_bytes());
+    }
+    Command::WebRequest { id, channel, token, method, path, headers, body } => {
+      u32(frame, ID_WEB_REQUEST);
+      u32(frame, *id);
+      u32(frame, *channel);
+      field(frame, token.as_bytes());
+      field(frame, method.as_bytes());
+      field(frame, path.as_bytes());
+      field(frame, headers.as_bytes());
+      match body {
+        Some(body) => field(frame, body),
+        None => u32(frame, STREAMED_BODY)
+      }
+    }
+    Command::WebBody { id, data } => {
+      u32(frame, ID_WEB_BODY);
+      u32(frame, *id);
+      field(frame, data);
+    }
+    Command::Hello { version, json } => {
+      u32(frame, ID_HELLO);
+      u32(frame, *version);
+      field(frame, json.as_bytes());
+    }
+    Command::Config { json } => {
+      u32(frame, ID_CONFIG);
+      field(frame, json.as_bytes());
+    }
+    Command::Ping { seq } => {
+      u32(frame, ID_PING);
+      u32(frame, *seq);
+    }
+    Command::Pong { seq } => {
+      u32(frame, ID_PONG);
+      u32(frame, *seq);
+    }
+    Command::Route { plugin, command } => {
+      u32(frame, ID_ROUTE);
+      u32(frame, *plugin);
+      write_command(frame, command);
+    }
+    Command::Custom { id, data } => {
+      u32(frame, *id);
+      field(frame, data);
+    }
+  }
+}
+
+/// The response as a frame: its channel, then the payload's length with
+/// the flags, the plugin's id if it isn't the first, and the payload.
+pub fn encode_response(response: &Response) -> Vec<u8> {
+  let mut len_word = response.payload.len() as u32;
+  match response.kind {
+    Kind::Binary => len_word |= FLAG_BINARY,
+    Kind::Raw => len_word |= FLAG_RAW,
+    Kind::Text => ()
+  }
+  if response.plugin != 0 {
+    len_word |= FLAG_PLUGIN;
+  }
+  if response.compressed {
+    len_word |= FLAG_COMPRESSED;
+  }
+
+  let mut frame = Vec::with_capacity(12 + response.payload.len());
+  frame.extend_from_slice(&response.channel.to_be_bytes());
+  frame.extend_from_slice(&len_word.to_be_bytes());
+  if response.plugin != 0 {
+    frame.extend_from_slice(&response.plugin.to_be_bytes());
+  }
+  frame.extend_from_slice(&response.payload);
+  frame
+}
+
+/// Reads the next response, for tools standing in for Thunder.
+pub fn read_response(stream: &mut impl Read) -> io::Result<Response> {
+  let mut buf = [0; 4];
+  stream.read_exact(&mut buf)?;
+  let channel = u32::from_be_bytes(buf);
+  stream.read_exact(&mut buf)?;
+  let len_word = u32::from_be_bytes(buf);
+  let plugin = if len_word & FLAG_PLUGIN != 0 {
+    stream.read_exact(&mut buf)?;
+    u32::from_be_bytes(buf)
+  } else {
+    0
+  };
+  let kind = if len_word & FLAG_BINARY != 0 {
+    Kind::Binary
+  } else if len_word & FLAG_RAW != 0 {
+    Kind::Raw
+  } else {
+    Kind::Text
+  };
+  let mut payload = vec![0u8; (len_word & LEN_MASK) as usize];
+  stream.read_exact(&mut payload)?;
+  Ok(Response { channel, plugin, kind, compressed: len_word & FLAG_COMPRESSED != 0, payload })
+}
+
+struct FrameReader<'a, R> {
+  stream: &'a mut R,
+  limits: &'a Limits,
+  problem: Option<String>
+}
+
+impl<'a, R: Read> FrameReader<'a, R> {
+  fn new(stream: &'a mut R, limits: &'a Limits) -> Self {
+    FrameReader { stream, limits, problem: None }
+  }
+
+  fn u32(&mut self, what: &str) -> io::Result<u32> {
+    let mut buf = [0; 4];
+    self.stream.read_exact(&mut buf).map_err(|e| context(e, what))?;
+    Ok(u32::from_be_bytes(buf))
+  }
+
+  fn u8(&mut self, what: &str) -> io::Result<u8> {
+    let mut buf = [0; 1];
+    self.stream.read_exact(&mut buf).map_err(|e| context(e, what))?;
+    Ok(buf[0])
+  }
+
+  /// `len` raw bytes, empty if they were over the payload limit.
+  fn bytes(&mut self, what: &str, len: u32) -> io::Result<Vec<u8>> {
+    let max = self.limits.max_payload;
+    Ok(self.take(what, len, max)?.unwrap_or_default())
+  }
+
+  /// A security token, which must be UTF-8.
+  fn token(&mut self, len: u32) -> io::Result<String> {
+    let max = self.limits.max_token;
+    let data = self.take("token", len, max)?;
+    Ok(self.utf8("token", data))
+  }
+
+  /// Text that must be UTF-8, e.g. a JSON-RPC request.
+  fn text(&mut self, what: &str, len: u32) -> io::Result<String> {
+    let max = self.limits.max_payload;
+    let data = self.take(what, len, max)?;
+    Ok(self.utf8(what, data))
+  }
+
+  /// Text only logged or matched on, where bad UTF-8 is replaced.
+  fn lossy_text(&mut self, what: &str, len: u32) -> io::Result<String> {
+    let max = self.limits.max_payload;
+    Ok(self.take(what, len, max)?
+      .map(|data| String::from_utf8_lossy(&data).into_owned())
+      .unwrap_or_default())
+  }
+
+  /// What was wrong with the frame, once all of it has been read. None if
+  /// it can be handled.
+  fn problem(self) -> Option<String> {
+    self.problem
+  }
+
+  fn take(&mut self, what: &str, len: u32, max: u32) -> io::Result<Option<Vec<u8>>> {
+    if len > max {
+      let skipped = io::copy(&mut (&mut *self.stream).take(len as u64), &mut io::sink())?;
+      if skipped < len as u64 {
+        return Err(context(io::ErrorKind::UnexpectedEof.into(), what));
+      }
+      self.problem.get_or_insert_with(|| format!("{} of {} bytes is over the limit of {}", what, len, max));
+      return Ok(None);
+    }
+    let mut data = vec![0u8; len as usize];
+    self.stream.read_exact(&mut data).map_err(|e| context(e, what))?;
+    Ok(Some(data))
+  }
+
+  fn utf8(&mut self, what: &str, data: Option<Vec<u8>>) -> String {
+    match String::from_utf8(data.unwrap_or_default()) {
+      Ok(text) => text,
+      Err(_) => {
+        self.problem.get_or_insert_with(|| format!("{} is not valid UTF-8", what));
+        String::new()
+      }
+    }
+  }
+}
+
+fn context(e: io::Error, what: &str) -> io::Error {
+  io::Error::new(e.kind(), format!("failed to read {}: {}", what, e))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn invoke(token: &str, json: &str) -> Command {
+    Command::Invoke { channel: 7, token: token.to_string(), json: json.to_string() }
+  }
+
+  fn small() -> Limits {
+    Limits { max_token: 8, max_payload: 16 }
+  }
+
+  #[test]
+  fn reads_what_it_encodes() {
+    let command = invoke("token", "{\"id\":1}");
+    let frame = encode_command(&command);
+    assert_eq!(read_command(&mut frame.as_slice(), &Limits::default()).unwrap(), Ok(command));
+  }
+
+  #[test]
+  fn field_over_its_limit_is_skipped_in_step() {
+    let mut stream = encode_command(&invoke("a token too long", "{}"));
+    stream.extend(encode_command(&invoke("token", &"x".repeat(17))));
+    stream.extend(encode_command(&Command::Ping { seq: 1 }));
+    let mut stream = stream.as_slice();
+
+    let bad = read_command(&mut stream, &small()).unwrap().unwrap_err();
+    assert_eq!(bad.command_id, ID_INVOKE);
+    assert_eq!(bad.problem, "token of 16 bytes is over the limit of 8");
+    let bad = read_command(&mut stream, &small()).unwrap().unwrap_err();
+    assert_eq!(bad.problem, "json of 17 bytes is over the limit of 16");
+    assert_eq!(read_command(&mut stream, &small()).unwrap(), Ok(Command::Ping { seq: 1 }));
+  }
+
+  #[test]
+  fn field_at_its_limit_is_read() {
+    let command = invoke("12345678", &"x".repeat(16));
+    let frame = encode_command(&command);
+    assert_eq!(read_command(&mut frame.as_slice(), &small()).unwrap(), Ok(command));
+  }
+
+  #[test]
+  fn bad_utf8_is_a_bad_frame() {
+    let mut frame = Vec::new();
+    frame.extend_from_slice(&ID_CONFIG.to_be_bytes());
+    frame.extend_from_slice(&2u32.to_be_bytes());
+    frame.extend_from_slice(&[0xff, 0xfe]);
+    let bad = read_command(&mut frame.as_slice(), &Limits::default()).unwrap().unwrap_err();
+    assert_eq!(bad.problem, "json is not valid UTF-8");
+  }
+
+  #[test]
+  fn routed_command_is_read() {
+    let command = Command::Route { plugin: 2, command: Box::new(invoke("token", "{}")) };
+    let frame = encode_command(&command);
+    assert_eq!(read_command(&mut frame.as_slice(), &Limits::default()).unwrap(), Ok(command));
+  }
+
+  #[test]
+  fn route_in_a_route_is_refused() {
+    let mut frame = Vec::new();
+    for _ in 0..3 {
+      frame.extend_from_slice(&ID_ROUTE.to_be_bytes());
+      frame.extend_from_slice(&1u32.to_be_bytes());
+    }
+    let e = read_command(&mut frame.as_slice(), &Limits::default()).unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+  }
+
+  #[test]
+  fn unknown_command_is_an_error() {
+    let frame = 0xdead_beefu32.to_be_bytes();
+    let e = read_command(&mut frame.as_slice(), &Limits::default()).unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+  }
+
+  #[test]
+  fn truncated_frame_is_an_error() {
+    let frame = encode_command(&invoke("token", "{}"));
+    let e = read_command(&mut &frame[..frame.len() - 1], &Limits::default()).unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+  }
+}
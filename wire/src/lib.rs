@@ -0,0 +1,317 @@
+/*
+ * Copyright 2022 Comcast Cable Communications Management, LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+//! The frames exchanged between Thunder and WPEHost, as types, with the two
+//! ways of putting them on the wire:
+//!
+//! * `legacy`, the hand laid out big-endian format every bridge speaks,
+//!   which the C++ bridge's test suite checks against `WPEHost
+//!   --protocol-doc`
+//! * `bincode`, each frame a u32 length followed by the frame encoded with
+//!   bincode, used once both sides agree to bincode_frames in the handshake
+//!
+//! * `protobuf`, with the protobuf feature: each frame a u32 length followed
+//!   by a message from proto/wire.proto, used once both sides agree to
+//!   protobuf_frames
+//!
+//! Kept apart from the host so tools that record, replay or inject frames
+//! speak exactly what the host does.
+use std::io::{self, Read};
+
+use serde::{Deserialize, Serialize};
+
+pub mod bincode;
+pub mod legacy;
+#[cfg(feature = "protobuf")]
+pub mod protobuf;
+
+pub const ID_INVOKE:      u32 = 1;
+pub const ID_ATTACH:      u32 = 2;
+pub const ID_EXIT:        u32 = 3;
+pub const ID_INVOKE_BINARY: u32 = 4;
+pub const ID_DUMP_JOURNAL: u32 = 5;
+pub const ID_CALL_RESULT: u32 = 6;
+pub const ID_SUBSYSTEM:   u32 = 7;
+pub const ID_MEMORY:      u32 = 8;
+pub const ID_RESET:       u32 = 9;
+pub const ID_METRICS:     u32 = 10;
+pub const ID_PEER:        u32 = 11;
+pub const ID_CONFIG_CHANGED: u32 = 12;
+pub const ID_WEB_REQUEST:  u32 = 13;
+pub const ID_WEB_BODY:     u32 = 14;
+pub const ID_INVOKE_RAW:   u32 = 15;
+pub const ID_HELLO:       u32 = 16;
+pub const ID_CONFIG:      u32 = 17;
+pub const ID_PING:        u32 = 18;
+pub const ID_PONG:        u32 = 19;
+pub const ID_ROUTE:       u32 = 20;
+
+/// Vendor defined commands, passed through to the plugin. The same range as
+/// thunder_rs::framework::CUSTOM_FRAME_IDS.
+pub const CUSTOM_IDS: std::ops::RangeInclusive<u32> = 0x1000_0000..=0x1fff_ffff;
+
+// Set in the length word of an outgoing frame whose payload is a binary
+// WebSocket frame rather than JSON text.
+pub const FLAG_BINARY:    u32 = 0x8000_0000;
+
+// Set in the length word of an outgoing frame whose payload is raw bytes for
+// a client on a raw socket channel.
+pub const FLAG_RAW:       u32 = 0x4000_0000;
+
+// Set in the length word of an outgoing frame from any plugin but the first;
+// the plugin's id follows the length word.
+pub const FLAG_PLUGIN:    u32 = 0x2000_0000;
+
+// Set in the length word of an outgoing frame whose payload is compressed
+// with the algorithm agreed in the handshake.
+pub const FLAG_COMPRESSED: u32 = 0x1000_0000;
+
+// What's left of the length word for the length.
+pub const LEN_MASK:       u32 = 0x0fff_ffff;
+
+// A web_request body_len meaning the body follows in web_body frames.
+pub const STREAMED_BODY:  u32 = u32::MAX;
+
+// Outgoing frames on this channel are JSON control messages for the bridge
+// rather than responses to a client.
+pub const CONTROL_CHANNEL: u32 = u32::MAX;
+
+/// The largest fields a reader accepts.
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+  /// Security tokens
+  pub max_token: u32,
+  /// Everything else: requests, binary data, bodies
+  pub max_payload: u32
+}
+
+impl Default for Limits {
+  fn default() -> Self {
+    Limits {
+      max_token: 64 * 1024,
+      max_payload: 16 * 1024 * 1024
+    }
+  }
+}
+
+/// A frame from Thunder to the host. Bincode numbers the variants in order,
+/// so new ones only ever go at the end.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Command {
+  Invoke { channel: u32, token: String, json: String },
+  Attach { channel: u32, attach: bool },
+  Exit,
+  InvokeBinary { channel: u32, token: String, #[serde(with = "serde_bytes")] data: Vec<u8> },
+  DumpJournal { path: String },
+  CallResult { id: u32, json: String },
+  Subsystem { subsystem: u32, active: bool },
+  Memory,
+  Reset { reason: String },
+  Metrics,
+  Peer { channel: u32, channel_type: u32, address: String, origin: String },
+  ConfigChanged { json: String },
+  /// `body` is None when it follows in WebBody frames
+  WebRequest {
+    id: u32,
+    channel: u32,
+    token: String,
+    method: String,
+    path: String,
+    headers: String,
+    #[serde(with = "serde_bytes")]
+    body: Option<Vec<u8>>
+  },
+  WebBody { id: u32, #[serde(with = "serde_bytes")] data: Vec<u8> },
+  InvokeRaw { channel: u32, token: String, #[serde(with = "serde_bytes")] data: Vec<u8> },
+  Hello { version: u32, json: String },
+  Config { json: String },
+  Ping { seq: u32 },
+  Pong { seq: u32 },
+  /// A command for plugin `plugin` of several
+  Route { plugin: u32, command: Box<Command> },
+  /// An id in CUSTOM_IDS
+  Custom { id: u32, #[serde(with = "serde_bytes")] data: Vec<u8> }
+}
+
+impl Command {
+  /// The command's id in the legacy format.
+  pub fn id(&self) -> u32 {
+    match self {
+      Command::Invoke { .. } => ID_INVOKE,
+      Command::Attach { .. } => ID_ATTACH,
+      Command::Exit => ID_EXIT,
+      Command::InvokeBinary { .. } => ID_INVOKE_BINARY,
+      Command::DumpJournal { .. } => ID_DUMP_JOURNAL,
+      Command::CallResult { .. } => ID_CALL_RESULT,
+      Command::Subsystem { .. } => ID_SUBSYSTEM,
+      Command::Memory => ID_MEMORY,
+      Command::Reset { .. } => ID_RESET,
+      Command::Metrics => ID_METRICS,
+      Command::Peer { .. } => ID_PEER,
+      Command::ConfigChanged { .. } => ID_CONFIG_CHANGED,
+      Command::WebRequest { .. } => ID_WEB_REQUEST,
+      Command::WebBody { .. } => ID_WEB_BODY,
+      Command::InvokeRaw { .. } => ID_INVOKE_RAW,
+      Command::Hello { .. } => ID_HELLO,
+      Command::Config { .. } => ID_CONFIG,
+      Command::Ping { .. } => ID_PING,
+      Command::Pong { .. } => ID_PONG,
+      Command::Route { .. } => ID_ROUTE,
+      Command::Custom { id, .. } => *id
+    }
+  }
+}
+
+/// A frame read whole that can't be handled, e.g. with a field over its
+/// limit. The stream is still in step.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BadFrame {
+  pub command_id: u32,
+  pub problem: String
+}
+
+impl std::fmt::Display for BadFrame {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "command {}: {}", self.command_id, self.problem)
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Kind {
+  Text,
+  Binary,
+  Raw
+}
+
+/// The only frame the host sends: a message for a client, or a control
+/// message on CONTROL_CHANNEL.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Response {
+  pub channel: u32,
+  /// The sending plugin, 0 for the first
+  pub plugin: u32,
+  pub kind: Kind,
+  /// The payload is compressed with the agreed algorithm
+  pub compressed: bool,
+  #[serde(with = "serde_bytes")]
+  pub payload: Vec<u8>
+}
+
+// Room for a frame's fixed fields and length words on top of its largest
+// field, for the length prefixed codecs.
+const FRAME_OVERHEAD: u32 = 4096;
+
+/// Reads a length prefixed frame, skipping it if it's over the limits.
+fn read_frame(stream: &mut impl Read, limits: &Limits) -> io::Result<Result<Vec<u8>, String>> {
+  let mut len = [0; 4];
+  stream.read_exact(&mut len).map_err(|e| io::Error::new(e.kind(), format!("failed to read frame length: {}", e)))?;
+  let len = u32::from_be_bytes(len);
+  let max = limits.max_payload.saturating_add(limits.max_token).saturating_add(FRAME_OVERHEAD);
+  if len > max {
+    let skipped = io::copy(&mut stream.take(len as u64), &mut io::sink())?;
+    if skipped < len as u64 {
+      return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    return Ok(Err(format!("frame of {} bytes is over the limit of {}", len, max)));
+  }
+  let mut frame = vec![0u8; len as usize];
+  stream.read_exact(&mut frame).map_err(|e| io::Error::new(e.kind(), format!("failed to read frame: {}", e)))?;
+  Ok(Ok(frame))
+}
+
+/// Holds the decoded command to the same limits as a legacy frame.
+fn check_limits(command: Command, limits: &Limits) -> Result<Command, BadFrame> {
+  let command_id = command.id();
+  let token_len = match command {
+    Command::Route { plugin, command } => {
+      if let Command::Route { .. } = *command {
+        return Err(BadFrame { command_id, problem: String::from("routed frame is routed again") });
+      }
+      return check_limits(*command, limits).map(|command| Command::Route { plugin, command: Box::new(command) });
+    }
+    Command::Invoke { ref token, .. }
+    | Command::InvokeBinary { ref token, .. }
+    | Command::InvokeRaw { ref token, .. }
+    | Command::WebRequest { ref token, .. } => token.len(),
+    _ => 0
+  };
+  if token_len > limits.max_token as usize {
+    return Err(BadFrame {
+      command_id,
+      problem: format!("token of {} bytes is over the limit of {}", token_len, limits.max_token)
+    });
+  }
+  Ok(command)
+}
+
+/// How frames are put on the wire. Every connection starts with Legacy.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Codec {
+  #[default]
+  Legacy,
+  Bincode,
+  #[cfg(feature = "protobuf")]
+  Protobuf
+}
+
+impl Codec {
+  /// The codec's name in the codec control message.
+  pub fn name(self) -> &'static str {
+    match self {
+      Codec::Legacy => "legacy",
+      Codec::Bincode => "bincode",
+      #[cfg(feature = "protobuf")]
+      Codec::Protobuf => "protobuf"
+    }
+  }
+
+  pub fn read_command(self, stream: &mut impl Read, limits: &Limits) -> io::Result<Result<Command, BadFrame>> {
+    match self {
+      Codec::Legacy => legacy::read_command(stream, limits),
+      Codec::Bincode => bincode::read_command(stream, limits),
+      #[cfg(feature = "protobuf")]
+      Codec::Protobuf => protobuf::read_command(stream, limits)
+    }
+  }
+
+  pub fn encode_command(self, command: &Command) -> Vec<u8> {
+    match self {
+      Codec::Legacy => legacy::encode_command(command),
+      Codec::Bincode => bincode::encode_command(command),
+      #[cfg(feature = "protobuf")]
+      Codec::Protobuf => protobuf::encode_command(command)
+    }
+  }
+
+  pub fn read_response(self, stream: &mut impl Read) -> io::Result<Response> {
+    match self {
+      Codec::Legacy => legacy::read_response(stream),
+      Codec::Bincode => bincode::read_response(stream),
+      #[cfg(feature = "protobuf")]
+      Codec::Protobuf => protobuf::read_response(stream)
+    }
+  }
+
+  pub fn encode_response(self, response: &Response) -> Vec<u8> {
+    match self {
+      Codec::Legacy => legacy::encode_response(response),
+      Codec::Bincode => bincode::encode_response(response),
+      #[cfg(feature = "protobuf")]
+      Codec::Protobuf => protobuf::encode_response(response)
+    }
+  }
+}
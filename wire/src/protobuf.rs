@@ -0,0 +1,252 @@
+/*
+ * Copyright 2022 Comcast Cable Communications Management, LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+//! Frames as a u32 big-endian length followed by a protobuf message from
+//! proto/wire.proto, which the bridge generates its own codec from. Read
+//! with the same limits and skipping as the bincode codec.
+use std::io::{self, Read};
+
+use prost::Message;
+
+use crate::*;
+
+/// The messages generated from proto/wire.proto.
+pub mod proto {
+  include!(concat!(env!("OUT_DIR"), "/thunder_rs.wire.rs"));
+}
+
+/// The .proto the codec is generated from, for `WPEHost --protocol-proto`.
+pub const PROTO: &str = include_str!("../proto/wire.proto");
+
+/// Reads the next command. As with legacy::read_command, a BadFrame was read
+/// whole and an error means the connection can't be read further.
+pub fn read_command(stream: &mut impl Read, limits: &Limits) -> io::Result<Result<Command, BadFrame>> {
+  let frame = match read_frame(stream, limits)? {
+    Ok(frame) => frame,
+    Err(problem) => return Ok(Err(BadFrame { command_id: 0, problem }))
+  };
+  let command = proto::Command::decode(frame.as_slice())
+    .map_err(|e| format!("frame doesn't decode: {}", e))
+    .and_then(Command::try_from);
+  Ok(match command {
+    Ok(command) => check_limits(command, limits),
+    Err(problem) => Err(BadFrame { command_id: 0, problem })
+  })
+}
+
+/// The command as a frame.
+pub fn encode_command(command: &Command) -> Vec<u8> {
+  frame(&proto::Command::from(command.clone()))
+}
+
+/// The response as a frame.
+pub fn encode_response(response: &Response) -> Vec<u8> {
+  frame(&proto::Response::from(response.clone()))
+}
+
+/// Reads the next response, for tools standing in for Thunder.
+pub fn read_response(stream: &mut impl Read) -> io::Result<Response> {
+  let mut len = [0; 4];
+  stream.read_exact(&mut len)?;
+  let mut frame = vec![0u8; u32::from_be_bytes(len) as usize];
+  stream.read_exact(&mut frame)?;
+  let response = proto::Response::decode(frame.as_slice())
+    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+  Response::try_from(response).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+fn frame(message: &impl Message) -> Vec<u8> {
+  let len = message.encoded_len();
+  let mut frame = Vec::with_capacity(4 + len);
+  frame.extend_from_slice(&(len as u32).to_be_bytes());
+  // Only running out of room can fail, and a Vec grows
+  message.encode(&mut frame).expect("frame encodes");
+  frame
+}
+
+impl From<Command> for proto::Command {
+  fn from(command: Command) -> Self {
+    use proto::command::Command as C;
+    let command = match command {
+      Command::Invoke { channel, token, json } => C::Invoke(proto::Invoke { channel, token, data: json.into_bytes() }),
+      Command::Attach { channel, attach } => C::Attach(proto::Attach { channel, attach }),
+      Command::Exit => C::Exit(proto::Exit {}),
+      Command::InvokeBinary { channel, token, data } => C::InvokeBinary(proto::Invoke { channel, token, data }),
+      Command::DumpJournal { path } => C::DumpJournal(proto::DumpJournal { path }),
+      Command::CallResult { id, json } => C::CallResult(proto::CallResult { id, json }),
+      Command::Subsystem { subsystem, active } => C::Subsystem(proto::Subsystem { subsystem, active }),
+      Command::Memory => C::Memory(proto::Memory {}),
+      Command::Reset { reason } => C::Reset(proto::Reset { reason }),
+      Command::Metrics => C::Metrics(proto::Metrics {}),
+      Command::Peer { channel, channel_type, address, origin } => {
+        C::Peer(proto::Peer { channel, channel_type, address, origin })
+      }
+      Command::ConfigChanged { json } => C::ConfigChanged(proto::ConfigChanged { json }),
+      Command::WebRequest { id, channel, token, method, path, headers, body } => {
+        C::WebRequest(proto::WebRequest { id, channel, token, method, path, headers, body })
+      }
+      Command::WebBody { id, data } => C::WebBody(proto::WebBody { id, data }),
+      Command::InvokeRaw { channel, token, data } => C::InvokeRaw(proto::Invoke { channel, token, data }),
+      Command::Hello { version, json } => C::Hello(proto::Hello { version, json }),
+      Command::Config { json } => C::Config(proto::Config { json }),
+      Command::Ping { seq } => C::Ping(proto::Ping { seq }),
+      Command::Pong { seq } => C::Pong(proto::Pong { seq }),
+      Command::Route { plugin, command } => C::Route(Box::new(proto::Route {
+        plugin,
+        command: Some(Box::new(proto::Command::from(*command)))
+      })),
+      Command::Custom { id, data } => C::Custom(proto::Custom { id, data })
+    };
+    proto::Command { command: Some(command) }
+  }
+}
+
+/// Fails for what the legacy format couldn't carry either: no command, JSON
+/// that isn't UTF-8, a route without a command, a custom id out of range.
+impl TryFrom<proto::Command> for Command {
+  type Error = String;
+
+  fn try_from(command: proto::Command) -> Result<Self, String> {
+    use proto::command::Command as C;
+    Ok(match command.command.ok_or("command is empty")? {
+      C::Invoke(proto::Invoke { channel, token, data }) => Command::Invoke {
+        channel,
+        token,
+        json: String::from_utf8(data).map_err(|_| "json is not valid UTF-8")?
+      },
+      C::Attach(proto::Attach { channel, attach }) => Command::Attach { channel, attach },
+      C::Exit(_) => Command::Exit,
+      C::InvokeBinary(proto::Invoke { channel, token, data }) => Command::InvokeBinary { channel, token, data },
+      C::DumpJournal(proto::DumpJournal { path }) => Command::DumpJournal { path },
+      C::CallResult(proto::CallResult { id, json }) => Command::CallResult { id, json },
+      C::Subsystem(proto::Subsystem { subsystem, active }) => Command::Subsystem { subsystem, active },
+      C::Memory(_) => Command::Memory,
+      C::Reset(proto::Reset { reason }) => Command::Reset { reason },
+      C::Metrics(_) => Command::Metrics,
+      C::Peer(proto::Peer { channel, channel_type, address, origin }) => {
+        Command::Peer { channel, channel_type, address, origin }
+      }
+      C::ConfigChanged(proto::ConfigChanged { json }) => Command::ConfigChanged { json },
+      C::WebRequest(proto::WebRequest { id, channel, token, method, path, headers, body }) => {
+        Command::WebRequest { id, channel, token, method, path, headers, body }
+      }
+      C::WebBody(proto::WebBody { id, data }) => Command::WebBody { id, data },
+      C::InvokeRaw(proto::Invoke { channel, token, data }) => Command::InvokeRaw { channel, token, data },
+      C::Hello(proto::Hello { version, json }) => Command::Hello { version, json },
+      C::Config(proto::Config { json }) => Command::Config { json },
+      C::Ping(proto::Ping { seq }) => Command::Ping { seq },
+      C::Pong(proto::Pong { seq }) => Command::Pong { seq },
+      C::Route(route) => Command::Route {
+        plugin: route.plugin,
+        command: Box::new(Command::try_from(*route.command.ok_or("route has no command")?)?)
+      },
+      C::Custom(proto::Custom { id, data }) => {
+        if !CUSTOM_IDS.contains(&id) {
+          return Err(format!("custom id {:#x} is outside the custom range", id));
+        }
+        Command::Custom { id, data }
+      }
+    })
+  }
+}
+
+impl From<Response> for proto::Response {
+  fn from(response: Response) -> Self {
+    let kind = match response.kind {
+      Kind::Text => proto::Kind::Text,
+      Kind::Binary => proto::Kind::Binary,
+      Kind::Raw => proto::Kind::Raw
+    };
+    proto::Response {
+      channel: response.channel,
+      plugin: response.plugin,
+      kind: kind as i32,
+      compressed: response.compressed,
+      payload: response.payload
+    }
+  }
+}
+
+impl TryFrom<proto::Response> for Response {
+  type Error = String;
+
+  fn try_from(response: proto::Response) -> Result<Self, String> {
+    let kind = match proto::Kind::try_from(response.kind) {
+      Ok(proto::Kind::Text) => Kind::Text,
+      Ok(proto::Kind::Binary) => Kind::Binary,
+      Ok(proto::Kind::Raw) => Kind::Raw,
+      Err(_) => return Err(format!("unknown kind {}", response.kind))
+    };
+    Ok(Response {
+      channel: response.channel,
+      plugin: response.plugin,
+      kind,
+      compressed: response.compressed,
+      payload: response.payload
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn invoke(token: &str, json: &str) -> Command {
+    Command::Invoke { channel: 7, token: token.to_string(), json: json.to_string() }
+  }
+
+  fn small() -> Limits {
+    Limits { max_token: 8, max_payload: 16 }
+  }
+
+  #[test]
+  fn reads_what_it_encodes() {
+    let command = Command::Route { plugin: 1, command: Box::new(invoke("token", "{\"id\":1}")) };
+    let frame = encode_command(&command);
+    assert_eq!(read_command(&mut frame.as_slice(), &Limits::default()).unwrap(), Ok(command));
+  }
+
+  #[test]
+  fn frame_over_the_limit_is_skipped_in_step() {
+    let max = 8 + 16 + FRAME_OVERHEAD;
+    let mut stream = encode_command(&invoke("token", &"x".repeat(max as usize)));
+    stream.extend(encode_command(&Command::Ping { seq: 1 }));
+    let mut stream = stream.as_slice();
+
+    let bad = read_command(&mut stream, &small()).unwrap().unwrap_err();
+    assert!(bad.problem.ends_with(&format!("over the limit of {}", max)), "{}", bad.problem);
+    assert_eq!(read_command(&mut stream, &small()).unwrap(), Ok(Command::Ping { seq: 1 }));
+  }
+
+  #[test]
+  fn token_over_its_limit_is_a_bad_frame() {
+    let frame = encode_command(&invoke("a token too long", "{}"));
+    let bad = read_command(&mut frame.as_slice(), &small()).unwrap().unwrap_err();
+    assert_eq!(bad.problem, "token of 16 bytes is over the limit of 8");
+  }
+
+  #[test]
+  fn frame_that_doesnt_decode_is_skipped_in_step() {
+    let mut stream = vec![0, 0, 0, 2, 0xff, 0xff];
+    stream.extend(encode_command(&Command::Ping { seq: 1 }));
+    let mut stream = stream.as_slice();
+
+    let bad = read_command(&mut stream, &Limits::default()).unwrap().unwrap_err();
+    assert!(bad.problem.starts_with("frame doesn't decode"), "{}", bad.problem);
+    assert_eq!(read_command(&mut stream, &Limits::default()).unwrap(), Ok(Command::Ping { seq: 1 }));
+  }
+}